@@ -7,11 +7,11 @@ cargo build --release
 # TODO: back this up in a sqlite database or something so we can track progress?
 echo "" > timings.txt
 
-( time ( ./target/release/bf_interpreter ./input/mandelbrot.b opt > out.mandelbrot.txt ) ) 2>> timings.txt
-( time ( ./target/release/bf_interpreter ./input/hanoi.b opt > out.hanoi.txt ) ) 2>> timings.txt
-( time ( ./target/release/bf_interpreter ./input/long.b opt > out.long.txt ) ) 2>> timings.txt
-( time ( ./target/release/bf_interpreter ./input/quine.b opt > out.quine.txt ) ) 2>> timings.txt
-( time ( echo 13333333333337 | ./target/release/bf_interpreter ./input/factor.b opt > out.factor.txt ) ) 2>> timings.txt
-( time ( echo fsdfw4f4fwcv | ./target/release/bf_interpreter ./input/rot13.b opt > out.rot13.txt ) ) 2>> timings.txt
+( time ( ./target/release/bf_interpreter --opt ./input/mandelbrot.b > out.mandelbrot.txt ) ) 2>> timings.txt
+( time ( ./target/release/bf_interpreter --opt ./input/hanoi.b > out.hanoi.txt ) ) 2>> timings.txt
+( time ( ./target/release/bf_interpreter --opt ./input/long.b > out.long.txt ) ) 2>> timings.txt
+( time ( ./target/release/bf_interpreter --opt ./input/quine.b > out.quine.txt ) ) 2>> timings.txt
+( time ( echo 13333333333337 | ./target/release/bf_interpreter --opt ./input/factor.b > out.factor.txt ) ) 2>> timings.txt
+( time ( echo fsdfw4f4fwcv | ./target/release/bf_interpreter --opt ./input/rot13.b > out.rot13.txt ) ) 2>> timings.txt
 
 cat timings.txt
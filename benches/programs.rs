@@ -0,0 +1,79 @@
+//! Parse + optimize time, and run time, for some of the sample programs. Run with `cargo bench`.
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+
+use bf_lib::io::{SliceInput, VecOutput};
+use bf_lib::{OptVM, ThreadedVM};
+
+// Name, source, and what to give it for input
+const PROGRAMS: &[(&str, &str, &[u8])] = &[
+    ("hello_world", include_str!("../input/hello_world.b"), b""),
+    (
+        "rot13",
+        include_str!("../input/rot13.b"),
+        b"The quick brown fox jumps over the lazy dog.\n",
+    ),
+    ("factor", include_str!("../input/factor.b"), b"179424691\n"),
+    ("hanoi", include_str!("../input/hanoi.b"), b""),
+    ("mandelbrot", include_str!("../input/mandelbrot.b"), b""),
+];
+
+fn parse(c: &mut Criterion) {
+    // Otherwise the optimizer's logging is most of what gets measured
+    bf_lib::set_logging(false);
+
+    let mut group = c.benchmark_group("parse");
+    for &(name, source, _) in PROGRAMS {
+        group.bench_function(name, |b| b.iter(|| bf_lib::optimized_parse(source).unwrap()));
+    }
+    group.finish();
+}
+
+fn run(c: &mut Criterion) {
+    bf_lib::set_logging(false);
+
+    let mut group = c.benchmark_group("run");
+    // The slow ones take long enough per run that the default 100 samples would be minutes
+    group.sample_size(10);
+    for &(name, source, input) in PROGRAMS {
+        let code = bf_lib::optimized_parse(source).unwrap();
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || OptVM::new(code.clone()),
+                |vm| {
+                    let mut output = VecOutput::new();
+                    vm.run(&mut SliceInput::new(input), &mut output).unwrap();
+                    output
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+// The same, with ThreadedVM, to compare its dispatch with OptVM's
+fn run_threaded(c: &mut Criterion) {
+    bf_lib::set_logging(false);
+
+    let mut group = c.benchmark_group("run_threaded");
+    group.sample_size(10);
+    for &(name, source, input) in PROGRAMS {
+        let code = bf_lib::optimized_parse(source).unwrap();
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || ThreadedVM::new(code.clone()),
+                |vm| {
+                    let mut output = VecOutput::new();
+                    vm.run(&mut SliceInput::new(input), &mut output).unwrap();
+                    output
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, parse, run, run_threaded);
+criterion_main!(benches);
@@ -0,0 +1,225 @@
+use bf_lib::io::{FlushPolicy, NewlineMode};
+use bf_lib::{OverflowPolicy, ParseOptions, DEFAULT_TAPE_SIZE};
+
+pub(crate) const USAGE: &str =
+    "Usage: cargo run --bin bf_interpreter -- [disasm | emit-c | emit-rust] [--opt | --simple | --verify] [--opt-passes N] [--tape-size N] \
+                                [--max-steps N] [--overflow wrap|saturate|error] \
+                                [--flush byte|newline|exit] [--newlines raw|strip-cr|lf] [--input-file path | --bang-input] \
+                                (infile | --code-file path | --repl)";
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub(crate) enum Backend {
+    #[cfg(feature = "simple")]
+    Simple,
+    Optimized,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub(crate) enum Language {
+    C,
+    Rust,
+}
+
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub(crate) enum Mode {
+    Run {
+        infile: String,
+    },
+    // Print the optimized code instead of running it
+    Disasm {
+        infile: String,
+    },
+    // Print the optimized code as a program in another language, instead of running it
+    Emit {
+        infile: String,
+        language: Language,
+    },
+    // Run the program through both VMs and check they do the same
+    #[cfg(feature = "simple")]
+    Verify {
+        infile: String,
+    },
+    #[cfg(feature = "simple")]
+    Repl,
+}
+
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub(crate) struct Args {
+    pub(crate) mode: Mode,
+    pub(crate) backend: Backend,
+    // How many passes the optimizer gets; it goes until it's done if this is None
+    pub(crate) opt_passes: Option<usize>,
+    pub(crate) tape_size: usize,
+    pub(crate) max_steps: Option<u64>,
+    pub(crate) overflow: OverflowPolicy,
+    // When to flush stdout; if this is None, it's up to BufferedOutput::stdout
+    pub(crate) flush: Option<FlushPolicy>,
+    // What happens to the line endings typed into stdin (not to piped input, or an input file)
+    pub(crate) newlines: NewlineMode,
+    // Where the program's input comes from; stdin if this is None
+    pub(crate) input_file: Option<String>,
+    // Whether the input comes from the infile itself, after the first !
+    pub(crate) bang_input: bool,
+    // Whether stdin is read byte for byte, as it is for pipes, rather than a line at a time; this
+    // is what the infile being given with --code-file means
+    pub(crate) raw_stdin: bool,
+}
+
+impl Args {
+    /// How to parse and optimize the program for the optimized backends
+    pub(crate) fn parse_options(&self) -> ParseOptions<'static> {
+        ParseOptions {
+            policy: self.overflow,
+            max_passes: self.opt_passes,
+            ..ParseOptions::default()
+        }
+    }
+}
+
+/// Parse the command line arguments (not including the program name). The flags can come in
+/// any order, but there has to be exactly one of an infile (maybe given with --code-file) or
+/// --repl; or, if the first argument is disasm, emit-c or emit-rust, an infile to disassemble or
+/// transpile. --verify takes an infile too, and none of those.
+pub(crate) fn parse_args<S: AsRef<str>>(args: &[S]) -> Result<Args, String> {
+    // Without the simple VM, the optimized one is all there is
+    #[cfg(feature = "simple")]
+    let mut backend = Backend::Simple;
+    #[cfg(not(feature = "simple"))]
+    let mut backend = Backend::Optimized;
+    let mut opt_passes = None;
+    let mut tape_size = DEFAULT_TAPE_SIZE;
+    let mut max_steps = None;
+    let mut overflow = OverflowPolicy::Wrap;
+    let mut flush = None;
+    let mut newlines = NewlineMode::Raw;
+    let mut input_file = None;
+    let mut bang_input = false;
+    let mut raw_stdin = false;
+    #[cfg(feature = "simple")]
+    let mut verify = false;
+    let mut mode = None;
+
+    let mut args = args.iter().map(|s| s.as_ref()).peekable();
+
+    let command = match args.peek() {
+        Some(&"disasm") | Some(&"emit-c") | Some(&"emit-rust") => args.next(),
+        _ => None,
+    };
+
+    while let Some(arg) = args.next() {
+        match arg {
+            "--opt" => backend = Backend::Optimized,
+            #[cfg(feature = "simple")]
+            "--simple" => backend = Backend::Simple,
+            "--opt-passes" => opt_passes = Some(parse_number(arg, args.next())?),
+            "--tape-size" => tape_size = parse_number(arg, args.next())?,
+            "--max-steps" => max_steps = Some(parse_number(arg, args.next())?),
+            "--overflow" => overflow = parse_overflow(arg, args.next())?,
+            "--flush" => flush = Some(parse_flush(arg, args.next())?),
+            "--newlines" => newlines = parse_newlines(arg, args.next())?,
+            "--input-file" => input_file = Some(flag_value(arg, args.next())?.to_string()),
+            "--bang-input" => bang_input = true,
+            "--code-file" => {
+                let infile = flag_value(arg, args.next())?.to_string();
+                set_mode(&mut mode, Mode::Run { infile })?;
+                raw_stdin = true;
+            }
+            #[cfg(feature = "simple")]
+            "--repl" => set_mode(&mut mode, Mode::Repl)?,
+            #[cfg(feature = "simple")]
+            "--verify" => verify = true,
+            #[cfg(not(feature = "simple"))]
+            "--simple" | "--repl" | "--verify" => return Err(format!("{} needs the simple feature", arg)),
+            flag if flag.starts_with("--") => return Err(format!("Unknown flag {}", flag)),
+            infile => set_mode(
+                &mut mode,
+                Mode::Run {
+                    infile: infile.to_string(),
+                },
+            )?,
+        }
+    }
+
+    let mode = match (mode.ok_or_else(|| "Expected an infile or --repl".to_string())?, command) {
+        (mode, None) => mode,
+        (Mode::Run { infile }, Some("disasm")) => Mode::Disasm { infile },
+        (Mode::Run { infile }, Some("emit-c")) => Mode::Emit {
+            infile,
+            language: Language::C,
+        },
+        (Mode::Run { infile }, Some(_)) => Mode::Emit {
+            infile,
+            language: Language::Rust,
+        },
+        (_, Some(command)) => return Err(format!("{} needs an infile, not --repl", command)),
+    };
+
+    #[cfg(feature = "simple")]
+    let mode = match (mode, verify) {
+        (Mode::Run { infile }, true) => Mode::Verify { infile },
+        (_, true) => return Err("--verify needs an infile, and can't go with --repl, disasm, emit-c or emit-rust".to_string()),
+        (mode, false) => mode,
+    };
+
+    if [bang_input, input_file.is_some(), raw_stdin].iter().filter(|given| **given).count() > 1 {
+        return Err("Expected only one of --input-file, --bang-input or --code-file".to_string());
+    }
+
+    Ok(Args {
+        mode,
+        backend,
+        opt_passes,
+        tape_size,
+        max_steps,
+        overflow,
+        flush,
+        newlines,
+        input_file,
+        bang_input,
+        raw_stdin,
+    })
+}
+
+fn set_mode(mode: &mut Option<Mode>, new_mode: Mode) -> Result<(), String> {
+    if mode.is_some() {
+        return Err("Expected only one of an infile or --repl".to_string());
+    }
+    *mode = Some(new_mode);
+    Ok(())
+}
+
+fn flag_value<'a>(flag: &str, value: Option<&'a str>) -> Result<&'a str, String> {
+    value.ok_or_else(|| format!("{} needs a value", flag))
+}
+
+fn parse_number<T: std::str::FromStr>(flag: &str, value: Option<&str>) -> Result<T, String> {
+    let value = flag_value(flag, value)?;
+    value.parse().map_err(|_| format!("{} needs a number, not {}", flag, value))
+}
+
+fn parse_overflow(flag: &str, value: Option<&str>) -> Result<OverflowPolicy, String> {
+    match flag_value(flag, value)? {
+        "wrap" => Ok(OverflowPolicy::Wrap),
+        "saturate" => Ok(OverflowPolicy::Saturate),
+        "error" => Ok(OverflowPolicy::Error),
+        other => Err(format!("{} needs one of wrap, saturate or error, not {}", flag, other)),
+    }
+}
+
+fn parse_flush(flag: &str, value: Option<&str>) -> Result<FlushPolicy, String> {
+    match flag_value(flag, value)? {
+        "byte" => Ok(FlushPolicy::EveryByte),
+        "newline" => Ok(FlushPolicy::OnNewline),
+        "exit" => Ok(FlushPolicy::OnExit),
+        other => Err(format!("{} needs one of byte, newline or exit, not {}", flag, other)),
+    }
+}
+
+fn parse_newlines(flag: &str, value: Option<&str>) -> Result<NewlineMode, String> {
+    match flag_value(flag, value)? {
+        "raw" => Ok(NewlineMode::Raw),
+        "strip-cr" => Ok(NewlineMode::StripCr),
+        "lf" => Ok(NewlineMode::Lf),
+        other => Err(format!("{} needs one of raw, strip-cr or lf, not {}", flag, other)),
+    }
+}
@@ -1,45 +1,180 @@
 use std::env;
 use std::fs;
 
-use bf_lib::{optimized_parse, simple_parse};
+use bf_lib::io::{self, Input};
+use bf_lib::{
+    disassemble, optimized_parse_with_options, set_logging, split_source_and_input, to_c, to_rust, CompiledInstr, OptVM, OverflowPolicy,
+    UsageTape, VecTape,
+};
+#[cfg(feature = "simple")]
+use bf_lib::{simple_parse, SimpleVM};
+
+use crate::args::{Args, Backend, Language, Mode};
+
+mod args;
+#[cfg(feature = "simple")]
+mod repl;
+#[cfg(feature = "simple")]
+mod verify;
+
+fn run(input_str: String, args: &Args) -> Result<(), ()> {
+    if args.bang_input {
+        let (code, input) = split_source_and_input(&input_str);
+        return run_with_input(code.to_string(), args, &mut io::SliceInput::new(input.as_bytes()));
+    }
+
+    match args.input_file {
+        Some(ref path) => {
+            let bytes = match fs::read(path) {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    println!("Error reading input file {}: {}", path, e);
+                    return Err(());
+                }
+            };
+            run_with_input(input_str, args, &mut io::SliceInput::new(&bytes))
+        }
+        None if args.raw_stdin => run_with_input(input_str, args, &mut io::ReaderInput::new(std::io::stdin().lock())),
+        None => run_with_input(input_str, args, &mut io::StdIn::new().with_newlines(args.newlines)),
+    }
+}
+
+// Parses and optimizes the code the way run does, for the modes which print it instead; the
+// optimizer's diagnostics are turned off, so stdout is only the printed code
+fn compile(input_str: &str, args: &Args) -> Result<Vec<CompiledInstr>, ()> {
+    let source = if args.bang_input {
+        split_source_and_input(input_str).0
+    } else {
+        input_str
+    };
+
+    set_logging(false);
+    match optimized_parse_with_options(source, &args.parse_options()) {
+        Ok(parsed) => Ok(parsed.code),
+        Err(e) => {
+            eprintln!("Parse error: {:#?}", e);
+            Err(())
+        }
+    }
+}
+
+// Prints the optimized code, without running anything
+fn disasm(input_str: &str, args: &Args) -> Result<(), ()> {
+    let code = compile(input_str, args)?;
+    print!("{}", disassemble(&code));
+    Ok(())
+}
 
-mod io;
-mod opt_vm;
-mod simple_vm;
+// Prints the optimized code as a C or Rust program, with the tape size baked in
+fn emit(input_str: &str, args: &Args, language: Language) -> Result<(), ()> {
+    // The generated code's cells always wrap
+    if args.overflow != OverflowPolicy::Wrap {
+        eprintln!("Only --overflow wrap can be transpiled");
+        return Err(());
+    }
 
-fn run(input_str: String, is_opt: bool) -> Result<(), ()> {
-    let mut input = io::StdIn::new();
-    let mut output = io::StdOut::new();
+    let code = compile(input_str, args)?;
+    match language {
+        Language::C => print!("{}", to_c(&code, args.tape_size)),
+        Language::Rust => print!("{}", to_rust(&code, args.tape_size)),
+    }
+    Ok(())
+}
+
+// Runs the program through both VMs and reports whether they agree. They need the same input, so
+// all of it is read up front, stdin included.
+#[cfg(feature = "simple")]
+fn verify(input_str: &str, args: &Args) -> Result<(), ()> {
+    let (source, input) = if args.bang_input {
+        let (code, input) = split_source_and_input(input_str);
+        (code, input.as_bytes().to_vec())
+    } else {
+        let read = match args.input_file {
+            Some(ref path) => fs::read(path).map_err(|e| format!("Error reading input file {}: {}", path, e)),
+            None => {
+                let mut bytes = Vec::new();
+                std::io::Read::read_to_end(&mut std::io::stdin().lock(), &mut bytes)
+                    .map(|_| bytes)
+                    .map_err(|e| format!("Error reading stdin: {}", e))
+            }
+        };
+        match read {
+            Ok(bytes) => (input_str, bytes),
+            Err(e) => {
+                println!("{}", e);
+                return Err(());
+            }
+        }
+    };
+
+    set_logging(false);
+    match verify::verify(source, &input, args) {
+        Ok(summary) => {
+            println!("PASS: {}", summary);
+            Ok(())
+        }
+        Err(difference) => {
+            println!("FAIL: {}", difference);
+            Err(())
+        }
+    }
+}
+
+fn run_with_input<I: Input>(input_str: String, args: &Args, input: &mut I) -> Result<(), ()> {
+    let mut output = match args.flush {
+        Some(policy) => io::BufferedOutput::new(std::io::stdout(), policy),
+        None => io::BufferedOutput::stdout(),
+    };
 
     let handle_parse_error = |e| {
         println!("Parse error: {:#?}", e);
-        ()
     };
 
-    let res = if is_opt {
-        let start = std::time::Instant::now();
+    let res = match args.backend {
+        Backend::Optimized => {
+            let start = std::time::Instant::now();
 
-        let code = optimized_parse(&input_str).map_err(handle_parse_error)?;
+            let code = optimized_parse_with_options(&input_str, &args.parse_options())
+                .map_err(handle_parse_error)?
+                .code;
 
-        let compile_time = start.elapsed().as_secs_f64();
+            let compile_time = start.elapsed().as_secs_f64();
 
-        println!("Parse/opt/compile took {:.3} seconds.", compile_time);
+            println!("Parse/opt/compile took {:.3} seconds.", compile_time);
 
-        println!("Post optimization, executing {} code lines", code.len());
-        let vm = opt_vm::OptVM::new(code);
+            println!("Post optimization, executing {} code lines", code.len());
+            let mut vm = OptVM::new(code)
+                .with_tape(UsageTape::new(VecTape::new(args.tape_size)))
+                .with_overflow_policy(args.overflow);
+            if let Some(max_steps) = args.max_steps {
+                vm = vm.with_max_steps(max_steps);
+            }
 
-        vm.run(&mut input, &mut output)
-    } else {
-        let code = simple_parse(&input_str)?;
-        println!("Post parse, executing {} code lines", code.len());
-        let vm = simple_vm::SimpleVM::new(code);
+            vm.run(input, &mut output)
+        }
+        #[cfg(feature = "simple")]
+        Backend::Simple => {
+            let code = simple_parse(&input_str).map_err(handle_parse_error)?;
+            println!("Post parse, executing {} code lines", code.len());
+            let mut vm = SimpleVM::new(code)
+                .with_tape(UsageTape::new(VecTape::new(args.tape_size)))
+                .with_overflow_policy(args.overflow);
+            if let Some(max_steps) = args.max_steps {
+                vm = vm.with_max_steps(max_steps);
+            }
 
-        vm.run(&mut input, &mut output)
+            vm.run(input, &mut output)
+        }
     };
 
+    // So the program's output comes before anything said about it
+    if let Err(e) = output.flush() {
+        println!("Error flushing output: {}", e);
+    }
+
     match res {
         Err(e) => {
-            println!("IO Error: {}", e);
+            println!("Runtime error: {}", e);
             Err(())
         }
         Ok(_) => Ok(()),
@@ -47,18 +182,26 @@ fn run(input_str: String, is_opt: bool) -> Result<(), ()> {
 }
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let raw_args: Vec<String> = env::args().skip(1).collect();
 
-    if args.len() < 2 {
-        println!("Usage: cargo run bf_interpreter -- infile [opt]");
-        std::process::exit(1);
-    }
-
-    let infile = args.get(1).unwrap();
+    let args = match args::parse_args(&raw_args) {
+        Ok(args) => args,
+        Err(e) => {
+            println!("{}", e);
+            println!("{}", args::USAGE);
+            std::process::exit(1);
+        }
+    };
 
-    let is_opt = match args.get(2) {
-        None => false,
-        Some(_) => true,
+    let infile = match args.mode {
+        #[cfg(feature = "simple")]
+        Mode::Repl => {
+            repl::run_repl(args.tape_size, args.newlines);
+            return;
+        }
+        Mode::Run { ref infile } | Mode::Disasm { ref infile } | Mode::Emit { ref infile, .. } => infile,
+        #[cfg(feature = "simple")]
+        Mode::Verify { ref infile } => infile,
     };
 
     let input_str = match fs::read_to_string(infile) {
@@ -69,75 +212,39 @@ fn main() {
         }
     };
 
-    let out = run(input_str, is_opt);
+    let out = match args.mode {
+        Mode::Disasm { .. } => disasm(&input_str, &args),
+        Mode::Emit { language, .. } => emit(&input_str, &args, language),
+        #[cfg(feature = "simple")]
+        Mode::Verify { .. } => verify(&input_str, &args),
+        _ => run(input_str, &args),
+    };
 
-    if let Err(_) = out {
+    if out.is_err() {
         std::process::exit(1)
     }
 }
 
-#[cfg(test)]
+// Nearly all of these check the optimized code against the simple VM
+#[cfg(all(test, feature = "simple"))]
 mod tests {
-    #[derive(Eq, PartialEq, Debug)]
-    struct FixedInput {
-        dp: usize,
-        reads: usize,
-        data: Vec<u8>,
-    }
-
-    impl FixedInput {
-        fn new(data: &str) -> FixedInput {
-            FixedInput {
-                data: data.as_bytes().to_vec(),
-                dp: 0,
-                reads: 0,
-            }
-        }
-    }
-
-    impl crate::io::Input for FixedInput {
-        type InputError = std::io::Error;
-
-        fn read_byte(&mut self) -> Result<u8, Self::InputError> {
-            if self.dp >= self.data.len() {
-                return Ok(0); // EOF, by definition
-            }
-
-            let out = self.data[self.dp];
-            self.dp += 1;
-            self.reads += 1;
-            Ok(out)
-        }
-    }
-
-    #[derive(Default, Eq, PartialEq, Debug)]
-    struct OutputCapture {
-        data: Vec<u8>,
-    }
-
-    impl crate::io::Output for OutputCapture {
-        type OutputError = std::io::Error;
-
-        fn write_byte(&mut self, byte: u8) -> Result<(), Self::OutputError> {
-            self.data.push(byte);
-            Ok(())
-        }
-    }
+    use bf_lib::io::{SliceInput, VecOutput};
+    use bf_lib::{OptVM, RuntimeError, SimpleVM, VmError};
 
     fn assert_opt_is_basic(source_str: &str, input_str: &str) {
-        let mut opt_input = FixedInput::new(input_str);
-        let mut opt_output = OutputCapture::default();
+        let mut opt_input = SliceInput::new(input_str.as_bytes());
+        let mut opt_output = VecOutput::new();
 
         let opt_code = bf_lib::optimized_parse(source_str).unwrap();
 
-        let opt_result = crate::opt_vm::OptVM::new(opt_code).run(&mut opt_input, &mut opt_output);
+        let opt_result = OptVM::new(opt_code).run(&mut opt_input, &mut opt_output);
 
-        let mut simple_input = FixedInput::new(input_str);
-        let mut simple_output = OutputCapture::default();
+        let mut simple_input = SliceInput::new(input_str.as_bytes());
+        let mut simple_output = VecOutput::new();
 
         let simple_code = bf_lib::simple_parse(source_str).unwrap();
 
-        let simple_result = crate::simple_vm::SimpleVM::new(simple_code).run(&mut simple_input, &mut simple_output);
+        let simple_result = SimpleVM::new(simple_code).run(&mut simple_input, &mut simple_output);
 
         // First, assert the "exit status" is the same
         assert_eq!(simple_result.is_ok(), opt_result.is_ok());
@@ -149,6 +256,300 @@ mod tests {
         assert_eq!(simple_input, opt_input);
     }
 
+    #[test]
+    fn test_leading_dec_ptr() {
+        assert_opt_is_basic("<", "");
+        assert_opt_is_basic("<+", "");
+        assert_opt_is_basic("<>+.", "");
+
+        let code = bf_lib::simple_parse("<+").unwrap();
+        let err = SimpleVM::new(code)
+            .run(&mut SliceInput::new(b""), &mut VecOutput::new())
+            .unwrap_err();
+        assert_eq!(err, VmError::Runtime(RuntimeError::OutOfBounds { dp: -1, role: None }));
+    }
+
+    #[test]
+    fn test_leading_triple_dec_ptr() {
+        assert_opt_is_basic("<<<", "");
+        assert_opt_is_basic("<<<+", "");
+        assert_opt_is_basic("<<<,>>>.", "a");
+
+        // The optimized code reports the cell it actually tried to touch, rather than wrapping around
+        let code = bf_lib::optimized_parse("<<<+.").unwrap();
+        let err = OptVM::new(code).run(&mut SliceInput::new(b""), &mut VecOutput::new()).unwrap_err();
+        assert_eq!(err, VmError::Runtime(RuntimeError::OutOfBounds { dp: -3, role: None }));
+
+        let code = vec![
+            bf_lib::CompiledInstr::SubPtr { amount: 3 },
+            bf_lib::CompiledInstr::WriteByte { dp_offset: 0 },
+        ];
+        let err = OptVM::new(code).run(&mut SliceInput::new(b""), &mut VecOutput::new()).unwrap_err();
+        assert_eq!(err, VmError::Runtime(RuntimeError::OutOfBounds { dp: -3, role: None }));
+
+        let code = vec![
+            bf_lib::CompiledInstr::SubPtr { amount: usize::MAX },
+            bf_lib::CompiledInstr::SubPtr { amount: usize::MAX },
+        ];
+        let err = OptVM::new(code).run(&mut SliceInput::new(b""), &mut VecOutput::new()).unwrap_err();
+        assert_eq!(
+            err,
+            VmError::Runtime(RuntimeError::OutOfBounds {
+                dp: isize::MIN,
+                role: None
+            })
+        );
+    }
+
+    #[test]
+    fn test_repl_keeps_state() {
+        let mut repl = crate::repl::Repl::new(bf_lib::DEFAULT_TAPE_SIZE);
+        let mut input = SliceInput::new(b"");
+        let mut output = VecOutput::new();
+
+        repl.feed_line("+++>++\n", &mut input, &mut output).unwrap();
+        repl.feed_line("<.>.\n", &mut input, &mut output).unwrap();
+        assert_eq!(output.as_bytes(), &[3, 2]);
+
+        // an unclosed loop waits for the rest of it
+        repl.feed_line("[<+>\n", &mut input, &mut output).unwrap();
+        assert!(repl.is_pending());
+        repl.feed_line("-]<.\n", &mut input, &mut output).unwrap();
+        assert!(!repl.is_pending());
+        assert_eq!(output.as_bytes(), &[3, 2, 5]);
+
+        // a stray ] is an error, and nothing on its line runs
+        assert!(matches!(
+            repl.feed_line("+]\n", &mut input, &mut output),
+            Err(crate::repl::ReplError::Parse(_))
+        ));
+        assert!(!repl.is_pending());
+        repl.feed_line(".\n", &mut input, &mut output).unwrap();
+        assert_eq!(output.as_bytes(), &[3, 2, 5, 5]);
+    }
+
+    #[test]
+    fn test_parse_args() {
+        use crate::args::{parse_args, Args, Backend, Language, Mode};
+        use bf_lib::OverflowPolicy;
+
+        assert_eq!(
+            parse_args(&["prog.b"]),
+            Ok(Args {
+                mode: Mode::Run {
+                    infile: "prog.b".to_string()
+                },
+                backend: Backend::Simple,
+                opt_passes: None,
+                tape_size: bf_lib::DEFAULT_TAPE_SIZE,
+                max_steps: None,
+                overflow: OverflowPolicy::Wrap,
+                flush: None,
+                newlines: bf_lib::io::NewlineMode::Raw,
+                input_file: None,
+                bang_input: false,
+                raw_stdin: false,
+            })
+        );
+
+        assert_eq!(
+            parse_args(&[
+                "--tape-size",
+                "100",
+                "prog.b",
+                "--opt",
+                "--opt-passes",
+                "3",
+                "--max-steps",
+                "5000",
+                "--input-file",
+                "in.txt",
+                "--overflow",
+                "saturate",
+                "--flush",
+                "byte",
+                "--newlines",
+                "strip-cr"
+            ]),
+            Ok(Args {
+                mode: Mode::Run {
+                    infile: "prog.b".to_string()
+                },
+                backend: Backend::Optimized,
+                opt_passes: Some(3),
+                tape_size: 100,
+                max_steps: Some(5000),
+                overflow: OverflowPolicy::Saturate,
+                flush: Some(bf_lib::io::FlushPolicy::EveryByte),
+                newlines: bf_lib::io::NewlineMode::StripCr,
+                input_file: Some("in.txt".to_string()),
+                bang_input: false,
+                raw_stdin: false,
+            })
+        );
+
+        assert_eq!(parse_args(&["--repl"]).map(|args| args.mode), Ok(Mode::Repl));
+        assert_eq!(
+            parse_args(&["disasm", "--opt-passes", "2", "prog.b"]).map(|args| args.mode),
+            Ok(Mode::Disasm {
+                infile: "prog.b".to_string()
+            })
+        );
+        assert!(parse_args(&["disasm", "--repl"]).is_err());
+        assert_eq!(
+            parse_args(&["emit-c", "--tape-size", "500", "prog.b"]).map(|args| (args.mode, args.tape_size)),
+            Ok((
+                Mode::Emit {
+                    infile: "prog.b".to_string(),
+                    language: Language::C
+                },
+                500
+            ))
+        );
+        assert_eq!(
+            parse_args(&["emit-rust", "prog.b"]).map(|args| args.mode),
+            Ok(Mode::Emit {
+                infile: "prog.b".to_string(),
+                language: Language::Rust
+            })
+        );
+        assert!(parse_args(&["emit-c", "--repl"]).is_err());
+        assert_eq!(
+            parse_args(&["--verify", "prog.b"]).map(|args| args.mode),
+            Ok(Mode::Verify {
+                infile: "prog.b".to_string()
+            })
+        );
+        assert!(parse_args(&["--verify", "--repl"]).is_err());
+        assert!(parse_args(&["disasm", "--verify", "prog.b"]).is_err());
+        assert!(parse_args(&["prog.b", "disasm"]).is_err());
+        assert_eq!(parse_args(&["prog.b", "--bang-input"]).map(|args| args.bang_input), Ok(true));
+        assert_eq!(
+            parse_args(&["--code-file", "prog.b"]).map(|args| (args.mode, args.raw_stdin)),
+            Ok((
+                Mode::Run {
+                    infile: "prog.b".to_string()
+                },
+                true
+            ))
+        );
+
+        // a stray second argument used to silently turn on the optimizer
+        assert!(parse_args(&["prog.b", "foo"]).is_err());
+        assert!(parse_args(&["prog.b", "--fast"]).is_err());
+        assert!(parse_args(&["prog.b", "--tape-size"]).is_err());
+        assert!(parse_args(&["prog.b", "--tape-size", "big"]).is_err());
+        assert!(parse_args(&["--opt"]).is_err());
+        assert!(parse_args(&["prog.b", "--overflow", "clamp"]).is_err());
+        assert!(parse_args(&["prog.b", "--newlines", "crlf"]).is_err());
+        assert!(parse_args(&["prog.b", "--bang-input", "--input-file", "in.txt"]).is_err());
+        assert!(parse_args(&["--code-file", "prog.b", "--input-file", "in.txt"]).is_err());
+        assert!(parse_args(&["--code-file", "prog.b", "other.b"]).is_err());
+        assert!(parse_args(&["--code-file"]).is_err());
+    }
+
+    #[test]
+    fn test_verify() {
+        use crate::verify::{first_difference, verify};
+
+        let args = crate::args::parse_args(&["--verify", "prog.b"]).unwrap();
+        let summary = verify(include_str!("../../input/rot13.b"), b"abc\n", &args).unwrap();
+        assert!(summary.contains("wrote the same 4 bytes"), "{}", summary);
+        // Both walk off the tape, if not at the same place
+        assert!(verify("+[>+]", b"", &args).is_ok());
+        assert!(verify("[", b"", &args).is_err());
+
+        assert_eq!(first_difference(b"abc", b"abc"), None);
+        assert_eq!(first_difference(b"abc", b"abd"), Some(2));
+        assert_eq!(first_difference(b"abc", b"ab"), Some(2));
+    }
+
+    #[test]
+    fn test_vm_limits() {
+        let spin = "+[]";
+
+        let err = SimpleVM::new(bf_lib::simple_parse(spin).unwrap())
+            .with_max_steps(100)
+            .run(&mut SliceInput::new(b""), &mut VecOutput::new())
+            .unwrap_err();
+        assert_eq!(err, VmError::Runtime(RuntimeError::StepLimitExceeded { max_steps: 100 }));
+
+        let err = OptVM::new(bf_lib::optimized_parse(",[.,]").unwrap())
+            .with_max_steps(10)
+            .run(&mut SliceInput::new(b"abcdefghijklmnop"), &mut VecOutput::new())
+            .unwrap_err();
+        assert_eq!(err, VmError::Runtime(RuntimeError::StepLimitExceeded { max_steps: 10 }));
+
+        // The optimizer can tell this one never finishes, so it doesn't need a step limit
+        let err = OptVM::new(bf_lib::optimized_parse(spin).unwrap())
+            .run(&mut SliceInput::new(b""), &mut VecOutput::new())
+            .unwrap_err();
+        assert_eq!(err, VmError::Runtime(RuntimeError::InfiniteLoopDetected));
+
+        let timeout = std::time::Duration::from_millis(20);
+        let err = SimpleVM::new(bf_lib::simple_parse(spin).unwrap())
+            .run_with_timeout(&mut SliceInput::new(b""), &mut VecOutput::new(), timeout)
+            .unwrap_err();
+        assert_eq!(err, VmError::Runtime(RuntimeError::Timeout { timeout }));
+
+        // but it can't tell with this one, since whether it finishes depends on what's read
+        let err = OptVM::new(bf_lib::optimized_parse(",[++]").unwrap())
+            .run_with_timeout(&mut SliceInput::new(b"a"), &mut VecOutput::new(), timeout)
+            .unwrap_err();
+        assert_eq!(err, VmError::Runtime(RuntimeError::Timeout { timeout }));
+
+        let err = SimpleVM::new(bf_lib::simple_parse(">>>+").unwrap())
+            .with_tape_size(3)
+            .run(&mut SliceInput::new(b""), &mut VecOutput::new())
+            .unwrap_err();
+        assert_eq!(err, VmError::Runtime(RuntimeError::OutOfBounds { dp: 3, role: None }));
+    }
+
+    #[test]
+    fn test_overflow_policy() {
+        use bf_lib::OverflowPolicy;
+
+        // Counts down from 1 past zero, then counts back up and prints
+        let source = "+-->+[<+>-]<.";
+
+        for policy in [OverflowPolicy::Wrap, OverflowPolicy::Saturate, OverflowPolicy::Error].iter() {
+            let simple = SimpleVM::new(bf_lib::simple_parse(source).unwrap())
+                .with_overflow_policy(*policy)
+                .run(&mut SliceInput::new(b""), &mut VecOutput::new());
+
+            let options = bf_lib::ParseOptions {
+                policy: *policy,
+                ..bf_lib::ParseOptions::default()
+            };
+            let parsed = bf_lib::optimized_parse_with_options(source, &options).unwrap();
+            let mut output = VecOutput::new();
+            let opt = OptVM::new(parsed.code)
+                .with_overflow_policy(*policy)
+                .run(&mut SliceInput::new(b""), &mut output);
+
+            match policy {
+                OverflowPolicy::Wrap => assert_eq!(output.as_bytes(), &[0]),
+                OverflowPolicy::Saturate => assert_eq!(output.as_bytes(), &[1]),
+                OverflowPolicy::Error => {
+                    assert_eq!(simple, Err(VmError::Runtime(RuntimeError::Overflow { dp: 0 })));
+                    assert_eq!(opt, Err(VmError::Runtime(RuntimeError::Overflow { dp: 0 })));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_ast_is_public() {
+        use bf_lib::AstNode;
+
+        let mut ast = bf_lib::parse_to_ast(",[->+<]>.").unwrap();
+        assert!(ast.iter().any(|node| matches!(node, AstNode::Loop { .. })));
+
+        bf_lib::optimize_ast(&mut ast, &bf_lib::ParseOptions::default());
+        assert!(!ast.iter().any(|node| matches!(node, AstNode::Loop { .. })));
+        assert!(ast.iter().any(|node| matches!(node, AstNode::IfNonZero { .. })));
+    }
+
     #[test]
     fn test_hello_world() {
         assert_opt_is_basic(include_str!("../../input/hello_world.b"), "");
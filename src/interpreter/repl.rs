@@ -0,0 +1,89 @@
+use std::io::Write;
+
+use bf_lib::io::{self, Input, Output};
+use bf_lib::{simple_parse, ParseError, SimpleVM, VmError};
+
+/// Why a line fed to the repl didn't run to the end
+#[derive(Debug)]
+pub(crate) enum ReplError<InputError, OutputError> {
+    Parse(ParseError),
+    Runtime(VmError<InputError, OutputError>),
+}
+
+/// Runs BF source a line at a time against a single machine, so the tape and data pointer
+/// carry over from one line to the next. This uses the simple VM, since the optimizer assumes
+/// it's starting from a blank tape.
+pub(crate) struct Repl {
+    vm: SimpleVM,
+    // Source which hasn't been run yet, because it has unclosed loops
+    pending: String,
+}
+
+impl Repl {
+    pub(crate) fn new(tape_size: usize) -> Self {
+        Repl {
+            vm: SimpleVM::new(Vec::new()).with_tape_size(tape_size),
+            pending: String::new(),
+        }
+    }
+
+    /// True if there are unclosed loops waiting on more lines
+    pub(crate) fn is_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Add a line of source. If all its loops are closed, everything buffered so far is run
+    /// immediately; otherwise it waits for the line that closes them. If what's buffered doesn't
+    /// parse (say, a stray `]`), it's all dropped and none of it runs.
+    pub(crate) fn feed_line<I: Input, O: Output>(
+        &mut self,
+        line: &str,
+        input: &mut I,
+        output: &mut O,
+    ) -> Result<(), ReplError<I::InputError, O::OutputError>> {
+        self.pending.push_str(line);
+
+        let opens = self.pending.chars().filter(|c| *c == '[').count();
+        let closes = self.pending.chars().filter(|c| *c == ']').count();
+
+        if opens > closes {
+            return Ok(());
+        }
+
+        let source = std::mem::take(&mut self.pending);
+
+        let code = simple_parse(&source).map_err(ReplError::Parse)?;
+        self.vm.load(code);
+        while self.vm.step(input, output).map_err(ReplError::Runtime)? {}
+
+        Ok(())
+    }
+}
+
+pub(crate) fn run_repl(tape_size: usize, newlines: io::NewlineMode) {
+    let mut repl = Repl::new(tape_size);
+    let mut input = io::StdIn::new().with_newlines(newlines);
+    let mut output = io::StdOut::new();
+
+    loop {
+        print!("{}", if repl.is_pending() { "... " } else { "bf> " });
+        // If this fails, the prompt is just missing, which is fine
+        let _ = std::io::stdout().flush();
+
+        let mut line = String::new();
+        match std::io::stdin().read_line(&mut line) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(e) => {
+                println!("Error reading line: {}", e);
+                break;
+            }
+        }
+
+        match repl.feed_line(&line, &mut input, &mut output) {
+            Ok(()) => {}
+            Err(ReplError::Parse(e)) => println!("Parse error: {:#?}", e),
+            Err(ReplError::Runtime(e)) => println!("Runtime error: {}", e),
+        }
+    }
+}
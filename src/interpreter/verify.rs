@@ -0,0 +1,91 @@
+use bf_lib::io::{SliceInput, VecOutput};
+use bf_lib::{optimized_parse_with_options, simple_parse, OptVM, SimpleVM};
+
+use crate::args::Args;
+
+/// Runs the source through both VMs on the same input, and says whether the optimized code did
+/// the same as the source: Ok with a summary if so, Err with the first difference if not. As with
+/// the fuzz tests, the two only have to agree on whether they failed, not on the error, since
+/// the optimizer is allowed to move where a program walks off the tape.
+pub(crate) fn verify(source: &str, input: &[u8], args: &Args) -> Result<String, String> {
+    let simple_code = simple_parse(source).map_err(|e| format!("Parse error: {:?}", e))?;
+    let opt_code = optimized_parse_with_options(source, &args.parse_options())
+        .map_err(|e| format!("Parse error: {:?}", e))?
+        .code;
+
+    let mut simple_input = SliceInput::new(input);
+    let mut simple_output = VecOutput::new();
+    let mut simple_vm = SimpleVM::new(simple_code)
+        .with_tape_size(args.tape_size)
+        .with_overflow_policy(args.overflow);
+    if let Some(max_steps) = args.max_steps {
+        simple_vm = simple_vm.with_max_steps(max_steps);
+    }
+    let simple_result = simple_vm.run(&mut simple_input, &mut simple_output);
+
+    let mut opt_input = SliceInput::new(input);
+    let mut opt_output = VecOutput::new();
+    let mut opt_vm = OptVM::new(opt_code)
+        .with_tape_size(args.tape_size)
+        .with_overflow_policy(args.overflow);
+    if let Some(max_steps) = args.max_steps {
+        opt_vm = opt_vm.with_max_steps(max_steps);
+    }
+    let opt_result = opt_vm.run(&mut opt_input, &mut opt_output);
+
+    let (simple_bytes, opt_bytes) = (simple_output.as_bytes(), opt_output.as_bytes());
+    if let Some(at) = first_difference(simple_bytes, opt_bytes) {
+        return Err(format!(
+            "The output differs at byte {}: the simple VM wrote {}, the optimized one {}",
+            at,
+            describe_byte(simple_bytes.get(at)),
+            describe_byte(opt_bytes.get(at))
+        ));
+    }
+
+    if simple_result.is_ok() != opt_result.is_ok() {
+        return Err(format!(
+            "The simple VM {}, but the optimized one {}",
+            describe_result(&simple_result),
+            describe_result(&opt_result)
+        ));
+    }
+
+    if simple_input.position() != opt_input.position() {
+        return Err(format!(
+            "The simple VM read {} bytes of input, but the optimized one read {}",
+            simple_input.position(),
+            opt_input.position()
+        ));
+    }
+
+    Ok(format!(
+        "Both VMs wrote the same {} bytes, read {} bytes of input, and {}",
+        simple_bytes.len(),
+        simple_input.position(),
+        describe_result(&simple_result)
+    ))
+}
+
+// The first index where the two differ, counting one running out before the other
+pub(crate) fn first_difference(a: &[u8], b: &[u8]) -> Option<usize> {
+    match a.iter().zip(b).position(|(x, y)| x != y) {
+        Some(at) => Some(at),
+        None if a.len() != b.len() => Some(a.len().min(b.len())),
+        None => None,
+    }
+}
+
+fn describe_byte(byte: Option<&u8>) -> String {
+    match byte {
+        Some(byte) => format!("{:?}", *byte as char),
+        None => "nothing more".to_string(),
+    }
+}
+
+fn describe_result<E: std::fmt::Display>(result: &Result<(), E>) -> String {
+    match result {
+        Ok(()) => "finished".to_string(),
+        Err(e) => format!("stopped with \"{}\"", e),
+    }
+}
@@ -0,0 +1,229 @@
+use alloc::format;
+use alloc::string::String;
+use core::fmt::Write;
+
+use super::{structure, Block};
+use crate::CompiledInstr;
+
+/// Emits a C program which runs the given (optimized) program, reading stdin and writing stdout.
+///
+/// The tape is a static array of `tape_size` cells and the data pointer starts at zero; nothing
+/// is bounds checked, so a program which leaves the tape is undefined behavior, rather than the
+/// error it would be in the VM. At the end of input, reads give zero, as they do for the VM's
+/// inputs. Where the VM would report an infinite loop, the program exits with status 1.
+///
+/// The jumps are turned back into while loops and ifs, which relies on them being well-nested, the
+/// way `compile_ast` produces them. Panics otherwise.
+///
+/// Cells always wrap, so the code should have been optimized for `OverflowPolicy::Wrap`.
+pub fn to_c(code: &[CompiledInstr], tape_size: usize) -> String {
+    let mut out = String::new();
+
+    out.push_str("#include <stdio.h>\n");
+    out.push_str("#include <stdlib.h>\n");
+    out.push_str("#include <string.h>\n\n");
+    writeln!(out, "static unsigned char tape[{}];\n", tape_size).unwrap();
+    out.push_str("static unsigned char read_byte(void) {\n");
+    out.push_str("    int c = getchar();\n");
+    out.push_str("    return c == EOF ? 0 : (unsigned char)c;\n");
+    out.push_str("}\n\n");
+    out.push_str("int main(void) {\n");
+    out.push_str("    long dp = 0;\n");
+
+    emit_blocks(&mut out, &structure(code), 1);
+
+    out.push_str("    return 0;\n");
+    out.push_str("}\n");
+
+    out
+}
+
+// The lvalue data[dp + dp_offset]
+fn cell(dp_offset: isize) -> String {
+    if dp_offset == 0 {
+        String::from("tape[dp]")
+    } else {
+        format!("tape[dp + {}]", dp_offset)
+    }
+}
+
+// A string literal with these exact bytes. Octal escapes stop after three digits, where hex ones
+// would run on into any digits that follow; question marks are escaped too, so none start a trigraph.
+fn literal(bytes: &[u8]) -> String {
+    let mut s = String::from("\"");
+    for &byte in bytes {
+        match byte {
+            b'"' | b'\\' => write!(s, "\\{}", byte as char).unwrap(),
+            b' '..=b'~' if byte != b'?' => s.push(byte as char),
+            _ => write!(s, "\\{:03o}", byte).unwrap(),
+        }
+    }
+    s.push('"');
+    s
+}
+
+fn emit_blocks(out: &mut String, blocks: &[Block], depth: usize) {
+    let indent = "    ".repeat(depth);
+
+    for block in blocks {
+        match *block {
+            Block::Straight(instrs) => {
+                for instr in instrs {
+                    emit_instr(out, instr, depth);
+                }
+            }
+            Block::Loop { cond_dp_offset, ref body } => {
+                writeln!(out, "{}while ({}) {{", indent, cell(cond_dp_offset)).unwrap();
+                emit_blocks(out, body, depth + 1);
+                writeln!(out, "{}}}", indent).unwrap();
+            }
+            Block::If { cond_dp_offset, ref body } => {
+                writeln!(out, "{}if ({}) {{", indent, cell(cond_dp_offset)).unwrap();
+                emit_blocks(out, body, depth + 1);
+                writeln!(out, "{}}}", indent).unwrap();
+            }
+        }
+    }
+}
+
+fn emit_instr(out: &mut String, instr: &CompiledInstr, depth: usize) {
+    let indent = "    ".repeat(depth);
+
+    match *instr {
+        CompiledInstr::JumpIfZero { .. } | CompiledInstr::JumpIfNonzero { .. } => {
+            unreachable!("Jumps are taken out by structure()")
+        }
+        CompiledInstr::InfiniteLoop => {
+            writeln!(out, "{}fflush(stdout);", indent).unwrap();
+            writeln!(out, "{}exit(1);", indent).unwrap();
+        }
+        CompiledInstr::HaltIfNonzero { cond_dp_offset } => {
+            writeln!(out, "{}if ({}) {{", indent, cell(cond_dp_offset)).unwrap();
+            writeln!(out, "{}    fflush(stdout);", indent).unwrap();
+            writeln!(out, "{}    exit(1);", indent).unwrap();
+            writeln!(out, "{}}}", indent).unwrap();
+        }
+        CompiledInstr::ResetTape => {
+            writeln!(out, "{}memset(tape, 0, sizeof tape);", indent).unwrap();
+            writeln!(out, "{}dp = 0;", indent).unwrap();
+        }
+        CompiledInstr::AddPtr { amount } => writeln!(out, "{}dp += {};", indent, amount).unwrap(),
+        CompiledInstr::SubPtr { amount } => writeln!(out, "{}dp -= {};", indent, amount).unwrap(),
+        // Storing back into an unsigned char takes care of the wrapping
+        CompiledInstr::AddData { amount, dp_offset } => {
+            writeln!(out, "{}{} += {};", indent, cell(dp_offset), amount).unwrap();
+        }
+        CompiledInstr::SetData { amount, dp_offset } => {
+            writeln!(out, "{}{} = {};", indent, cell(dp_offset), amount).unwrap();
+        }
+        CompiledInstr::AssertEquals { dp_offset, val } => {
+            writeln!(out, "{}/* assert data[dp + {}] == {} */", indent, dp_offset, val).unwrap();
+        }
+        CompiledInstr::AddTwoData {
+            source_dp_offset,
+            target_dp_offset,
+            source_amt_mult,
+        } => {
+            writeln!(
+                out,
+                "{}{} += {} * {};",
+                indent,
+                cell(target_dp_offset),
+                cell(source_dp_offset),
+                source_amt_mult
+            )
+            .unwrap();
+        }
+        CompiledInstr::ReadByte { dp_offset } => {
+            writeln!(out, "{}{} = read_byte();", indent, cell(dp_offset)).unwrap();
+        }
+        CompiledInstr::WriteByte { dp_offset } => {
+            writeln!(out, "{}putchar({});", indent, cell(dp_offset)).unwrap();
+        }
+        CompiledInstr::WriteTransformed { dp_offset, add } => {
+            writeln!(out, "{}putchar((unsigned char)({} + {}));", indent, cell(dp_offset), add).unwrap();
+        }
+        CompiledInstr::WriteConst { out: byte } => {
+            writeln!(out, "{}putchar({});", indent, byte).unwrap();
+        }
+        CompiledInstr::WriteConstStr { ref bytes } => {
+            writeln!(out, "{}fwrite({}, 1, {}, stdout);", indent, literal(bytes), bytes.len()).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::to_c;
+
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+
+    // A C compiler isn't a dependency of the crate, so if there isn't one these tests don't check anything
+    fn have_cc() -> bool {
+        let found = Command::new("cc").arg("--version").stdout(Stdio::null()).status().is_ok();
+        if !found {
+            println!("cc isn't installed; skipping");
+        }
+        found
+    }
+
+    // Compiles the C with cc, runs it, and checks it prints the same as the VM
+    fn assert_runs_like_vm(name: &str, source: &str, input: &[u8]) {
+        if !have_cc() {
+            return;
+        }
+
+        let code = crate::optimized_parse(source).unwrap();
+        let c = to_c(&code, crate::DEFAULT_TAPE_SIZE);
+        let dir = std::env::temp_dir();
+        let c_path = dir.join(format!("bf_c_{}_{}.c", name, std::process::id()));
+        let exe_path = dir.join(format!("bf_c_{}_{}", name, std::process::id()));
+        std::fs::write(&c_path, &c).unwrap();
+
+        let compiled = Command::new("cc")
+            .arg("-O1")
+            .arg(&c_path)
+            .arg("-o")
+            .arg(&exe_path)
+            .output()
+            .unwrap();
+        std::fs::remove_file(&c_path).unwrap();
+        assert!(
+            compiled.status.success(),
+            "cc failed: {}\n{}",
+            String::from_utf8_lossy(&compiled.stderr),
+            c
+        );
+
+        let mut exe = Command::new(&exe_path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        exe.stdin.take().unwrap().write_all(input).unwrap();
+        let result = exe.wait_with_output().unwrap();
+        std::fs::remove_file(&exe_path).unwrap();
+        assert!(result.status.success(), "{}", c);
+
+        let mut output = crate::io::VecOutput::new();
+        crate::OptVM::new(code)
+            .run(&mut crate::io::SliceInput::new(input), &mut output)
+            .unwrap();
+        assert_eq!(result.stdout, output.as_bytes());
+    }
+
+    #[test]
+    fn programs_run() {
+        assert_runs_like_vm("hello_world", include_str!("../../../input/hello_world.b"), b"");
+        assert_runs_like_vm("rot13", include_str!("../../../input/rot13.b"), b"f2rf2wfc!!#2eds\n@de");
+        assert_runs_like_vm("quine", include_str!("../../../input/quine.b"), b"");
+        assert_runs_like_vm("halt_if_nonzero", ">,[]", b"\0");
+    }
+
+    #[test]
+    fn tape_size_is_used() {
+        let c = to_c(&crate::optimized_parse("+[>+]").unwrap(), 1234);
+        assert!(c.contains("static unsigned char tape[1234];"), "{}", c);
+    }
+}
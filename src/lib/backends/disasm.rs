@@ -0,0 +1,114 @@
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+use crate::CompiledInstr;
+
+/// Lists the (optimized) program one instruction per line, with its ip, for reading rather than
+/// running. Cells are written relative to dp, like [dp+2], and the bodies of loops and branches
+/// are indented, so the structure the optimizer left is easy to see.
+///
+/// Amounts are shown as signed bytes, which is how they're read unless cells wrap (in which case
+/// it makes no difference).
+pub fn disassemble(code: &[CompiledInstr]) -> String {
+    let mut out = String::new();
+
+    // Where each of the blocks we're in ends; branches and loops can end in the same place
+    let mut block_ends: Vec<usize> = Vec::new();
+
+    for (ip, instr) in code.iter().enumerate() {
+        while block_ends.last() == Some(&ip) {
+            block_ends.pop();
+        }
+
+        let indent = "  ".repeat(block_ends.len());
+        writeln!(out, "{:>5}  {}{}", ip, indent, describe(instr)).unwrap();
+
+        if let CompiledInstr::JumpIfZero { target_ip, .. } = *instr {
+            block_ends.push(target_ip);
+        }
+    }
+
+    out
+}
+
+fn cell(dp_offset: isize) -> String {
+    match dp_offset {
+        0 => String::from("[dp]"),
+        o if o > 0 => format!("[dp+{}]", o),
+        o => format!("[dp{}]", o),
+    }
+}
+
+pub(super) fn describe(instr: &CompiledInstr) -> String {
+    match *instr {
+        CompiledInstr::JumpIfZero { cond_dp_offset, target_ip } => {
+            format!("jump_if_zero {} -> {}", cell(cond_dp_offset), target_ip)
+        }
+        CompiledInstr::JumpIfNonzero { cond_dp_offset, target_ip } => {
+            format!("jump_if_nonzero {} -> {}", cell(cond_dp_offset), target_ip)
+        }
+        CompiledInstr::InfiniteLoop => String::from("infinite_loop"),
+        CompiledInstr::HaltIfNonzero { cond_dp_offset } => format!("halt_if_nonzero {}", cell(cond_dp_offset)),
+        CompiledInstr::ResetTape => String::from("reset_tape"),
+        CompiledInstr::AddPtr { amount } => format!("dp += {}", amount),
+        CompiledInstr::SubPtr { amount } => format!("dp -= {}", amount),
+        CompiledInstr::AddData { amount, dp_offset } => format!("{} += {}", cell(dp_offset), amount as i8),
+        CompiledInstr::SetData { amount, dp_offset } => format!("{} = {}", cell(dp_offset), amount),
+        CompiledInstr::AssertEquals { dp_offset, val } => format!("assert {} == {}", cell(dp_offset), val),
+        CompiledInstr::AddTwoData {
+            source_dp_offset,
+            target_dp_offset,
+            source_amt_mult,
+        } => format!(
+            "{} += {} * {}",
+            cell(target_dp_offset),
+            cell(source_dp_offset),
+            source_amt_mult as i8
+        ),
+        CompiledInstr::ReadByte { dp_offset } => format!("read {}", cell(dp_offset)),
+        CompiledInstr::WriteByte { dp_offset } => format!("write {}", cell(dp_offset)),
+        CompiledInstr::WriteTransformed { dp_offset, add } => format!("write {} + {}", cell(dp_offset), add as i8),
+        CompiledInstr::WriteConst { out } => format!("write_const {:?}", out as char),
+        CompiledInstr::WriteConstStr { ref bytes } => format!("write_const {:?}", String::from_utf8_lossy(bytes)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::disassemble;
+
+    #[test]
+    fn loops_are_indented() {
+        let code = crate::optimized_parse(",[>,[.-]<-]").unwrap();
+        let listing = disassemble(&code);
+
+        assert!(listing.starts_with("    0  read [dp]\n"), "{}", listing);
+        assert!(listing.contains("    3    jump_if_zero [dp+1] -> 7\n"), "{}", listing);
+        assert!(listing.contains("    4      write [dp+1]\n"), "{}", listing);
+        assert_eq!(listing.lines().count(), code.len());
+    }
+
+    #[test]
+    fn constants_are_readable() {
+        let code = crate::optimized_parse(include_str!("../../../input/hello_world.b")).unwrap();
+        let listing = disassemble(&code);
+        assert_eq!(listing, "    0  write_const \"Hello World!\\n\"\n");
+
+        // Without dropping the cells it leaves behind
+        let passes = crate::OptPasses {
+            dead_tail: false,
+            ..crate::OptPasses::default()
+        };
+        let source = include_str!("../../../input/hello_world.b");
+        let options = crate::ParseOptions {
+            passes,
+            ..crate::ParseOptions::default()
+        };
+        let code = crate::optimized_parse_with_options(source, &options).unwrap().code;
+        let listing = disassemble(&code);
+        assert!(listing.starts_with("    0  write_const \"Hello World!\\n\"\n"), "{}", listing);
+        assert!(listing.contains("  [dp+2] = 100\n"), "{}", listing);
+    }
+}
@@ -0,0 +1,113 @@
+use alloc::collections::BTreeSet;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+use super::disasm::describe;
+use crate::CompiledInstr;
+
+/// Draws the (optimized) program's control flow as a GraphViz graph, for `dot -Tsvg` and the
+/// like. Each basic block (a run of instructions only entered at the top and only left at the
+/// bottom) is a node, listing its instructions the way disassemble does. Jumps are edges labeled
+/// with whether the cell was zero, and there's one extra node, end, for leaving the program.
+pub fn to_dot(code: &[CompiledInstr]) -> String {
+    let mut out = String::new();
+    out.push_str("digraph program {\n");
+    out.push_str("  node [shape=box, fontname=\"monospace\"];\n");
+    out.push_str("  end [shape=oval];\n");
+
+    let starts = block_starts(code);
+    let node = |ip: usize| {
+        if ip >= code.len() {
+            String::from("end")
+        } else {
+            format!("b{}", ip)
+        }
+    };
+
+    for (i, &start) in starts.iter().enumerate() {
+        let end = starts.get(i + 1).copied().unwrap_or(code.len());
+
+        let mut label = String::new();
+        for (ip, instr) in code.iter().enumerate().take(end).skip(start) {
+            write!(label, "{}: {}\\l", ip, escape(&describe(instr))).unwrap();
+        }
+        writeln!(out, "  {} [label=\"{}\"];", node(start), label).unwrap();
+
+        match code[end - 1] {
+            CompiledInstr::JumpIfZero { target_ip, .. } => {
+                writeln!(out, "  {} -> {} [label=\"zero\"];", node(start), node(target_ip)).unwrap();
+                writeln!(out, "  {} -> {} [label=\"nonzero\"];", node(start), node(end)).unwrap();
+            }
+            CompiledInstr::JumpIfNonzero { target_ip, .. } => {
+                writeln!(out, "  {} -> {} [label=\"nonzero\"];", node(start), node(target_ip)).unwrap();
+                writeln!(out, "  {} -> {} [label=\"zero\"];", node(start), node(end)).unwrap();
+            }
+            // It never gets out of this one
+            CompiledInstr::InfiniteLoop => {}
+            _ => writeln!(out, "  {} -> {};", node(start), node(end)).unwrap(),
+        }
+    }
+
+    if code.is_empty() {
+        out.push_str("  start [shape=oval];\n  start -> end;\n");
+    }
+
+    out.push_str("}\n");
+    out
+}
+
+// The ip of the first instruction of each basic block, in order: the start of the program, every
+// jump target, and whatever comes after a jump or an infinite loop
+fn block_starts(code: &[CompiledInstr]) -> Vec<usize> {
+    let mut starts = BTreeSet::new();
+    if !code.is_empty() {
+        starts.insert(0);
+    }
+
+    for (ip, instr) in code.iter().enumerate() {
+        match *instr {
+            CompiledInstr::JumpIfZero { target_ip, .. } | CompiledInstr::JumpIfNonzero { target_ip, .. } => {
+                starts.insert(target_ip);
+                starts.insert(ip + 1);
+            }
+            CompiledInstr::InfiniteLoop => {
+                starts.insert(ip + 1);
+            }
+            _ => {}
+        }
+    }
+
+    starts.into_iter().filter(|&ip| ip < code.len()).collect()
+}
+
+// Quotes and backslashes have to be escaped inside a DOT string
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::to_dot;
+
+    #[test]
+    fn blocks_are_nodes_and_jumps_are_edges() {
+        // The read, the loop test, and the loop body, which goes back to the test or out
+        let code = crate::optimized_parse(",[.,]").unwrap();
+        let graph = to_dot(&code);
+
+        // The second word of each line says what it is: a block's label, or an edge
+        let count = |kind: &str| {
+            graph
+                .lines()
+                .filter(|line| line.split_whitespace().nth(1).is_some_and(|w| w.starts_with(kind)))
+                .count()
+        };
+        assert_eq!((count("[label="), count("->")), (3, 5), "{}", graph);
+        assert!(graph.contains("[label=\"zero\"]"), "{}", graph);
+
+        let hello = to_dot(&crate::optimized_parse(include_str!("../../../input/hello_world.b")).unwrap());
+        assert!(hello.contains("write_const \\\"Hello World!\\\\n\\\""), "{}", hello);
+    }
+}
@@ -0,0 +1,355 @@
+use alloc::format;
+use alloc::string::String;
+use core::fmt::Write;
+
+use super::{structure, Block};
+use crate::CompiledInstr;
+
+/// Emits a textual LLVM IR module which runs the given (optimized) program, for llc, lli, or
+/// anything else which reads IR.
+///
+/// The program is `void @bf_run(i8* %tape)`, where the tape is owned by the caller and the data
+/// pointer starts at its first cell; nothing checks the ends of the tape, so it had better be big
+/// enough. IO goes through two external functions, `i32 @bf_read_byte()` and
+/// `void @bf_write_byte(i32)`, which the caller provides as well. Where the VM would stop with
+/// InfiniteLoopDetected or WouldNotTerminate, this traps (with `@llvm.trap`).
+///
+/// The data pointer lives in an alloca, which mem2reg turns into registers, so there are no phis
+/// here. The jumps are turned back into basic blocks, which relies on them being well-nested, the
+/// way `compile_ast` produces them. Panics otherwise.
+///
+/// Cells always wrap, so the code should have been optimized for `OverflowPolicy::Wrap`. Since
+/// the tape's size is up to the caller, there's no way to reset it; the code can't contain
+/// `CompiledInstr::ResetTape`, and this panics if it does.
+pub fn to_llvm_ir(code: &[CompiledInstr]) -> String {
+    let mut emitter = Emitter {
+        out: String::new(),
+        next_reg: 0,
+        next_label: 0,
+    };
+
+    emitter.out.push_str("declare i32 @bf_read_byte()\n");
+    emitter.out.push_str("declare void @bf_write_byte(i32)\n");
+    emitter.out.push_str("declare void @llvm.trap()\n");
+    emitter.out.push('\n');
+    emitter.out.push_str("define void @bf_run(i8* %tape) {\n");
+    emitter.out.push_str("entry:\n");
+    emitter.out.push_str("  %dp = alloca i64\n");
+    emitter.out.push_str("  store i64 0, i64* %dp\n");
+
+    emitter.emit_blocks(&structure(code));
+
+    emitter.out.push_str("  ret void\n");
+    emitter.out.push_str("}\n");
+
+    emitter.out
+}
+
+struct Emitter {
+    out: String,
+    next_reg: usize,
+    next_label: usize,
+}
+
+impl Emitter {
+    fn reg(&mut self) -> String {
+        self.next_reg += 1;
+        format!("%r{}", self.next_reg)
+    }
+
+    fn label(&mut self, name: &str) -> String {
+        self.next_label += 1;
+        format!("{}{}", name, self.next_label)
+    }
+
+    // A pointer to data[dp + dp_offset]
+    fn addr(&mut self, dp_offset: isize) -> String {
+        let dp = self.reg();
+        writeln!(self.out, "  {} = load i64, i64* %dp", dp).unwrap();
+
+        let index = if dp_offset == 0 {
+            dp
+        } else {
+            let index = self.reg();
+            writeln!(self.out, "  {} = add i64 {}, {}", index, dp, dp_offset).unwrap();
+            index
+        };
+
+        let ptr = self.reg();
+        writeln!(self.out, "  {} = getelementptr i8, i8* %tape, i64 {}", ptr, index).unwrap();
+        ptr
+    }
+
+    fn load(&mut self, dp_offset: isize) -> String {
+        let ptr = self.addr(dp_offset);
+        let val = self.reg();
+        writeln!(self.out, "  {} = load i8, i8* {}", val, ptr).unwrap();
+        val
+    }
+
+    fn store(&mut self, dp_offset: isize, val: &str) {
+        let ptr = self.addr(dp_offset);
+        writeln!(self.out, "  store i8 {}, i8* {}", val, ptr).unwrap();
+    }
+
+    // Branches to if_nonzero or if_zero, depending on data[dp + dp_offset]
+    fn branch_on(&mut self, dp_offset: isize, if_nonzero: &str, if_zero: &str) {
+        let val = self.load(dp_offset);
+        let nonzero = self.reg();
+        writeln!(self.out, "  {} = icmp ne i8 {}, 0", nonzero, val).unwrap();
+        writeln!(self.out, "  br i1 {}, label %{}, label %{}", nonzero, if_nonzero, if_zero).unwrap();
+    }
+
+    fn move_dp(&mut self, op: &str, amount: usize) {
+        let dp = self.reg();
+        let moved = self.reg();
+        writeln!(self.out, "  {} = load i64, i64* %dp", dp).unwrap();
+        writeln!(self.out, "  {} = {} i64 {}, {}", moved, op, dp, amount).unwrap();
+        writeln!(self.out, "  store i64 {}, i64* %dp", moved).unwrap();
+    }
+
+    // Stops the program, as the VM does with an error
+    fn trap(&mut self) {
+        self.out.push_str("  call void @llvm.trap()\n");
+        self.out.push_str("  unreachable\n");
+    }
+
+    fn write_const(&mut self, byte: u8) {
+        writeln!(self.out, "  call void @bf_write_byte(i32 {})", byte).unwrap();
+    }
+
+    fn emit_blocks(&mut self, blocks: &[Block]) {
+        for block in blocks {
+            match *block {
+                Block::Straight(instrs) => {
+                    for instr in instrs {
+                        self.emit_instr(instr);
+                    }
+                }
+                Block::Loop { cond_dp_offset, ref body } => {
+                    let top = self.label("body");
+                    let done = self.label("done");
+                    self.branch_on(cond_dp_offset, &top, &done);
+                    writeln!(self.out, "{}:", top).unwrap();
+                    self.emit_blocks(body);
+                    self.branch_on(cond_dp_offset, &top, &done);
+                    writeln!(self.out, "{}:", done).unwrap();
+                }
+                Block::If { cond_dp_offset, ref body } => {
+                    let then = self.label("body");
+                    let done = self.label("done");
+                    self.branch_on(cond_dp_offset, &then, &done);
+                    writeln!(self.out, "{}:", then).unwrap();
+                    self.emit_blocks(body);
+                    writeln!(self.out, "  br label %{}", done).unwrap();
+                    writeln!(self.out, "{}:", done).unwrap();
+                }
+            }
+        }
+    }
+
+    fn emit_instr(&mut self, instr: &CompiledInstr) {
+        match *instr {
+            CompiledInstr::JumpIfZero { .. } | CompiledInstr::JumpIfNonzero { .. } => {
+                unreachable!("Jumps are taken out by structure()")
+            }
+            // The VM stops the program here (with an error), so this does too; anything after it goes in a
+            // block nothing jumps to, which LLVM is fine with
+            CompiledInstr::InfiniteLoop => {
+                let dead = self.label("dead");
+                self.trap();
+                writeln!(self.out, "{}:", dead).unwrap();
+            }
+            CompiledInstr::HaltIfNonzero { cond_dp_offset } => {
+                let halt = self.label("halt");
+                let done = self.label("done");
+                self.branch_on(cond_dp_offset, &halt, &done);
+                writeln!(self.out, "{}:", halt).unwrap();
+                self.trap();
+                writeln!(self.out, "{}:", done).unwrap();
+            }
+            CompiledInstr::ResetTape => {
+                panic!("ResetTape; the tape's size isn't known, so it can't be emitted as LLVM IR");
+            }
+            CompiledInstr::AddPtr { amount } => self.move_dp("add", amount),
+            CompiledInstr::SubPtr { amount } => self.move_dp("sub", amount),
+            // i8 arithmetic takes care of the wrapping
+            CompiledInstr::AddData { amount, dp_offset } => {
+                let old = self.load(dp_offset);
+                let new = self.reg();
+                writeln!(self.out, "  {} = add i8 {}, {}", new, old, amount as i8).unwrap();
+                self.store(dp_offset, &new);
+            }
+            CompiledInstr::SetData { amount, dp_offset } => {
+                self.store(dp_offset, &format!("{}", amount as i8));
+            }
+            CompiledInstr::AssertEquals { dp_offset, val } => {
+                writeln!(self.out, "  ; assert data[dp + {}] == {}", dp_offset, val).unwrap();
+            }
+            CompiledInstr::AddTwoData {
+                source_dp_offset,
+                target_dp_offset,
+                source_amt_mult,
+            } => {
+                let source = self.load(source_dp_offset);
+                let target = self.load(target_dp_offset);
+                let product = self.reg();
+                let sum = self.reg();
+                writeln!(self.out, "  {} = mul i8 {}, {}", product, source, source_amt_mult as i8).unwrap();
+                writeln!(self.out, "  {} = add i8 {}, {}", sum, target, product).unwrap();
+                self.store(target_dp_offset, &sum);
+            }
+            CompiledInstr::ReadByte { dp_offset } => {
+                let read = self.reg();
+                let byte = self.reg();
+                writeln!(self.out, "  {} = call i32 @bf_read_byte()", read).unwrap();
+                writeln!(self.out, "  {} = trunc i32 {} to i8", byte, read).unwrap();
+                self.store(dp_offset, &byte);
+            }
+            CompiledInstr::WriteByte { dp_offset } => {
+                let val = self.load(dp_offset);
+                let wide = self.reg();
+                writeln!(self.out, "  {} = zext i8 {} to i32", wide, val).unwrap();
+                writeln!(self.out, "  call void @bf_write_byte(i32 {})", wide).unwrap();
+            }
+            CompiledInstr::WriteTransformed { dp_offset, add } => {
+                let old = self.load(dp_offset);
+                let val = self.reg();
+                writeln!(self.out, "  {} = add i8 {}, {}", val, old, add as i8).unwrap();
+                let wide = self.reg();
+                writeln!(self.out, "  {} = zext i8 {} to i32", wide, val).unwrap();
+                writeln!(self.out, "  call void @bf_write_byte(i32 {})", wide).unwrap();
+            }
+            CompiledInstr::WriteConst { out } => self.write_const(out),
+            CompiledInstr::WriteConstStr { ref bytes } => {
+                for byte in bytes {
+                    self.write_const(*byte);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::to_llvm_ir;
+
+    use std::io::Write;
+    use std::path::PathBuf;
+    use std::process::{Command, Output, Stdio};
+
+    // A main which gives the program a tape and hooks the IO up to stdin and stdout
+    const HARNESS: &str = "
+declare i32 @getchar()
+declare i32 @putchar(i32)
+declare void @llvm.memset.p0i8.i64(i8*, i8, i64, i1)
+
+define i32 @bf_read_byte() {
+  %c = call i32 @getchar()
+  %eof = icmp slt i32 %c, 0
+  %byte = select i1 %eof, i32 0, i32 %c
+  ret i32 %byte
+}
+
+define void @bf_write_byte(i32 %c) {
+  call i32 @putchar(i32 %c)
+  ret void
+}
+
+define i32 @main() {
+  %tape = alloca [30000 x i8]
+  %start = getelementptr [30000 x i8], [30000 x i8]* %tape, i64 0, i64 0
+  call void @llvm.memset.p0i8.i64(i8* %start, i8 0, i64 30000, i1 false)
+  call void @bf_run(i8* %start)
+  ret i32 0
+}
+";
+
+    fn ir_file(name: &str, source: &str) -> (PathBuf, String) {
+        let code = crate::optimized_parse(source).unwrap();
+        // The harness defines the IO functions, so they can't be declared as well
+        let ir = to_llvm_ir(&code)
+            .replace("declare i32 @bf_read_byte()\n", "")
+            .replace("declare void @bf_write_byte(i32)\n", "")
+            + HARNESS;
+        let path = std::env::temp_dir().join(format!("bf_llvm_{}_{}.ll", name, std::process::id()));
+        std::fs::write(&path, &ir).unwrap();
+        (path, ir)
+    }
+
+    fn assert_valid_ir(name: &str, source: &str) {
+        let (path, ir) = ir_file(name, source);
+        let result = Command::new("llvm-as")
+            .arg(&path)
+            .arg("-o")
+            .arg("/dev/null")
+            .output()
+            .expect("couldn't run llvm-as");
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(
+            result.status.success(),
+            "Invalid IR: {}\n{}",
+            String::from_utf8_lossy(&result.stderr),
+            ir
+        );
+    }
+
+    fn run_lli(name: &str, source: &str, input: &[u8]) -> (Output, String) {
+        let (path, ir) = ir_file(name, source);
+        let mut lli = Command::new("lli")
+            .arg(&path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("couldn't run lli");
+        lli.stdin.take().unwrap().write_all(input).unwrap();
+        let result = lli.wait_with_output().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        (result, ir)
+    }
+
+    // Runs the IR with lli and checks it prints the same as the VM
+    fn assert_runs_like_vm(name: &str, source: &str, input: &[u8]) {
+        let (result, ir) = run_lli(name, source, input);
+
+        assert!(
+            result.status.success(),
+            "lli failed: {}\n{}",
+            String::from_utf8_lossy(&result.stderr),
+            ir
+        );
+
+        let mut output = crate::io::VecOutput::new();
+        crate::OptVM::new(crate::optimized_parse(source).unwrap())
+            .run(&mut crate::io::SliceInput::new(input), &mut output)
+            .unwrap();
+        assert_eq!(result.stdout, output.as_bytes());
+    }
+
+    // LLVM isn't a dependency of the crate, so these only run when asked for, with
+    // `cargo test -- --ignored`; without llvm-as and lli on the path they fail
+    #[test]
+    #[ignore = "needs llvm-as"]
+    fn programs_are_valid() {
+        assert_valid_ir("hello_world", include_str!("../../../input/hello_world.b"));
+        assert_valid_ir("quine", include_str!("../../../input/quine.b"));
+        assert_valid_ir("mandelbrot", include_str!("../../../input/mandelbrot.b"));
+        assert_valid_ir("spin", "+[]>+[>]");
+    }
+
+    #[test]
+    #[ignore = "needs lli"]
+    fn programs_run() {
+        assert_runs_like_vm("hello_world_run", include_str!("../../../input/hello_world.b"), b"");
+        assert_runs_like_vm("rot13_run", include_str!("../../../input/rot13.b"), b"f2rf2wfc!!#2eds\n@de");
+        assert_runs_like_vm("quine_run", include_str!("../../../input/quine.b"), b"");
+
+        // The VM stops these with an error, so they mustn't finish normally either
+        for (name, source, input) in [("spin_run", "+[]", &b""[..]), ("halt_run", ",[]", b"a")] {
+            let (result, ir) = run_lli(name, source, input);
+            assert!(!result.status.success(), "{} finished normally\n{}", source, ir);
+        }
+    }
+}
@@ -0,0 +1,112 @@
+use alloc::vec::Vec;
+
+use crate::CompiledInstr;
+
+mod c;
+mod disasm;
+mod dot;
+mod llvm;
+mod rust;
+mod wat;
+
+pub use self::c::to_c;
+pub use self::disasm::disassemble;
+pub use self::dot::to_dot;
+pub use self::llvm::to_llvm_ir;
+pub use self::rust::to_rust;
+pub use self::wat::to_wat;
+
+// Compiled code with its jumps turned back into the loops and ifs they came from, for the
+// backends whose targets want structured control flow
+enum Block<'a> {
+    // A run of instructions with no jumps among them
+    Straight(&'a [CompiledInstr]),
+    // Runs the body for as long as data[dp + cond_dp_offset] is nonzero
+    Loop { cond_dp_offset: isize, body: Vec<Block<'a>> },
+    // Runs the body once, if data[dp + cond_dp_offset] is nonzero
+    If { cond_dp_offset: isize, body: Vec<Block<'a>> },
+}
+
+// This relies on the jumps being well-nested, the way `compile_ast` produces them; it panics otherwise
+fn structure(code: &[CompiledInstr]) -> Vec<Block<'_>> {
+    structure_range(code, 0, code.len())
+}
+
+fn structure_range(code: &[CompiledInstr], start: usize, end: usize) -> Vec<Block<'_>> {
+    let mut blocks = Vec::new();
+    let mut straight_start = start;
+    let mut ip = start;
+
+    while ip < end {
+        match code[ip] {
+            CompiledInstr::JumpIfZero { cond_dp_offset, target_ip } => {
+                assert!(
+                    ip < target_ip && target_ip <= end,
+                    "Unstructured JumpIfZero at ip {}; its target is outside the enclosing block",
+                    ip
+                );
+
+                if straight_start < ip {
+                    blocks.push(Block::Straight(&code[straight_start..ip]));
+                }
+
+                // A loop closes with a JumpIfNonzero back to its JumpIfZero, which is just the loop
+                // header; so "jump to the top if nonzero" is the same thing
+                let is_loop = matches!(
+                    code[target_ip - 1],
+                    CompiledInstr::JumpIfNonzero { target_ip: back_ip, .. } if back_ip == ip
+                );
+
+                if is_loop {
+                    let body = structure_range(code, ip + 1, target_ip - 1);
+                    blocks.push(Block::Loop { cond_dp_offset, body });
+                } else {
+                    let body = structure_range(code, ip + 1, target_ip);
+                    blocks.push(Block::If { cond_dp_offset, body });
+                }
+
+                ip = target_ip;
+                straight_start = ip;
+            }
+            CompiledInstr::JumpIfNonzero { .. } => {
+                panic!("Unstructured JumpIfNonzero at ip {}; it doesn't close a loop", ip);
+            }
+            _ => ip += 1,
+        }
+    }
+
+    if straight_start < end {
+        blocks.push(Block::Straight(&code[straight_start..end]));
+    }
+
+    blocks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{structure, Block};
+    use crate::CompiledInstr;
+
+    #[test]
+    fn loops_nest() {
+        let code = crate::optimized_parse(",[>,[.,]<.]").unwrap();
+        let blocks = structure(&code);
+
+        match blocks.as_slice() {
+            [Block::Straight(_), Block::Loop { body, .. }] => {
+                assert!(body.iter().any(|block| matches!(block, Block::Loop { .. })));
+            }
+            _ => panic!("Unexpected structure for {:?}", code),
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Unstructured JumpIfNonzero")]
+    fn stray_jumps_panic() {
+        let code = vec![CompiledInstr::JumpIfNonzero {
+            cond_dp_offset: 0,
+            target_ip: 0,
+        }];
+        structure(&code);
+    }
+}
@@ -0,0 +1,227 @@
+use alloc::format;
+use alloc::string::String;
+use core::fmt::Write;
+
+use super::{structure, Block};
+use crate::CompiledInstr;
+
+/// Emits a standalone Rust program (a `main.rs`, with no dependencies) which runs the given
+/// (optimized) program, reading stdin and writing stdout.
+///
+/// The tape is a `Vec` of `tape_size` cells and the data pointer starts at zero. Leaving the tape
+/// panics on the index, where the VM would give an error. At the end of input, reads give zero,
+/// as they do for the VM's inputs. Where the VM would report an infinite loop, the program exits
+/// with status 1.
+///
+/// The jumps are turned back into while loops and ifs, which relies on them being well-nested, the
+/// way `compile_ast` produces them. Panics otherwise.
+///
+/// Cells always wrap, so the code should have been optimized for `OverflowPolicy::Wrap`.
+pub fn to_rust(code: &[CompiledInstr], tape_size: usize) -> String {
+    let mut out = String::new();
+
+    // Not every program moves the pointer, does IO, or gets past an infinite loop
+    out.push_str("#![allow(unused_mut, unused_variables, unused_assignments, unused_imports, unreachable_code)]\n\n");
+    out.push_str("use std::io::{Read, Write};\n\n");
+    out.push_str("fn main() {\n");
+    writeln!(out, "    let mut tape = vec![0u8; {}];", tape_size).unwrap();
+    out.push_str("    let mut dp: isize = 0;\n");
+    out.push_str("    let stdin = std::io::stdin();\n");
+    out.push_str("    let mut input = stdin.lock().bytes();\n");
+    out.push_str("    let stdout = std::io::stdout();\n");
+    out.push_str("    let mut output = std::io::BufWriter::new(stdout.lock());\n");
+
+    emit_blocks(&mut out, &structure(code), 1);
+
+    out.push_str("    output.flush().unwrap();\n");
+    out.push_str("}\n");
+
+    out
+}
+
+// The place data[dp + dp_offset]
+fn cell(dp_offset: isize) -> String {
+    if dp_offset == 0 {
+        String::from("tape[dp as usize]")
+    } else {
+        format!("tape[(dp + {}) as usize]", dp_offset)
+    }
+}
+
+// A byte string literal with these exact bytes
+fn literal(bytes: &[u8]) -> String {
+    let mut s = String::from("b\"");
+    for &byte in bytes {
+        match byte {
+            b'"' | b'\\' => write!(s, "\\{}", byte as char).unwrap(),
+            b' '..=b'~' => s.push(byte as char),
+            _ => write!(s, "\\x{:02x}", byte).unwrap(),
+        }
+    }
+    s.push('"');
+    s
+}
+
+fn emit_blocks(out: &mut String, blocks: &[Block], depth: usize) {
+    let indent = "    ".repeat(depth);
+
+    for block in blocks {
+        match *block {
+            Block::Straight(instrs) => {
+                for instr in instrs {
+                    emit_instr(out, instr, depth);
+                }
+            }
+            Block::Loop { cond_dp_offset, ref body } => {
+                writeln!(out, "{}while {} != 0 {{", indent, cell(cond_dp_offset)).unwrap();
+                emit_blocks(out, body, depth + 1);
+                writeln!(out, "{}}}", indent).unwrap();
+            }
+            Block::If { cond_dp_offset, ref body } => {
+                writeln!(out, "{}if {} != 0 {{", indent, cell(cond_dp_offset)).unwrap();
+                emit_blocks(out, body, depth + 1);
+                writeln!(out, "{}}}", indent).unwrap();
+            }
+        }
+    }
+}
+
+fn emit_instr(out: &mut String, instr: &CompiledInstr, depth: usize) {
+    let indent = "    ".repeat(depth);
+
+    match *instr {
+        CompiledInstr::JumpIfZero { .. } | CompiledInstr::JumpIfNonzero { .. } => {
+            unreachable!("Jumps are taken out by structure()")
+        }
+        CompiledInstr::InfiniteLoop => {
+            writeln!(out, "{}output.flush().unwrap();", indent).unwrap();
+            writeln!(out, "{}std::process::exit(1);", indent).unwrap();
+        }
+        CompiledInstr::HaltIfNonzero { cond_dp_offset } => {
+            writeln!(out, "{}if {} != 0 {{", indent, cell(cond_dp_offset)).unwrap();
+            writeln!(out, "{}    output.flush().unwrap();", indent).unwrap();
+            writeln!(out, "{}    std::process::exit(1);", indent).unwrap();
+            writeln!(out, "{}}}", indent).unwrap();
+        }
+        CompiledInstr::ResetTape => {
+            writeln!(out, "{}tape.iter_mut().for_each(|cell| *cell = 0);", indent).unwrap();
+            writeln!(out, "{}dp = 0;", indent).unwrap();
+        }
+        CompiledInstr::AddPtr { amount } => writeln!(out, "{}dp += {};", indent, amount).unwrap(),
+        CompiledInstr::SubPtr { amount } => writeln!(out, "{}dp -= {};", indent, amount).unwrap(),
+        CompiledInstr::AddData { amount, dp_offset } => {
+            let cell = cell(dp_offset);
+            writeln!(out, "{}{} = {}.wrapping_add({});", indent, cell, cell, amount).unwrap();
+        }
+        CompiledInstr::SetData { amount, dp_offset } => {
+            writeln!(out, "{}{} = {};", indent, cell(dp_offset), amount).unwrap();
+        }
+        CompiledInstr::AssertEquals { dp_offset, val } => {
+            writeln!(out, "{}// assert data[dp + {}] == {}", indent, dp_offset, val).unwrap();
+        }
+        CompiledInstr::AddTwoData {
+            source_dp_offset,
+            target_dp_offset,
+            source_amt_mult,
+        } => {
+            let target = cell(target_dp_offset);
+            writeln!(
+                out,
+                "{}{} = {}.wrapping_add({}.wrapping_mul({}));",
+                indent,
+                target,
+                target,
+                cell(source_dp_offset),
+                source_amt_mult
+            )
+            .unwrap();
+        }
+        CompiledInstr::ReadByte { dp_offset } => {
+            writeln!(out, "{}{} = input.next().map_or(0, |b| b.unwrap());", indent, cell(dp_offset)).unwrap();
+        }
+        CompiledInstr::WriteByte { dp_offset } => {
+            writeln!(out, "{}output.write_all(&[{}]).unwrap();", indent, cell(dp_offset)).unwrap();
+        }
+        CompiledInstr::WriteTransformed { dp_offset, add } => {
+            writeln!(
+                out,
+                "{}output.write_all(&[{}.wrapping_add({})]).unwrap();",
+                indent,
+                cell(dp_offset),
+                add
+            )
+            .unwrap();
+        }
+        CompiledInstr::WriteConst { out: byte } => {
+            writeln!(out, "{}output.write_all(&[{}]).unwrap();", indent, byte).unwrap();
+        }
+        CompiledInstr::WriteConstStr { ref bytes } => {
+            writeln!(out, "{}output.write_all({}).unwrap();", indent, literal(bytes)).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::to_rust;
+
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+
+    // Compiles the Rust with rustc, runs it, and checks it prints the same as the VM
+    fn assert_runs_like_vm(name: &str, source: &str, input: &[u8]) {
+        let code = crate::optimized_parse(source).unwrap();
+        let rust = to_rust(&code, crate::DEFAULT_TAPE_SIZE);
+        let dir = std::env::temp_dir();
+        let rs_path = dir.join(format!("bf_rust_{}_{}.rs", name, std::process::id()));
+        let exe_path = dir.join(format!("bf_rust_{}_{}", name, std::process::id()));
+        std::fs::write(&rs_path, &rust).unwrap();
+
+        let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+        let compiled = Command::new(rustc)
+            .arg("--edition=2018")
+            .arg("-Dwarnings")
+            .arg(&rs_path)
+            .arg("-o")
+            .arg(&exe_path)
+            .output()
+            .unwrap();
+        std::fs::remove_file(&rs_path).unwrap();
+        assert!(
+            compiled.status.success(),
+            "rustc failed: {}\n{}",
+            String::from_utf8_lossy(&compiled.stderr),
+            rust
+        );
+
+        let mut exe = Command::new(&exe_path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        exe.stdin.take().unwrap().write_all(input).unwrap();
+        let result = exe.wait_with_output().unwrap();
+        std::fs::remove_file(&exe_path).unwrap();
+        assert!(result.status.success(), "{}", rust);
+
+        let mut output = crate::io::VecOutput::new();
+        crate::OptVM::new(code)
+            .run(&mut crate::io::SliceInput::new(input), &mut output)
+            .unwrap();
+        assert_eq!(result.stdout, output.as_bytes());
+    }
+
+    #[test]
+    fn programs_run() {
+        assert_runs_like_vm("hello_world", include_str!("../../../input/hello_world.b"), b"");
+        assert_runs_like_vm("rot13", include_str!("../../../input/rot13.b"), b"f2rf2wfc!!#2eds\n@de");
+        assert_runs_like_vm("quine", include_str!("../../../input/quine.b"), b"");
+        assert_runs_like_vm("halt_if_nonzero", ">,[]", b"\0");
+    }
+
+    #[test]
+    fn tape_size_is_used() {
+        let rust = to_rust(&crate::optimized_parse("+[>+]").unwrap(), 1234);
+        assert!(rust.contains("vec![0u8; 1234]"), "{}", rust);
+    }
+}
@@ -0,0 +1,201 @@
+use alloc::format;
+use alloc::string::{String, ToString};
+use core::fmt::Write;
+
+use super::{structure, Block};
+use crate::CompiledInstr;
+
+/// Emits a WebAssembly text module which runs the given (optimized) program.
+///
+/// The tape is the module's linear memory (exported as `memory`), the data pointer starts at
+/// address zero, and IO goes through two imported functions, `env.read_byte: () -> i32` and
+/// `env.write_byte: (i32) -> ()`. The program itself is the exported function `run`.
+///
+/// Wasm only has structured control flow, so the jumps are turned back into blocks; this relies
+/// on them being well-nested, the way `compile_ast` produces them. Panics otherwise.
+///
+/// Cells always wrap, so the code should have been optimized for `OverflowPolicy::Wrap`.
+pub fn to_wat(code: &[CompiledInstr]) -> String {
+    let mut out = String::new();
+
+    out.push_str("(module\n");
+    out.push_str("  (import \"env\" \"read_byte\" (func $read_byte (result i32)))\n");
+    out.push_str("  (import \"env\" \"write_byte\" (func $write_byte (param i32)))\n");
+    out.push_str("  (memory (export \"memory\") 1)\n");
+    out.push_str("  (func (export \"run\")\n");
+    out.push_str("    (local $dp i32)\n");
+
+    emit_blocks(&mut out, &structure(code), 2);
+
+    out.push_str("  )\n");
+    out.push_str(")\n");
+
+    out
+}
+
+// The address of data[dp + dp_offset]
+fn addr(dp_offset: isize) -> String {
+    if dp_offset == 0 {
+        "(local.get $dp)".to_string()
+    } else {
+        format!("(i32.add (local.get $dp) (i32.const {}))", dp_offset)
+    }
+}
+
+fn load(dp_offset: isize) -> String {
+    format!("(i32.load8_u {})", addr(dp_offset))
+}
+
+fn emit_blocks(out: &mut String, blocks: &[Block], depth: usize) {
+    let indent = "  ".repeat(depth);
+
+    for block in blocks {
+        match *block {
+            Block::Straight(instrs) => {
+                for instr in instrs {
+                    emit_instr(out, instr, depth);
+                }
+            }
+            Block::Loop { cond_dp_offset, ref body } => {
+                writeln!(out, "{}(block", indent).unwrap();
+                writeln!(out, "{}  (br_if 0 (i32.eqz {}))", indent, load(cond_dp_offset)).unwrap();
+                writeln!(out, "{}  (loop", indent).unwrap();
+                emit_blocks(out, body, depth + 2);
+                writeln!(out, "{}    (br_if 0 {})))", indent, load(cond_dp_offset)).unwrap();
+            }
+            Block::If { cond_dp_offset, ref body } => {
+                writeln!(out, "{}(if {}", indent, load(cond_dp_offset)).unwrap();
+                writeln!(out, "{}  (then", indent).unwrap();
+                emit_blocks(out, body, depth + 2);
+                writeln!(out, "{}  ))", indent).unwrap();
+            }
+        }
+    }
+}
+
+fn emit_instr(out: &mut String, instr: &CompiledInstr, depth: usize) {
+    let indent = "  ".repeat(depth);
+
+    match *instr {
+        CompiledInstr::JumpIfZero { .. } | CompiledInstr::JumpIfNonzero { .. } => {
+            unreachable!("Jumps are taken out by structure()")
+        }
+        CompiledInstr::InfiniteLoop => {
+            writeln!(out, "{}(unreachable)", indent).unwrap();
+        }
+        CompiledInstr::HaltIfNonzero { cond_dp_offset } => {
+            writeln!(out, "{}(if {}", indent, load(cond_dp_offset)).unwrap();
+            writeln!(out, "{}  (then (unreachable)))", indent).unwrap();
+        }
+        // memory.fill is from the bulk memory proposal, which every current runtime has
+        CompiledInstr::ResetTape => {
+            writeln!(
+                out,
+                "{}(memory.fill (i32.const 0) (i32.const 0) (i32.mul (memory.size) (i32.const 65536)))",
+                indent
+            )
+            .unwrap();
+            writeln!(out, "{}(local.set $dp (i32.const 0))", indent).unwrap();
+        }
+        CompiledInstr::AddPtr { amount } => {
+            writeln!(out, "{}(local.set $dp (i32.add (local.get $dp) (i32.const {})))", indent, amount).unwrap();
+        }
+        CompiledInstr::SubPtr { amount } => {
+            writeln!(out, "{}(local.set $dp (i32.sub (local.get $dp) (i32.const {})))", indent, amount).unwrap();
+        }
+        // store8 only keeps the low byte, which takes care of the wrapping
+        CompiledInstr::AddData { amount, dp_offset } => {
+            writeln!(
+                out,
+                "{}(i32.store8 {} (i32.add {} (i32.const {})))",
+                indent,
+                addr(dp_offset),
+                load(dp_offset),
+                amount
+            )
+            .unwrap();
+        }
+        CompiledInstr::SetData { amount, dp_offset } => {
+            writeln!(out, "{}(i32.store8 {} (i32.const {}))", indent, addr(dp_offset), amount).unwrap();
+        }
+        CompiledInstr::AssertEquals { dp_offset, val } => {
+            writeln!(out, "{};; assert data[dp + {}] == {}", indent, dp_offset, val).unwrap();
+        }
+        CompiledInstr::AddTwoData {
+            source_dp_offset,
+            target_dp_offset,
+            source_amt_mult,
+        } => {
+            writeln!(
+                out,
+                "{}(i32.store8 {} (i32.add {} (i32.mul {} (i32.const {}))))",
+                indent,
+                addr(target_dp_offset),
+                load(target_dp_offset),
+                load(source_dp_offset),
+                source_amt_mult
+            )
+            .unwrap();
+        }
+        CompiledInstr::ReadByte { dp_offset } => {
+            writeln!(out, "{}(i32.store8 {} (call $read_byte))", indent, addr(dp_offset)).unwrap();
+        }
+        CompiledInstr::WriteByte { dp_offset } => {
+            writeln!(out, "{}(call $write_byte {})", indent, load(dp_offset)).unwrap();
+        }
+        CompiledInstr::WriteTransformed { dp_offset, add } => {
+            writeln!(
+                out,
+                "{}(call $write_byte (i32.and (i32.add {} (i32.const {})) (i32.const 255)))",
+                indent,
+                load(dp_offset),
+                add
+            )
+            .unwrap();
+        }
+        CompiledInstr::WriteConst { out: byte } => {
+            writeln!(out, "{}(call $write_byte (i32.const {}))", indent, byte).unwrap();
+        }
+        CompiledInstr::WriteConstStr { ref bytes } => {
+            for byte in bytes {
+                writeln!(out, "{}(call $write_byte (i32.const {}))", indent, byte).unwrap();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::to_wat;
+
+    fn assert_valid_wat(source: &str) {
+        let code = crate::optimized_parse(source).unwrap();
+        let wat = to_wat(&code);
+        let wasm = ::wat::parse_str(&wat).unwrap_or_else(|e| panic!("Invalid wat: {}\n{}", e, wat));
+        wasmparser::validate(&wasm).unwrap_or_else(|e| panic!("Invalid wasm: {}\n{}", e, wat));
+    }
+
+    #[test]
+    fn hello_world_is_valid() {
+        assert_valid_wat(include_str!("../../../input/hello_world.b"));
+    }
+
+    #[test]
+    fn rot13_is_valid() {
+        assert_valid_wat(include_str!("../../../input/rot13.b"));
+    }
+
+    #[test]
+    fn quine_is_valid() {
+        assert_valid_wat(include_str!("../../../input/quine.b"));
+    }
+
+    #[test]
+    fn loops_and_branches() {
+        let code = crate::optimized_parse(",[>+<-]>[.,]").unwrap();
+        let wat = to_wat(&code);
+
+        assert!(wat.contains("(loop"));
+        assert_valid_wat(",[>+<-]>[.,]");
+    }
+}
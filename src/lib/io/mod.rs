@@ -0,0 +1,735 @@
+#[cfg(feature = "std")]
+use alloc::collections::VecDeque;
+#[cfg(feature = "std")]
+use std::io::Write;
+
+use alloc::vec::Vec;
+
+/// What the IO traits need from their errors. With std this is just std::error::Error;
+/// without it, anything printable will do.
+#[cfg(feature = "std")]
+pub trait IoError: std::error::Error + 'static {}
+
+#[cfg(feature = "std")]
+impl<T: std::error::Error + 'static> IoError for T {}
+
+#[cfg(not(feature = "std"))]
+pub trait IoError: core::fmt::Debug + core::fmt::Display + 'static {}
+
+#[cfg(not(feature = "std"))]
+impl<T: core::fmt::Debug + core::fmt::Display + 'static> IoError for T {}
+
+pub trait Input {
+    type InputError: IoError;
+
+    fn read_byte(&mut self) -> Result<u8, Self::InputError>;
+
+    /// Read a byte, or None at EOF; this is what the VMs use, so they can treat EOF according to
+    /// their EofPolicy. Inputs which can tell should override it, since otherwise EOF is just
+    /// whatever read_byte gives.
+    fn read_byte_or_eof(&mut self) -> Result<Option<u8>, Self::InputError> {
+        self.read_byte().map(Some)
+    }
+}
+
+/// What a VM does when the program reads past the end of its input.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub enum EofPolicy {
+    /// The cell is set to 0, as if that had been read
+    #[default]
+    Zero,
+    /// The program stops with RuntimeError::UnexpectedEof
+    Error,
+    /// The program stops, successfully, as if it had run to the end
+    Halt,
+}
+
+/// What StdIn does with the line endings it reads. Terminals on Windows end lines with \r\n,
+/// which most BF programs don't expect.
+#[cfg(feature = "std")]
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub enum NewlineMode {
+    /// Pass every byte through as it is
+    #[default]
+    Raw,
+    /// Drop the \r of each \r\n, leaving other \rs alone
+    StripCr,
+    /// Turn \r\n, and any \r by itself, into \n
+    Lf,
+}
+
+#[cfg(feature = "std")]
+pub struct StdIn {
+    eof: bool,
+    input_buffer: VecDeque<u8>,
+    newlines: NewlineMode,
+}
+
+#[cfg(feature = "std")]
+impl StdIn {
+    pub fn new() -> Self {
+        Self {
+            eof: false,
+            input_buffer: VecDeque::new(),
+            newlines: NewlineMode::Raw,
+        }
+    }
+
+    /// Translate line endings according to the given mode; by default they're left alone
+    pub fn with_newlines(mut self, newlines: NewlineMode) -> Self {
+        self.newlines = newlines;
+        self
+    }
+}
+
+// Add a line (or the end of one) to the buffer, with its line endings translated
+#[cfg(feature = "std")]
+fn push_line(buffer: &mut VecDeque<u8>, line: &[u8], newlines: NewlineMode) {
+    let mut bytes = line.iter().copied().peekable();
+    while let Some(byte) = bytes.next() {
+        if byte != b'\r' {
+            buffer.push_back(byte);
+            continue;
+        }
+
+        let before_newline = bytes.peek() == Some(&NEWLINE_OUTPUT);
+        match newlines {
+            NewlineMode::Raw => buffer.push_back(byte),
+            NewlineMode::StripCr if before_newline => {}
+            NewlineMode::StripCr => buffer.push_back(byte),
+            NewlineMode::Lf if before_newline => {}
+            NewlineMode::Lf => buffer.push_back(NEWLINE_OUTPUT),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Default for StdIn {
+    fn default() -> Self {
+        StdIn::new()
+    }
+}
+
+const EOF_OUTPUT: u8 = 0;
+#[cfg(feature = "std")]
+const NEWLINE_OUTPUT: u8 = 10;
+
+#[cfg(feature = "std")]
+impl Input for StdIn {
+    type InputError = std::io::Error;
+
+    fn read_byte(&mut self) -> Result<u8, Self::InputError> {
+        Ok(self.read_byte_or_eof()?.unwrap_or(EOF_OUTPUT))
+    }
+
+    fn read_byte_or_eof(&mut self) -> Result<Option<u8>, Self::InputError> {
+        if self.eof {
+            return Ok(None);
+        }
+
+        while self.input_buffer.is_empty() {
+            let mut to_read = std::string::String::new();
+            std::io::stdin().read_line(&mut to_read)?;
+            if to_read.is_empty() {
+                self.eof = true;
+                return Ok(None);
+            }
+            // Tediously, it's impossible to do input from the terminal without adding newlines
+            // or bringing in a huge and frustrating dependency (a curses variant)
+            push_line(&mut self.input_buffer, to_read.as_bytes(), self.newlines);
+        }
+
+        Ok(self.input_buffer.pop_front())
+    }
+}
+
+/// Input read byte for byte from anything readable, e.g. a file, or stdin when it's a pipe; unlike
+/// StdIn, nothing is done to line endings. Reads go straight to the reader, so it's worth
+/// buffering (a locked stdin already is). Once the reader runs out, every read is EOF.
+#[cfg(feature = "std")]
+pub struct ReaderInput<R: std::io::Read> {
+    reader: R,
+    eof: bool,
+}
+
+#[cfg(feature = "std")]
+impl<R: std::io::Read> ReaderInput<R> {
+    pub fn new(reader: R) -> Self {
+        ReaderInput { reader, eof: false }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<R: std::io::Read> Input for ReaderInput<R> {
+    type InputError = std::io::Error;
+
+    fn read_byte(&mut self) -> Result<u8, Self::InputError> {
+        Ok(self.read_byte_or_eof()?.unwrap_or(EOF_OUTPUT))
+    }
+
+    fn read_byte_or_eof(&mut self) -> Result<Option<u8>, Self::InputError> {
+        if self.eof {
+            return Ok(None);
+        }
+
+        let mut byte = [0];
+        loop {
+            match self.reader.read(&mut byte) {
+                Ok(0) => {
+                    self.eof = true;
+                    return Ok(None);
+                }
+                Ok(_) => return Ok(Some(byte[0])),
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Input from a fixed buffer; once that runs out, every read is EOF.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct SliceInput<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> SliceInput<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        SliceInput { data, pos: 0 }
+    }
+
+    /// How many bytes have actually been read (EOF reads don't count)
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+}
+
+impl Input for SliceInput<'_> {
+    type InputError = core::convert::Infallible;
+
+    fn read_byte(&mut self) -> Result<u8, Self::InputError> {
+        Ok(self.read_byte_or_eof()?.unwrap_or(EOF_OUTPUT))
+    }
+
+    fn read_byte_or_eof(&mut self) -> Result<Option<u8>, Self::InputError> {
+        let byte = self.data.get(self.pos).copied();
+        if byte.is_some() {
+            self.pos += 1;
+        }
+        Ok(byte)
+    }
+}
+
+/// Input which never runs out: pseudo-random bytes from a generator seeded with the given seed,
+/// so the same seed always gives the same bytes, and a run which went wrong can be replayed.
+#[cfg(feature = "rand")]
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct RandomInput {
+    seed: u64,
+    rng: rand::rngs::Xoshiro256PlusPlus,
+}
+
+#[cfg(feature = "rand")]
+impl RandomInput {
+    pub fn new(seed: u64) -> Self {
+        RandomInput {
+            seed,
+            rng: rand::SeedableRng::seed_from_u64(seed),
+        }
+    }
+
+    /// The seed this started from, to give new for the same bytes again
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+}
+
+#[cfg(feature = "rand")]
+impl Input for RandomInput {
+    type InputError = core::convert::Infallible;
+
+    fn read_byte(&mut self) -> Result<u8, Self::InputError> {
+        Ok(rand::Rng::next_u32(&mut self.rng) as u8)
+    }
+}
+
+pub trait Output {
+    type OutputError: IoError;
+
+    fn write_byte(&mut self, byte: u8) -> Result<(), Self::OutputError>;
+
+    /// Write several bytes at once. This is the same as writing them one at a time, which is
+    /// what it does unless it's overridden with something quicker.
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), Self::OutputError> {
+        for byte in bytes {
+            self.write_byte(*byte)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std")]
+pub struct StdOut(());
+
+#[cfg(feature = "std")]
+impl StdOut {
+    pub fn new() -> Self {
+        StdOut(())
+    }
+}
+
+#[cfg(feature = "std")]
+impl Default for StdOut {
+    fn default() -> Self {
+        StdOut::new()
+    }
+}
+
+#[cfg(feature = "std")]
+impl Output for StdOut {
+    type OutputError = std::io::Error;
+
+    fn write_byte(&mut self, byte: u8) -> Result<(), Self::OutputError> {
+        std::io::stdout().lock().write_all(&[byte])?;
+        Ok(())
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), Self::OutputError> {
+        std::io::stdout().lock().write_all(bytes)?;
+        Ok(())
+    }
+}
+
+/// When BufferedOutput hands what it's buffered on to the underlying writer.
+#[cfg(feature = "std")]
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum FlushPolicy {
+    /// Straight away, which is the same as not buffering at all
+    EveryByte,
+    /// At the end of each line
+    OnNewline,
+    /// Only when the buffer fills up, or the output is dropped or flushed
+    OnExit,
+}
+
+/// Output which is buffered, and flushed according to a FlushPolicy. Note that a prompt without a
+/// newline won't be seen before the program blocks on input unless it's flushed on every byte.
+#[cfg(feature = "std")]
+pub struct BufferedOutput<W: Write> {
+    out: std::io::BufWriter<W>,
+    policy: FlushPolicy,
+}
+
+#[cfg(feature = "std")]
+impl<W: Write> BufferedOutput<W> {
+    pub fn new(inner: W, policy: FlushPolicy) -> Self {
+        BufferedOutput {
+            out: std::io::BufWriter::new(inner),
+            policy,
+        }
+    }
+
+    pub fn flush(&mut self) -> Result<(), std::io::Error> {
+        self.out.flush()
+    }
+
+    pub fn get_ref(&self) -> &W {
+        self.out.get_ref()
+    }
+}
+
+#[cfg(feature = "std")]
+impl BufferedOutput<std::io::Stdout> {
+    /// Buffered stdout, flushed on every newline if that's a terminal, and otherwise only at the end
+    pub fn stdout() -> Self {
+        use std::io::IsTerminal;
+
+        let stdout = std::io::stdout();
+        let policy = if stdout.is_terminal() {
+            FlushPolicy::OnNewline
+        } else {
+            FlushPolicy::OnExit
+        };
+        BufferedOutput::new(stdout, policy)
+    }
+}
+
+// Dropping the BufWriter flushes whatever's left, which takes care of OnExit
+#[cfg(feature = "std")]
+impl<W: Write> Output for BufferedOutput<W> {
+    type OutputError = std::io::Error;
+
+    fn write_byte(&mut self, byte: u8) -> Result<(), Self::OutputError> {
+        self.out.write_all(&[byte])?;
+        match self.policy {
+            FlushPolicy::EveryByte => self.out.flush(),
+            FlushPolicy::OnNewline if byte == b'\n' => self.out.flush(),
+            FlushPolicy::OnNewline | FlushPolicy::OnExit => Ok(()),
+        }
+    }
+
+    // Flushes in the same places writing the bytes one at a time would have
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), Self::OutputError> {
+        match self.policy {
+            FlushPolicy::EveryByte => {
+                self.out.write_all(bytes)?;
+                self.out.flush()
+            }
+            FlushPolicy::OnNewline => match bytes.iter().rposition(|byte| *byte == b'\n') {
+                Some(last_newline) => {
+                    self.out.write_all(&bytes[..=last_newline])?;
+                    self.out.flush()?;
+                    self.out.write_all(&bytes[last_newline + 1..])
+                }
+                None => self.out.write_all(bytes),
+            },
+            FlushPolicy::OnExit => self.out.write_all(bytes),
+        }
+    }
+}
+
+/// Output which is just collected in memory.
+#[derive(Default, Clone, Eq, PartialEq, Debug)]
+pub struct VecOutput {
+    data: Vec<u8>,
+}
+
+impl VecOutput {
+    pub fn new() -> Self {
+        VecOutput::default()
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+impl Output for VecOutput {
+    type OutputError = core::convert::Infallible;
+
+    fn write_byte(&mut self, byte: u8) -> Result<(), Self::OutputError> {
+        self.data.push(byte);
+        Ok(())
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), Self::OutputError> {
+        self.data.extend_from_slice(bytes);
+        Ok(())
+    }
+}
+
+/// Output which goes to both of two others, say a file and the terminal. Each byte goes to the
+/// first and then the second; if the first fails, the second never gets it.
+pub struct TeeOutput<A: Output, B: Output> {
+    first: A,
+    second: B,
+}
+
+impl<A: Output, B: Output> TeeOutput<A, B> {
+    pub fn new(first: A, second: B) -> Self {
+        TeeOutput { first, second }
+    }
+
+    pub fn into_inner(self) -> (A, B) {
+        (self.first, self.second)
+    }
+}
+
+/// Which side of a TeeOutput failed, and how
+#[derive(Debug)]
+pub enum TeeError<A, B> {
+    First(A),
+    Second(B),
+}
+
+impl<A: core::fmt::Display, B: core::fmt::Display> core::fmt::Display for TeeError<A, B> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            TeeError::First(e) => write!(f, "Error writing to the first output: {}", e),
+            TeeError::Second(e) => write!(f, "Error writing to the second output: {}", e),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<A: IoError, B: IoError> std::error::Error for TeeError<A, B> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TeeError::First(e) => Some(e),
+            TeeError::Second(e) => Some(e),
+        }
+    }
+}
+
+impl<A: Output, B: Output> Output for TeeOutput<A, B> {
+    type OutputError = TeeError<A::OutputError, B::OutputError>;
+
+    fn write_byte(&mut self, byte: u8) -> Result<(), Self::OutputError> {
+        self.first.write_byte(byte).map_err(TeeError::First)?;
+        self.second.write_byte(byte).map_err(TeeError::Second)
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), Self::OutputError> {
+        self.first.write_bytes(bytes).map_err(TeeError::First)?;
+        self.second.write_bytes(bytes).map_err(TeeError::Second)
+    }
+}
+
+/// Output which gives up with LimitError::LimitReached once a program tries to write more than
+/// limit bytes, so one stuck printing forever can't flood whatever's behind it. Along with a
+/// step limit, that bounds everything a program can do. The bytes up to the limit are written.
+pub struct LimitedOutput<O: Output> {
+    inner: O,
+    limit: usize,
+    written: usize,
+}
+
+impl<O: Output> LimitedOutput<O> {
+    pub fn new(inner: O, limit: usize) -> Self {
+        LimitedOutput { inner, limit, written: 0 }
+    }
+
+    /// How many bytes have gone through so far
+    pub fn written(&self) -> usize {
+        self.written
+    }
+
+    pub fn into_inner(self) -> O {
+        self.inner
+    }
+}
+
+/// Why a LimitedOutput failed
+#[derive(Debug)]
+pub enum LimitError<E> {
+    LimitReached { limit: usize },
+    Inner(E),
+}
+
+impl<E: core::fmt::Display> core::fmt::Display for LimitError<E> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            LimitError::LimitReached { limit } => write!(f, "Tried to write more than {} bytes", limit),
+            LimitError::Inner(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<E: IoError> std::error::Error for LimitError<E> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LimitError::LimitReached { .. } => None,
+            LimitError::Inner(e) => Some(e),
+        }
+    }
+}
+
+impl<O: Output> Output for LimitedOutput<O> {
+    type OutputError = LimitError<O::OutputError>;
+
+    fn write_byte(&mut self, byte: u8) -> Result<(), Self::OutputError> {
+        self.write_bytes(&[byte])
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), Self::OutputError> {
+        let room = self.limit - self.written;
+        let fits = &bytes[..bytes.len().min(room)];
+        self.inner.write_bytes(fits).map_err(LimitError::Inner)?;
+        self.written += fits.len();
+
+        if fits.len() < bytes.len() {
+            return Err(LimitError::LimitReached { limit: self.limit });
+        }
+        Ok(())
+    }
+}
+
+/// Output which decodes the bytes as UTF-8 and hands each char to a callback, for something like
+/// a GUI which wants text rather than bytes. A char split over several writes is held until the
+/// last of its bytes arrives. Bytes which can't be UTF-8 come out as U+FFFD, the replacement
+/// character, as String::from_utf8_lossy would have it.
+pub struct Utf8Output<F: FnMut(char)> {
+    callback: F,
+    // The start of a char whose other bytes haven't been written yet
+    pending: Vec<u8>,
+}
+
+impl<F: FnMut(char)> Utf8Output<F> {
+    pub fn new(callback: F) -> Self {
+        Utf8Output {
+            callback,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Call this once the program is done; if it stopped partway through a char, that comes out
+    /// as a replacement character.
+    pub fn finish(&mut self) {
+        if !self.pending.is_empty() {
+            self.pending.clear();
+            (self.callback)(char::REPLACEMENT_CHARACTER);
+        }
+    }
+}
+
+impl<F: FnMut(char)> Output for Utf8Output<F> {
+    type OutputError = core::convert::Infallible;
+
+    fn write_byte(&mut self, byte: u8) -> Result<(), Self::OutputError> {
+        self.pending.push(byte);
+
+        // There's never more than one char pending, so whatever's valid is all of it
+        while !self.pending.is_empty() {
+            match core::str::from_utf8(&self.pending) {
+                Ok(s) => {
+                    s.chars().for_each(&mut self.callback);
+                    self.pending.clear();
+                }
+                Err(e) => match e.error_len() {
+                    // The char isn't finished yet
+                    None => break,
+                    // Replace what can't be a char, and try again with anything after it
+                    Some(bad) => {
+                        self.pending.drain(..bad);
+                        (self.callback)(char::REPLACEMENT_CHARACTER);
+                    }
+                },
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::{
+        push_line, BufferedOutput, FlushPolicy, Input, LimitError, LimitedOutput, NewlineMode, Output, ReaderInput, TeeError, TeeOutput,
+        Utf8Output, VecOutput,
+    };
+    use alloc::collections::VecDeque;
+
+    fn written(policy: FlushPolicy, bytes: &[u8]) -> Vec<u8> {
+        let mut out = BufferedOutput::new(Vec::new(), policy);
+        for byte in bytes {
+            out.write_byte(*byte).unwrap();
+        }
+        let byte_by_byte = out.get_ref().clone();
+
+        let mut out = BufferedOutput::new(Vec::new(), policy);
+        out.write_bytes(bytes).unwrap();
+        assert_eq!(out.get_ref(), &byte_by_byte);
+
+        byte_by_byte
+    }
+
+    #[test]
+    fn flush_policies() {
+        assert_eq!(written(FlushPolicy::EveryByte, b"ab\ncd"), b"ab\ncd");
+        assert_eq!(written(FlushPolicy::OnNewline, b"ab\ncd"), b"ab\n");
+        assert_eq!(written(FlushPolicy::OnNewline, b"ab\ncd\n"), b"ab\ncd\n");
+        assert_eq!(written(FlushPolicy::OnExit, b"ab\ncd"), b"");
+
+        let mut out = BufferedOutput::new(Vec::new(), FlushPolicy::OnExit);
+        out.write_byte(b'a').unwrap();
+        out.flush().unwrap();
+        assert_eq!(out.get_ref(), b"a");
+    }
+
+    #[test]
+    fn newlines_are_translated() {
+        let translated = |line: &[u8], newlines| {
+            let mut buffer = VecDeque::new();
+            push_line(&mut buffer, line, newlines);
+            buffer.into_iter().collect::<Vec<u8>>()
+        };
+
+        assert_eq!(translated(b"a\rb\r\n", NewlineMode::Raw), b"a\rb\r\n");
+        assert_eq!(translated(b"a\rb\r\n", NewlineMode::StripCr), b"a\rb\n");
+        assert_eq!(translated(b"a\rb\r\n", NewlineMode::Lf), b"a\nb\n");
+        // A line which doesn't end in a newline (at the end of the input) is left as it is
+        assert_eq!(translated(b"ab", NewlineMode::Lf), b"ab");
+    }
+
+    #[test]
+    fn readers_are_read_as_is() {
+        let mut input = ReaderInput::new(&b"a\r\nb"[..]);
+        let read: Vec<u8> = (0..6).map(|_| input.read_byte().unwrap()).collect();
+        assert_eq!(read, b"a\r\nb\0\0");
+    }
+
+    #[test]
+    fn tees_write_to_both() {
+        let mut out = TeeOutput::new(VecOutput::new(), BufferedOutput::new(Vec::new(), FlushPolicy::EveryByte));
+        out.write_byte(b'a').unwrap();
+        out.write_bytes(b"bc").unwrap();
+
+        let (first, second) = out.into_inner();
+        assert_eq!(first.as_bytes(), b"abc");
+        assert_eq!(second.get_ref(), b"abc");
+
+        // A full buffer makes the second one fail, after the first has the byte
+        let mut buffer = [0u8; 1];
+        let mut out = TeeOutput::new(VecOutput::new(), BufferedOutput::new(&mut buffer[..], FlushPolicy::EveryByte));
+        out.write_byte(b'a').unwrap();
+        assert!(matches!(out.write_byte(b'b'), Err(TeeError::Second(_))));
+        assert_eq!(out.into_inner().0.as_bytes(), b"ab");
+    }
+
+    #[test]
+    fn utf8_is_decoded_across_writes() {
+        let decode = |bytes: &[u8]| {
+            let mut chars = String::new();
+            let mut out = Utf8Output::new(|c| chars.push(c));
+            let code = crate::optimized_parse(&",.".repeat(bytes.len())).unwrap();
+            crate::OptVM::new(code).run(&mut super::SliceInput::new(bytes), &mut out).unwrap();
+            out.finish();
+            chars
+        };
+
+        // An é is two bytes, each its own write
+        assert_eq!(decode("é".as_bytes()), "é");
+        assert_eq!(decode("a€b😀".as_bytes()), "a€b😀");
+        // A char cut short by the next one, a stray continuation byte, and one cut short at the end
+        assert_eq!(decode(&[0xC3, b'A', 0xA9, 0xE2, 0x82]), "\u{FFFD}A\u{FFFD}\u{FFFD}");
+        assert_eq!(
+            decode(&[0xC3, b'A', 0xA9, 0xE2, 0x82]),
+            String::from_utf8_lossy(&[0xC3, b'A', 0xA9, 0xE2, 0x82])
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "rand")]
+    fn random_input_replays_from_its_seed() {
+        let read = |input: &mut super::RandomInput| (0..32).map(|_| input.read_byte().unwrap()).collect::<Vec<u8>>();
+
+        let mut input = super::RandomInput::new(1234);
+        let first = read(&mut input);
+        assert_ne!(read(&mut input), first);
+        assert_eq!(read(&mut super::RandomInput::new(input.seed())), first);
+        assert_ne!(read(&mut super::RandomInput::new(1235)), first);
+    }
+
+    #[test]
+    fn output_is_cut_off_at_the_limit() {
+        // Prints forever, unless something stops it
+        let code = crate::optimized_parse("+[.]").unwrap();
+        let mut out = LimitedOutput::new(VecOutput::new(), 10);
+        let err = crate::OptVM::new(code).run(&mut super::SliceInput::new(b""), &mut out).unwrap_err();
+        assert!(matches!(err, crate::VmError::Output(LimitError::LimitReached { limit: 10 })));
+        assert_eq!(out.into_inner().as_bytes(), &[1; 10]);
+
+        // As much of a longer write as fits still goes through
+        let mut out = LimitedOutput::new(VecOutput::new(), 4);
+        out.write_bytes(b"abc").unwrap();
+        assert!(out.write_bytes(b"def").is_err());
+        assert_eq!(out.written(), 4);
+        assert_eq!(out.into_inner().as_bytes(), b"abcd");
+    }
+}
@@ -1,5 +1,69 @@
+//! Without the `std` feature this is `no_std` (it still needs `alloc`); the only things which go
+//! missing are the stdin/stdout IO types and the optimizer's diagnostics.
+#![cfg_attr(all(not(feature = "std"), not(test)), no_std)]
+
+extern crate alloc;
+
+// The optimizer and VMs narrate what they're doing; this is only printed with the `logging` feature,
+// and only while set_logging hasn't turned it off. Otherwise the arguments are still type checked
+// (so nothing goes unused), but never formatted.
+#[cfg(feature = "logging")]
+macro_rules! log {
+    ($($arg:tt)*) => {
+        if crate::LOGGING.load(core::sync::atomic::Ordering::Relaxed) {
+            println!($($arg)*)
+        }
+    };
+}
+
+#[cfg(feature = "logging")]
+static LOGGING: core::sync::atomic::AtomicBool = core::sync::atomic::AtomicBool::new(true);
+
+/// Turns the optimizer's and VMs' diagnostics off (or back on), for callers which need stdout to
+/// themselves, like a transpiler printing its output. This does nothing without the `logging`
+/// feature, since then there aren't any.
+pub fn set_logging(enabled: bool) {
+    #[cfg(feature = "logging")]
+    LOGGING.store(enabled, core::sync::atomic::Ordering::Relaxed);
+    #[cfg(not(feature = "logging"))]
+    let _ = enabled;
+}
+
+#[cfg(not(feature = "logging"))]
+macro_rules! log {
+    ($($arg:tt)*) => {
+        if false {
+            let _ = format_args!($($arg)*);
+        }
+    };
+}
+
+mod backends;
+pub mod io;
 mod optimized;
+mod overflow;
+mod run;
 mod simple;
+mod vm;
 
-pub use optimized::{full_parse as optimized_parse, CompiledInstr};
-pub use simple::{parse as simple_parse, BfInstr};
+pub use backends::{disassemble, to_c, to_dot, to_llvm_ir, to_rust, to_wat};
+pub use optimized::{
+    analyze, canonicalize, find_nonterminating_loops, from_relative, full_parse as optimized_parse,
+    full_parse_strict as optimized_parse_strict, full_parse_with_options as optimized_parse_with_options, optimize_ast,
+    optimize_ast_with_snapshots, output_depends_on_input, parse_all_errors, parse_to_ast, to_relative, validate, visit_instr_variant, walk,
+    CommandMap, CompiledInstr, DatamodKind, InstrVisitor, OffsetBounds, OptPasses, ParseError, ParseOptions, Parsed, ProgramStats,
+    RelInstr, SourceMap, SourcePos, SourceSpan, ValidationError, AST as AstNode, MAX_LOOP_DEPTH,
+};
+#[cfg(feature = "std")]
+pub use optimized::{full_parse_reader as optimized_parse_reader, ReadParseError};
+pub use overflow::OverflowPolicy;
+pub use run::{run_program, run_program_str, RunError};
+pub use simple::{format_source, loop_pairs, parse as simple_parse, split_source_and_input, to_source, BfInstr};
+#[cfg(feature = "jit")]
+pub use vm::JitVM;
+#[cfg(feature = "simple")]
+pub use vm::SimpleVM;
+pub use vm::{
+    BadJumpTarget, DryRunReport, FixedTape, GrowableTape, Operand, OptVM, PointerMode, RuntimeError, Tape, ThreadedVM, UsageTape, VecTape,
+    VmError, VmState, WatchEvent, DEFAULT_TAPE_SIZE,
+};
@@ -0,0 +1,182 @@
+use alloc::vec::Vec;
+
+use super::{DatamodKind, AST};
+
+/// Puts the tree into a standard order, so two trees which only differ in the order of
+/// independent arithmetic come out equal; for comparing what the optimizer makes of a program
+/// without depending on the exact order sort_commands happened to leave things in.
+///
+/// Within each stretch of ModData and CombineData, commands are ordered by the cell they write,
+/// then by kind, then by the rest of their fields; but never past another command touching a
+/// cell they write, or reading one they write, so the result does exactly what the original did.
+/// Everything else (shifts, IO, loops) stays where it is, with the inside of loops and branches
+/// put in order the same way.
+pub fn canonicalize(cmds: &mut Vec<AST>) {
+    let old = core::mem::take(cmds);
+    let mut run = Vec::new();
+
+    for mut cmd in old {
+        match cmd {
+            AST::ModData { .. } | AST::CombineData { .. } => {
+                run.push(cmd);
+                continue;
+            }
+            AST::Loop { ref mut elements, .. } | AST::IfNonZero { ref mut elements, .. } => canonicalize(elements),
+            _ => {}
+        }
+
+        order_run(&mut run, cmds);
+        cmds.push(cmd);
+    }
+
+    order_run(&mut run, cmds);
+}
+
+// Moves the run into out, smallest first, where a command can only go once nothing before it
+// that it depends on is still waiting
+fn order_run(run: &mut Vec<AST>, out: &mut Vec<AST>) {
+    while !run.is_empty() {
+        let next = (0..run.len())
+            .filter(|&i| run[..i].iter().all(|earlier| !conflicts(earlier, &run[i])))
+            .min_by_key(|&i| sort_key(&run[i]))
+            .expect("The first command can always go");
+        out.push(run.remove(next));
+    }
+}
+
+// The cell the command writes, then which kind it is, then the rest
+fn sort_key(cmd: &AST) -> (isize, u8, isize, u8) {
+    match *cmd {
+        AST::ModData {
+            kind: DatamodKind::SetData { amount },
+            dp_offset,
+            ..
+        } => (dp_offset, 0, 0, amount),
+        AST::ModData {
+            kind: DatamodKind::AddData { amount },
+            dp_offset,
+            ..
+        } => (dp_offset, 1, 0, amount),
+        AST::CombineData {
+            source_dp_offset,
+            target_dp_offset,
+            source_amt_mult,
+            ..
+        } => (target_dp_offset, 2, source_dp_offset, source_amt_mult),
+        _ => unreachable!("Only arithmetic is reordered"),
+    }
+}
+
+// Whether the two have to stay in the order they're in: one writes a cell the other touches
+fn conflicts(a: &AST, b: &AST) -> bool {
+    let (a_write, a_read) = cells(a);
+    let (b_write, b_read) = cells(b);
+    a_write == b_write || Some(a_write) == b_read || a_read == Some(b_write)
+}
+
+// The cell written, and the other cell read, if there is one
+fn cells(cmd: &AST) -> (isize, Option<isize>) {
+    match *cmd {
+        AST::ModData { dp_offset, .. } => (dp_offset, None),
+        AST::CombineData {
+            source_dp_offset,
+            target_dp_offset,
+            ..
+        } => (target_dp_offset, Some(source_dp_offset)),
+        _ => unreachable!("Only arithmetic is reordered"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::canonicalize;
+    use crate::optimized::{compile_ast, parse, DatamodKind, SourcePos, AST};
+
+    fn add(dp_offset: isize, amount: u8) -> AST {
+        AST::ModData {
+            kind: DatamodKind::AddData { amount },
+            dp_offset,
+            code_p: SourcePos(None),
+        }
+    }
+
+    fn set(dp_offset: isize, amount: u8) -> AST {
+        AST::ModData {
+            kind: DatamodKind::SetData { amount },
+            dp_offset,
+            code_p: SourcePos(None),
+        }
+    }
+
+    fn combine(source_dp_offset: isize, target_dp_offset: isize) -> AST {
+        AST::CombineData {
+            source_dp_offset,
+            target_dp_offset,
+            source_amt_mult: 1,
+            code_p: SourcePos(None),
+        }
+    }
+
+    fn write(dp_offset: isize) -> AST {
+        AST::WriteByte {
+            dp_offset,
+            code_p: SourcePos(None),
+        }
+    }
+
+    #[test]
+    fn independent_arithmetic_is_sorted() {
+        let mut a = vec![add(2, 1), set(0, 5), write(0), add(1, 3), add(-1, 3)];
+        let mut b = vec![set(0, 5), add(2, 1), write(0), add(-1, 3), add(1, 3)];
+        canonicalize(&mut a);
+        canonicalize(&mut b);
+        assert_eq!(a, b);
+        assert_eq!(a, vec![set(0, 5), add(2, 1), write(0), add(-1, 3), add(1, 3)]);
+
+        // Inside loops too
+        let mut looped = vec![AST::Loop {
+            known_to_be_nontrivial: false,
+            cond_dp_offset: 0,
+            elements: vec![add(1, 1), add(0, 255)],
+            code_p: SourcePos(None),
+        }];
+        canonicalize(&mut looped);
+        assert!(matches!(&looped[0], AST::Loop { elements, .. } if elements[..] == [add(0, 255), add(1, 1)]));
+    }
+
+    #[test]
+    fn dependent_arithmetic_keeps_its_order() {
+        // The add reads cell 3 before the set, so it has to stay first, and so does the set to 1 it
+        // reads after
+        let mut cmds = vec![set(1, 2), combine(3, 1), set(3, 0)];
+        canonicalize(&mut cmds);
+        assert_eq!(cmds, vec![set(1, 2), combine(3, 1), set(3, 0)]);
+
+        let mut cmds = vec![set(5, 2), combine(5, 0), add(5, 1)];
+        canonicalize(&mut cmds);
+        assert_eq!(cmds, vec![set(5, 2), combine(5, 0), add(5, 1)]);
+    }
+
+    #[test]
+    fn canonical_code_does_the_same() {
+        let source = include_str!("../../../input/rot13.b");
+        let mut ast = parse(source).unwrap();
+        crate::optimize_ast(&mut ast, &crate::ParseOptions::default());
+        let mut canonical = ast.clone();
+        canonicalize(&mut canonical);
+
+        let run = |ast: &[AST]| {
+            let mut output = crate::io::VecOutput::new();
+            crate::OptVM::new(compile_ast(ast))
+                .run(&mut crate::io::SliceInput::new(b"Hello, World!\n"), &mut output)
+                .unwrap();
+            output.into_bytes()
+        };
+        assert_eq!(run(&ast), run(&canonical));
+
+        // Doing it again changes nothing
+        let mut again = canonical.clone();
+        canonicalize(&mut again);
+        assert_eq!(again, canonical);
+    }
+}
@@ -0,0 +1,67 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use super::optimization::leaves_cell_alone;
+use super::{lex, parse, BfCmd, CommandMap, ParseError};
+
+/// Where a loop is in the source: the code points of its [ and its ]
+pub type SourceSpan = (usize, usize);
+
+/// Finds every loop whose body never changes the cell it checks (and leaves dp where it found
+/// it), so the loop either never starts or never stops; e.g. +[] or ,[.], which should usually
+/// have been ,[.,]. This is for warning about a program before running it, so it goes by the
+/// source alone, not what the optimizer makes of it. The loops come in the order they start.
+///
+/// A loop the program can never reach, like one used for a comment at the very start, is found
+/// too, since there's no telling which case it is without running it. A loop whose body moves
+/// dp, or changes the cell inside another loop, isn't, though it may well not stop either.
+pub fn find_nonterminating_loops(src: &str) -> Result<Vec<SourceSpan>, ParseError> {
+    parse(src)?;
+
+    let chars: Vec<char> = src.chars().collect();
+    let mut starts = Vec::new();
+    let mut spans = Vec::new();
+
+    for (code_p, token) in lex(&mut chars.iter().copied(), &CommandMap::default()) {
+        match token {
+            BfCmd::LoopStart => starts.push(code_p),
+            BfCmd::LoopEnd => {
+                let start = starts.pop().expect("The brackets were checked by parsing");
+                let body: String = chars[start + 1..code_p].iter().collect();
+                let body = parse(&body).expect("The body of a matched loop is matched too");
+                if leaves_cell_alone(&body, 0) {
+                    spans.push((start, code_p));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    spans.sort_unstable();
+    Ok(spans)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loops_which_cannot_stop_are_found() {
+        assert_eq!(find_nonterminating_loops("+[]").unwrap(), vec![(1, 2)]);
+        assert_eq!(find_nonterminating_loops("+[-]").unwrap(), vec![]);
+
+        // Echoing without reading the next byte; the fix is fine
+        assert_eq!(find_nonterminating_loops(",[.]").unwrap(), vec![(1, 3)]);
+        assert_eq!(find_nonterminating_loops(",[.,]").unwrap(), vec![]);
+
+        // Changing another cell doesn't count, but changing it in an inner loop does
+        assert_eq!(find_nonterminating_loops("+[>+<] [[-]]").unwrap(), vec![(1, 5)]);
+        // Loops which move are left alone, and positions count comments
+        assert_eq!(find_nonterminating_loops("+[>] x +[ [>+<]. ]").unwrap(), vec![(8, 17), (10, 14)]);
+
+        assert!(matches!(
+            find_nonterminating_loops("[]]"),
+            Err(ParseError::EndLoopWithoutStart { code_p: 2 })
+        ));
+    }
+}
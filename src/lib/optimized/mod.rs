@@ -1,5 +1,11 @@
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::OverflowPolicy;
+
 /// These are the "compiled instructions" which are to be used after parsing and optimizing.
-#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[derive(Clone, Eq, PartialEq, Hash, PartialOrd, Ord, Debug)]
 pub enum CompiledInstr {
     // Read the value data pointer; if zero, jump to target, otherwise increment ip
     JumpIfZero {
@@ -13,6 +19,13 @@ pub enum CompiledInstr {
     },
     // Happens with bad code; interpreter can just bomb out if they want
     InfiniteLoop,
+    // If data[dp + cond_dp_offset] is nonzero, the program spins forever from here; this is what
+    // an IfNonZero around an InfiniteLoop compiles to, so the VM can say which cell it was
+    HaltIfNonzero {
+        cond_dp_offset: isize,
+    },
+    // Zero every cell on the tape, and put the data pointer back at the start; see CommandMap::reset_tape
+    ResetTape,
     // Adds a given amount to the data pointer.
     AddPtr {
         amount: usize,
@@ -21,10 +34,10 @@ pub enum CompiledInstr {
     SubPtr {
         amount: usize,
     },
-    // Add the given amount to the byte at the data pointer. Note that due to wrapping,
-    // we can (e.g.) subtract 1 by adding 255.
-    // dp_offset means "add amount to byte at dp + dp_offset". Out of bounds writes are UB and
-    // not handled (sorry).
+    // Add the given amount to the byte at the data pointer. The amount is a signed byte, so
+    // we can (e.g.) subtract 1 by adding 255 (see OverflowPolicy).
+    // dp_offset means "add amount to byte at dp + dp_offset". Out of bounds accesses are an error
+    // in the VM, but the optimizer assumes the tape is infinite, so it may fold some of them away.
     AddData {
         amount: u8,
         dp_offset: isize,
@@ -33,7 +46,7 @@ pub enum CompiledInstr {
         amount: u8,
         dp_offset: isize,
     },
-    // TODO: Put this behind a compile feature (it's a post-compile check for branch elimination optimizations)
+    // A post-compile check for branch elimination optimizations; left out with the strip_asserts feature
     AssertEquals {
         dp_offset: isize,
         val: u8,
@@ -52,44 +65,65 @@ pub enum CompiledInstr {
     WriteByte {
         dp_offset: isize,
     },
+    // Write data[dp + dp_offset] + add (as AddData would add it), leaving the cell as it was;
+    // what AST::WriteTransformed compiles to
+    WriteTransformed {
+        dp_offset: isize,
+        add: u8,
+    },
     WriteConst {
         out: u8,
     },
+    // Write several known bytes at once
+    WriteConstStr {
+        bytes: Vec<u8>,
+    },
 }
 
 /// Very similar to the compiled situation, but nested for the benefit of loop folding
 /// This is structured for the benefit of compiling / optimizing; this is not the bytecode
 /// format for the interpreter.
+///
+/// This is public (as AstNode) for tools which want the structured form; see parse_to_ast.
 #[derive(Debug, Clone, Eq, PartialEq)]
-pub(crate) enum AST {
+#[allow(clippy::upper_case_acronyms)]
+pub enum AST {
     Loop {
         // If this is true, it is known that it will be executed at least once
         // If this is false, nothing is known
         known_to_be_nontrivial: bool,
         cond_dp_offset: isize,
         elements: Vec<AST>,
+        code_p: SourcePos,
     },
     // only executes the interior if the conditional address' value is nonzero
     IfNonZero {
         cond_dp_offset: isize,
         elements: Vec<AST>,
+        code_p: SourcePos,
     },
-    // TODO: Put this behind a compile feature (it's a post-compile check for branch elimination optimizations)
+    // A post-compile check for branch elimination optimizations; left out with the strip_asserts feature
     AssertEquals {
         dp_offset: isize,
         val: u8,
+        code_p: SourcePos,
     },
-    // Simplified loop variant; just "until data[dp+offset] == 0, apply shift"
+    // Simplified loop variant; just "until data[dp+offset] == 0, apply shift". The shift can be any
+    // size, so [>>] only looks at every other cell
     ShiftLoop {
         known_to_be_nontrivial: bool,
         cond_dp_offset: isize,
         dp_shift: isize,
+        code_p: SourcePos,
     },
     // Happens with bad code; required to make the loop unrolling sound
     InfiniteLoop,
+    // Zero the whole tape and go back to the first cell; nothing about dp or the data survives it
+    ResetTape,
     // Adds a given amount to the data pointer. Can be negative to shift left.
     ShiftDataPtr {
         amount: isize,
+        code_p: SourcePos,
     },
     // Add the given amount to the byte at the data pointer. Note that due to wrapping,
     // we can (e.g.) subtract 1 by adding 255.
@@ -97,33 +131,87 @@ pub(crate) enum AST {
     ModData {
         kind: DatamodKind,
         dp_offset: isize,
+        code_p: SourcePos,
     },
     // data[dp + tdo] += data[dp + sdo] * sam
     CombineData {
         source_dp_offset: isize,
         target_dp_offset: isize,
         source_amt_mult: u8,
+        code_p: SourcePos,
     },
     // Read a byte from stdin, or whatever IO method is configured
     ReadByte {
         dp_offset: isize,
+        code_p: SourcePos,
     },
     // Write a byte to stdout, or whatever IO method is configured
     WriteByte {
         dp_offset: isize,
+        code_p: SourcePos,
+    },
+    // Write data[dp + dp_offset] + add, leaving the cell as it was; what an add and then a write
+    // of the same cell are fused into (see OptPasses::fuse_writes), when the sum is never read
+    WriteTransformed {
+        dp_offset: isize,
+        add: u8,
+        code_p: SourcePos,
     },
     WriteConst {
         out: u8,
     },
+    // Consecutive WriteConsts, merged together
+    WriteConstStr {
+        bytes: Vec<u8>,
+    },
+}
+
+/// Where in the source (counting characters) a command in the tree came from, if it came from
+/// the source at all, for saying where a runtime error happened; see ParseOptions::source_map.
+/// The optimizer merges, moves and rewrites commands, so this is only ever roughly right, and
+/// it's left out of comparisons: commands which only differ in where they came from are equal.
+#[derive(Copy, Clone, Default)]
+pub struct SourcePos(pub Option<usize>);
+
+impl PartialEq for SourcePos {
+    fn eq(&self, _other: &SourcePos) -> bool {
+        true
+    }
+}
+
+impl Eq for SourcePos {}
+
+impl core::fmt::Debug for SourcePos {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self.0 {
+            Some(code_p) => write!(f, "{}", code_p),
+            None => write!(f, "?"),
+        }
+    }
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
-pub(crate) enum DatamodKind {
+pub enum DatamodKind {
     SetData { amount: u8 },
     AddData { amount: u8 },
 }
 
+mod canonical;
+mod lint;
 mod optimization;
+mod relative;
+mod taint;
+mod validate;
+mod visit;
+
+pub use canonical::canonicalize;
+pub use lint::{find_nonterminating_loops, SourceSpan};
+pub use optimization::OptPasses;
+pub use relative::{from_relative, to_relative, RelInstr};
+pub use taint::output_depends_on_input;
+
+pub use validate::{validate, ValidationError};
+pub use visit::{visit_instr_variant, walk, InstrVisitor};
 
 #[derive(Debug)]
 pub enum ParseError {
@@ -131,12 +219,300 @@ pub enum ParseError {
     EndLoopWithoutStart { code_p: usize },
     // Code point of the started loop that never ended
     UnterminatedLoop { code_p: usize },
+    // A cell the program is sure to touch (unless it hangs first) which isn't on a tape this size
+    TapeTooSmall { dp: isize, tape_size: usize },
+    // Code point of the [ which opens a loop nested more than max_depth deep
+    NestingTooDeep { code_p: usize, max_depth: usize },
+    // Code point of a character full_parse_strict doesn't allow, and what it was
+    UnexpectedChar { code_p: usize, c: char },
 }
 
+/// How deeply loops can nest before the parser gives up with NestingTooDeep, unless it's told
+/// otherwise (see ParseOptions::max_depth). The optimizer recurses into each loop, so a deep
+/// enough program would otherwise overflow the stack; this much fits in the 2MB of a spawned
+/// thread, even in a debug build. Real programs don't come close.
+pub const MAX_LOOP_DEPTH: usize = 256;
+
+/// Parses and optimizes the program, for a VM with a fresh tape: the optimizer assumes every cell
+/// starts at zero. For a routine which runs on data already on the tape, use full_parse_with_options
+/// with OptPasses::blank_tape off, so nothing is folded away on the strength of those zeros.
 pub fn full_parse(input_str: &str) -> Result<Vec<CompiledInstr>, ParseError> {
-    let mut parsed = parse(input_str)?;
-    optimization::optimize(&mut parsed);
-    Ok(compile_ast(&parsed))
+    full_parse_with_options(input_str, &ParseOptions::default()).map(|parsed| parsed.code)
+}
+
+/// The lowest and highest cells a program touches, as offsets from the dp it starts with
+pub type OffsetBounds = (isize, isize);
+
+/// How full_parse_with_options parses and optimizes a program. The default is what full_parse
+/// does; set whichever fields need to be different, and leave the rest with `..Default::default()`.
+#[derive(Clone, Debug)]
+pub struct ParseOptions<'a> {
+    /// How cells overflow. The code has to be run by an OptVM with the same policy.
+    pub policy: OverflowPolicy,
+    /// Which optimizations run, to narrow down which one is at fault
+    pub passes: OptPasses,
+    /// Give up optimizing after this many passes over the program, rather than going until a pass
+    /// finds nothing left to do. The code is correct either way; this just bounds how long
+    /// optimizing a huge program can take.
+    pub max_passes: Option<usize>,
+    /// The characters for the commands, for a dialect which uses other ones
+    pub commands: CommandMap,
+    /// How deep loops can nest before parsing gives up with NestingTooDeep. Each level takes some
+    /// stack to optimize, so a program much deeper than MAX_LOOP_DEPTH needs a thread with a
+    /// bigger stack than usual.
+    pub max_depth: usize,
+    /// Input known ahead of time: as much of the program as can be is run on it while optimizing
+    /// (see optimize_ast). Parsed::input_used says how much of it the code doesn't need anymore.
+    pub input: Option<&'a [u8]>,
+    /// Fail with TapeTooSmall if the program is certain to walk off a tape this size. This only
+    /// fires when it can tell where the data pointer is, so a program whose shifts depend on the
+    /// data always gets through, and may still walk off at runtime.
+    pub tape_size: Option<usize>,
+    /// Work out where in the source each instruction came from; see Parsed::source_map
+    pub source_map: bool,
+}
+
+impl Default for ParseOptions<'_> {
+    fn default() -> Self {
+        ParseOptions {
+            policy: OverflowPolicy::Wrap,
+            passes: OptPasses::default(),
+            max_passes: None,
+            commands: CommandMap::default(),
+            max_depth: MAX_LOOP_DEPTH,
+            input: None,
+            tape_size: None,
+            source_map: false,
+        }
+    }
+}
+
+/// What full_parse_with_options gives back: the optimized code, and what it found out about it
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct Parsed {
+    pub code: Vec<CompiledInstr>,
+    /// The lowest and highest cells (relative to the starting dp) the code could possibly touch,
+    /// if those can be worked out statically. See OptVM::check_bounds.
+    pub bounds: Option<OffsetBounds>,
+    /// The code reads nothing, writes nothing, and has no loops left which might not end, so
+    /// running it does nothing anyone can see (unless it walks off the tape). That's rarely what
+    /// was meant, so it's usually the sign of a typo.
+    pub no_op: bool,
+    /// How many bytes of ParseOptions::input were used up while optimizing; the code has to be
+    /// run on the rest of them. Zero if there wasn't any input.
+    pub input_used: usize,
+    /// Where in the source each instruction came from, if ParseOptions::source_map was set, for an
+    /// OptVM to say where a runtime error happened; see OptVM::with_source_map.
+    pub source_map: Option<SourceMap>,
+}
+
+/// Parses and optimizes the program, as full_parse does, but as the options say.
+pub fn full_parse_with_options(input_str: &str, options: &ParseOptions) -> Result<Parsed, ParseError> {
+    let parsed = parse_chars(&mut input_str.chars(), &options.commands, options.max_depth)?;
+    optimize_parsed(parsed, options)
+}
+
+// Everything full_parse_with_options does after parsing
+fn optimize_parsed(mut parsed: Vec<AST>, options: &ParseOptions) -> Result<Parsed, ParseError> {
+    if let Some(tape_size) = options.tape_size {
+        // Before optimizing, since that can fold away cells the program would have touched
+        if let Some(dp) = optimization::first_certain_oob(&parsed, tape_size) {
+            return Err(ParseError::TapeTooSmall { dp, tape_size });
+        }
+    }
+
+    let input_used = optimize_ast(&mut parsed, options);
+    let bounds = optimization::static_bounds(&parsed);
+    let (code, source_map) = if options.source_map {
+        let (code, source_map) = compile_ast_with_source_map(&parsed);
+        (code, Some(source_map))
+    } else {
+        (compile_ast(&parsed), None)
+    };
+
+    let no_op = is_no_op(&code);
+    if no_op {
+        log!("Warning: the program does no IO and always finishes, so it does nothing anyone can see; is it the right file?");
+    }
+
+    Ok(Parsed {
+        code,
+        bounds,
+        no_op,
+        input_used,
+        source_map,
+    })
+}
+
+// Whether the code does no IO and always finishes; anything left with a jump in it might not
+fn is_no_op(code: &[CompiledInstr]) -> bool {
+    code.iter().all(|instr| match instr {
+        CompiledInstr::AddData { .. }
+        | CompiledInstr::SetData { .. }
+        | CompiledInstr::AddTwoData { .. }
+        | CompiledInstr::AddPtr { .. }
+        | CompiledInstr::SubPtr { .. }
+        | CompiledInstr::ResetTape
+        | CompiledInstr::AssertEquals { .. } => true,
+        CompiledInstr::JumpIfZero { .. }
+        | CompiledInstr::JumpIfNonzero { .. }
+        | CompiledInstr::ReadByte { .. }
+        | CompiledInstr::WriteByte { .. }
+        | CompiledInstr::WriteTransformed { .. }
+        | CompiledInstr::WriteConst { .. }
+        | CompiledInstr::WriteConstStr { .. }
+        | CompiledInstr::InfiniteLoop
+        | CompiledInstr::HaltIfNonzero { .. } => false,
+    })
+}
+
+/// The same as full_parse, but rather than taking anything which isn't a command as a comment,
+/// this fails with UnexpectedChar at the first character which is neither a command nor
+/// whitespace, to catch typos. Comments can still go after comment_marker, if there is one; they
+/// run to the end of the line, and the commands in them don't count.
+pub fn full_parse_strict(input_str: &str, comment_marker: Option<char>) -> Result<Vec<CompiledInstr>, ParseError> {
+    let commands = CommandMap::default();
+
+    // The comments are blanked out rather than dropped, so the positions in errors still match
+    let mut code = String::with_capacity(input_str.len());
+    let mut in_comment = false;
+    for (code_p, c) in input_str.chars().enumerate() {
+        if in_comment {
+            in_comment = c != '\n';
+            code.push(' ');
+        } else if Some(c) == comment_marker {
+            in_comment = true;
+            code.push(' ');
+        } else if commands.command(c).is_some() || c.is_whitespace() {
+            code.push(c);
+        } else {
+            return Err(ParseError::UnexpectedChar { code_p, c });
+        }
+    }
+
+    full_parse(&code)
+}
+
+/// Parses the program into the nested tree the optimizer works on, without optimizing it.
+pub fn parse_to_ast(input_str: &str) -> Result<Vec<AST>, ParseError> {
+    parse(input_str)
+}
+
+/// The same as parse_to_ast, but if the brackets don't match up, this finds every problem rather
+/// than stopping at the first: each ] with no [ to close, and each [ which is never closed, in
+/// the order they come in the source.
+pub fn parse_all_errors(input_str: &str) -> Result<Vec<AST>, Vec<ParseError>> {
+    let mut errors = Vec::new();
+    let mut open_loops = Vec::new();
+
+    for (code_p, token) in lex(&mut input_str.chars(), &CommandMap::default()) {
+        match token {
+            BfCmd::LoopStart => open_loops.push(code_p),
+            BfCmd::LoopEnd => {
+                let opened_at = open_loops.pop();
+                if opened_at.is_none() {
+                    errors.push(ParseError::EndLoopWithoutStart { code_p });
+                }
+            }
+            _ => {}
+        }
+    }
+
+    errors.extend(open_loops.into_iter().map(|code_p| ParseError::UnterminatedLoop { code_p }));
+    errors.sort_by_key(|e| match *e {
+        ParseError::EndLoopWithoutStart { code_p } | ParseError::UnterminatedLoop { code_p } => code_p,
+        ParseError::TapeTooSmall { .. } | ParseError::NestingTooDeep { .. } | ParseError::UnexpectedChar { .. } => {
+            unreachable!("Only bracket errors are collected")
+        }
+    });
+
+    if errors.is_empty() {
+        parse(input_str).map_err(|e| vec![e])
+    } else {
+        Err(errors)
+    }
+}
+
+/// Runs the optimizer over a tree from parse_to_ast, as full_parse_with_options does with these
+/// options; the ones about parsing don't matter here. If there's input, the program is run ahead
+/// on it, replacing what it ran with the output and tape it left; a program which only needs that
+/// input is left as constant writes. Gives back how many bytes of the input were used, since what's
+/// left of the program reads the rest of them (and then whatever comes after) at runtime.
+pub fn optimize_ast(cmds: &mut Vec<AST>, options: &ParseOptions) -> usize {
+    match options.input {
+        Some(input) => optimization::optimize_with_input(cmds, options.policy, input, options.max_passes, options.passes),
+        None => {
+            optimization::optimize(cmds, options.policy, options.max_passes, options.passes);
+            0
+        }
+    }
+}
+
+/// The same as optimize_ast, but also gives back what the tree looked like after each pass the
+/// optimizer made, rendered with {:#?} and named as in OptPasses, in the order they ran; for
+/// diffing one against the next to find the pass which broke a program. Rendering them all is
+/// slow, so optimize_ast doesn't. The program isn't run ahead on any input here.
+pub fn optimize_ast_with_snapshots(cmds: &mut Vec<AST>, options: &ParseOptions) -> Vec<(&'static str, String)> {
+    let mut snapshots = Vec::new();
+    optimization::optimize_with_snapshots(cmds, options.policy, options.max_passes, options.passes, Some(&mut snapshots));
+    snapshots
+}
+
+/// Why full_parse_reader failed
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub enum ReadParseError {
+    Io(std::io::Error),
+    Parse(ParseError),
+}
+
+/// The same as full_parse_with_options, but lexes the source as it's read, so it never has to be
+/// in memory all at once (though the parsed program is). Positions in ParseErrors count
+/// characters, as with full_parse. Since every command is ASCII, the source doesn't have to be
+/// valid UTF-8; any other bytes are just comments.
+#[cfg(feature = "std")]
+pub fn full_parse_reader<R: std::io::BufRead>(reader: R, options: &ParseOptions) -> Result<Parsed, ReadParseError> {
+    let mut chars = ReaderChars {
+        bytes: reader.bytes(),
+        error: None,
+    };
+    let parsed = parse_chars(&mut chars, &options.commands, options.max_depth);
+
+    // A read error looks like the end of the input to the parser, so it comes first
+    if let Some(e) = chars.error {
+        return Err(ReadParseError::Io(e));
+    }
+
+    optimize_parsed(parsed.map_err(ReadParseError::Parse)?, options).map_err(ReadParseError::Parse)
+}
+
+// The characters of a reader, one per code point; anything which isn't ASCII comes out as a
+// replacement character, since the lexer only cares whether it's a command. Ends at the first
+// read error, which is kept for the caller to check.
+#[cfg(feature = "std")]
+struct ReaderChars<R> {
+    bytes: std::io::Bytes<R>,
+    error: Option<std::io::Error>,
+}
+
+#[cfg(feature = "std")]
+impl<R: std::io::Read> Iterator for ReaderChars<R> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        loop {
+            match self.bytes.next()? {
+                // The rest of a multi-byte character, which was counted at its first byte
+                Ok(byte) if byte & 0b1100_0000 == 0b1000_0000 => continue,
+                Ok(byte) if byte.is_ascii() => return Some(byte as char),
+                Ok(_) => return Some(char::REPLACEMENT_CHARACTER),
+                Err(e) => {
+                    self.error = Some(e);
+                    return None;
+                }
+            }
+        }
+    }
 }
 
 struct ParseStack {
@@ -181,16 +557,24 @@ impl ParseStack {
 }
 
 pub(crate) fn parse(data: &str) -> Result<Vec<AST>, ParseError> {
+    parse_chars(&mut data.chars(), &CommandMap::default(), MAX_LOOP_DEPTH)
+}
+
+// Stops at the first unmatched ], or loop nested deeper than max_depth, without looking at the
+// rest of the input
+fn parse_chars<T: Iterator<Item = char>>(chars: &mut T, commands: &CommandMap, max_depth: usize) -> Result<Vec<AST>, ParseError> {
     let mut parse_stack = ParseStack::new();
 
-    for (code_p, token) in lex(&mut data.chars()) {
+    for (code_p, token) in lex(chars, commands) {
+        let at = SourcePos(Some(code_p));
         match token {
             BfCmd::LoopEnd => {
-                if let Some((_, running_loop)) = parse_stack.pop_loop() {
+                if let Some((start_p, running_loop)) = parse_stack.pop_loop() {
                     let next = AST::Loop {
                         elements: running_loop,
                         known_to_be_nontrivial: false,
                         cond_dp_offset: 0,
+                        code_p: SourcePos(Some(start_p)),
                     };
                     parse_stack.push_command(next);
                 } else {
@@ -198,46 +582,142 @@ pub(crate) fn parse(data: &str) -> Result<Vec<AST>, ParseError> {
                 }
             }
             BfCmd::LoopStart => {
+                if parse_stack.running_loops.len() >= max_depth {
+                    return Err(ParseError::NestingTooDeep { code_p, max_depth });
+                }
                 parse_stack.start_loop(code_p);
             }
-            BfCmd::ReadByte => parse_stack.push_command(AST::ReadByte { dp_offset: 0 }),
-            BfCmd::WriteByte => parse_stack.push_command(AST::WriteByte { dp_offset: 0 }),
+            BfCmd::ReadByte => parse_stack.push_command(AST::ReadByte { dp_offset: 0, code_p: at }),
+            BfCmd::ResetTape => parse_stack.push_command(AST::ResetTape),
+            BfCmd::WriteByte => parse_stack.push_command(AST::WriteByte { dp_offset: 0, code_p: at }),
             BfCmd::DecData => parse_stack.push_command(AST::ModData {
                 kind: DatamodKind::AddData {
                     amount: 0_u8.wrapping_sub(1),
                 },
                 dp_offset: 0,
+                code_p: at,
             }),
             BfCmd::IncData => parse_stack.push_command(AST::ModData {
                 kind: DatamodKind::AddData { amount: 1 },
                 dp_offset: 0,
+                code_p: at,
             }),
-            BfCmd::DecPtr => parse_stack.push_command(AST::ShiftDataPtr { amount: -1 }),
-            BfCmd::IncPtr => parse_stack.push_command(AST::ShiftDataPtr { amount: 1 }),
+            BfCmd::DecPtr => parse_stack.push_command(AST::ShiftDataPtr { amount: -1, code_p: at }),
+            BfCmd::IncPtr => parse_stack.push_command(AST::ShiftDataPtr { amount: 1, code_p: at }),
         }
     }
 
     parse_stack.complete()
 }
 
-// Lexing BF code is ... astoundingly simple
-fn lex<T: Iterator<Item = char>>(iter: &mut T) -> impl Iterator<Item = (usize, BfCmd)> + '_ {
-    fn match_char(c: char) -> Option<BfCmd> {
+/// The characters which stand for the eight commands; everything else is a comment. The default
+/// is the usual ><+-.,[], but a dialect can swap them around, or use different ones entirely.
+/// If two commands get the same character, it means whichever comes first here.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct CommandMap {
+    pub inc_ptr: char,
+    pub dec_ptr: char,
+    pub inc_data: char,
+    pub dec_data: char,
+    pub write_byte: char,
+    pub read_byte: char,
+    pub loop_start: char,
+    pub loop_end: char,
+    /// A debugging command, for test files with several scenarios in them: zero the tape and put
+    /// the data pointer back at the start. None by default, so programs which happen to use the
+    /// character in their comments aren't affected.
+    pub reset_tape: Option<char>,
+}
+
+impl Default for CommandMap {
+    fn default() -> Self {
+        CommandMap {
+            inc_ptr: '>',
+            dec_ptr: '<',
+            inc_data: '+',
+            dec_data: '-',
+            write_byte: '.',
+            read_byte: ',',
+            loop_start: '[',
+            loop_end: ']',
+            reset_tape: None,
+        }
+    }
+}
+
+impl CommandMap {
+    fn command(&self, c: char) -> Option<BfCmd> {
         match c {
-            '>' => Some(BfCmd::IncPtr),
-            '<' => Some(BfCmd::DecPtr),
-            '+' => Some(BfCmd::IncData),
-            '-' => Some(BfCmd::DecData),
-            '.' => Some(BfCmd::WriteByte),
-            ',' => Some(BfCmd::ReadByte),
-            '[' => Some(BfCmd::LoopStart),
-            ']' => Some(BfCmd::LoopEnd),
+            _ if c == self.inc_ptr => Some(BfCmd::IncPtr),
+            _ if c == self.dec_ptr => Some(BfCmd::DecPtr),
+            _ if c == self.inc_data => Some(BfCmd::IncData),
+            _ if c == self.dec_data => Some(BfCmd::DecData),
+            _ if c == self.write_byte => Some(BfCmd::WriteByte),
+            _ if c == self.read_byte => Some(BfCmd::ReadByte),
+            _ if c == self.loop_start => Some(BfCmd::LoopStart),
+            _ if c == self.loop_end => Some(BfCmd::LoopEnd),
+            _ if Some(c) == self.reset_tape => Some(BfCmd::ResetTape),
             _ => None,
         }
     }
+}
 
+// Lexing BF code is ... astoundingly simple
+fn lex<'a, T: Iterator<Item = char>>(iter: &'a mut T, commands: &'a CommandMap) -> impl Iterator<Item = (usize, BfCmd)> + 'a {
     iter.enumerate()
-        .filter_map(|(code_p, text_char)| match_char(text_char).map(|cmd| (code_p, cmd)))
+        .filter_map(move |(code_p, text_char)| commands.command(text_char).map(|cmd| (code_p, cmd)))
+}
+
+/// Counts of what's in a program, for tooling; see analyze.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub struct ProgramStats {
+    /// Every command character, brackets included
+    pub instructions: usize,
+    /// How deeply the loops nest; 0 if there are none
+    pub max_loop_depth: usize,
+    pub inc_ptr: usize,
+    pub dec_ptr: usize,
+    pub inc_data: usize,
+    pub dec_data: usize,
+    pub reads: usize,
+    pub writes: usize,
+    pub loop_starts: usize,
+    pub loop_ends: usize,
+    /// Every character which isn't a command
+    pub comment_chars: usize,
+}
+
+/// Counts up the commands in the source without parsing or optimizing it. This never fails; an
+/// unmatched ] just doesn't count towards the depth, so run the real parser to check the brackets.
+pub fn analyze(src: &str) -> ProgramStats {
+    let mut stats = ProgramStats::default();
+    let mut depth: usize = 0;
+
+    for (_, token) in lex(&mut src.chars(), &CommandMap::default()) {
+        stats.instructions += 1;
+        match token {
+            BfCmd::IncPtr => stats.inc_ptr += 1,
+            BfCmd::DecPtr => stats.dec_ptr += 1,
+            BfCmd::IncData => stats.inc_data += 1,
+            BfCmd::DecData => stats.dec_data += 1,
+            BfCmd::ReadByte => stats.reads += 1,
+            BfCmd::WriteByte => stats.writes += 1,
+            // Not one of the standard commands, which are all this looks for
+            BfCmd::ResetTape => {}
+            BfCmd::LoopStart => {
+                stats.loop_starts += 1;
+                depth += 1;
+                stats.max_loop_depth = stats.max_loop_depth.max(depth);
+            }
+            BfCmd::LoopEnd => {
+                stats.loop_ends += 1;
+                depth = depth.saturating_sub(1);
+            }
+        }
+    }
+
+    stats.comment_chars = src.chars().count() - stats.instructions;
+    stats
 }
 
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
@@ -250,96 +730,185 @@ enum BfCmd {
     WriteByte,
     LoopStart,
     LoopEnd,
+    ResetTape,
 }
 
 pub(crate) fn compile_ast(cmds: &[AST]) -> Vec<CompiledInstr> {
-    println!("optimized AST {:#?}", cmds);
-    let mut out = Vec::new();
+    compile_ast_with_source_map(cmds).0
+}
+
+// The same as compile_ast, but also gives back where each instruction came from
+pub(crate) fn compile_ast_with_source_map(cmds: &[AST]) -> (Vec<CompiledInstr>, SourceMap) {
+    log!("optimized AST {:#?}", cmds);
+    let mut out = Assembler::default();
 
     // Note: we assume brackets are matched, so we don't ever check for it
     // This is just a recursion helper
     compile_ast_helper(&mut out, cmds);
 
-    out
+    out.finish()
+}
+
+/// Where in the source (counting characters) each instruction of some compiled code came from,
+/// indexed by ip; see ParseOptions::source_map and OptVM::with_source_map. It's None for an
+/// instruction which came from nowhere in particular, like a constant write the optimizer worked
+/// out from several commands. A loop's jumps are at its [.
+pub type SourceMap = Vec<Option<usize>>;
+
+// A jump target which might not have an ip yet
+#[derive(Copy, Clone)]
+struct Label(usize);
+
+// Collects the compiled instructions. Jumps go to labels rather than ips, so a block's jumps can
+// be emitted before anyone knows where the block ends; the labels are filled in once it's all
+// compiled, in one go, so none of the arms below have to go back and patch anything.
+#[derive(Default)]
+struct Assembler {
+    out: Vec<CompiledInstr>,
+    // Where each instruction in out came from
+    source_map: SourceMap,
+    // The ip of each label, once it's been placed
+    labels: Vec<Option<usize>>,
+    // The ip of each jump, and the label it goes to
+    fixups: Vec<(usize, Label)>,
+}
+
+impl Assembler {
+    fn push(&mut self, instr: CompiledInstr, code_p: SourcePos) {
+        self.out.push(instr);
+        self.source_map.push(code_p.0);
+    }
+
+    fn new_label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    // The label now refers to the next instruction pushed
+    fn place(&mut self, label: Label) {
+        self.labels[label.0] = Some(self.out.len());
+    }
+
+    fn jump_if_zero(&mut self, cond_dp_offset: isize, label: Label, code_p: SourcePos) {
+        self.fixups.push((self.out.len(), label));
+        self.push(
+            CompiledInstr::JumpIfZero {
+                target_ip: 0,
+                cond_dp_offset,
+            },
+            code_p,
+        );
+    }
+
+    fn jump_if_nonzero(&mut self, cond_dp_offset: isize, label: Label, code_p: SourcePos) {
+        self.fixups.push((self.out.len(), label));
+        self.push(
+            CompiledInstr::JumpIfNonzero {
+                target_ip: 0,
+                cond_dp_offset,
+            },
+            code_p,
+        );
+    }
+
+    fn finish(mut self) -> (Vec<CompiledInstr>, SourceMap) {
+        for (ip, label) in self.fixups {
+            let ip_of_label = self.labels[label.0].expect("Every label is placed by the end");
+            match self.out[ip] {
+                CompiledInstr::JumpIfZero { ref mut target_ip, .. } | CompiledInstr::JumpIfNonzero { ref mut target_ip, .. } => {
+                    *target_ip = ip_of_label;
+                }
+                _ => unreachable!("Only jumps have fixups"),
+            }
+        }
+
+        (self.out, self.source_map)
+    }
 }
 
-fn compile_ast_helper(out: &mut Vec<CompiledInstr>, cmds: &[AST]) {
+fn compile_ast_helper(out: &mut Assembler, cmds: &[AST]) {
     for cmd in cmds {
         match cmd {
             AST::Loop {
                 elements,
                 cond_dp_offset,
                 known_to_be_nontrivial: _,
+                code_p,
             } => {
-                let start_ip = out.len();
-
-                // we need to fix this target_ip but only know what it is after we compile it
-                out.push(CompiledInstr::JumpIfZero {
-                    target_ip: 0,
-                    cond_dp_offset: *cond_dp_offset,
-                });
+                let start = out.new_label();
+                let end = out.new_label();
 
+                out.place(start);
+                out.jump_if_zero(*cond_dp_offset, end, *code_p);
                 compile_ast_helper(out, elements);
-
-                out.push(CompiledInstr::JumpIfNonzero {
-                    target_ip: start_ip,
-                    cond_dp_offset: *cond_dp_offset,
-                });
+                out.jump_if_nonzero(*cond_dp_offset, start, *code_p);
 
                 // Jump to the next instruction after the loop is over
-                let end_ip = out.len();
-
-                *out.get_mut(start_ip).unwrap() = CompiledInstr::JumpIfZero {
-                    target_ip: end_ip,
-                    cond_dp_offset: *cond_dp_offset,
-                };
+                out.place(end);
             }
             AST::ShiftLoop {
                 dp_shift,
                 known_to_be_nontrivial,
                 cond_dp_offset,
+                code_p,
             } => {
                 let compile_as = AST::Loop {
                     cond_dp_offset: *cond_dp_offset,
-                    elements: vec![AST::ShiftDataPtr { amount: *dp_shift }],
+                    elements: vec![AST::ShiftDataPtr {
+                        amount: *dp_shift,
+                        code_p: *code_p,
+                    }],
                     known_to_be_nontrivial: *known_to_be_nontrivial,
+                    code_p: *code_p,
                 };
                 compile_ast_helper(out, &[compile_as]);
             }
-            AST::IfNonZero { cond_dp_offset, elements } => {
-                let start_ip = out.len();
-
-                // we need to fix this target_ip but only know what it is after we compile it
-                out.push(CompiledInstr::JumpIfZero {
-                    target_ip: 0,
-                    cond_dp_offset: *cond_dp_offset,
-                });
+            AST::IfNonZero {
+                cond_dp_offset,
+                elements,
+                code_p,
+            } if elements.as_slice() == [AST::InfiniteLoop] => {
+                out.push(
+                    CompiledInstr::HaltIfNonzero {
+                        cond_dp_offset: *cond_dp_offset,
+                    },
+                    *code_p,
+                );
+            }
+            AST::IfNonZero {
+                cond_dp_offset,
+                elements,
+                code_p,
+            } => {
+                let end = out.new_label();
 
+                out.jump_if_zero(*cond_dp_offset, end, *code_p);
                 compile_ast_helper(out, elements);
 
                 // Jump to the next instruction after the branch stuff is over
-                let end_ip = out.len();
-
-                *out.get_mut(start_ip).unwrap() = CompiledInstr::JumpIfZero {
-                    target_ip: end_ip,
-                    cond_dp_offset: *cond_dp_offset,
-                };
+                out.place(end);
             }
             AST::InfiniteLoop => {
-                out.push(CompiledInstr::InfiniteLoop);
+                out.push(CompiledInstr::InfiniteLoop, SourcePos(None));
             }
-            AST::ShiftDataPtr { amount } => {
+            AST::ResetTape => {
+                out.push(CompiledInstr::ResetTape, SourcePos(None));
+            }
+            AST::ShiftDataPtr { amount, code_p } => {
                 let amount = *amount;
                 if amount > 0 {
-                    out.push(CompiledInstr::AddPtr { amount: amount as usize });
+                    out.push(CompiledInstr::AddPtr { amount: amount as usize }, *code_p);
                 } else if amount < 0 {
-                    out.push(CompiledInstr::SubPtr {
-                        amount: (-amount) as usize,
-                    });
+                    out.push(
+                        CompiledInstr::SubPtr {
+                            amount: (-amount) as usize,
+                        },
+                        *code_p,
+                    );
                 }
             }
-            AST::ModData { kind, dp_offset } => {
-                out.push(match kind {
+            AST::ModData { kind, dp_offset, code_p } => {
+                let instr = match kind {
                     DatamodKind::AddData { amount } => CompiledInstr::AddData {
                         amount: *amount,
                         dp_offset: *dp_offset,
@@ -348,28 +917,847 @@ fn compile_ast_helper(out: &mut Vec<CompiledInstr>, cmds: &[AST]) {
                         amount: *amount,
                         dp_offset: *dp_offset,
                     },
-                });
+                };
+                out.push(instr, *code_p);
             }
-            AST::ReadByte { dp_offset } => out.push(CompiledInstr::ReadByte { dp_offset: *dp_offset }),
-            AST::WriteByte { dp_offset } => out.push(CompiledInstr::WriteByte { dp_offset: *dp_offset }),
-            AST::WriteConst { out: out_byte } => out.push(CompiledInstr::WriteConst { out: *out_byte }),
+            AST::ReadByte { dp_offset, code_p } => out.push(CompiledInstr::ReadByte { dp_offset: *dp_offset }, *code_p),
+            AST::WriteByte { dp_offset, code_p } => out.push(CompiledInstr::WriteByte { dp_offset: *dp_offset }, *code_p),
+            AST::WriteTransformed { dp_offset, add, code_p } => out.push(
+                CompiledInstr::WriteTransformed {
+                    dp_offset: *dp_offset,
+                    add: *add,
+                },
+                *code_p,
+            ),
+            AST::WriteConst { out: out_byte } => out.push(CompiledInstr::WriteConst { out: *out_byte }, SourcePos(None)),
+            AST::WriteConstStr { bytes } => out.push(CompiledInstr::WriteConstStr { bytes: bytes.clone() }, SourcePos(None)),
             AST::CombineData {
                 source_dp_offset,
                 target_dp_offset,
                 source_amt_mult,
+                code_p,
             } => {
-                out.push(CompiledInstr::AddTwoData {
-                    source_dp_offset: *source_dp_offset,
-                    target_dp_offset: *target_dp_offset,
-                    source_amt_mult: *source_amt_mult,
-                });
+                out.push(
+                    CompiledInstr::AddTwoData {
+                        source_dp_offset: *source_dp_offset,
+                        target_dp_offset: *target_dp_offset,
+                        source_amt_mult: *source_amt_mult,
+                    },
+                    *code_p,
+                );
             }
-            AST::AssertEquals { dp_offset, val } => {
-                out.push(CompiledInstr::AssertEquals {
-                    dp_offset: *dp_offset,
-                    val: *val,
-                });
+            AST::AssertEquals { dp_offset, val, code_p } => {
+                out.push(
+                    CompiledInstr::AssertEquals {
+                        dp_offset: *dp_offset,
+                        val: *val,
+                    },
+                    *code_p,
+                );
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_with(source: &str, options: ParseOptions) -> Parsed {
+        full_parse_with_options(source, &options).unwrap()
+    }
+
+    #[test]
+    fn hello_world_is_one_write() {
+        let code = full_parse(include_str!("../../../input/hello_world.b")).unwrap();
+
+        let writes: Vec<&CompiledInstr> = code
+            .iter()
+            .filter(|instr| {
+                matches!(
+                    instr,
+                    CompiledInstr::WriteByte { .. } | CompiledInstr::WriteConst { .. } | CompiledInstr::WriteConstStr { .. }
+                )
+            })
+            .collect();
+
+        assert_eq!(
+            writes,
+            vec![&CompiledInstr::WriteConstStr {
+                bytes: b"Hello World!\n".to_vec()
+            }]
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "simple")]
+    fn greetings_leave_nothing_on_the_tape() {
+        // Says "Ha,I", with the letters built in three cells and moves in between, then echoes
+        // its input in a fourth
+        let source = "++++++++[>+++++++++>++++++++++++>+++++<<<-]>.>+.>++++.<<+.>>>,[.,]";
+        let code = full_parse(source).unwrap();
+
+        assert_eq!(code[0], CompiledInstr::WriteConstStr { bytes: b"Ha,I".to_vec() });
+        assert!(
+            !code
+                .iter()
+                .any(|instr| matches!(instr, CompiledInstr::SetData { .. } | CompiledInstr::AddData { .. })),
+            "{:?}",
+            code
+        );
+        assert_same_as_simple(source, &[b"", b"echo"]);
+
+        // Unless something looks at them afterwards, here adding how many bytes were read to the a
+        let source = "++++++++[>+++++++++>++++++++++++>+++++<<<-]>.>+.>++++.<<+.>>>>,[.>+<,]>[-<<<<+>>>>]<<<<.";
+        let code = full_parse(source).unwrap();
+        assert!(
+            code.iter().any(|instr| matches!(instr, CompiledInstr::SetData { amount: 97, .. })),
+            "{:?}",
+            code
+        );
+        assert_same_as_simple(source, &[b"", b"echo"]);
+    }
+
+    #[test]
+    fn instructions_can_be_deduplicated() {
+        use alloc::collections::BTreeSet;
+        use std::collections::HashSet;
+
+        let code = full_parse(",>,<[->+<]>.,.").unwrap();
+        let hashed: HashSet<&CompiledInstr> = code.iter().collect();
+        let sorted: BTreeSet<&CompiledInstr> = code.iter().collect();
+
+        assert!(hashed.len() < code.len(), "{:?}", code);
+        assert_eq!(hashed.len(), sorted.len());
+    }
+
+    #[cfg(feature = "simple")]
+    fn has_loops(code: &[CompiledInstr]) -> bool {
+        code.iter().any(|instr| matches!(instr, CompiledInstr::JumpIfNonzero { .. }))
+    }
+
+    // Runs the source through both VMs on each input, and checks they agree
+    #[cfg(feature = "simple")]
+    fn assert_same_as_simple(source: &str, inputs: &[&[u8]]) {
+        use crate::io::{SliceInput, VecOutput};
+        use crate::vm::{OptVM, SimpleVM};
+
+        for input in inputs {
+            let mut simple_output = VecOutput::new();
+            SimpleVM::new(crate::simple_parse(source).unwrap())
+                .run(&mut SliceInput::new(input), &mut simple_output)
+                .unwrap();
+
+            let mut opt_output = VecOutput::new();
+            OptVM::new(full_parse(source).unwrap())
+                .run(&mut SliceInput::new(input), &mut opt_output)
+                .unwrap();
+
+            assert_eq!(simple_output, opt_output, "{} on input {:?}", source, input);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "simple")]
+    fn adds_only_written_go_into_the_write() {
+        let inputs: &[&[u8]] = &[b"", b"abc", &[255, 254, 1, 0, 7]];
+
+        // The sum is written, then read over
+        let echo_next = ",[+.,]";
+        let code = full_parse(echo_next).unwrap();
+        assert!(
+            code.contains(&CompiledInstr::WriteTransformed { dp_offset: 0, add: 1 }),
+            "{:?}",
+            code
+        );
+        assert!(!code.iter().any(|instr| matches!(instr, CompiledInstr::AddData { .. })));
+        assert_same_as_simple(echo_next, inputs);
+
+        // The sum is written twice, or left on the tape at the end, so the add has to stay
+        for source in &[",[-.>.<.,]", ",+.>,--."] {
+            let code = full_parse(source).unwrap();
+            assert!(
+                !code.iter().any(|instr| matches!(instr, CompiledInstr::WriteTransformed { .. })),
+                "{:?}",
+                code
+            );
+            assert_same_as_simple(source, inputs);
+        }
+    }
+
+    #[test]
+    fn writes_are_only_fused_by_their_pass() {
+        let parse = |source, passes| {
+            parse_with(
+                source,
+                ParseOptions {
+                    passes,
+                    ..Default::default()
+                },
+            )
+            .code
+        };
+        let fused = |code: &[CompiledInstr]| code.iter().any(|instr| matches!(instr, CompiledInstr::WriteTransformed { .. }));
+
+        for source in &[",[+.,]", "+.,", ",--.,"] {
+            assert!(!fused(&parse(source, OptPasses::none())), "{}", source);
+            assert!(!fused(&parse(
+                source,
+                OptPasses {
+                    fuse_writes: false,
+                    ..OptPasses::default()
+                }
+            )));
+        }
+
+        let only_fusing = OptPasses {
+            fuse_writes: true,
+            ..OptPasses::none()
+        };
+        assert!(fused(&parse(",[+.,]", only_fusing)));
+    }
+
+    #[test]
+    #[cfg(feature = "simple")]
+    fn odd_steps_are_folded_for_every_start() {
+        let starts: Vec<[u8; 1]> = (0..=u8::MAX).map(|start| [start]).collect();
+        let all: Vec<&[u8]> = starts.iter().map(|start| &start[..]).collect();
+
+        // Whatever the start, an odd step gets the condition to zero, after start * (-step)^-1
+        // trips (mod 256); which is what the other cells get multiples of
+        for step in &[3, 5, 7] {
+            for cond in &["+", "-"] {
+                let source = format!(",[{}>+>--<<]>.>.", cond.repeat(*step));
+                assert!(!has_loops(&full_parse(&source).unwrap()), "{}", source);
+                assert_same_as_simple(&source, &all);
+            }
+        }
+
+        // With an even step, only some starts ever get to zero, so there's nothing to fold it to
+        let evens: Vec<&[u8]> = all.iter().copied().filter(|start| start[0] % 2 == 0).collect();
+        let source = ",[++>+<]>.";
+        assert!(has_loops(&full_parse(source).unwrap()));
+        assert_same_as_simple(source, &evens);
+    }
+
+    #[test]
+    #[cfg(feature = "simple")]
+    fn nested_accumulation_is_folded() {
+        let inputs: &[&[u8]] = &[&[0, 0], &[1, 7], &[5, 2], &[200, 99], &[255, 255]];
+
+        // drain data[1] into data[2] on the first trip, then top it back up with 3 each time
+        let refill = ",>,<[->[->+<]+++<]>.>.";
+        assert!(!has_loops(&full_parse(refill).unwrap()));
+        assert_same_as_simple(refill, inputs);
+
+        // the same, but counting the outer loop up by three rather than down by one
+        let odd_step = ",>,<[+++>[->>+<<]>+<<]>.>.>.";
+        assert!(!has_loops(&full_parse(odd_step).unwrap()));
+        assert_same_as_simple(odd_step, inputs);
+    }
+
+    #[test]
+    fn known_input_is_run_ahead() {
+        use crate::io::{SliceInput, VecOutput};
+        use crate::vm::OptVM;
+
+        let rot13 = include_str!("../../../input/rot13.b");
+
+        // rot13 stops at a zero, so with one at the end the whole thing is known
+        let parsed = parse_with(
+            rot13,
+            ParseOptions {
+                input: Some(b"Hello, World!\0"),
+                ..Default::default()
+            },
+        );
+        assert_eq!(parsed.input_used, 14);
+        assert_eq!(
+            parsed.code,
+            vec![CompiledInstr::WriteConstStr {
+                bytes: b"Uryyb, Jbeyq!".to_vec()
+            }]
+        );
+
+        // Without one, it's run up to the read after the input runs out, and carries on from there
+        let parsed = parse_with(
+            rot13,
+            ParseOptions {
+                input: Some(b"Hello"),
+                ..Default::default()
+            },
+        );
+        assert_eq!(parsed.input_used, 5);
+        assert_eq!(parsed.code[0], CompiledInstr::WriteConstStr { bytes: b"Uryyb".to_vec() });
+
+        let mut output = VecOutput::new();
+        OptVM::new(parsed.code).run(&mut SliceInput::new(b"abc\0"), &mut output).unwrap();
+        assert_eq!(output.as_bytes(), b"Uryybnop");
+    }
+
+    #[cfg(feature = "simple")]
+    fn shift_loop_strides(ast: &[AST]) -> Vec<isize> {
+        ast.iter()
+            .filter_map(|cmd| match cmd {
+                AST::ShiftLoop { dp_shift, .. } => Some(*dp_shift),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    #[cfg(feature = "simple")]
+    fn wide_scans_match_simple() {
+        // Every other cell is read in, with a marker after each; the scan stops at the first zero
+        // read, and the marker after it says which one that was
+        let by_two = ",>+>,>++>,>+++>,>++++>>+++++<<<<<<<<<[>>]>.";
+        let mut ast = parse_to_ast(by_two).unwrap();
+        optimize_ast(&mut ast, &ParseOptions::default());
+        assert_eq!(shift_loop_strides(&ast), vec![2]);
+        assert_same_as_simple(
+            by_two,
+            &[&[0, 0, 0, 0], &[1, 0, 1, 1], &[7, 7, 0, 1], &[1, 2, 3, 0], &[255, 1, 9, 4]],
+        );
+
+        // The same going down by three, from cell 12 to the zero in cell 0
+        let by_three = ">++++>>,>+++>>,>++>>,>+>>,[<<<]>.";
+        let mut ast = parse_to_ast(by_three).unwrap();
+        optimize_ast(&mut ast, &ParseOptions::default());
+        assert_eq!(shift_loop_strides(&ast), vec![-3]);
+        assert_same_as_simple(
+            by_three,
+            &[&[0, 0, 0, 0], &[0, 1, 1, 1], &[1, 0, 1, 1], &[1, 1, 0, 1], &[5, 6, 7, 8]],
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "simple")]
+    fn sets_in_const_loops_are_folded() {
+        for source in &[
+            ",[->+>[-]<<]>.>.",
+            ",>,<[>[-]+<-]>.",
+            ",>+<[>>[-]++<+<-]>.>.",
+            ",>,<[>>+++<[-]<-]>.>.",
+        ] {
+            assert!(!has_loops(&full_parse(source).unwrap()), "{}", source);
+            assert_same_as_simple(source, &[&[0, 0], &[3, 5], &[255, 7]]);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "simple")]
+    fn multiplication_is_not_folded() {
+        // data[2] = data[0] * data[1] can't be written with CombineData
+        let multiply = ",>,<[->[->+>+<<]>>[-<<+>>]<<<]>>.";
+        assert!(has_loops(&full_parse(multiply).unwrap()));
+        assert_same_as_simple(multiply, &[&[0, 0], &[3, 4], &[17, 19]]);
+    }
+
+    #[test]
+    #[cfg(feature = "simple")]
+    fn constant_writes_inside_loops() {
+        // data[1] is set before the loop and nothing in the loop changes it, so the write inside
+        // is a constant
+        let source = ",>+++<[>.<-]>>,[<.>-]";
+        let code = full_parse(source).unwrap();
+        assert!(code.contains(&CompiledInstr::WriteConst { out: 3 }));
+        assert!(!code.iter().any(|instr| matches!(instr, CompiledInstr::WriteByte { .. })));
+        assert_same_as_simple(source, &[&[0, 0], &[2, 3], &[5, 1]]);
+    }
+
+    #[test]
+    #[cfg(feature = "simple")]
+    fn cleanup_after_the_last_write_is_dropped() {
+        // Moves the input over, prints it, and tidies up after itself
+        let source = ",[->+<]>.[-]<+>>>++[-]";
+        let code = full_parse(source).unwrap();
+        assert_eq!(code.last(), Some(&CompiledInstr::WriteByte { dp_offset: 1 }), "{:?}", code);
+        assert_same_as_simple(source, &[&[0], &[7]]);
+
+        // The read at the end still uses up a byte of input
+        let code = full_parse(",.[-],[-]>+").unwrap();
+        assert_eq!(code.last(), Some(&CompiledInstr::ReadByte { dp_offset: 0 }), "{:?}", code);
+
+        // Anything which might be left of the start of the tape stays, to fail there
+        let code = full_parse(",.<<+").unwrap();
+        assert_eq!(code.len(), 3, "{:?}", code);
+    }
+
+    #[test]
+    fn deep_nesting_is_refused() {
+        let nested = |depth| format!(",{}-{}.", "[".repeat(depth), "]".repeat(depth));
+
+        // Deep enough that optimizing it would overflow the stack
+        let err = full_parse(&nested(100_000)).unwrap_err();
+        assert!(matches!(err, ParseError::NestingTooDeep { code_p, max_depth: MAX_LOOP_DEPTH } if code_p == MAX_LOOP_DEPTH + 1));
+        assert!(full_parse(&nested(MAX_LOOP_DEPTH)).is_ok());
+
+        let options = ParseOptions {
+            max_depth: 2,
+            ..Default::default()
+        };
+        assert!(full_parse_with_options(&nested(2), &options).is_ok());
+        assert!(matches!(
+            full_parse_with_options(&nested(3), &options),
+            Err(ParseError::NestingTooDeep { code_p: 3, max_depth: 2 })
+        ));
+    }
+
+    #[test]
+    fn programs_which_do_nothing_are_reported() {
+        let no_op = |source| parse_with(source, ParseOptions::default()).no_op;
+
+        assert!(no_op(""));
+        // Missing the . at the end
+        assert!(no_op("++++++++[>++++++++<-]>+"));
+        assert!(no_op("+>[-]<[>+<-]"));
+
+        assert!(!no_op("++++++++[>++++++++<-]>+."));
+        assert!(!no_op(",[-]"));
+        // Spins forever
+        assert!(!no_op("+[]"));
+        // Might spin forever, depending on what's on the tape
+        assert!(!no_op("+[>+]"));
+    }
+
+    #[test]
+    fn strict_parsing_only_allows_commands() {
+        // Anything goes normally
+        assert_eq!(full_parse("+{.}").unwrap(), full_parse("+.").unwrap());
+
+        assert!(matches!(
+            full_parse_strict("+ {.}", None),
+            Err(ParseError::UnexpectedChar { code_p: 2, c: '{' })
+        ));
+        assert_eq!(full_parse_strict("+\n\t.\n", None).unwrap(), full_parse("+.").unwrap());
+
+        // Comments have to be marked, and the commands in them don't count
+        let commented = "+ # add one, then print it.\n.";
+        assert!(matches!(
+            full_parse_strict(commented, None),
+            Err(ParseError::UnexpectedChar { code_p: 2, c: '#' })
+        ));
+        assert_eq!(full_parse_strict(commented, Some('#')).unwrap(), full_parse("+.").unwrap());
+        assert!(matches!(
+            full_parse_strict("# fine\n+ wrong", Some('#')),
+            Err(ParseError::UnexpectedChar { code_p: 9, c: 'w' })
+        ));
+
+        // Positions are still right after a comment
+        assert!(matches!(
+            full_parse_strict("# [\n]", Some('#')),
+            Err(ParseError::EndLoopWithoutStart { code_p: 4 })
+        ));
+    }
+
+    #[test]
+    fn snapshots_follow_each_pass() {
+        let source = "++[->+++<]>.";
+        let mut plain = parse_to_ast(source).unwrap();
+        optimize_ast(&mut plain, &ParseOptions::default());
+
+        let mut ast = parse_to_ast(source).unwrap();
+        let snapshots = optimize_ast_with_snapshots(&mut ast, &ParseOptions::default());
+        assert_eq!(ast, plain);
+
+        // Every pass runs each time around, in order, and the last one leaves the final tree
+        let names: Vec<&str> = snapshots.iter().map(|&(name, _)| name).take(8).collect();
+        assert_eq!(
+            names,
+            vec![
+                "sort",
+                "collapse",
+                "const_loops",
+                "simulation",
+                "one_step_loops",
+                "dead_stores",
+                "dead_tail",
+                "fuse_writes"
+            ]
+        );
+        assert_eq!(snapshots.len() % 8, 0);
+        assert_eq!(snapshots.last().unwrap().1, alloc::format!("{:#?}", plain));
+
+        // The loop goes in the first pass that can take it apart, and stays gone
+        let first_without_loop = snapshots.iter().position(|(_, tree)| !tree.contains("Loop")).unwrap();
+        assert_eq!(snapshots[first_without_loop].0, "const_loops");
+        assert!(snapshots[first_without_loop..].iter().all(|(_, tree)| !tree.contains("Loop")));
+    }
+
+    #[test]
+    fn every_bracket_error_is_found() {
+        let errors = parse_all_errors("]+[[-]>[]]][").unwrap_err();
+        let positions: Vec<_> = errors
+            .iter()
+            .map(|e| match *e {
+                ParseError::EndLoopWithoutStart { code_p } => (']', code_p),
+                ParseError::UnterminatedLoop { code_p } => ('[', code_p),
+                _ => panic!("{:?}", e),
+            })
+            .collect();
+        assert_eq!(positions, vec![(']', 0), (']', 10), ('[', 11)]);
+
+        assert_eq!(parse_all_errors("[[]").unwrap_err().len(), 1);
+        assert_eq!(parse_all_errors("+[->+<]").unwrap(), parse("+[->+<]").unwrap());
+    }
+
+    #[test]
+    fn adds_from_a_set_cell_are_folded() {
+        let set = |dp_offset, amount| AST::ModData {
+            kind: DatamodKind::SetData { amount },
+            dp_offset,
+            code_p: SourcePos(None),
+        };
+        let combine = AST::CombineData {
+            source_dp_offset: 0,
+            target_dp_offset: 1,
+            source_amt_mult: 2,
+            code_p: SourcePos(None),
+        };
+
+        // Both cells known: data[1] = 5 + 3 * 2, so it's only a write of 11
+        let mut cmds = vec![
+            set(0, 3),
+            set(1, 5),
+            combine.clone(),
+            AST::WriteByte {
+                dp_offset: 1,
+                code_p: SourcePos(None),
+            },
+        ];
+        optimize_ast(&mut cmds, &ParseOptions::default());
+        assert_eq!(compile_ast(&cmds), vec![CompiledInstr::WriteConst { out: 11 }]);
+
+        // Only the source known: an add of 6
+        let mut cmds = vec![
+            AST::ReadByte {
+                dp_offset: 1,
+                code_p: SourcePos(None),
+            },
+            set(0, 3),
+            combine,
+            AST::WriteByte {
+                dp_offset: 1,
+                code_p: SourcePos(None),
+            },
+        ];
+        optimize_ast(&mut cmds, &ParseOptions::default());
+        let code = compile_ast(&cmds);
+        assert!(
+            !code.iter().any(|instr| matches!(instr, CompiledInstr::AddTwoData { .. })),
+            "{:?}",
+            code
+        );
+        assert!(code.contains(&CompiledInstr::AddData { amount: 6, dp_offset: 1 }), "{:?}", code);
+    }
+
+    #[test]
+    fn clears_then_adds_are_one_set() {
+        use crate::OverflowPolicy;
+
+        // Nothing looks at the cell afterwards, so the set would otherwise be dropped too
+        let passes = OptPasses {
+            dead_tail: false,
+            ..OptPasses::default()
+        };
+
+        for &policy in &[OverflowPolicy::Wrap, OverflowPolicy::Saturate, OverflowPolicy::Error] {
+            let code = parse_with(
+                "[-]+++++",
+                ParseOptions {
+                    policy,
+                    passes,
+                    ..Default::default()
+                },
+            )
+            .code;
+            assert_eq!(code, vec![CompiledInstr::SetData { amount: 5, dp_offset: 0 }], "{:?}", policy);
+
+            // Where the cell isn't already known to be zero
+            let code = parse_with(
+                ",[-]+++++.",
+                ParseOptions {
+                    policy,
+                    passes,
+                    ..Default::default()
+                },
+            )
+            .code;
+            assert_eq!(code.len(), 3, "{:?}", policy);
+            assert!(code.contains(&CompiledInstr::SetData { amount: 5, dp_offset: 0 }), "{:?}", policy);
+        }
+    }
+
+    #[test]
+    fn bounds_are_checked_up_front() {
+        use crate::vm::{OptVM, RuntimeError};
+
+        let parsed = parse_with(",[->>+<<]>>.<<<,", ParseOptions::default());
+        assert_eq!(parsed.bounds, Some((-1, 2)));
+        assert_eq!(
+            OptVM::new(parsed.code).check_bounds((-1, 2)),
+            Err(RuntimeError::OutOfBounds { dp: -1, role: None })
+        );
+
+        assert_eq!(parse_with(",[>]", ParseOptions::default()).bounds, None);
+    }
+
+    #[test]
+    fn small_tapes_are_rejected() {
+        let too_small = |source, tape_size| match full_parse_with_options(
+            source,
+            &ParseOptions {
+                tape_size: Some(tape_size),
+                ..Default::default()
+            },
+        ) {
+            Err(ParseError::TapeTooSmall { dp, .. }) => Some(dp),
+            Ok(_) => None,
+            Err(other) => panic!("Unexpected error {:?}", other),
+        };
+
+        assert_eq!(too_small(",>>>>.", 3), Some(4));
+        assert_eq!(too_small(",>>>>.", 5), None);
+        assert_eq!(too_small("<,", 3), Some(-1));
+        // Only if the input isn't zero
+        assert_eq!(too_small(",[>>>>>+<<<<<-]", 3), None);
+        // After [>] there's no telling where dp is
+        assert_eq!(too_small(",[>],>>>>>>.", 3), None);
+        assert_eq!(
+            too_small(include_str!("../../../input/hello_world.b"), crate::DEFAULT_TAPE_SIZE),
+            None
+        );
+    }
+
+    #[test]
+    fn programs_are_analyzed() {
+        let stats = analyze("read: ,[>+<-] nested: [[.]]");
+        assert_eq!(stats.instructions, 12);
+        assert_eq!(stats.max_loop_depth, 2);
+        assert_eq!((stats.inc_ptr, stats.dec_ptr, stats.inc_data, stats.dec_data), (1, 1, 1, 1));
+        assert_eq!((stats.reads, stats.writes, stats.loop_starts, stats.loop_ends), (1, 1, 3, 3));
+        assert_eq!(stats.comment_chars, 15);
+
+        assert_eq!(analyze("]][").max_loop_depth, 1);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn programs_are_parsed_from_readers() {
+        let source = include_str!("../../../input/hello_world.b");
+        let read = full_parse_reader(std::io::BufReader::new(source.as_bytes()), &ParseOptions::default()).unwrap();
+        assert_eq!(read.code, full_parse(source).unwrap());
+
+        // Positions count characters, not bytes
+        let source = "é ü +]";
+        match full_parse_reader(source.as_bytes(), &ParseOptions::default()) {
+            Err(ReadParseError::Parse(ParseError::EndLoopWithoutStart { code_p })) => assert_eq!(code_p, 5),
+            other => panic!("Expected an unmatched ], got {:?}", other),
+        }
+        assert!(matches!(full_parse(source), Err(ParseError::EndLoopWithoutStart { code_p: 5 })));
+
+        assert!(full_parse_reader(&b"\xff[\xfe+]."[..], &ParseOptions::default()).is_ok());
+    }
+
+    #[test]
+    fn optimizing_can_stop_early() {
+        use crate::io::{SliceInput, VecOutput};
+        use crate::OptVM;
+
+        let source = include_str!("../../../input/hello_world.b");
+        let passes = |max_passes| {
+            parse_with(
+                source,
+                ParseOptions {
+                    max_passes,
+                    ..Default::default()
+                },
+            )
+            .code
+        };
+        let unoptimized = passes(Some(0));
+        let partial = passes(Some(1));
+        assert_eq!(unoptimized, compile_ast(&parse(source).unwrap()));
+        assert!(partial.len() < unoptimized.len());
+        assert!(partial.len() > full_parse(source).unwrap().len());
+
+        let mut output = VecOutput::new();
+        OptVM::new(partial).run(&mut SliceInput::new(b""), &mut output).unwrap();
+        assert_eq!(output.as_bytes(), b"Hello World!\n");
+    }
+
+    #[test]
+    fn passes_can_be_turned_off() {
+        use crate::io::{SliceInput, VecOutput};
+        use crate::OptVM;
+
+        let source = include_str!("../../../input/hello_world.b");
+        let none = parse_with(
+            source,
+            ParseOptions {
+                passes: OptPasses::none(),
+                ..Default::default()
+            },
+        )
+        .code;
+        assert_eq!(none, compile_ast(&parse(source).unwrap()));
+
+        let toggles: [fn(&mut OptPasses); 6] = [
+            |p| p.sort = !p.sort,
+            |p| p.collapse = !p.collapse,
+            |p| p.const_loops = !p.const_loops,
+            |p| p.simulation = !p.simulation,
+            |p| p.one_step_loops = !p.one_step_loops,
+            |p| p.dead_stores = !p.dead_stores,
+        ];
+
+        // Each pass by itself, and everything but each pass
+        for toggle in &toggles {
+            for mut passes in [OptPasses::none(), OptPasses::default()] {
+                toggle(&mut passes);
+                let code = parse_with(
+                    source,
+                    ParseOptions {
+                        passes,
+                        ..Default::default()
+                    },
+                )
+                .code;
+
+                let mut output = VecOutput::new();
+                OptVM::new(code).run(&mut SliceInput::new(b""), &mut output).unwrap();
+                assert_eq!(output.as_bytes(), b"Hello World!\n", "{:?}", passes);
+            }
+        }
+    }
+
+    #[test]
+    fn dialects_are_parsed() {
+        use crate::io::{SliceInput, VecOutput};
+        use crate::OptVM;
+
+        // + and - swap places, as do < and >
+        let swapped = CommandMap {
+            inc_ptr: '<',
+            dec_ptr: '>',
+            inc_data: '-',
+            dec_data: '+',
+            ..CommandMap::default()
+        };
+        let source: String = include_str!("../../../input/hello_world.b")
+            .chars()
+            .map(|c| match c {
+                '+' => '-',
+                '-' => '+',
+                '<' => '>',
+                '>' => '<',
+                c => c,
+            })
+            .collect();
+
+        let run = |code| {
+            let mut output = VecOutput::new();
+            OptVM::new(code).run(&mut SliceInput::new(b""), &mut output).unwrap();
+            output.into_bytes()
+        };
+        assert_eq!(
+            run(parse_with(
+                &source,
+                ParseOptions {
+                    commands: swapped,
+                    ..Default::default()
+                }
+            )
+            .code),
+            run(full_parse(include_str!("../../../input/hello_world.b")).unwrap())
+        );
+    }
+
+    #[test]
+    fn tapes_are_reset() {
+        use crate::io::{SliceInput, VecOutput};
+        use crate::OptVM;
+
+        let debug = CommandMap {
+            reset_tape: Some('$'),
+            ..CommandMap::default()
+        };
+
+        let run = |src: &str, commands: &CommandMap, input: &[u8]| {
+            let mut output = VecOutput::new();
+            let code = parse_with(
+                src,
+                ParseOptions {
+                    commands: *commands,
+                    ..Default::default()
+                },
+            )
+            .code;
+            OptVM::new(code).run(&mut SliceInput::new(input), &mut output).unwrap();
+            output.into_bytes()
+        };
+
+        assert_eq!(run("+++>++$.", &debug, b""), vec![0]);
+        assert_eq!(run(",>,$>+.<.", &debug, b"ab"), vec![1, 0]);
+        // The reset also ends the loop, since its cell is zero afterwards
+        assert_eq!(run(",[>+>$]>+.", &debug, b"\x05"), vec![1]);
+        // Opt-in only; otherwise $ is a comment like anything else
+        assert_eq!(run("+++>++$.", &CommandMap::default(), b""), vec![2]);
+    }
+
+    #[test]
+    fn options_combine() {
+        // A dialect with its input known ahead, which still says where each instruction came from
+        let options = ParseOptions {
+            commands: CommandMap {
+                read_byte: '?',
+                ..CommandMap::default()
+            },
+            input: Some(b"a"),
+            source_map: true,
+            ..Default::default()
+        };
+        let parsed = full_parse_with_options("?+.?.", &options).unwrap();
+        assert_eq!(parsed.input_used, 1);
+        assert_eq!(parsed.code[0], CompiledInstr::WriteConst { out: b'b' });
+        assert_eq!(parsed.source_map.unwrap().len(), parsed.code.len());
+
+        let options = ParseOptions {
+            tape_size: Some(2),
+            max_depth: 1,
+            ..Default::default()
+        };
+        assert!(matches!(
+            full_parse_with_options(">>+", &options),
+            Err(ParseError::TapeTooSmall { dp: 2, tape_size: 2 })
+        ));
+        assert!(matches!(
+            full_parse_with_options("+[[-]]", &options),
+            Err(ParseError::NestingTooDeep { code_p: 2, max_depth: 1 })
+        ));
+    }
+
+    #[test]
+    fn instructions_know_where_they_came_from() {
+        let with_map = |source| {
+            let parsed = parse_with(
+                source,
+                ParseOptions {
+                    source_map: true,
+                    ..Default::default()
+                },
+            );
+            (parsed.code, parsed.source_map.unwrap())
+        };
+
+        let (code, source_map) = with_map(",[.,]");
+        assert_eq!(code.len(), source_map.len());
+        // Both of the loop's jumps are at its [
+        assert_eq!(source_map, vec![Some(0), Some(1), Some(2), Some(3), Some(1)]);
+
+        // A constant write was worked out from everything before it
+        let (code, source_map) = with_map("+++.");
+        assert_eq!(code, vec![CompiledInstr::WriteConst { out: 3 }]);
+        assert_eq!(source_map, vec![None]);
+    }
+}
@@ -1,17 +1,107 @@
-use std::collections::HashMap;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
 
 use crate::optimized::DatamodKind;
+use crate::OverflowPolicy;
 
-use super::AST;
+use super::{OffsetBounds, SourcePos, AST};
 use crate::optimized::optimization::data_usage::{DataUsage, DataUsageTracker};
 
-pub(crate) fn optimize(cmds: &mut Vec<AST>) {
+/// Which of the optimizer's passes run; by default, all of them. Every pass leaves the program
+/// correct by itself, so any combination is fine, which makes this useful for tracking down the
+/// pass to blame when the optimized code does something the source doesn't.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct OptPasses {
+    /// Reorder commands which don't affect each other, so the others find more to do
+    pub sort: bool,
+    /// Merge neighbouring commands, e.g. two adds to the same cell
+    pub collapse: bool,
+    /// Turn loops which only do arithmetic into straight-line code
+    pub const_loops: bool,
+    /// Track what's known about the tape, to drop or constant-fold what it decides
+    pub simulation: bool,
+    /// Turn loops which only ever run once into branches
+    pub one_step_loops: bool,
+    /// Drop writes which are overwritten before anything reads them
+    pub dead_stores: bool,
+    /// Let simulation and one_step_loops assume every cell starts at zero, as it does on a fresh
+    /// VM. Turn this off for code which runs on a tape with data already on it (see
+    /// OptVM::set_tape) or a tape filled with something else (see OptVM::with_fill); then they
+    /// start out knowing nothing.
+    pub blank_tape: bool,
+    /// Drop what's left at the end of the program after its last output (besides reads), and
+    /// writes to cells nothing looks at again, e.g. those that set up a string which simulation
+    /// has already turned into constant writes. This only changes the tape the program leaves
+    /// behind, so turn it off to look at that.
+    pub dead_tail: bool,
+    /// Fuse an add to a cell and then a write of it into one write of the sum (a
+    /// WriteTransformed), when nothing looks at the cell again before it's overwritten. The cell
+    /// is left without the add, so turn this off to watch it, or if it's an IO port.
+    pub fuse_writes: bool,
+}
+
+impl OptPasses {
+    /// None of the passes, to turn the ones under suspicion on one at a time
+    pub fn none() -> Self {
+        OptPasses {
+            sort: false,
+            collapse: false,
+            const_loops: false,
+            simulation: false,
+            one_step_loops: false,
+            dead_stores: false,
+            blank_tape: false,
+            dead_tail: false,
+            fuse_writes: false,
+        }
+    }
+}
+
+impl Default for OptPasses {
+    fn default() -> Self {
+        OptPasses {
+            sort: true,
+            collapse: true,
+            const_loops: true,
+            simulation: true,
+            one_step_loops: true,
+            dead_stores: true,
+            blank_tape: true,
+            dead_tail: true,
+            fuse_writes: true,
+        }
+    }
+}
+
+// Everything here is written for wrapping cells; under the other overflow policies, each pass only
+// does what's still correct for that policy (see OverflowPolicy), which is less.
+// Runs passes until one makes no changes, or there have been max_passes of them. Every pass
+// leaves the program correct, so stopping early just leaves it less optimized.
+pub(crate) fn optimize(cmds: &mut Vec<AST>, policy: OverflowPolicy, max_passes: Option<usize>, passes: OptPasses) {
+    optimize_with_snapshots(cmds, policy, max_passes, passes, None);
+}
+
+// The tree after one of the passes in opt_step, named as in OptPasses, and rendered with {:#?}
+pub(crate) type Snapshot = (&'static str, String);
+
+// The same as optimize, but if snapshots is given, the tree is rendered into it after every pass
+// which runs; that's slow, so it's only for tracking down which pass broke something.
+pub(crate) fn optimize_with_snapshots(
+    cmds: &mut Vec<AST>,
+    policy: OverflowPolicy,
+    max_passes: Option<usize>,
+    passes: OptPasses,
+    mut snapshots: Option<&mut Vec<Snapshot>>,
+) {
     let mut step = 0;
 
-    loop {
-        let step_count = opt_step(cmds);
+    while max_passes.is_none_or(|max_passes| step < max_passes) {
+        let step_count = opt_step(cmds, policy, passes, snapshots.as_deref_mut());
 
-        println!("Step {} did {} changes.\n", step, step_count);
+        log!("Step {} did {} changes.\n", step, step_count);
 
         if step_count == 0 {
             break;
@@ -21,29 +111,75 @@ pub(crate) fn optimize(cmds: &mut Vec<AST>) {
     }
 }
 
-fn opt_step(cmds: &mut Vec<AST>) -> usize {
-    let swap = sort_commands(cmds);
-    println!("Swapped {} commands total", swap);
-
-    let coll = collapse_consecutive(cmds);
-    println!("Collapse {} consecutive pure commands total", coll);
-
-    let deloop = const_loop_remove(cmds);
-    println!("Killed {} const loops!", deloop);
-
-    let simulate_removal = run_simulation(cmds);
-    println!("Killed {} instructions by simulation.", simulate_removal);
-
-    let one_step_loops = one_step_loops(cmds);
-    println!("Killed {} instructions by one-step-loop simulation", one_step_loops);
-
-    swap + coll + deloop + simulate_removal + one_step_loops
+fn opt_step(cmds: &mut Vec<AST>, policy: OverflowPolicy, passes: OptPasses, mut snapshots: Option<&mut Vec<Snapshot>>) -> usize {
+    let mut snapshot = |ran: bool, name: &'static str, cmds: &[AST]| {
+        if let Some(snapshots) = snapshots.as_deref_mut() {
+            if ran {
+                snapshots.push((name, format!("{:#?}", cmds)));
+            }
+        }
+    };
+
+    let swap = if passes.sort { sort_commands(cmds, policy) } else { 0 };
+    log!("Swapped {} commands total", swap);
+    snapshot(passes.sort, "sort", cmds);
+
+    let coll = if passes.collapse { collapse_consecutive(cmds, policy) } else { 0 };
+    log!("Collapse {} consecutive pure commands total", coll);
+    snapshot(passes.collapse, "collapse", cmds);
+
+    let deloop = if passes.const_loops { const_loop_remove(cmds, policy) } else { 0 };
+    log!("Killed {} const loops!", deloop);
+    snapshot(passes.const_loops, "const_loops", cmds);
+
+    // What the simulations know about a cell before anything writes to it
+    let start = if passes.blank_tape {
+        sim_state::DataState::Known(0)
+    } else {
+        sim_state::DataState::Unknown
+    };
+
+    let simulate_removal = if passes.simulation {
+        run_simulation(cmds, start, policy)
+    } else {
+        0
+    };
+    log!("Killed {} instructions by simulation.", simulate_removal);
+    snapshot(passes.simulation, "simulation", cmds);
+
+    let one_step_loops = if passes.one_step_loops {
+        one_step_loops(cmds, start, policy)
+    } else {
+        0
+    };
+    log!("Killed {} instructions by one-step-loop simulation", one_step_loops);
+    snapshot(passes.one_step_loops, "one_step_loops", cmds);
+
+    let dead_stores = if passes.dead_stores { dead_store_elim(cmds, policy) } else { 0 };
+    log!("Killed {} dead stores", dead_stores);
+    snapshot(passes.dead_stores, "dead_stores", cmds);
+
+    let dead_tail = if passes.dead_tail {
+        dead_tail_elim(cmds, policy) + unread_store_elim(cmds, policy)
+    } else {
+        0
+    };
+    log!("Killed {} instructions after the last output", dead_tail);
+    snapshot(passes.dead_tail, "dead_tail", cmds);
+
+    let fused = if passes.fuse_writes { fuse_writes(cmds) } else { 0 };
+    log!("Fused {} adds into the writes after them", fused);
+    snapshot(passes.fuse_writes, "fuse_writes", cmds);
+
+    swap + coll + deloop + simulate_removal + one_step_loops + dead_stores + dead_tail + fused
 }
 
 mod sim_state {
     use crate::optimized::DatamodKind;
-    use std::collections::HashMap;
-    use std::fmt;
+    use crate::OverflowPolicy;
+    use alloc::collections::{BTreeMap, BTreeSet};
+    use alloc::vec::Vec;
+    use core::fmt;
 
     #[derive(Copy, Clone, Eq, PartialEq)]
     pub enum DataState {
@@ -62,9 +198,19 @@ mod sim_state {
         }
     }
 
+    // What's known about a cell which could be either of these
+    fn widen(a: DataState, b: DataState) -> DataState {
+        if a == b {
+            a
+        } else {
+            DataState::Unknown
+        }
+    }
+
     pub struct SimState {
-        data: HashMap<isize, DataState>,
+        data: BTreeMap<isize, DataState>,
         def_value: DataState,
+        policy: OverflowPolicy,
         dp: isize,
         wipes: usize,
     }
@@ -73,10 +219,11 @@ mod sim_state {
     pub struct BranchMarker(usize);
 
     impl SimState {
-        pub fn new(def_value: DataState) -> Self {
+        pub fn new(def_value: DataState, policy: OverflowPolicy) -> Self {
             SimState {
-                data: HashMap::new(),
+                data: BTreeMap::new(),
                 def_value,
+                policy,
                 dp: 0,
                 wipes: 0,
             }
@@ -86,6 +233,7 @@ mod sim_state {
             let branch = SimState {
                 data: self.data.clone(),
                 def_value: self.def_value,
+                policy: self.policy,
                 dp: self.dp,
                 wipes: self.wipes,
             };
@@ -101,26 +249,22 @@ mod sim_state {
                 return;
             }
 
-            if self.def_value != branch.def_value {
-                println!(
-                    "Default values differ ({:?} vs {:?}), not sure how this occurred",
-                    self.def_value, branch.def_value
-                );
-                self.clear_knowledge();
-                return;
-            }
+            // Everything is relative to dp, so if the branches disagree on dp, we compare each
+            // cell relative to that branch's own dp; anything which isn't the same on both
+            // sides is widened afterward. Cells neither side has touched get the defaults widened
+            // in the same way, since one branch may have lost track of everything and the other not.
+            let mut offsets: BTreeSet<isize> = self.data.keys().map(|k| k - self.dp).collect();
+            offsets.extend(branch.data.keys().map(|k| k - branch.dp));
 
-            if self.dp != branch.dp {
-                println!("Merging: dp differs, this is solveable but I didn't do it yet");
-                self.clear_knowledge();
-                return;
-            }
+            let merged: Vec<(isize, DataState)> = offsets
+                .into_iter()
+                .map(|offset| (offset, widen(self.get_data(offset), branch.get_data(offset))))
+                .collect();
 
-            let old_data: HashMap<isize, DataState> = std::mem::replace(&mut self.data, HashMap::new());
-            for (k, v) in old_data {
-                if branch.data.get(&k) == Some(&v) {
-                    self.data.insert(k, v);
-                }
+            self.def_value = widen(self.def_value, branch.def_value);
+            self.data.clear();
+            for (offset, val) in merged {
+                self.set_data(offset, val);
             }
         }
 
@@ -146,9 +290,11 @@ mod sim_state {
                     DataState::Unknown | DataState::UnknownNonzero => {
                         self.set_data(dp_offset, DataState::Unknown);
                     }
-                    DataState::Known(old) => {
-                        self.set_data(dp_offset, DataState::Known(u8::wrapping_add(old, amount)));
-                    }
+                    DataState::Known(old) => match self.policy.add(old, amount) {
+                        Some(new_val) => self.set_data(dp_offset, DataState::Known(new_val)),
+                        // it's going to be a runtime error, so there's nothing to know afterward
+                        None => self.set_data(dp_offset, DataState::Unknown),
+                    },
                 },
             }
         }
@@ -160,16 +306,23 @@ mod sim_state {
 
             match (self.get_data(source_dp_offset), self.get_data(target_dp_offset)) {
                 (DataState::Known(0), _) => {}
-                (DataState::Known(a), DataState::Known(b)) => {
-                    let new_val = u8::wrapping_add(b, u8::wrapping_mul(a, source_amt_mult));
-                    self.set_data(target_dp_offset, DataState::Known(new_val));
-                }
+                (DataState::Known(a), DataState::Known(b)) => match self.policy.add_product(b, a, source_amt_mult) {
+                    Some(new_val) => self.set_data(target_dp_offset, DataState::Known(new_val)),
+                    None => self.set_data(target_dp_offset, DataState::Unknown),
+                },
                 (DataState::Unknown, _) | (DataState::UnknownNonzero, _) | (_, DataState::Unknown) | (_, DataState::UnknownNonzero) => {
                     self.set_data(target_dp_offset, DataState::Unknown)
                 }
             }
         }
 
+        // Forget the default without losing track of dp, unlike clear_knowledge; nothing does
+        // this yet, so it's only for testing merge_divergent
+        #[cfg(test)]
+        pub fn forget_default(&mut self) {
+            self.def_value = DataState::Unknown;
+        }
+
         /// Clear everything we know about the state
         pub fn clear_knowledge(&mut self) {
             self.data.clear();
@@ -178,6 +331,13 @@ mod sim_state {
             self.def_value = DataState::Unknown;
             self.wipes += 1;
         }
+
+        /// The tape was reset: every cell is zero again, and dp is back where it started, which
+        /// is as good as the start of the program
+        pub fn reset_tape(&mut self) {
+            self.clear_knowledge();
+            self.def_value = DataState::Known(0);
+        }
     }
 
     impl fmt::Debug for SimState {
@@ -202,10 +362,18 @@ mod sim_state {
     }
 }
 
-fn one_step_loops(cmds: &mut Vec<AST>) -> usize {
+// The optimizer's check on itself, that a cell is what it worked out it has to be; these are
+// left out entirely with the strip_asserts feature
+fn push_assert(cmds: &mut Vec<AST>, dp_offset: isize, val: u8, code_p: SourcePos) {
+    if cfg!(not(feature = "strip_asserts")) {
+        cmds.push(AST::AssertEquals { dp_offset, val, code_p });
+    }
+}
+
+fn one_step_loops(cmds: &mut Vec<AST>, start: sim_state::DataState, policy: OverflowPolicy) -> usize {
     use sim_state::{DataState, SimState};
 
-    fn one_step_loops_ctx(old: Vec<AST>, state: &mut SimState) -> (Vec<AST>, usize) {
+    fn one_step_loops_ctx(old: Vec<AST>, state: &mut SimState, policy: OverflowPolicy) -> (Vec<AST>, usize) {
         let mut removed = 0;
 
         let mut cmds = Vec::new();
@@ -216,37 +384,33 @@ fn one_step_loops(cmds: &mut Vec<AST>) -> usize {
                     cond_dp_offset,
                     elements,
                     known_to_be_nontrivial,
+                    code_p,
                 } => {
-                    let mut inner_state = SimState::new(DataState::Unknown);
+                    let mut inner_state = SimState::new(DataState::Unknown, policy);
                     inner_state.set_data(cond_dp_offset, DataState::UnknownNonzero);
 
-                    let (mut new_elements, inner_removed) = one_step_loops_ctx(elements, &mut inner_state);
+                    let (mut new_elements, inner_removed) = one_step_loops_ctx(elements, &mut inner_state, policy);
                     removed += inner_removed;
 
                     if inner_state.get_data(cond_dp_offset) == DataState::Known(0) {
                         if known_to_be_nontrivial {
-                            println!(
+                            log!(
                                     "Loop terminated in one iteration; branch always taken. Was loop CDO {}, hint {}, elements unchanged. Old state {:?}, inner state {:?}",
                                     cond_dp_offset, known_to_be_nontrivial, state, inner_state
                                 );
-                            new_elements.push(AST::AssertEquals {
-                                dp_offset: cond_dp_offset,
-                                val: 0,
-                            });
+                            push_assert(&mut new_elements, cond_dp_offset, 0, code_p);
                             cmds.append(&mut new_elements);
                             removed += 1;
                         } else {
-                            println!(
+                            log!(
                                     "Loop terminated in one iteration; turned to branch. Was loop CDO {}, hint {}, elements unchanged. Old state {:?}, inner state {:?}",
                                     cond_dp_offset, known_to_be_nontrivial, state, inner_state
                                 );
-                            new_elements.push(AST::AssertEquals {
-                                dp_offset: cond_dp_offset,
-                                val: 0,
-                            });
+                            push_assert(&mut new_elements, cond_dp_offset, 0, code_p);
                             cmds.push(AST::IfNonZero {
                                 elements: new_elements,
                                 cond_dp_offset,
+                                code_p,
                             });
                             removed += 1;
                         }
@@ -255,23 +419,28 @@ fn one_step_loops(cmds: &mut Vec<AST>) -> usize {
                             known_to_be_nontrivial,
                             cond_dp_offset,
                             elements: new_elements,
+                            code_p,
                         });
                     }
 
                     state.clear_knowledge();
                     state.set_data(cond_dp_offset, DataState::Known(0));
                 }
-                AST::IfNonZero { elements, cond_dp_offset } => {
+                AST::IfNonZero {
+                    elements,
+                    cond_dp_offset,
+                    code_p,
+                } => {
                     match state.get_data(cond_dp_offset) {
                         DataState::Known(0) => {
-                            println!("Deleted a branch (not executed)");
+                            log!("Deleted a branch (not executed)");
                             removed += 1 + elements.len();
                         }
                         DataState::Known(_) | DataState::UnknownNonzero => {
                             // Improvement was made, no need to analyze it now, we'll get it next time
-                            println!("Deleted a branch (executed). CDO {}, State {:?}", cond_dp_offset, state);
+                            log!("Deleted a branch (executed). CDO {}, State {:?}", cond_dp_offset, state);
                             removed += 1;
-                            let (mut new_elements, inner_removed) = one_step_loops_ctx(elements, state);
+                            let (mut new_elements, inner_removed) = one_step_loops_ctx(elements, state, policy);
                             removed += inner_removed;
                             cmds.append(&mut new_elements);
                         }
@@ -281,10 +450,11 @@ fn one_step_loops(cmds: &mut Vec<AST>) -> usize {
 
                             branch_state.set_data(cond_dp_offset, DataState::UnknownNonzero);
 
-                            let (new_elements, inner_removed) = one_step_loops_ctx(elements, &mut branch_state);
+                            let (new_elements, inner_removed) = one_step_loops_ctx(elements, &mut branch_state, policy);
                             cmds.push(AST::IfNonZero {
                                 cond_dp_offset,
                                 elements: new_elements,
+                                code_p,
                             });
                             removed += inner_removed;
 
@@ -296,6 +466,7 @@ fn one_step_loops(cmds: &mut Vec<AST>) -> usize {
                     known_to_be_nontrivial: _,
                     cond_dp_offset,
                     dp_shift: _,
+                    ..
                 } => {
                     state.clear_knowledge();
                     state.set_data(cond_dp_offset, DataState::Known(0));
@@ -304,11 +475,15 @@ fn one_step_loops(cmds: &mut Vec<AST>) -> usize {
                 AST::InfiniteLoop => {
                     cmds.push(cmd);
                 }
-                AST::ShiftDataPtr { amount } => {
+                AST::ResetTape => {
+                    state.reset_tape();
+                    cmds.push(cmd);
+                }
+                AST::ShiftDataPtr { amount, .. } => {
                     state.shift_ptr(amount);
                     cmds.push(cmd);
                 }
-                AST::ModData { kind, dp_offset } => {
+                AST::ModData { kind, dp_offset, .. } => {
                     state.process_mod_data(kind, dp_offset);
                     cmds.push(cmd);
                 }
@@ -316,21 +491,22 @@ fn one_step_loops(cmds: &mut Vec<AST>) -> usize {
                     source_dp_offset,
                     target_dp_offset,
                     source_amt_mult,
+                    ..
                 } => {
                     state.process_combine_data(source_dp_offset, target_dp_offset, source_amt_mult);
                     cmds.push(cmd);
                 }
-                AST::ReadByte { dp_offset } => {
+                AST::ReadByte { dp_offset, .. } => {
                     state.set_data(dp_offset, DataState::Unknown);
                     cmds.push(cmd);
                 }
-                AST::WriteByte { .. } => {
+                AST::WriteByte { .. } | AST::WriteTransformed { .. } => {
                     cmds.push(cmd);
                 }
-                AST::WriteConst { .. } => {
+                AST::WriteConst { .. } | AST::WriteConstStr { .. } => {
                     cmds.push(cmd);
                 }
-                AST::AssertEquals { dp_offset, val } => {
+                AST::AssertEquals { dp_offset, val, .. } => {
                     state.set_data(dp_offset, DataState::Known(val));
                     cmds.push(cmd);
                 }
@@ -340,53 +516,74 @@ fn one_step_loops(cmds: &mut Vec<AST>) -> usize {
         (cmds, removed)
     }
 
-    let old = std::mem::replace(cmds, Vec::new());
+    let old = core::mem::take(cmds);
 
-    let (new_cmds, removed) = one_step_loops_ctx(old, &mut SimState::new(DataState::Known(0)));
+    let (new_cmds, removed) = one_step_loops_ctx(old, &mut SimState::new(start, policy), policy);
     *cmds = new_cmds;
     removed
 }
 
+// How far a shift loop moves dp, if every cell it checks along the way is known: it passes over
+// the nonzero ones and stops at the first zero. Unknown if it gets to a cell we don't know.
+fn scan_distance(state: &sim_state::SimState, cond_dp_offset: isize, dp_shift: isize) -> Option<isize> {
+    use sim_state::DataState;
+
+    if dp_shift == 0 {
+        return None;
+    }
+
+    // There are only so many known cells, so this gets to a zero or an unknown eventually
+    let mut distance = 0;
+    loop {
+        match state.get_data(cond_dp_offset + distance) {
+            DataState::Known(0) => return Some(distance),
+            DataState::Known(_) | DataState::UnknownNonzero => distance += dp_shift,
+            DataState::Unknown => return None,
+        }
+    }
+}
+
 // Do a one-pass simulation to see if we can use const analysis to eliminate branches, etc.
 // This is NOT gonna just be a "run the thing at compile time" situation because I consider that
 // cheating; this will be an O(n) operation where n is cmds.len(); we just sweep through and anything
 // we can sort of determine in advance, we collapse
-fn run_simulation(cmds: &mut Vec<AST>) -> usize {
+fn run_simulation(cmds: &mut Vec<AST>, start: sim_state::DataState, policy: OverflowPolicy) -> usize {
     use sim_state::{DataState, SimState};
 
-    fn run_simulation_ctx(cmds: &mut Vec<AST>, state: &mut SimState) -> usize {
+    fn run_simulation_ctx(cmds: &mut Vec<AST>, state: &mut SimState, policy: OverflowPolicy) -> usize {
         let mut removed = 0; // or simplified, or whatever
 
-        let old = std::mem::replace(cmds, Vec::new());
+        let old = core::mem::take(cmds);
 
         for cmd in old {
             match cmd {
-                AST::IfNonZero { cond_dp_offset, elements } => {
+                AST::IfNonZero {
+                    cond_dp_offset,
+                    elements,
+                    code_p,
+                } => {
                     match state.get_data(cond_dp_offset) {
                         DataState::Unknown => {
-                            let cmd = AST::IfNonZero { cond_dp_offset, elements };
-                            let usage = track_usage(&cmd);
-                            cmds.push(cmd);
+                            // Inside the branch, everything we know so far still holds, and the condition isn't zero
+                            let mut elements = elements;
+                            let (mut branch_state, marker) = state.make_branch();
+                            branch_state.set_data(cond_dp_offset, DataState::UnknownNonzero);
+                            removed += run_simulation_ctx(&mut elements, &mut branch_state, policy);
+                            cmds.push(AST::IfNonZero {
+                                cond_dp_offset,
+                                elements,
+                                code_p,
+                            });
 
-                            match usage {
-                                DataUsage::DataTracked { dp_shift: 0, data_mods } => {
-                                    for m in data_mods {
-                                        state.set_data(m, DataState::Unknown);
-                                    }
-                                }
-                                DataUsage::DataTracked { dp_shift, data_mods } => {
-                                    println!("If statement has conditional shift, but it's unhandled; if you see this message, cover this case: shift: {}, mods: {:?}", dp_shift, data_mods);
-                                    state.clear_knowledge();
-                                }
-                                _ => state.clear_knowledge(),
-                            }
+                            // Afterward, only what's the same whether or not it ran is still known, as in one_step_loops
+                            state.merge_divergent(branch_state, marker);
                         }
                         DataState::Known(0) => {
-                            println!("Eliminated branch {:?} (not executed)", elements);
+                            log!("Eliminated branch {:?} (not executed)", elements);
                             removed += 1;
                         }
                         DataState::UnknownNonzero | DataState::Known(_) => {
-                            println!("Eliminated branch (executed)");
+                            log!("Eliminated branch (executed)");
                             // successive passes will manage this? I guess
                             for elt in elements {
                                 cmds.push(elt);
@@ -396,37 +593,56 @@ fn run_simulation(cmds: &mut Vec<AST>) -> usize {
                         }
                     }
                 }
-                AST::ShiftDataPtr { amount } => {
+                AST::ShiftDataPtr { amount, .. } => {
                     state.shift_ptr(amount);
                     cmds.push(cmd);
                 }
-                AST::ShiftLoop { .. } => {
-                    cmds.push(cmd);
-                    state.clear_knowledge();
-                    state.set_data(0, DataState::Known(0));
-                }
+                AST::ShiftLoop {
+                    cond_dp_offset,
+                    dp_shift,
+                    code_p,
+                    ..
+                } => match scan_distance(state, cond_dp_offset, dp_shift) {
+                    Some(0) => {
+                        log!("Eliminated shift loop (not executed)");
+                        removed += 1;
+                    }
+                    Some(distance) => {
+                        log!("Shift loop always moves {}", distance);
+                        cmds.push(AST::ShiftDataPtr { amount: distance, code_p });
+                        state.shift_ptr(distance);
+                        removed += 1;
+                    }
+                    None => {
+                        cmds.push(cmd);
+                        state.clear_knowledge();
+                        state.set_data(cond_dp_offset, DataState::Known(0));
+                    }
+                },
                 AST::Loop {
                     cond_dp_offset,
                     elements,
                     mut known_to_be_nontrivial,
+                    code_p,
                 } => {
                     let keep_loop: bool;
 
                     match state.get_data(cond_dp_offset) {
                         DataState::Known(0) => {
-                            println!("Eliminated loop (not executed)");
+                            log!("Eliminated loop (not executed)");
                             removed += 1;
                             keep_loop = false;
                         }
                         DataState::Known(_) | DataState::UnknownNonzero => {
                             if known_to_be_nontrivial {
-                                println!(
+                                log!(
                                     "Gave up on a loop, it already had the hint. State: {:?}, Elts: {:#?}",
-                                    state, elements
+                                    state,
+                                    elements
                                 );
                                 keep_loop = true;
                             } else {
-                                println!("Gave up on a loop, but emitted a 'will be executed' hint");
+                                log!("Gave up on a loop, but emitted a 'will be executed' hint");
                                 // not really removed, but at least simplified / improved?
                                 keep_loop = true;
                                 known_to_be_nontrivial = true;
@@ -434,59 +650,86 @@ fn run_simulation(cmds: &mut Vec<AST>) -> usize {
                             }
                         }
                         DataState::Unknown => {
-                            println!("Gave up on a loop, no hint could be emitted anyway");
+                            log!("Gave up on a loop, no hint could be emitted anyway");
                             keep_loop = true;
                         }
                     }
 
                     if keep_loop {
-                        let cmd = AST::Loop {
-                            known_to_be_nontrivial,
-                            elements,
-                            cond_dp_offset,
-                        };
-                        let usage = track_usage(&cmd);
-                        cmds.push(cmd);
+                        let mut elements = elements;
+                        let usage = track_block_usage(&elements);
 
-                        if let DataUsage::DataTracked { dp_shift: 0, data_mods } = usage {
+                        if let DataUsage::DataTracked {
+                            dp_shift: 0, data_mods, ..
+                        } = usage
+                        {
                             for m in data_mods {
                                 state.set_data(m, DataState::Unknown);
                             }
+
+                            // Anything the loop doesn't modify is the same on every trip through it, so
+                            // what we know about it still holds inside; e.g. a cell set before the loop
+                            // and written out inside it can be a constant write
+                            let (mut body_state, _) = state.make_branch();
+                            body_state.set_data(cond_dp_offset, DataState::UnknownNonzero);
+                            removed += run_simulation_ctx(&mut elements, &mut body_state, policy);
                         } else {
                             state.clear_knowledge();
                         }
+
+                        cmds.push(AST::Loop {
+                            known_to_be_nontrivial,
+                            elements,
+                            cond_dp_offset,
+                            code_p,
+                        });
                     }
                     state.set_data(cond_dp_offset, DataState::Known(0));
                 }
-                AST::ReadByte { dp_offset } => {
+                AST::ReadByte { dp_offset, code_p } => {
                     state.set_data(dp_offset, DataState::Unknown);
-                    cmds.push(AST::ReadByte { dp_offset });
+                    cmds.push(AST::ReadByte { dp_offset, code_p });
                 }
-                AST::WriteByte { dp_offset } => match state.get_data(dp_offset) {
+                AST::WriteByte { dp_offset, code_p } => match state.get_data(dp_offset) {
                     DataState::Unknown | DataState::UnknownNonzero => {
-                        cmds.push(AST::WriteByte { dp_offset });
+                        cmds.push(AST::WriteByte { dp_offset, code_p });
                     }
                     DataState::Known(val) => {
                         removed += 1;
                         cmds.push(AST::WriteConst { out: val });
                     }
                 },
-                AST::WriteConst { .. } => {
+                // Unless the add would fail, in which case it's left to fail at runtime
+                AST::WriteTransformed { dp_offset, add, code_p } => match state.get_data(dp_offset) {
+                    DataState::Known(val) if policy.add(val, add).is_some() => {
+                        removed += 1;
+                        cmds.push(AST::WriteConst {
+                            out: policy.add(val, add).unwrap(),
+                        });
+                    }
+                    _ => cmds.push(AST::WriteTransformed { dp_offset, add, code_p }),
+                },
+                AST::WriteConst { .. } | AST::WriteConstStr { .. } => {
                     cmds.push(cmd);
                 }
                 AST::CombineData {
                     source_dp_offset,
                     target_dp_offset,
                     source_amt_mult,
+                    code_p,
                 } => {
-                    if let DataState::Known(old) = state.get_data(source_dp_offset) {
-                        println!("Combine turned to add"); // if it's settable it'll be found on the next pass
+                    let as_add = match state.get_data(source_dp_offset) {
+                        DataState::Known(old) => policy.product_amount(old, source_amt_mult),
+                        _ => None,
+                    };
+
+                    if let Some(amount) = as_add {
+                        log!("Combine turned to add"); // if it's settable it'll be found on the next pass
                         removed += 1;
                         cmds.push(AST::ModData {
                             dp_offset: target_dp_offset,
-                            kind: DatamodKind::AddData {
-                                amount: u8::wrapping_mul(source_amt_mult, old),
-                            },
+                            kind: DatamodKind::AddData { amount },
+                            code_p,
                         });
 
                         state.process_combine_data(source_dp_offset, target_dp_offset, source_amt_mult);
@@ -496,18 +739,19 @@ fn run_simulation(cmds: &mut Vec<AST>) -> usize {
                         let end_data = state.get_data(target_dp_offset);
 
                         if let DataState::Known(amount) = end_data {
-                            println!("Combine turned to set");
+                            log!("Combine turned to set");
                             removed += 1;
                             cmds.push(AST::ModData {
                                 dp_offset: target_dp_offset,
                                 kind: DatamodKind::SetData { amount },
+                                code_p,
                             });
                         } else {
                             cmds.push(cmd);
                         }
                     }
                 }
-                AST::ModData { kind, dp_offset } => {
+                AST::ModData { kind, dp_offset, code_p } => {
                     let start_data = state.get_data(dp_offset);
 
                     state.process_mod_data(kind, dp_offset);
@@ -516,12 +760,13 @@ fn run_simulation(cmds: &mut Vec<AST>) -> usize {
 
                     if let DataState::Known(amount) = end_data {
                         if start_data == end_data {
-                            println!("No-op mod data deleted");
+                            log!("No-op mod data deleted");
                             removed += 1;
                         } else {
                             let new_cmd = AST::ModData {
                                 kind: DatamodKind::SetData { amount },
                                 dp_offset,
+                                code_p,
                             };
                             match kind {
                                 DatamodKind::SetData { amount: _ } => {}
@@ -535,9 +780,23 @@ fn run_simulation(cmds: &mut Vec<AST>) -> usize {
                         cmds.push(cmd);
                     }
                 }
+                AST::AssertEquals { dp_offset, val, .. } => {
+                    if state.get_data(dp_offset) == DataState::Known(val) {
+                        log!("Eliminated assert (already known)");
+                        removed += 1;
+                    } else {
+                        // If it's still running afterward, the assert held
+                        state.set_data(dp_offset, DataState::Known(val));
+                        cmds.push(cmd);
+                    }
+                }
+                AST::ResetTape => {
+                    state.reset_tape();
+                    cmds.push(cmd);
+                }
                 _ => {
                     state.clear_knowledge();
-                    println!("Gave up on {:?}", cmd);
+                    log!("Gave up on {:?}", cmd);
                     cmds.push(cmd);
                 }
             }
@@ -546,27 +805,56 @@ fn run_simulation(cmds: &mut Vec<AST>) -> usize {
         removed
     }
 
-    let mut state = SimState::new(DataState::Known(0));
-    run_simulation_ctx(cmds, &mut state)
+    let mut state = SimState::new(start, policy);
+    run_simulation_ctx(cmds, &mut state, policy)
 }
 
-// the result of "a, then b" on the same offset
-fn collapse_kinds(a: DatamodKind, b: DatamodKind) -> DatamodKind {
+// the result of "a, then b" on the same offset, if that's a single DatamodKind
+fn collapse_kinds(a: DatamodKind, b: DatamodKind, policy: OverflowPolicy) -> Option<DatamodKind> {
     match b {
-        DatamodKind::SetData { amount } => DatamodKind::SetData { amount },
+        // unless the add could have overflowed first
+        DatamodKind::SetData { .. } if policy == OverflowPolicy::Error && matches!(a, DatamodKind::AddData { amount } if amount != 0) => {
+            None
+        }
+        DatamodKind::SetData { amount } => Some(DatamodKind::SetData { amount }),
         DatamodKind::AddData { amount: b_amt } => match a {
-            DatamodKind::SetData { amount } => DatamodKind::SetData {
-                amount: u8::wrapping_add(amount, b_amt),
-            },
-            DatamodKind::AddData { amount } => DatamodKind::AddData {
-                amount: u8::wrapping_add(amount, b_amt),
-            },
+            DatamodKind::SetData { amount } => policy.add(amount, b_amt).map(|amount| DatamodKind::SetData { amount }),
+            DatamodKind::AddData { amount } => policy.merge_amounts(amount, b_amt).map(|amount| DatamodKind::AddData { amount }),
         },
     }
 }
 
+// The cell an arithmetic command adds to, if it's one; under the overflow policies other than
+// Wrap these are the commands whose order can matter
+fn adds_to(cmd: &AST) -> Option<isize> {
+    match cmd {
+        AST::ModData {
+            kind: DatamodKind::AddData { .. },
+            dp_offset,
+            ..
+        } => Some(*dp_offset),
+        AST::CombineData { target_dp_offset, .. } => Some(*target_dp_offset),
+        _ => None,
+    }
+}
+
+// True if swapping these (or dropping the first, for an infinite loop) would change what happens
+// under this policy, even though it wouldn't when wrapping. Two adds to the same cell don't commute
+// when saturating (or erroring), and when erroring, an add that might fail can't move past IO.
+fn overflow_pins_order(first: &AST, second: &AST, policy: OverflowPolicy) -> bool {
+    let observable = |cmd: &AST| !matches!(cmd, AST::ModData { .. } | AST::CombineData { .. } | AST::ShiftDataPtr { .. });
+
+    match (policy, adds_to(first), adds_to(second)) {
+        (OverflowPolicy::Wrap, _, _) => false,
+        (_, Some(a), Some(b)) => a == b,
+        (OverflowPolicy::Error, Some(_), None) => observable(second),
+        (OverflowPolicy::Error, None, Some(_)) => observable(first),
+        _ => false,
+    }
+}
+
 // Precondition: everything is sorted and collapsed
-fn const_loop_remove(cmds: &mut Vec<AST>) -> usize {
+fn const_loop_remove(cmds: &mut Vec<AST>, policy: OverflowPolicy) -> usize {
     // first, apply recursively; so we have loops that could be removed, but not loops that
     // have removable loops as elements
 
@@ -577,9 +865,10 @@ fn const_loop_remove(cmds: &mut Vec<AST>) -> usize {
             ref mut elements,
             cond_dp_offset: _,
             known_to_be_nontrivial: _,
+            ..
         } = cmd
         {
-            total_removed += const_loop_remove(elements);
+            total_removed += const_loop_remove(elements, policy);
         }
     }
 
@@ -592,10 +881,14 @@ fn const_loop_remove(cmds: &mut Vec<AST>) -> usize {
         InnerLoops,
         IO,
         InfiniteLoop,
+        ResetTape,
     }
 
-    fn only_data(cmds: &[AST]) -> Result<HashMap<isize, DatamodKind>, NonConstResult> {
-        let mut offsets: HashMap<isize, DatamodKind> = HashMap::new();
+    // Shifts are fine, so long as they cancel out by the end of the body; the offsets are from
+    // where dp is at the top of the body, which is where it is every trip
+    fn only_data(cmds: &[AST], policy: OverflowPolicy) -> Result<BTreeMap<isize, DatamodKind>, NonConstResult> {
+        let mut offsets: BTreeMap<isize, DatamodKind> = BTreeMap::new();
+        let mut shift = 0;
 
         let mut running_error: Option<NonConstResult> = None;
 
@@ -609,11 +902,15 @@ fn const_loop_remove(cmds: &mut Vec<AST>) -> usize {
 
         for cmd in cmds {
             match cmd {
-                AST::ModData { kind, dp_offset } => {
-                    let val = offsets.entry(*dp_offset).or_insert(DatamodKind::AddData { amount: 0 });
-                    *val = collapse_kinds(*val, *kind);
+                AST::ModData { kind, dp_offset, .. } => {
+                    let dp_offset = dp_offset + shift;
+                    let val = offsets.entry(dp_offset).or_insert(DatamodKind::AddData { amount: 0 });
+                    match collapse_kinds(*val, *kind, policy) {
+                        Some(collapsed) => *val = collapsed,
+                        None => update_err(NonConstResult::ComplexArithmetic),
+                    }
                     if *val == (DatamodKind::AddData { amount: 0 }) {
-                        offsets.remove(dp_offset);
+                        offsets.remove(&dp_offset);
                     }
                 }
                 AST::CombineData { .. } => {
@@ -628,130 +925,132 @@ fn const_loop_remove(cmds: &mut Vec<AST>) -> usize {
                 AST::IfNonZero { .. } => {
                     update_err(NonConstResult::InnerCond);
                 }
-                AST::ShiftDataPtr { .. } => {
-                    update_err(NonConstResult::Shifts);
+                AST::ShiftDataPtr { amount, .. } => {
+                    shift += amount;
                 }
-                AST::ReadByte { .. } | AST::WriteByte { .. } | AST::WriteConst { .. } => {
+                AST::ReadByte { .. }
+                | AST::WriteByte { .. }
+                | AST::WriteTransformed { .. }
+                | AST::WriteConst { .. }
+                | AST::WriteConstStr { .. } => {
                     update_err(NonConstResult::IO);
                 }
                 AST::InfiniteLoop => {
                     update_err(NonConstResult::InfiniteLoop);
                 }
+                AST::ResetTape => {
+                    update_err(NonConstResult::ResetTape);
+                }
                 AST::AssertEquals { .. } => {}
             }
         }
 
+        if shift != 0 {
+            update_err(NonConstResult::Shifts);
+        }
+
         match running_error {
             Some(e) => Err(e),
             None => Ok(offsets),
         }
     }
 
-    let old = std::mem::replace(cmds, Vec::new());
+    // Whether a loop doing these to the tape forever really is an infinite loop; when erroring, adding
+    // forever is an overflow sooner or later
+    fn spins_harmlessly(offsets: &BTreeMap<isize, DatamodKind>, policy: OverflowPolicy) -> bool {
+        policy != OverflowPolicy::Error || offsets.values().all(|kind| matches!(kind, DatamodKind::SetData { .. }))
+    }
+
+    // What a loop which has been folded away does; unless the loop is known to run, that only
+    // happens if the condition is nonzero to start with, since otherwise the loop runs zero times
+    fn push_folded(cmds: &mut Vec<AST>, cond_dp_offset: isize, known_to_be_nontrivial: bool, folded: Vec<AST>, code_p: SourcePos) {
+        if known_to_be_nontrivial {
+            cmds.extend(folded);
+        } else {
+            cmds.push(AST::IfNonZero {
+                cond_dp_offset,
+                elements: folded,
+                code_p,
+            });
+        }
+    }
+
+    let old = core::mem::take(cmds);
 
     for mut cmd in old {
         if let AST::Loop {
             ref mut elements,
             cond_dp_offset,
             known_to_be_nontrivial,
+            code_p,
         } = cmd
         {
-            match only_data(elements) {
+            match only_data(elements, policy) {
+                Ok(ref offsets) if !offsets.contains_key(&cond_dp_offset) && !spins_harmlessly(offsets, policy) => {
+                    cmds.push(cmd);
+                }
                 Ok(mut offsets) => {
-                    if !offsets.contains_key(&cond_dp_offset) {
-                        if known_to_be_nontrivial {
-                            println!("Emitted IL");
-                            cmds.push(AST::InfiniteLoop);
-                        } else {
-                            println!("Emitted cond IL");
-                            cmds.push(AST::IfNonZero {
-                                cond_dp_offset,
-                                elements: vec![AST::InfiniteLoop],
-                            });
+                    let zero_offset = match offsets.remove(&cond_dp_offset) {
+                        Some(zero_offset) => zero_offset,
+                        None => {
+                            // Nothing in the loop touches the condition (the loop may even be empty),
+                            // so once it's entered it never ends
+                            log!("Emitted IL");
+                            push_folded(cmds, cond_dp_offset, known_to_be_nontrivial, vec![AST::InfiniteLoop], code_p);
+                            total_removed += 1;
+                            continue;
                         }
-                    }
+                    };
+
+                    let loop_adds = match zero_offset {
+                        // The loop repeats until data[cond] + reps * amount wraps around to zero. With an
+                        // odd amount there is always exactly one such (smallest) reps, namely
+                        // data[cond] * (-amount)^-1, and the inverse exists mod 256. With an even amount
+                        // it depends on the actual value whether the loop ever ends, so it stays a loop.
+                        //
+                        // Without wrapping, that's only counting down by one: the loop runs data[cond]
+                        // times, and since each trip adds the same (signed) amount to each target,
+                        // saturating or overflowing once at the end is the same as doing it along the way.
+                        DatamodKind::AddData { amount } if amount % 2 == 1 && (policy == OverflowPolicy::Wrap || amount == u8::MAX) => {
+                            let reps_mult = mod_inverse(u8::wrapping_neg(amount));
 
-                    if offsets.len() == 1 {
-                        // TODO BUG: this is actually wrong; it should be "if the 2-ness of offsets[0] is <= the 2-ness of S, set 0; else inf loop"
-                        // this won't actually detect an infinite loop, sadly
-
-                        // Note we don't need the hint to eliminate the branch -- we know the offset
-                        // is valid (assuming the loop is evaluable) so "if x != 0 { x = 0 }" is
-                        // more simply stated as "x = 0"
-                        cmds.push(AST::ModData {
-                            kind: DatamodKind::SetData { amount: 0 },
-                            dp_offset: cond_dp_offset,
-                        });
-                        total_removed += 1;
-                    } else {
-                        let zero_offset = offsets.remove(&cond_dp_offset).unwrap();
-
-                        // in this case it literally just iterates exactly data[dp] times, so it's really easy
-                        // this seems like a weird special case but it's really common
-                        if let DatamodKind::AddData { amount } = zero_offset {
-                            if amount != 1 && amount != u8::max_value() {
-                                // I mean this literally never happens in my benchmark???
-                                println!(
-                                    "Found a const loop with offsets {:?}, zero offset {:?}, which should be solvable, but which I could not kill",
-                                    offsets, zero_offset
-                                );
-                                cmds.push(cmd);
-                            } else {
-                                // The number of loop repetitions is the value of zero, times this number
-                                let reps_mult = {
-                                    if amount == u8::max_value() {
-                                        1
-                                    } else if amount == 1 {
-                                        u8::max_value()
-                                    } else {
-                                        unreachable!()
+                            let mut loop_adds = Vec::new();
+                            for (target_dp_offset, kind) in offsets {
+                                match kind {
+                                    DatamodKind::AddData { amount: base_amt_mult } => {
+                                        loop_adds.push(AST::CombineData {
+                                            source_dp_offset: cond_dp_offset,
+                                            target_dp_offset,
+                                            // Each repetition adds base_amt_mult to the target
+                                            // We repeat this operation resp_mult * source_data times
+                                            // So equivalently target_data += base_amt_mult * source_data * reps_mult
+                                            // This is only confusing because everything has overflow, but modular + and * work so it's fine
+                                            source_amt_mult: u8::wrapping_mul(reps_mult, base_amt_mult),
+                                            code_p,
+                                        });
                                     }
-                                };
-
-                                let mut loop_adds = Vec::new();
-                                for (target_dp_offset, kind) in offsets {
-                                    match kind {
-                                        DatamodKind::AddData { amount: base_amt_mult } => {
-                                            loop_adds.push(AST::CombineData {
-                                                source_dp_offset: cond_dp_offset,
-                                                target_dp_offset,
-                                                // Each repetition adds base_amt_mult to the target
-                                                // We repeat this operation resp_mult * source_data times
-                                                // So equivalently target_data += base_amt_mult * source_data * reps_mult
-                                                // This is only confusing because everything has overflow, but modular + and * work so it's fine
-                                                source_amt_mult: u8::wrapping_mul(reps_mult, base_amt_mult),
-                                            });
-                                        }
-                                        // Disappointingly never seems to happen?
-                                        DatamodKind::SetData { amount: target_set_amt } => {
-                                            loop_adds.push(AST::ModData {
-                                                kind: DatamodKind::SetData { amount: target_set_amt },
-                                                dp_offset: target_dp_offset,
-                                            });
-                                        }
+                                    // Whatever the number of trips, the cell ends up with the value it's set
+                                    // to (e.g. the [-] in [->+>[-]<<], once that's been folded)
+                                    DatamodKind::SetData { amount: target_set_amt } => {
+                                        loop_adds.push(AST::ModData {
+                                            kind: DatamodKind::SetData { amount: target_set_amt },
+                                            dp_offset: target_dp_offset,
+                                            code_p,
+                                        });
                                     }
                                 }
+                            }
 
-                                loop_adds.push(AST::ModData {
-                                    kind: DatamodKind::SetData { amount: 0 },
-                                    dp_offset: cond_dp_offset,
-                                });
-
-                                total_removed += 1;
+                            loop_adds.push(AST::ModData {
+                                kind: DatamodKind::SetData { amount: 0 },
+                                dp_offset: cond_dp_offset,
+                                code_p,
+                            });
 
-                                if known_to_be_nontrivial {
-                                    for inner in loop_adds {
-                                        cmds.push(inner);
-                                    }
-                                } else {
-                                    cmds.push(AST::IfNonZero {
-                                        cond_dp_offset,
-                                        elements: loop_adds,
-                                    });
-                                }
-                            }
-                        } else if let DatamodKind::SetData { amount } = zero_offset {
-                            // then this is actually a single execution (if amount is zero) or an infinite loop (if it's not)
+                            loop_adds
+                        }
+                        // then this is actually a single execution (if amount is zero) or an infinite loop (if it's not)
+                        DatamodKind::SetData { amount } if amount == 0 || spins_harmlessly(&offsets, policy) => {
                             let mut loop_adds = Vec::new();
                             if amount != 0 {
                                 loop_adds.push(AST::InfiniteLoop);
@@ -760,55 +1059,64 @@ fn const_loop_remove(cmds: &mut Vec<AST>) -> usize {
                                     loop_adds.push(AST::ModData {
                                         kind,
                                         dp_offset: target_dp_offset,
+                                        code_p,
                                     });
                                 }
                                 loop_adds.push(AST::ModData {
                                     kind: DatamodKind::SetData { amount: 0 },
                                     dp_offset: cond_dp_offset,
+                                    code_p,
                                 });
                             }
-                            if known_to_be_nontrivial {
-                                loop_adds.into_iter().for_each(|e| cmds.push(e));
-                            } else {
-                                cmds.push(AST::IfNonZero {
-                                    cond_dp_offset,
-                                    elements: loop_adds,
-                                });
-                            }
-                        } else {
-                            // I mean this literally never happens in my benchmark???
-                            println!(
-                                "Found a const loop with offsets {:?}, zero offset {:?}, which should be solvable, but which I could not kill",
-                                offsets, zero_offset
+                            loop_adds
+                        }
+                        DatamodKind::AddData { .. } | DatamodKind::SetData { .. } => {
+                            log!(
+                                "Found a const loop with offsets {:?}, zero offset {:?}, which may not terminate",
+                                offsets,
+                                zero_offset
                             );
                             cmds.push(cmd);
+                            continue;
                         }
-                    }
+                    };
+
+                    total_removed += 1;
+
+                    // Note we don't need the hint to eliminate the branch if all that's left is zeroing
+                    // the condition -- "if x != 0 { x = 0 }" is more simply stated as "x = 0"
+                    let only_zeroes_cond = loop_adds.len() == 1 && matches!(loop_adds[0], AST::ModData { .. });
+
+                    push_folded(cmds, cond_dp_offset, known_to_be_nontrivial || only_zeroes_cond, loop_adds, code_p);
                 }
                 Err(_reason) => {
-                    if elements.is_empty() {
-                        println!("Emitted infinite loop (empty loop)");
-                        cmds.push(AST::IfNonZero {
-                            elements: vec![AST::InfiniteLoop],
-                            cond_dp_offset,
-                        });
+                    // The affine folding is all modular arithmetic, so it only applies when wrapping
+                    let folded = match policy {
+                        OverflowPolicy::Wrap => fold_affine_loop(elements, cond_dp_offset, code_p),
+                        _ => None,
+                    };
+                    if let Some(folded) = folded {
+                        log!("Folded an arithmetic loop into {} commands", folded.len());
                         total_removed += 1;
+                        push_folded(cmds, cond_dp_offset, known_to_be_nontrivial, folded, code_p);
                     } else if elements.len() == 1 {
-                        match elements.get(0).unwrap() {
-                            AST::ShiftDataPtr { amount } => {
+                        match elements.first().unwrap() {
+                            AST::ShiftDataPtr { amount, .. } => {
                                 cmds.push(AST::ShiftLoop {
                                     dp_shift: *amount,
                                     known_to_be_nontrivial,
                                     cond_dp_offset,
+                                    code_p,
                                 });
                                 total_removed += 1;
                             }
                             other => {
-                                println!("Singleton loop, non eliminable: {:?}", other);
+                                log!("Singleton loop, non eliminable: {:?}", other);
+                                cmds.push(cmd);
                             }
                         }
                     } else {
-                        // println!("Could not destroy loop for reason {:?}", reason);
+                        // log!("Could not destroy loop for reason {:?}", reason);
                         cmds.push(cmd);
                     }
                 }
@@ -821,95 +1129,438 @@ fn const_loop_remove(cmds: &mut Vec<AST>) -> usize {
     total_removed
 }
 
-fn collapse_consecutive(cmds: &mut Vec<AST>) -> usize {
-    if cmds.is_empty() {
-        return 0;
+// The multiplicative inverse of an odd number, mod 256
+fn mod_inverse(a: u8) -> u8 {
+    (1..=u8::MAX)
+        .find(|b| u8::wrapping_mul(a, *b) == 1)
+        .expect("Only odd numbers are invertible")
+}
+
+// True if running these commands does nothing at all when data[cond] is zero; this is what's in
+// the branch const_loop_remove leaves behind for a flat loop like [->+<]
+fn is_noop_when_zero(cmds: &[AST], cond_dp_offset: isize) -> bool {
+    cmds.iter().all(|cmd| match cmd {
+        AST::CombineData { source_dp_offset, .. } => *source_dp_offset == cond_dp_offset,
+        AST::ModData {
+            kind: DatamodKind::SetData { amount: 0 },
+            dp_offset,
+            ..
+        } => *dp_offset == cond_dp_offset,
+        _ => false,
+    })
+}
+
+// Folds a loop whose body is just arithmetic (ModData and CombineData), as long as every trip
+// through the loop after the first one does the same thing -- adds a fixed amount to each cell.
+// This is what nested accumulation looks like once the inner loops have been folded, e.g.
+// [->[->+<]+++<] moves data[1] into data[2] the first time, and after that it's just adding 3.
+//
+// So the loop is one run of the body, and then the remaining (data[cond] * reps_mult) trips are
+// CombineData from the condition, the same as for a flat const loop. If some trip adds a
+// different amount than the next (e.g. [->[->+>+<<]>>[-<<+>>]<<<], which multiplies) it can't be written
+// with CombineData, since that would need a product of two cells, so the loop is left alone.
+//
+// Returns what should run in place of the loop, if the loop is entered.
+fn fold_affine_loop(elements: &[AST], cond_dp_offset: isize, code_p: SourcePos) -> Option<Vec<AST>> {
+    use affine::AffineState;
+
+    let mut once = AffineState::new();
+    once.run(elements).ok()?;
+
+    let mut twice = once.clone();
+    twice.run(elements).ok()?;
+
+    // What each trip after the first adds to each cell; all wrapping, so "adding" 255 subtracts 1
+    let mut deltas: BTreeMap<isize, u8> = BTreeMap::new();
+    for cell in twice.cells() {
+        let delta = twice.get(cell).minus(&once.get(cell));
+        if !delta.coeffs.is_empty() {
+            return None;
+        }
+        if delta.constant != 0 {
+            deltas.insert(cell, delta.constant);
+        }
     }
 
-    // the idea of this optimization is to just collapse consecutive commands that can be
-    // expressed as a single command; eg. Add 3, then Add 2, becomes Add 5
+    // As with flat const loops, an odd step means the loop always ends, after
+    // data[cond] * (-step)^-1 more trips; with an even step it might not
+    let cond_step = deltas.remove(&cond_dp_offset)?;
+    if cond_step % 2 == 0 {
+        return None;
+    }
+    let reps_mult = mod_inverse(u8::wrapping_neg(cond_step));
 
-    let mut old = Vec::new();
-    std::mem::swap(cmds, &mut old);
+    let remaining_trips: Vec<AST> = deltas
+        .into_iter()
+        .map(|(target_dp_offset, delta)| AST::CombineData {
+            source_dp_offset: cond_dp_offset,
+            target_dp_offset,
+            source_amt_mult: u8::wrapping_mul(reps_mult, delta),
+            code_p,
+        })
+        .collect();
+
+    let mut out = elements.to_vec();
+    // Only touch those cells if there really are more trips, the same as the loop would
+    if !remaining_trips.is_empty() {
+        out.push(AST::IfNonZero {
+            cond_dp_offset,
+            elements: remaining_trips,
+            code_p,
+        });
+    }
+    out.push(AST::ModData {
+        kind: DatamodKind::SetData { amount: 0 },
+        dp_offset: cond_dp_offset,
+        code_p,
+    });
 
-    let mut collapsed = 0;
+    Some(out)
+}
 
-    let mut accumulator: Option<AST> = None;
+fn dead_store_elim(cmds: &mut Vec<AST>, policy: OverflowPolicy) -> usize {
+    use alloc::collections::BTreeSet;
 
-    // First, recursively apply to loops
-    for cmd in old.iter_mut() {
-        if let AST::Loop {
-            ref mut elements,
-            cond_dp_offset: _,
-            known_to_be_nontrivial: _,
-        } = cmd
-        {
-            collapsed += collapse_consecutive(elements);
+    // Walk each straight-line run backward, remembering which cells are going to be overwritten
+    // (by a SetData or a ReadByte) before anything reads them; a data modification to one of
+    // those cells can't be observed, so it goes. Cells are tracked relative to the dp at the end
+    // of the block, which is adjusted as we walk back past shifts. Anything with control flow
+    // reads who knows what, so it just forgets everything. When erroring, an add can be observed by
+    // overflowing, so only sets are dead.
+
+    let mut removed = 0;
+
+    for cmd in cmds.iter_mut() {
+        match cmd {
+            AST::Loop { elements, .. } | AST::IfNonZero { elements, .. } => {
+                removed += dead_store_elim(elements, policy);
+            }
+            _ => {}
         }
     }
 
-    // Then for top-level, do any collapsing of consecutive "matching" terms
-    for cmd in old {
-        if accumulator.is_none() {
-            accumulator = Some(cmd);
-            continue;
-        }
+    let old = core::mem::take(cmds);
+    let mut kept = Vec::with_capacity(old.len());
 
-        let acc = accumulator.unwrap();
+    let mut overwritten: BTreeSet<isize> = BTreeSet::new();
+    let mut dp: isize = 0;
 
-        match acc {
-            AST::ModData { kind, dp_offset } => {
-                match cmd {
-                    AST::ModData {
-                        kind: second_kind,
-                        dp_offset: second_dp_offset,
-                    } => {
-                        if dp_offset == second_dp_offset {
-                            let out_kind = match (kind, second_kind) {
-                                (DatamodKind::AddData { amount: a }, DatamodKind::AddData { amount: b }) => DatamodKind::AddData {
-                                    amount: u8::wrapping_add(a, b),
-                                },
-                                (DatamodKind::SetData { amount: a }, DatamodKind::AddData { amount: b }) => DatamodKind::SetData {
-                                    amount: u8::wrapping_add(a, b),
-                                },
-                                (_, DatamodKind::SetData { amount }) => DatamodKind::SetData { amount },
-                            };
-                            accumulator = Some(AST::ModData { kind: out_kind, dp_offset });
-                            collapsed += 1;
-                        } else {
-                            cmds.push(acc);
-                            accumulator = Some(cmd);
-                        }
-                    }
-                    AST::ReadByte { dp_offset: read_dpo } if read_dpo == dp_offset => {
-                        // the read just overwrites
-                        accumulator = Some(cmd);
-                        collapsed += 1;
-                    }
-                    AST::InfiniteLoop => {
-                        println!("Swallowed by IL");
-                        accumulator = Some(AST::InfiniteLoop);
-                        collapsed += 1;
+    for cmd in old.into_iter().rev() {
+        match cmd {
+            AST::ModData { kind, dp_offset, .. } => {
+                let target = dp + dp_offset;
+
+                let removable = policy != OverflowPolicy::Error || matches!(kind, DatamodKind::SetData { .. });
+
+                if removable && overwritten.contains(&target) {
+                    removed += 1;
+                    continue;
+                }
+
+                // An add reads the cell it's adding to; unless it was kept for overflowing, that's
+                // already not in the set
+                match kind {
+                    DatamodKind::SetData { .. } => {
+                        overwritten.insert(target);
                     }
-                    _ => {
-                        cmds.push(acc);
-                        accumulator = Some(cmd);
+                    DatamodKind::AddData { .. } => {
+                        overwritten.remove(&target);
                     }
                 }
             }
-            AST::ShiftDataPtr { amount } => match cmd {
-                AST::ShiftDataPtr { amount: other_amount } => {
-                    let new_amount = amount + other_amount;
-                    if new_amount == 0 {
-                        accumulator = None;
-                        collapsed += 2;
-                    } else {
-                        accumulator = Some(AST::ShiftDataPtr { amount: new_amount });
-                        collapsed += 1;
-                    }
+            AST::CombineData {
+                source_dp_offset,
+                target_dp_offset,
+                ..
+            } => {
+                if policy != OverflowPolicy::Error && overwritten.contains(&(dp + target_dp_offset)) {
+                    removed += 1;
+                    continue;
                 }
-                AST::InfiniteLoop => {
-                    println!("Swallowed by IL");
+
+                overwritten.remove(&(dp + source_dp_offset));
+                overwritten.remove(&(dp + target_dp_offset));
+            }
+            // Never removed, since the read itself is IO
+            AST::ReadByte { dp_offset, .. } => {
+                overwritten.insert(dp + dp_offset);
+            }
+            AST::WriteByte { dp_offset, .. } | AST::WriteTransformed { dp_offset, .. } | AST::AssertEquals { dp_offset, .. } => {
+                overwritten.remove(&(dp + dp_offset));
+            }
+            AST::ShiftDataPtr { amount, .. } => {
+                dp -= amount;
+            }
+            AST::WriteConst { .. } | AST::WriteConstStr { .. } => {}
+            // Resetting writes every cell, but the cells are relative to a dp it also resets
+            AST::Loop { .. } | AST::IfNonZero { .. } | AST::ShiftLoop { .. } | AST::InfiniteLoop | AST::ResetTape => {
+                overwritten.clear();
+            }
+        }
+
+        kept.push(cmd);
+    }
+
+    kept.reverse();
+    *cmds = kept;
+
+    removed
+}
+
+// Drop whatever comes after the program's last output, since nothing can see it: it only changes
+// the tape the program leaves behind. Reads stay, because they use up input. Loops stay too,
+// since they might never end (const_loop_remove has already turned the ones which surely do into
+// something else). When erroring, an add or combine can be observed by overflowing, so those stay
+// as well. So does anything which might touch a cell left of where the program started, which
+// would be off the tape; that's only dropped where the tracked dp says it's safe.
+fn dead_tail_elim(cmds: &mut Vec<AST>, policy: OverflowPolicy) -> usize {
+    fn is_dead(cmd: &AST, policy: OverflowPolicy) -> bool {
+        match cmd {
+            AST::ModData { kind, .. } => policy != OverflowPolicy::Error || matches!(kind, DatamodKind::SetData { .. }),
+            AST::CombineData { .. } => policy != OverflowPolicy::Error,
+            AST::ShiftDataPtr { .. } | AST::AssertEquals { .. } => true,
+            AST::IfNonZero { elements, .. } => elements.iter().all(|cmd| is_dead(cmd, policy)),
+            // Tracking dp is lost after a reset, but it's rare enough at the end not to matter
+            AST::Loop { .. }
+            | AST::ShiftLoop { .. }
+            | AST::InfiniteLoop
+            | AST::ResetTape
+            | AST::ReadByte { .. }
+            | AST::WriteByte { .. }
+            | AST::WriteTransformed { .. }
+            | AST::WriteConst { .. }
+            | AST::WriteConstStr { .. } => false,
+        }
+    }
+
+    let start = cmds
+        .iter()
+        .rposition(|cmd| !is_dead(cmd, policy) && !matches!(cmd, AST::ReadByte { .. }))
+        .map_or(0, |last_kept| last_kept + 1);
+    if start == cmds.len() {
+        return 0;
+    }
+
+    // Where dp is, relative to where it started, if that's known
+    let mut dp = match track_block_usage(&cmds[..start]) {
+        DataUsage::DpLost => None,
+        DataUsage::DataTracked { dp_shift, .. } => Some(dp_shift),
+    };
+
+    let tail = cmds.split_off(start);
+    let tail_len = tail.len();
+
+    // Shifts which were dropped, but which what's kept after them still needs
+    let mut skipped_shift = 0;
+    let mut skipped_at = SourcePos(None);
+
+    for cmd in tail {
+        let usage = track_usage(&cmd);
+        let on_tape = match (dp, &usage) {
+            (Some(dp), DataUsage::DataTracked { data_mods, data_reads, .. }) => {
+                data_mods.iter().chain(data_reads.iter()).all(|offset| dp + offset >= 0)
+            }
+            _ => false,
+        };
+
+        dp = match (dp, usage) {
+            (Some(dp), DataUsage::DataTracked { dp_shift, .. }) => Some(dp + dp_shift),
+            _ => None,
+        };
+
+        if on_tape && is_dead(&cmd, policy) {
+            if let AST::ShiftDataPtr { amount, code_p } = cmd {
+                if skipped_shift == 0 {
+                    skipped_at = code_p;
+                }
+                skipped_shift += amount;
+            }
+        } else {
+            if skipped_shift != 0 {
+                cmds.push(AST::ShiftDataPtr {
+                    amount: skipped_shift,
+                    code_p: skipped_at,
+                });
+                skipped_shift = 0;
+            }
+            cmds.push(cmd);
+        }
+    }
+
+    tail_len - (cmds.len() - start)
+}
+
+// Drop arithmetic on cells which nothing in the rest of the program touches, wherever it is; like
+// dead_tail_elim, this only changes the tape the program leaves behind. That's often the cells a
+// greeting was built in, once simulation has turned writing them into constants, when the program
+// goes on to do something else. Cells are tracked from where the program started, which takes
+// knowing where dp is; anything before a command which loses track of it stays, as does anything
+// which might touch a cell left of the start, or might overflow when erroring.
+fn unread_store_elim(cmds: &mut Vec<AST>, policy: OverflowPolicy) -> usize {
+    use alloc::collections::BTreeSet;
+
+    fn is_write_only(cmd: &AST, policy: OverflowPolicy) -> bool {
+        match cmd {
+            AST::ModData { kind, .. } => policy != OverflowPolicy::Error || matches!(kind, DatamodKind::SetData { .. }),
+            AST::CombineData { .. } => policy != OverflowPolicy::Error,
+            _ => false,
+        }
+    }
+
+    // Where dp is before each command, relative to where it started, as far as that's known
+    let mut dp = Some(0);
+    let dps: Vec<Option<isize>> = cmds
+        .iter()
+        .map(|cmd| {
+            let before = dp;
+            dp = match (dp, track_usage(cmd)) {
+                (Some(dp), DataUsage::DataTracked { dp_shift, .. }) => Some(dp + dp_shift),
+                _ => None,
+            };
+            before
+        })
+        .collect();
+
+    let old = core::mem::take(cmds);
+    let mut kept = Vec::with_capacity(old.len());
+    let mut removed = 0;
+
+    // The cells something later on touches, from where dp started; once this is None, that
+    // could be any of them
+    let mut touched: Option<BTreeSet<isize>> = Some(BTreeSet::new());
+
+    for (cmd, dp) in old.into_iter().zip(dps).rev() {
+        let lost = match (touched.as_mut(), dp, track_usage(&cmd)) {
+            (Some(touched), Some(dp), DataUsage::DataTracked { data_mods, data_reads, .. }) => {
+                let unread = data_mods.iter().all(|offset| !touched.contains(&(dp + offset)));
+                let on_tape = data_mods.iter().chain(data_reads.iter()).all(|offset| dp + offset >= 0);
+                if unread && on_tape && is_write_only(&cmd, policy) {
+                    removed += 1;
+                    continue;
+                }
+                touched.extend(data_mods.iter().chain(data_reads.iter()).map(|offset| dp + offset));
+                false
+            }
+            _ => true,
+        };
+        if lost {
+            touched = None;
+        }
+        kept.push(cmd);
+    }
+
+    kept.reverse();
+    *cmds = kept;
+
+    removed
+}
+
+fn collapse_consecutive(cmds: &mut Vec<AST>, policy: OverflowPolicy) -> usize {
+    if cmds.is_empty() {
+        return 0;
+    }
+
+    // the idea of this optimization is to just collapse consecutive commands that can be
+    // expressed as a single command; eg. Add 3, then Add 2, becomes Add 5
+
+    let mut old = Vec::new();
+    core::mem::swap(cmds, &mut old);
+
+    let mut collapsed = 0;
+
+    let mut accumulator: Option<AST> = None;
+
+    // First, recursively apply to loops and branches
+    for cmd in old.iter_mut() {
+        if let AST::Loop {
+            ref mut elements,
+            cond_dp_offset: _,
+            known_to_be_nontrivial: _,
+            ..
+        }
+        | AST::IfNonZero {
+            ref mut elements,
+            cond_dp_offset: _,
+            ..
+        } = cmd
+        {
+            collapsed += collapse_consecutive(elements, policy);
+        }
+    }
+
+    collapsed += fuse_adds_into_combines(&mut old, policy);
+    collapsed += fold_swaps(&mut old);
+
+    // Then for top-level, do any collapsing of consecutive "matching" terms
+    for cmd in old {
+        if accumulator.is_none() {
+            accumulator = Some(cmd);
+            continue;
+        }
+
+        let acc = accumulator.unwrap();
+
+        match acc {
+            AST::ModData { kind, dp_offset, code_p } => {
+                match cmd {
+                    AST::ModData {
+                        kind: second_kind,
+                        dp_offset: second_dp_offset,
+                        ..
+                    } if dp_offset == second_dp_offset => match collapse_kinds(kind, second_kind, policy) {
+                        Some(out_kind) => {
+                            accumulator = Some(AST::ModData {
+                                kind: out_kind,
+                                dp_offset,
+                                code_p,
+                            });
+                            collapsed += 1;
+                        }
+                        None => {
+                            cmds.push(acc);
+                            accumulator = Some(cmd);
+                        }
+                    },
+                    // The cell was just zeroed, so the loop or branch on it never runs
+                    AST::Loop { cond_dp_offset, .. } | AST::ShiftLoop { cond_dp_offset, .. } | AST::IfNonZero { cond_dp_offset, .. }
+                        if cond_dp_offset == dp_offset && kind == (DatamodKind::SetData { amount: 0 }) =>
+                    {
+                        log!("Deleted a loop on a cell which was just zeroed");
+                        accumulator = Some(acc);
+                        collapsed += 1;
+                    }
+                    AST::ReadByte { dp_offset: read_dpo, .. } if read_dpo == dp_offset && !overflow_pins_order(&acc, &cmd, policy) => {
+                        // the read just overwrites
+                        accumulator = Some(cmd);
+                        collapsed += 1;
+                    }
+                    AST::InfiniteLoop if !overflow_pins_order(&acc, &cmd, policy) => {
+                        log!("Swallowed by IL");
+                        accumulator = Some(AST::InfiniteLoop);
+                        collapsed += 1;
+                    }
+                    _ => {
+                        cmds.push(acc);
+                        accumulator = Some(cmd);
+                    }
+                }
+            }
+            AST::ShiftDataPtr { amount, code_p } => match cmd {
+                AST::ShiftDataPtr { amount: other_amount, .. } => {
+                    let new_amount = amount + other_amount;
+                    if new_amount == 0 {
+                        accumulator = None;
+                        collapsed += 2;
+                    } else {
+                        accumulator = Some(AST::ShiftDataPtr {
+                            amount: new_amount,
+                            code_p,
+                        });
+                        collapsed += 1;
+                    }
+                }
+                AST::InfiniteLoop => {
+                    log!("Swallowed by IL");
                     accumulator = Some(AST::InfiniteLoop);
                     collapsed += 1;
                 }
@@ -922,25 +1573,26 @@ fn collapse_consecutive(cmds: &mut Vec<AST>) -> usize {
                 source_dp_offset,
                 target_dp_offset,
                 source_amt_mult,
+                code_p,
             } => match cmd {
                 AST::CombineData {
                     source_dp_offset: other_sdo,
                     target_dp_offset: other_tdo,
                     source_amt_mult: other_sam,
-                } => {
-                    if source_dp_offset == other_sdo && target_dp_offset == other_tdo {
-                        accumulator = Some(AST::CombineData {
-                            source_dp_offset,
-                            target_dp_offset,
-                            source_amt_mult: u8::wrapping_add(source_amt_mult, other_sam),
-                        });
-                    } else {
-                        cmds.push(acc);
-                        accumulator = Some(cmd);
-                    }
+                    ..
+                } if source_dp_offset == other_sdo
+                    && target_dp_offset == other_tdo
+                    && policy.merge_amounts(source_amt_mult, other_sam).is_some() =>
+                {
+                    accumulator = Some(AST::CombineData {
+                        source_dp_offset,
+                        target_dp_offset,
+                        source_amt_mult: policy.merge_amounts(source_amt_mult, other_sam).unwrap(),
+                        code_p,
+                    });
                 }
-                AST::InfiniteLoop => {
-                    println!("Swallowed by IL");
+                AST::InfiniteLoop if !overflow_pins_order(&acc, &cmd, policy) => {
+                    log!("Swallowed by IL");
                     accumulator = Some(AST::InfiniteLoop);
                     collapsed += 1;
                 }
@@ -951,7 +1603,7 @@ fn collapse_consecutive(cmds: &mut Vec<AST>) -> usize {
             },
             // Infinite loops never terminate, so any following commands can be dropped
             AST::InfiniteLoop => {
-                println!("Deleted command following an infinite loop");
+                log!("Deleted command following an infinite loop");
                 accumulator = Some(acc);
                 collapsed += 1;
             }
@@ -971,7 +1623,69 @@ fn collapse_consecutive(cmds: &mut Vec<AST>) -> usize {
                     accumulator = Some(cmd);
                 }
             },
-            AST::ReadByte { .. } | AST::WriteByte { .. } | AST::WriteConst { .. } | AST::IfNonZero { .. } => {
+            AST::WriteConst { out } => match cmd {
+                AST::WriteConst { out: other_out } => {
+                    accumulator = Some(AST::WriteConstStr {
+                        bytes: vec![out, other_out],
+                    });
+                    collapsed += 1;
+                }
+                AST::WriteConstStr { mut bytes } => {
+                    bytes.insert(0, out);
+                    accumulator = Some(AST::WriteConstStr { bytes });
+                    collapsed += 1;
+                }
+                _ => {
+                    cmds.push(acc);
+                    accumulator = Some(cmd);
+                }
+            },
+            AST::WriteConstStr { mut bytes } => match cmd {
+                AST::WriteConst { out: other_out } => {
+                    bytes.push(other_out);
+                    accumulator = Some(AST::WriteConstStr { bytes });
+                    collapsed += 1;
+                }
+                AST::WriteConstStr { bytes: mut other_bytes } => {
+                    bytes.append(&mut other_bytes);
+                    accumulator = Some(AST::WriteConstStr { bytes });
+                    collapsed += 1;
+                }
+                _ => {
+                    cmds.push(AST::WriteConstStr { bytes });
+                    accumulator = Some(cmd);
+                }
+            },
+            AST::IfNonZero {
+                cond_dp_offset,
+                mut elements,
+                code_p,
+            } => match cmd {
+                // If the first branch ends where it started without touching the condition, the
+                // second one runs exactly when the first one did, so they can be one branch
+                AST::IfNonZero {
+                    cond_dp_offset: other_cdo,
+                    elements: mut other_elements,
+                    ..
+                } if other_cdo == cond_dp_offset && leaves_cell_alone(&elements, cond_dp_offset) => {
+                    elements.append(&mut other_elements);
+                    accumulator = Some(AST::IfNonZero {
+                        cond_dp_offset,
+                        elements,
+                        code_p,
+                    });
+                    collapsed += 1;
+                }
+                _ => {
+                    cmds.push(AST::IfNonZero {
+                        cond_dp_offset,
+                        elements,
+                        code_p,
+                    });
+                    accumulator = Some(cmd);
+                }
+            },
+            AST::ReadByte { .. } | AST::WriteByte { .. } | AST::WriteTransformed { .. } | AST::ResetTape => {
                 cmds.push(acc);
                 accumulator = Some(cmd);
             }
@@ -989,19 +1703,235 @@ fn collapse_consecutive(cmds: &mut Vec<AST>) -> usize {
     collapsed
 }
 
-fn sort_commands(cmds: &mut [AST]) -> usize {
+// data[s] += a, then data[t] += data[s] * m, then data[s] is overwritten: the add to data[s]
+// only mattered for what it added to data[t], so it can be data[t] += data[s] * m, then
+// data[t] += a * m, then the overwrite. The add to data[t] can then go with the others on that
+// cell; this happens in loop bodies which the simulation can't see into. It's only the same when
+// wrapping, since otherwise data[s] + a might not have fit.
+fn fuse_adds_into_combines(cmds: &mut Vec<AST>, policy: OverflowPolicy) -> usize {
+    if policy != OverflowPolicy::Wrap {
+        return 0;
+    }
+
+    let mut fused = 0;
+    let mut i = 0;
+
+    while i + 2 < cmds.len() {
+        let replacement = match (&cmds[i], &cmds[i + 1], &cmds[i + 2]) {
+            (
+                &AST::ModData {
+                    kind: DatamodKind::AddData { amount },
+                    dp_offset,
+                    code_p,
+                },
+                &AST::CombineData {
+                    source_dp_offset,
+                    target_dp_offset,
+                    source_amt_mult,
+                    ..
+                },
+                third,
+            ) if source_dp_offset == dp_offset && target_dp_offset != dp_offset && overwrites(third, dp_offset) => {
+                let mut replacement = vec![cmds[i + 1].clone()];
+                let added = amount.wrapping_mul(source_amt_mult);
+                if added != 0 {
+                    replacement.push(AST::ModData {
+                        kind: DatamodKind::AddData { amount: added },
+                        dp_offset: target_dp_offset,
+                        code_p,
+                    });
+                }
+                Some(replacement)
+            }
+            _ => None,
+        };
+
+        match replacement {
+            Some(replacement) => {
+                log!("Fused an add into the combine after it");
+                cmds.splice(i..i + 2, replacement);
+                fused += 1;
+            }
+            None => i += 1,
+        }
+    }
+
+    fused
+}
+
+// The usual way to swap data[a] and data[b] through a temporary, t[-]a[t+a-]b[a+b-]t[b+t-], comes
+// out of const_loop_remove as three moves, each in a branch since the loop might not have run. But
+// each branch's condition is the cell the one before it moved into, so all three cells are touched
+// whether or not the branches run, and the moves do nothing if the cell was already zero; so the
+// branches can go, leaving straight-line code. Clearing t at the end can go too if nothing reads
+// it before it's overwritten. This doesn't need t to start at zero, any more than the branches did.
+fn fold_swaps(cmds: &mut Vec<AST>) -> usize {
+    // The cells of If data[from] { data[to] += data[from]; data[from] = 0 }, and where it came from
+    fn as_move(cmd: &AST) -> Option<(isize, isize, SourcePos)> {
+        match cmd {
+            AST::IfNonZero {
+                cond_dp_offset,
+                elements,
+                code_p,
+            } => match elements[..] {
+                [AST::CombineData {
+                    source_dp_offset,
+                    target_dp_offset,
+                    source_amt_mult: 1,
+                    ..
+                }, AST::ModData {
+                    kind: DatamodKind::SetData { amount: 0 },
+                    dp_offset,
+                    ..
+                }] if source_dp_offset == *cond_dp_offset && dp_offset == *cond_dp_offset && target_dp_offset != dp_offset => {
+                    Some((source_dp_offset, target_dp_offset, *code_p))
+                }
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    let mut folded = 0;
+    let mut i = 0;
+
+    while i + 2 < cmds.len() {
+        // a into t, b into a, t into b
+        let (a, b, t, at) = match (as_move(&cmds[i]), as_move(&cmds[i + 1]), as_move(&cmds[i + 2])) {
+            (Some((a, t, at_a)), Some((b, a2, at_b)), Some((t2, b2, at_t))) if a2 == a && t2 == t && b2 == b && b != t => {
+                (a, b, t, [at_a, at_b, at_t])
+            }
+            _ => {
+                i += 1;
+                continue;
+            }
+        };
+
+        let mut swap = Vec::with_capacity(6);
+        for (&(from, to), &code_p) in [(a, t), (b, a), (t, b)].iter().zip(&at) {
+            swap.push(AST::CombineData {
+                source_dp_offset: from,
+                target_dp_offset: to,
+                source_amt_mult: 1,
+                code_p,
+            });
+            swap.push(AST::ModData {
+                kind: DatamodKind::SetData { amount: 0 },
+                dp_offset: from,
+                code_p,
+            });
+        }
+        if is_dead_after(&cmds[i + 3..], t) {
+            swap.pop();
+        }
+
+        log!("Folded a swap of {} and {} through {}", a, b, t);
+        let len = swap.len();
+        cmds.splice(i..i + 3, swap);
+        folded += 1;
+        i += len;
+    }
+
+    folded
+}
+
+// Whether data[dp + dp_offset] is overwritten by these commands before anything could look at
+// it; if anything loses track of dp, or the commands run out first, it might not be
+fn is_dead_after(cmds: &[AST], mut dp_offset: isize) -> bool {
+    for cmd in cmds {
+        if overwrites(cmd, dp_offset) {
+            return true;
+        }
+        match track_usage(cmd) {
+            DataUsage::DataTracked {
+                dp_shift,
+                data_mods,
+                data_reads,
+            } if !data_mods.contains(&dp_offset) && !data_reads.contains(&dp_offset) => dp_offset -= dp_shift,
+            _ => return false,
+        }
+    }
+
+    false
+}
+
+// Turns each add to a cell, then a write of it, into a WriteTransformed, if the cell is overwritten
+// before anything else looks at it; gives back how many it fused
+fn fuse_writes(cmds: &mut Vec<AST>) -> usize {
+    let mut fused = 0;
+    let mut i = 0;
+
+    while i < cmds.len() {
+        match cmds[i..] {
+            [AST::ModData {
+                kind: DatamodKind::AddData { amount },
+                dp_offset,
+                ..
+            }, AST::WriteByte {
+                dp_offset: write_offset,
+                code_p,
+            }, ref rest @ ..]
+                if dp_offset == write_offset && is_dead_after(rest, dp_offset) =>
+            {
+                cmds[i] = AST::WriteTransformed {
+                    dp_offset,
+                    add: amount,
+                    code_p,
+                };
+                cmds.remove(i + 1);
+                fused += 1;
+            }
+            [AST::Loop { ref mut elements, .. }, ..] | [AST::IfNonZero { ref mut elements, .. }, ..] => {
+                fused += fuse_writes(elements);
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+
+    fused
+}
+
+// Whether the command sets data[dp + dp_offset] without looking at what was there
+fn overwrites(cmd: &AST, dp_offset: isize) -> bool {
+    match *cmd {
+        AST::ModData {
+            kind: DatamodKind::SetData { .. },
+            dp_offset: set_offset,
+            ..
+        } => set_offset == dp_offset,
+        AST::ReadByte {
+            dp_offset: read_offset, ..
+        } => read_offset == dp_offset,
+        _ => false,
+    }
+}
+
+// Whether the block ends with dp where it started, without having modified data[dp + dp_offset]
+pub(crate) fn leaves_cell_alone(cmds: &[AST], dp_offset: isize) -> bool {
+    match track_block_usage(cmds) {
+        DataUsage::DataTracked {
+            dp_shift: 0, data_mods, ..
+        } => !data_mods.contains(&dp_offset),
+        _ => false,
+    }
+}
+
+fn sort_commands(cmds: &mut [AST], policy: OverflowPolicy) -> usize {
     for cmd in cmds.iter_mut() {
         if let AST::Loop {
             ref mut elements,
             cond_dp_offset: _,
             known_to_be_nontrivial: _,
+            ..
         }
         | AST::IfNonZero {
             ref mut elements,
             cond_dp_offset: _,
+            ..
         } = cmd
         {
-            sort_commands(elements);
+            sort_commands(elements, policy);
         }
     }
 
@@ -1009,7 +1939,7 @@ fn sort_commands(cmds: &mut [AST]) -> usize {
 
     let mut total_swaps = 0;
     while slice_len >= 2 {
-        let local_swaps = sort_commands_step(&mut cmds[0..slice_len]);
+        let local_swaps = sort_commands_step(&mut cmds[0..slice_len], policy);
         if local_swaps == 0 {
             return total_swaps;
         }
@@ -1022,7 +1952,7 @@ fn sort_commands(cmds: &mut [AST]) -> usize {
 
 // This is essentially one iteration of a bubblesort; because the "swap" actually alters the
 // elements it's hard to be sure that something faster would still work
-fn sort_commands_step(cmds: &mut [AST]) -> usize {
+fn sort_commands_step(cmds: &mut [AST], policy: OverflowPolicy) -> usize {
     if cmds.len() < 2 {
         return 0;
     }
@@ -1032,31 +1962,43 @@ fn sort_commands_step(cmds: &mut [AST]) -> usize {
     // First read/write, then infiniteLoop, then modData, then addData, then shiftPtr
     // Loop is considered unswappable for now
     // Note that the order of IO operations is not swappable
-    fn maybe_swap(first: &mut AST, second: &mut AST) -> usize {
+    fn maybe_swap(first: &mut AST, second: &mut AST, policy: OverflowPolicy) -> usize {
+        if overflow_pins_order(first, second, policy) {
+            return 0;
+        }
+
         let mut swap = false;
         match first {
             AST::WriteByte { .. }
+            | AST::WriteTransformed { .. }
             | AST::WriteConst { .. }
+            | AST::WriteConstStr { .. }
             | AST::ReadByte { .. }
             | AST::Loop { .. }
             | AST::ShiftLoop { .. }
             | AST::IfNonZero { .. }
             | AST::AssertEquals { .. }
-            | AST::InfiniteLoop => {}
-            AST::ModData { kind: _, dp_offset } => match second {
+            | AST::InfiniteLoop
+            | AST::ResetTape => {}
+            AST::ModData { kind: _, dp_offset, .. } => match second {
                 AST::InfiniteLoop => swap = true,
                 AST::ModData {
                     kind: _,
                     dp_offset: second_offset,
-                } => {
-                    if dp_offset > second_offset {
-                        swap = true;
-                    }
+                    ..
+                } if *dp_offset > *second_offset => {
+                    swap = true;
+                }
+                AST::ReadByte { dp_offset: io_offset, .. } if io_offset != dp_offset => {
+                    swap = true;
                 }
-                AST::ReadByte { dp_offset: io_offset } if io_offset != dp_offset => {
+                AST::WriteByte { dp_offset: io_offset, .. } | AST::WriteTransformed { dp_offset: io_offset, .. }
+                    if io_offset != dp_offset =>
+                {
                     swap = true;
                 }
-                AST::WriteByte { dp_offset: io_offset } if io_offset != dp_offset => {
+                // constant writes don't touch the tape at all
+                AST::WriteConst { .. } | AST::WriteConstStr { .. } => {
                     swap = true;
                 }
                 _ => {}
@@ -1065,12 +2007,14 @@ fn sort_commands_step(cmds: &mut [AST]) -> usize {
                 source_dp_offset,
                 target_dp_offset,
                 source_amt_mult: _,
+                ..
             } => match second {
                 AST::InfiniteLoop => swap = true,
                 AST::CombineData {
                     source_dp_offset: other_sdo,
                     target_dp_offset: other_tdo,
                     source_amt_mult: _,
+                    ..
                 } => {
                     if source_dp_offset == other_sdo {
                         // pretty simple, if they're the same source, order doesn't matter; subsort by target
@@ -1089,23 +2033,27 @@ fn sort_commands_step(cmds: &mut [AST]) -> usize {
                         }
                     }
                 }
-                AST::ModData { kind: _, dp_offset } => {
-                    // we want complex things after simple things (I guess?) but not everything swaps easily
-                    // basically A += B; C += x can be swapped so long as C and B aren't pointing to the same place
-                    if source_dp_offset != dp_offset {
-                        swap = true;
-                    }
+                // we want complex things after simple things (I guess?) but not everything swaps easily
+                // basically A += B; C += x can be swapped so long as C and B aren't pointing to the same place
+                AST::ModData { kind: _, dp_offset, .. } if source_dp_offset != dp_offset => {
+                    swap = true;
+                }
+                AST::ReadByte { dp_offset: io_offset, .. } if io_offset != source_dp_offset && io_offset != target_dp_offset => {
+                    swap = true;
                 }
-                AST::ReadByte { dp_offset: io_offset } if io_offset != source_dp_offset && io_offset != target_dp_offset => {
+                AST::WriteByte { dp_offset: io_offset, .. } | AST::WriteTransformed { dp_offset: io_offset, .. }
+                    if io_offset != source_dp_offset && io_offset != target_dp_offset =>
+                {
                     swap = true;
                 }
-                AST::WriteByte { dp_offset: io_offset } if io_offset != source_dp_offset && io_offset != target_dp_offset => {
+                AST::WriteConst { .. } | AST::WriteConstStr { .. } => {
                     swap = true;
                 }
                 _ => {}
             },
-            AST::ShiftDataPtr { amount: shift_amount } => {
-                if !matches!(second, AST::ShiftDataPtr {..}) {
+            // After a reset, dp is wherever the reset put it, so a shift can't be pushed past one
+            AST::ShiftDataPtr { amount: shift_amount, .. } => {
+                if !matches!(second, AST::ShiftDataPtr { .. }) && !resets_tape(second) {
                     shift_command(second, *shift_amount);
                     swap = true;
                 }
@@ -1113,7 +2061,7 @@ fn sort_commands_step(cmds: &mut [AST]) -> usize {
         }
 
         if swap {
-            std::mem::swap(first, second);
+            core::mem::swap(first, second);
             1
         } else {
             0
@@ -1122,7 +2070,7 @@ fn sort_commands_step(cmds: &mut [AST]) -> usize {
 
     for i in 0..cmds.len() - 1 {
         if let [ref mut a, ref mut b] = cmds[i..i + 2] {
-            changed += maybe_swap(a, b);
+            changed += maybe_swap(a, b, policy);
         } else {
             // The slice has length two, but the rust compiler doesn't (yet) know how to know that
             unreachable!()
@@ -1138,6 +2086,7 @@ fn shift_command(cmd: &mut AST, dp_shift: isize) {
             ref mut cond_dp_offset,
             ref mut elements,
             known_to_be_nontrivial: _,
+            ..
         } => {
             *cond_dp_offset += dp_shift;
             elements.iter_mut().for_each(|e| shift_command(e, dp_shift));
@@ -1147,12 +2096,13 @@ fn shift_command(cmd: &mut AST, dp_shift: isize) {
         } => {
             *cond_dp_offset += dp_shift;
         }
-        AST::ShiftDataPtr { amount: _ } => {
+        AST::ShiftDataPtr { amount: _, .. } => {
             // It's fine, no need to shift, they commute
         }
         AST::ModData {
             kind: _,
             ref mut dp_offset,
+            ..
         } => {
             *dp_offset += dp_shift;
         }
@@ -1160,65 +2110,194 @@ fn shift_command(cmd: &mut AST, dp_shift: isize) {
             source_dp_offset,
             target_dp_offset,
             source_amt_mult: _,
+            ..
         } => {
             *source_dp_offset += dp_shift;
             *target_dp_offset += dp_shift;
         }
-        AST::ReadByte { dp_offset } => {
+        AST::ReadByte { dp_offset, .. } => {
             *dp_offset += dp_shift;
         }
-        AST::WriteByte { dp_offset } => {
+        AST::WriteByte { dp_offset, .. } | AST::WriteTransformed { dp_offset, .. } => {
             *dp_offset += dp_shift;
         }
         AST::IfNonZero {
             ref mut cond_dp_offset,
             ref mut elements,
+            ..
         } => {
             *cond_dp_offset += dp_shift;
             elements.iter_mut().for_each(|e| shift_command(e, dp_shift));
         }
-        AST::WriteConst { .. } => {
+        AST::WriteConst { .. } | AST::WriteConstStr { .. } => {
             // It's fine, it's done
         }
         AST::InfiniteLoop => {
             // It's fine, it's done
         }
-        AST::AssertEquals { ref mut dp_offset, val: _ } => {
+        AST::ResetTape => {
+            unreachable!("Shifts are never moved past a tape reset")
+        }
+        AST::AssertEquals {
+            ref mut dp_offset, val: _, ..
+        } => {
             *dp_offset += dp_shift;
         }
     }
 }
 
-mod data_usage {
-    use std::collections::HashSet;
+// Whether the command resets the tape, or might inside a loop or branch
+fn resets_tape(cmd: &AST) -> bool {
+    match cmd {
+        AST::ResetTape => true,
+        AST::Loop { elements, .. } | AST::IfNonZero { elements, .. } => elements.iter().any(resets_tape),
+        _ => false,
+    }
+}
 
-    pub struct DataUsageTracker(DataUsage);
+mod affine {
+    use crate::optimized::{DatamodKind, AST};
+    use alloc::collections::BTreeMap;
 
-    pub enum DataUsage {
-        // Data pointer was lost, usually due to control flow confusion, so
-        // no detailed results can be shown. Note that infinite loops / OOBs
-        // don't "use data" or "lose dp"
-        DpLost,
-        DataTracked { dp_shift: isize, data_mods: HashSet<isize> },
+    /// constant + sum(coeffs[j] * data[j]), where data is the tape as it was when we started
+    /// simulating. Everything is mod 256, same as the tape.
+    #[derive(Clone, Eq, PartialEq, Debug)]
+    pub struct Affine {
+        pub constant: u8,
+        pub coeffs: BTreeMap<isize, u8>,
     }
 
-    impl DataUsageTracker {
-        pub fn new() -> Self {
-            DataUsageTracker(DataUsage::DataTracked {
-                dp_shift: 0,
-                data_mods: HashSet::new(),
-            })
+    impl Affine {
+        fn cell(dp_offset: isize) -> Self {
+            let mut coeffs = BTreeMap::new();
+            coeffs.insert(dp_offset, 1);
+            Affine { constant: 0, coeffs }
         }
 
-        pub fn shift(&mut self, shift_amount: isize) {
-            match &mut self.0 {
-                DataUsage::DpLost => {}
-                DataUsage::DataTracked {
-                    ref mut dp_shift,
-                    ref mut data_mods,
-                } => {
+        fn constant(constant: u8) -> Self {
+            Affine {
+                constant,
+                coeffs: BTreeMap::new(),
+            }
+        }
+
+        // self += mult * other
+        fn add_scaled(&mut self, other: &Affine, mult: u8) {
+            self.constant = u8::wrapping_add(self.constant, u8::wrapping_mul(other.constant, mult));
+            for (j, c) in other.coeffs.iter() {
+                let coeff = self.coeffs.entry(*j).or_insert(0);
+                *coeff = u8::wrapping_add(*coeff, u8::wrapping_mul(*c, mult));
+                if *coeff == 0 {
+                    self.coeffs.remove(j);
+                }
+            }
+        }
+
+        pub fn minus(&self, other: &Affine) -> Affine {
+            let mut out = self.clone();
+            out.add_scaled(other, u8::MAX);
+            out
+        }
+    }
+
+    /// Symbolically runs straight-line arithmetic, keeping every cell as an Affine function of
+    /// the starting tape. Cells which haven't been touched are just themselves.
+    #[derive(Clone, Debug)]
+    pub struct AffineState(BTreeMap<isize, Affine>);
+
+    impl AffineState {
+        pub fn new() -> Self {
+            AffineState(BTreeMap::new())
+        }
+
+        pub fn get(&self, dp_offset: isize) -> Affine {
+            self.0.get(&dp_offset).cloned().unwrap_or_else(|| Affine::cell(dp_offset))
+        }
+
+        pub fn cells(&self) -> impl Iterator<Item = isize> + '_ {
+            self.0.keys().copied()
+        }
+
+        /// Run the commands; fails (partway through) if any of them isn't ModData or CombineData,
+        /// or a branch which can be treated as if it were just its contents
+        pub fn run(&mut self, cmds: &[AST]) -> Result<(), ()> {
+            for cmd in cmds {
+                match cmd {
+                    AST::ModData {
+                        kind: DatamodKind::SetData { amount },
+                        dp_offset,
+                        ..
+                    } => {
+                        self.0.insert(*dp_offset, Affine::constant(*amount));
+                    }
+                    AST::ModData {
+                        kind: DatamodKind::AddData { amount },
+                        dp_offset,
+                        ..
+                    } => {
+                        let mut val = self.get(*dp_offset);
+                        val.add_scaled(&Affine::constant(1), *amount);
+                        self.0.insert(*dp_offset, val);
+                    }
+                    AST::CombineData {
+                        source_dp_offset,
+                        target_dp_offset,
+                        source_amt_mult,
+                        ..
+                    } => {
+                        let source = self.get(*source_dp_offset);
+                        let mut val = self.get(*target_dp_offset);
+                        val.add_scaled(&source, *source_amt_mult);
+                        self.0.insert(*target_dp_offset, val);
+                    }
+                    // A folded inner loop; skipping it when data[cond] is zero is the same as running it
+                    AST::IfNonZero {
+                        cond_dp_offset, elements, ..
+                    } if super::is_noop_when_zero(elements, *cond_dp_offset) => {
+                        self.run(elements)?;
+                    }
+                    _ => return Err(()),
+                }
+            }
+
+            Ok(())
+        }
+    }
+}
+
+mod data_usage {
+    use alloc::collections::BTreeSet;
+
+    pub struct DataUsageTracker(DataUsage);
+
+    pub enum DataUsage {
+        // Data pointer was lost, usually due to control flow confusion, so
+        // no detailed results can be shown. Note that infinite loops / OOBs
+        // don't "use data" or "lose dp"
+        DpLost,
+        // data_mods are offsets relative to the dp before the tracked command ran; so are
+        // data_reads, which are cells only read from (e.g. the source of a CombineData)
+        DataTracked {
+            dp_shift: isize,
+            data_mods: BTreeSet<isize>,
+            data_reads: BTreeSet<isize>,
+        },
+    }
+
+    impl DataUsageTracker {
+        pub fn new() -> Self {
+            DataUsageTracker(DataUsage::DataTracked {
+                dp_shift: 0,
+                data_mods: BTreeSet::new(),
+                data_reads: BTreeSet::new(),
+            })
+        }
+
+        pub fn shift(&mut self, shift_amount: isize) {
+            match &mut self.0 {
+                DataUsage::DpLost => {}
+                DataUsage::DataTracked { ref mut dp_shift, .. } => {
                     *dp_shift += shift_amount;
-                    *data_mods = data_mods.iter().map(|dp| dp + shift_amount).collect()
                 }
             }
         }
@@ -1227,15 +2306,31 @@ mod data_usage {
             self.0
         }
 
-        // This is data (potentially) modified
+        // This is data (potentially) modified; dp_offset is relative to the current dp, but
+        // is stored relative to the dp at the start of tracking
         pub fn data_used(&mut self, dp_offset: isize) {
             match &mut self.0 {
                 DataUsage::DpLost => {}
                 DataUsage::DataTracked {
-                    dp_shift: _,
+                    dp_shift,
                     ref mut data_mods,
+                    ..
+                } => {
+                    data_mods.insert(*dp_shift + dp_offset);
+                }
+            }
+        }
+
+        // This is data which is read but not modified; same convention as data_used
+        pub fn data_read(&mut self, dp_offset: isize) {
+            match &mut self.0 {
+                DataUsage::DpLost => {}
+                DataUsage::DataTracked {
+                    dp_shift,
+                    ref mut data_reads,
+                    ..
                 } => {
-                    data_mods.insert(dp_offset);
+                    data_reads.insert(*dp_shift + dp_offset);
                 }
             }
         }
@@ -1247,6 +2342,11 @@ mod data_usage {
 }
 
 fn track_usage(cmd: &AST) -> DataUsage {
+    track_block_usage(core::slice::from_ref(cmd))
+}
+
+// Usage of a sequence of commands run one after another, e.g. the interior of a branch
+fn track_block_usage(cmds: &[AST]) -> DataUsage {
     fn track_usage_step(cmd: &AST, tracker: &mut DataUsageTracker) {
         match cmd {
             // For a loop or a branch; if there is no net dp shift inside
@@ -1255,12 +2355,14 @@ fn track_usage(cmd: &AST) -> DataUsage {
                 known_to_be_nontrivial: _,
                 cond_dp_offset,
                 ref elements,
+                ..
             }
             | AST::IfNonZero {
                 cond_dp_offset,
                 ref elements,
+                ..
             } => {
-                tracker.data_used(*cond_dp_offset);
+                tracker.data_read(*cond_dp_offset);
 
                 let mut inside_tracker = DataUsageTracker::new();
                 for elt in elements {
@@ -1271,7 +2373,11 @@ fn track_usage(cmd: &AST) -> DataUsage {
                     DataUsage::DpLost => {
                         tracker.lose_dp();
                     }
-                    DataUsage::DataTracked { dp_shift, data_mods } => {
+                    DataUsage::DataTracked {
+                        dp_shift,
+                        data_mods,
+                        data_reads,
+                    } => {
                         if dp_shift != 0 {
                             tracker.lose_dp();
                         }
@@ -1279,42 +2385,912 @@ fn track_usage(cmd: &AST) -> DataUsage {
                         for dm in data_mods {
                             tracker.data_used(dm);
                         }
+                        for dr in data_reads {
+                            tracker.data_read(dr);
+                        }
                     }
                 }
             }
             AST::ShiftLoop { .. } => {
                 tracker.lose_dp();
             }
-            AST::ShiftDataPtr { amount } => {
+            AST::ShiftDataPtr { amount, .. } => {
                 tracker.shift(*amount);
             }
-            AST::ModData { kind: _, dp_offset } => {
+            AST::ModData { kind: _, dp_offset, .. } => {
                 tracker.data_used(*dp_offset);
             }
             AST::CombineData {
-                source_dp_offset: _,
+                source_dp_offset,
                 target_dp_offset,
                 source_amt_mult: _,
+                ..
             } => {
+                tracker.data_read(*source_dp_offset);
                 tracker.data_used(*target_dp_offset);
             }
-            AST::ReadByte { dp_offset } => {
+            AST::ReadByte { dp_offset, .. } => {
                 tracker.data_used(*dp_offset);
             }
-            AST::WriteByte { dp_offset } => {
-                tracker.data_used(*dp_offset);
+            AST::WriteByte { dp_offset, .. } | AST::WriteTransformed { dp_offset, .. } => {
+                tracker.data_read(*dp_offset);
             }
             AST::InfiniteLoop => {}
-            AST::WriteConst { .. } => {}
-            AST::AssertEquals { dp_offset, val: _ } => {
-                tracker.data_used(*dp_offset);
+            // Everything after this is relative to the start of the tape, not to where dp was
+            AST::ResetTape => {
+                tracker.lose_dp();
+            }
+            AST::WriteConst { .. } | AST::WriteConstStr { .. } => {}
+            AST::AssertEquals { dp_offset, val: _, .. } => {
+                tracker.data_read(*dp_offset);
             }
         }
     }
 
     let mut tracker = DataUsageTracker::new();
 
-    track_usage_step(cmd, &mut tracker);
+    for cmd in cmds {
+        track_usage_step(cmd, &mut tracker);
+    }
 
     tracker.complete()
 }
+
+/// The lowest and highest offsets (relative to the starting dp) the commands could touch, or
+/// None if the data pointer moves by an amount that depends on the data, e.g. in a ShiftLoop.
+/// This counts everything which might be touched, including inside branches which never run.
+/// Commands which don't touch any cells at all come back as (0, 0).
+pub(crate) fn static_bounds(code: &[AST]) -> Option<OffsetBounds> {
+    match track_block_usage(code) {
+        DataUsage::DpLost => None,
+        DataUsage::DataTracked { data_mods, data_reads, .. } => {
+            let touched = || data_mods.iter().chain(data_reads.iter()).copied();
+            match (touched().min(), touched().max()) {
+                (Some(min), Some(max)) => Some((min, max)),
+                _ => Some((0, 0)),
+            }
+        }
+    }
+}
+
+// The first cell off a tape of the given size which the program is certain to touch (if it gets
+// that far, i.e. the loops before it finish). Unlike static_bounds this only looks at the top
+// level, since what's inside a loop or branch might never run, and it stops looking as soon as a
+// data-dependent shift means it can't know where dp is any more.
+pub(crate) fn first_certain_oob(code: &[AST], tape_size: usize) -> Option<isize> {
+    let off_tape = |cell: isize| cell < 0 || cell as usize >= tape_size;
+
+    let mut dp: isize = 0;
+
+    for cmd in code {
+        let touched: &[isize] = match cmd {
+            AST::ModData { dp_offset, .. }
+            | AST::ReadByte { dp_offset, .. }
+            | AST::WriteByte { dp_offset, .. }
+            | AST::WriteTransformed { dp_offset, .. }
+            | AST::AssertEquals { dp_offset, .. } => &[*dp_offset],
+            AST::CombineData {
+                source_dp_offset,
+                target_dp_offset,
+                ..
+            } => &[*source_dp_offset, *target_dp_offset],
+            AST::Loop { cond_dp_offset, .. } | AST::IfNonZero { cond_dp_offset, .. } | AST::ShiftLoop { cond_dp_offset, .. } => {
+                &[*cond_dp_offset]
+            }
+            AST::ShiftDataPtr { amount, .. } => {
+                dp += amount;
+                &[]
+            }
+            AST::WriteConst { .. } | AST::WriteConstStr { .. } => &[],
+            AST::ResetTape => {
+                dp = 0;
+                &[]
+            }
+            AST::InfiniteLoop => return None,
+        };
+
+        if let Some(cell) = touched.iter().map(|offset| dp + offset).find(|cell| off_tape(*cell)) {
+            return Some(cell);
+        }
+
+        // Whatever control flow there is only counts if it always leaves dp where it was
+        let keeps_dp = match cmd {
+            AST::Loop { .. } | AST::IfNonZero { .. } => matches!(track_usage(cmd), DataUsage::DataTracked { dp_shift: 0, .. }),
+            AST::ShiftLoop { .. } => false,
+            _ => true,
+        };
+
+        if !keeps_dp {
+            return None;
+        }
+    }
+
+    None
+}
+
+// How many commands optimize_with_input runs ahead of time before leaving the rest for runtime;
+// without a limit, a program which never finishes would never finish optimizing either
+const RUN_AHEAD_STEPS: usize = 1 << 20;
+
+/// The same as optimize, but with the program's input known ahead of time: the program is run
+/// as far as it can go on that input, and what it ran is replaced by its effect, which is the
+/// output it wrote (as constants). If it finished, that's all; otherwise the effect also sets
+/// every cell it left nonzero, and shifts dp to where it left off, and the rest of the program,
+/// from wherever it got stuck, is optimized as usual.
+///
+/// It gets stuck when it runs out of input, touches a cell left of where it started, overflows
+/// (under OverflowPolicy::Error), fails an assert, resets the tape, or runs too long. Whatever it
+/// reads after that comes from the real input, so this gives back how many bytes of the known
+/// input it used; the code should be run on the rest of them, then whatever comes after. Like
+/// the other passes, this assumes the tape is as long as it needs to be on the right.
+///
+/// Running ahead starts from a tape of zeros, so with OptPasses::blank_tape off, it's skipped:
+/// this is just optimize, and uses none of the input.
+pub(crate) fn optimize_with_input(
+    cmds: &mut Vec<AST>,
+    policy: OverflowPolicy,
+    input: &[u8],
+    max_passes: Option<usize>,
+    passes: OptPasses,
+) -> usize {
+    // Optimizing first makes the program quicker to run ahead, and can't change which reads happen when
+    optimize(cmds, policy, max_passes, passes);
+    if !passes.blank_tape {
+        return 0;
+    }
+    let used = run_ahead(cmds, policy, input);
+    optimize(cmds, policy, max_passes, passes);
+    used
+}
+
+// A loop or branch body being run ahead
+#[derive(Copy, Clone)]
+struct Frame<'a> {
+    body: &'a [AST],
+    // The next command to run
+    ip: usize,
+    // The condition of the loop this is the body of and where the loop came from, or None for a
+    // branch (or the whole program)
+    looping: Option<(isize, SourcePos)>,
+}
+
+struct RunAhead<'a> {
+    cells: BTreeMap<isize, u8>,
+    dp: isize,
+    input: &'a [u8],
+    used: usize,
+    output: Vec<u8>,
+    policy: OverflowPolicy,
+}
+
+impl<'a> RunAhead<'a> {
+    // The cell at this offset from dp, or None if it's left of the start, where running stops
+    fn cell(&self, offset: isize) -> Option<u8> {
+        let cell = self.dp + offset;
+        if cell < 0 {
+            None
+        } else {
+            Some(self.cells.get(&cell).copied().unwrap_or(0))
+        }
+    }
+
+    fn set(&mut self, offset: isize, val: u8) {
+        self.cells.insert(self.dp + offset, val);
+    }
+
+    // Runs one command, and gives back the body to go into, if it's a loop or branch which runs.
+    // None means it's stuck, and nothing has been done; except that a shift loop may have moved
+    // part of the way, which is fine, since it can carry on from there.
+    fn run<'b>(&mut self, cmd: &'b AST) -> Option<Option<Frame<'b>>> {
+        match *cmd {
+            AST::ModData { kind, dp_offset, .. } => {
+                let old = self.cell(dp_offset)?;
+                let new = match kind {
+                    DatamodKind::SetData { amount } => amount,
+                    DatamodKind::AddData { amount } => self.policy.add(old, amount)?,
+                };
+                self.set(dp_offset, new);
+            }
+            AST::CombineData {
+                source_dp_offset,
+                target_dp_offset,
+                source_amt_mult,
+                ..
+            } => {
+                let new = self
+                    .policy
+                    .add_product(self.cell(target_dp_offset)?, self.cell(source_dp_offset)?, source_amt_mult)?;
+                self.set(target_dp_offset, new);
+            }
+            AST::ShiftDataPtr { amount, .. } => self.dp += amount,
+            AST::ReadByte { dp_offset, .. } => {
+                self.cell(dp_offset)?;
+                let byte = *self.input.get(self.used)?;
+                self.used += 1;
+                self.set(dp_offset, byte);
+            }
+            AST::WriteByte { dp_offset, .. } => {
+                let byte = self.cell(dp_offset)?;
+                self.output.push(byte);
+            }
+            AST::WriteTransformed { dp_offset, add, .. } => {
+                let byte = self.policy.add(self.cell(dp_offset)?, add)?;
+                self.output.push(byte);
+            }
+            AST::WriteConst { out } => self.output.push(out),
+            AST::WriteConstStr { ref bytes } => self.output.extend_from_slice(bytes),
+            AST::AssertEquals { dp_offset, val, .. } => {
+                if self.cell(dp_offset)? != val {
+                    return None;
+                }
+            }
+            AST::Loop {
+                cond_dp_offset,
+                ref elements,
+                code_p,
+                ..
+            } => {
+                if self.cell(cond_dp_offset)? != 0 {
+                    return Some(Some(Frame {
+                        body: elements,
+                        ip: 0,
+                        looping: Some((cond_dp_offset, code_p)),
+                    }));
+                }
+            }
+            AST::IfNonZero {
+                cond_dp_offset,
+                ref elements,
+                ..
+            } => {
+                if self.cell(cond_dp_offset)? != 0 {
+                    return Some(Some(Frame {
+                        body: elements,
+                        ip: 0,
+                        looping: None,
+                    }));
+                }
+            }
+            AST::ShiftLoop {
+                cond_dp_offset, dp_shift, ..
+            } => {
+                while self.cell(cond_dp_offset)? != 0 {
+                    if dp_shift == 0 {
+                        return None;
+                    }
+                    self.dp += dp_shift;
+                }
+            }
+            AST::InfiniteLoop | AST::ResetTape => return None,
+        }
+
+        Some(None)
+    }
+}
+
+// Does the running ahead for optimize_with_input, and gives back how much input it used
+fn run_ahead(cmds: &mut Vec<AST>, policy: OverflowPolicy, input: &[u8]) -> usize {
+    let mut state = RunAhead {
+        cells: BTreeMap::new(),
+        dp: 0,
+        input,
+        used: 0,
+        output: Vec::new(),
+        policy,
+    };
+
+    let mut frames = vec![Frame {
+        body: cmds,
+        ip: 0,
+        looping: None,
+    }];
+    let mut steps = 0;
+
+    // Whether the program ran to the end, rather than getting stuck
+    let finished = loop {
+        if steps == RUN_AHEAD_STEPS {
+            break false;
+        }
+        steps += 1;
+
+        let top = frames.len() - 1;
+        let frame = frames[top];
+
+        if frame.ip < frame.body.len() {
+            match state.run(&frame.body[frame.ip]) {
+                Some(entered) => {
+                    frames[top].ip += 1;
+                    frames.extend(entered);
+                }
+                None => break false,
+            }
+        } else {
+            match frame.looping {
+                None if top == 0 => break true,
+                None => {
+                    frames.pop();
+                }
+                Some((cond_dp_offset, _)) => match state.cell(cond_dp_offset) {
+                    None => break false,
+                    Some(0) => {
+                        frames.pop();
+                    }
+                    Some(_) => frames[top].ip = 0,
+                },
+            }
+        }
+    };
+
+    log!("Ran ahead {} steps, using {} bytes of input", steps, state.used);
+
+    let mut specialized = Vec::new();
+    match state.output.len() {
+        0 => {}
+        1 => specialized.push(AST::WriteConst { out: state.output[0] }),
+        _ => specialized.push(AST::WriteConstStr {
+            bytes: core::mem::take(&mut state.output),
+        }),
+    }
+
+    if !finished {
+        for (&cell, &amount) in &state.cells {
+            if amount != 0 {
+                specialized.push(AST::ModData {
+                    kind: DatamodKind::SetData { amount },
+                    dp_offset: cell,
+                    code_p: SourcePos(None),
+                });
+            }
+        }
+        if state.dp != 0 {
+            specialized.push(AST::ShiftDataPtr {
+                amount: state.dp,
+                code_p: SourcePos(None),
+            });
+        }
+
+        // The rest of each body it's in, innermost first, with loops going round again after
+        for frame in frames.iter().rev() {
+            specialized.extend(frame.body[frame.ip..].iter().cloned());
+            if let Some((cond_dp_offset, code_p)) = frame.looping {
+                specialized.push(AST::Loop {
+                    known_to_be_nontrivial: false,
+                    cond_dp_offset,
+                    elements: frame.body.to_vec(),
+                    code_p,
+                });
+            }
+        }
+    }
+
+    *cmds = specialized;
+    state.used
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sim_state::DataState;
+
+    fn set(amount: u8, dp_offset: isize) -> AST {
+        AST::ModData {
+            kind: DatamodKind::SetData { amount },
+            dp_offset,
+            code_p: SourcePos(None),
+        }
+    }
+
+    fn read(dp_offset: isize) -> AST {
+        AST::ReadByte {
+            dp_offset,
+            code_p: SourcePos(None),
+        }
+    }
+
+    fn write(dp_offset: isize) -> AST {
+        AST::WriteByte {
+            dp_offset,
+            code_p: SourcePos(None),
+        }
+    }
+
+    fn shift(amount: isize) -> AST {
+        AST::ShiftDataPtr {
+            amount,
+            code_p: SourcePos(None),
+        }
+    }
+
+    #[test]
+    fn adds_overwritten_by_sets_are_dropped() {
+        let add = |amount, dp_offset| AST::ModData {
+            kind: DatamodKind::AddData { amount },
+            dp_offset,
+            code_p: SourcePos(None),
+        };
+        let passes = OptPasses {
+            sort: true,
+            collapse: true,
+            ..OptPasses::none()
+        };
+
+        // The add to cell 0 is pointless, wrapping or not; the add to cell 1 in between mustn't
+        // keep sorting from bringing it next to the set
+        for &policy in &[OverflowPolicy::Wrap, OverflowPolicy::Saturate] {
+            let mut cmds = vec![read(0), add(200, 0), add(1, 1), set(7, 0), write(0)];
+            optimize(&mut cmds, policy, None, passes);
+            assert!(!cmds.contains(&add(200, 0)), "{:?} {:?}", policy, cmds);
+            assert!(cmds.contains(&set(7, 0)) && cmds.contains(&add(1, 1)), "{:?} {:?}", policy, cmds);
+        }
+
+        // But when erroring, the add might fail first
+        let mut cmds = vec![read(0), add(200, 0), add(1, 1), set(7, 0)];
+        optimize(&mut cmds, OverflowPolicy::Error, None, passes);
+        assert!(cmds.contains(&add(200, 0)), "{:?}", cmds);
+    }
+
+    #[test]
+    fn loops_with_cancelling_shifts_are_const() {
+        // Without the collapse pass to fold them into offsets, the shifts are still in the body
+        let mut cmds = crate::optimized::parse(",[<>-]").unwrap();
+        const_loop_remove(&mut cmds, OverflowPolicy::Wrap);
+        assert_eq!(cmds, vec![read(0), set(0, 0)]);
+
+        // The offsets after a shift are from wherever it left dp
+        let mut cmds = crate::optimized::parse(",[>++<<+>-]").unwrap();
+        const_loop_remove(&mut cmds, OverflowPolicy::Wrap);
+        let combine = |target_dp_offset, source_amt_mult| AST::CombineData {
+            source_dp_offset: 0,
+            target_dp_offset,
+            source_amt_mult,
+            code_p: SourcePos(None),
+        };
+        assert_eq!(
+            cmds[1],
+            AST::IfNonZero {
+                cond_dp_offset: 0,
+                elements: vec![combine(-1, 1), combine(1, 2), set(0, 0)],
+                code_p: SourcePos(None)
+            }
+        );
+
+        // A body which moves dp for good is a scan, not a constant loop
+        let mut cmds = crate::optimized::parse(",[>-]").unwrap();
+        const_loop_remove(&mut cmds, OverflowPolicy::Wrap);
+        assert!(matches!(cmds[1], AST::Loop { .. }), "{:?}", cmds);
+    }
+
+    #[test]
+    fn merge_with_different_dp() {
+        use sim_state::SimState;
+
+        let mut state = SimState::new(DataState::Known(0), OverflowPolicy::Wrap);
+        state.set_data(0, DataState::Known(3));
+        state.set_data(1, DataState::Known(5));
+        state.set_data(2, DataState::Known(5));
+        state.set_data(3, DataState::Known(9));
+
+        let (mut branch, marker) = state.make_branch();
+        branch.shift_ptr(1);
+        branch.set_data(2, DataState::Known(5));
+
+        state.merge_divergent(branch, marker);
+
+        // Relative to dp, the branch sees [3] 5 5 5 0 where the original sees 0 [3] 5 5 9
+        assert_eq!(state.get_data(-1), DataState::Unknown);
+        assert_eq!(state.get_data(0), DataState::Unknown);
+        assert_eq!(state.get_data(1), DataState::Known(5));
+        assert_eq!(state.get_data(2), DataState::Known(5));
+        assert_eq!(state.get_data(3), DataState::Unknown);
+        assert_eq!(state.get_data(10), DataState::Known(0));
+    }
+
+    #[test]
+    fn merge_with_different_defaults() {
+        use sim_state::SimState;
+
+        let mut state = SimState::new(DataState::Known(0), OverflowPolicy::Wrap);
+        state.set_data(0, DataState::Known(3));
+        state.set_data(1, DataState::Known(4));
+        state.set_data(2, DataState::Known(5));
+
+        // The branch forgets everything it hasn't touched, and changes one cell
+        let (mut branch, marker) = state.make_branch();
+        branch.forget_default();
+        branch.set_data(1, DataState::Known(7));
+
+        state.merge_divergent(branch, marker);
+
+        assert_eq!(state.get_data(0), DataState::Known(3));
+        assert_eq!(state.get_data(1), DataState::Unknown);
+        assert_eq!(state.get_data(2), DataState::Known(5));
+        assert_eq!(state.get_data(10), DataState::Unknown);
+    }
+
+    #[test]
+    fn conditional_shift_keeps_agreeing_cells() {
+        // cells 1 and 2 are both 7, so after maybe shifting right by one, data[dp+1] is still 7;
+        // data[dp+2] is 7 or 0 depending on the branch, so it has to stay a real read
+        let mut cmds = vec![
+            set(7, 1),
+            set(7, 2),
+            read(0),
+            AST::IfNonZero {
+                cond_dp_offset: 0,
+                elements: vec![shift(1)],
+                code_p: SourcePos(None),
+            },
+            write(1),
+            write(2),
+        ];
+
+        run_simulation(&mut cmds, DataState::Known(0), OverflowPolicy::Wrap);
+
+        assert_eq!(cmds[4], AST::WriteConst { out: 7 });
+        assert_eq!(cmds[5], write(2));
+    }
+
+    #[test]
+    fn conditional_shift_keeps_cells_set_in_branch() {
+        // data[dp+2] is 7 if the branch isn't taken, and if it is, the branch sets the cell which
+        // will be data[dp+2] afterward to 7 too
+        let mut cmds = vec![
+            set(7, 1),
+            set(7, 2),
+            read(0),
+            AST::IfNonZero {
+                cond_dp_offset: 0,
+                elements: vec![set(7, 3), shift(1)],
+                code_p: SourcePos(None),
+            },
+            write(2),
+        ];
+
+        run_simulation(&mut cmds, DataState::Known(0), OverflowPolicy::Wrap);
+
+        assert_eq!(cmds[4], AST::WriteConst { out: 7 });
+    }
+
+    #[test]
+    fn conditional_shift_forgets_modified_cells() {
+        // the branch clobbers what will be data[dp+1] if it's taken
+        let mut cmds = vec![
+            set(7, 1),
+            set(7, 2),
+            read(0),
+            AST::IfNonZero {
+                cond_dp_offset: 0,
+                elements: vec![read(2), shift(1)],
+                code_p: SourcePos(None),
+            },
+            write(1),
+        ];
+
+        run_simulation(&mut cmds, DataState::Known(0), OverflowPolicy::Wrap);
+
+        assert_eq!(cmds[4], write(1));
+    }
+
+    #[test]
+    fn known_asserts_are_removed() {
+        let assert = |dp_offset, val| AST::AssertEquals {
+            dp_offset,
+            val,
+            code_p: SourcePos(None),
+        };
+        let mut cmds = vec![set(5, 1), assert(1, 5), read(2), assert(2, 0), assert(2, 0), write(2)];
+
+        run_simulation(&mut cmds, DataState::Known(0), OverflowPolicy::Wrap);
+
+        // The second assert on data[dp+2] only runs if the first one held
+        assert_eq!(cmds, vec![set(5, 1), read(2), assert(2, 0), AST::WriteConst { out: 0 }]);
+    }
+
+    #[test]
+    fn swaps_through_a_temporary_are_folded() {
+        use crate::io::{SliceInput, VecOutput};
+        use crate::vm::OptVM;
+
+        // What t[-]a[t+a-]b[a+b-]t[b+t-] leaves behind, without the t[-], for a = 0, b = 1, t = 2
+        let moved = |from, to| AST::IfNonZero {
+            cond_dp_offset: from,
+            elements: vec![
+                AST::CombineData {
+                    source_dp_offset: from,
+                    target_dp_offset: to,
+                    source_amt_mult: 1,
+                    code_p: SourcePos(None),
+                },
+                set(0, from),
+            ],
+            code_p: SourcePos(None),
+        };
+        let swap = |after: AST| {
+            vec![
+                read(0),
+                read(1),
+                read(2),
+                moved(0, 2),
+                moved(1, 0),
+                moved(2, 1),
+                after,
+                write(0),
+                write(1),
+                write(2),
+            ]
+        };
+        let run = |code: &[AST], input: &[u8]| {
+            let mut output = VecOutput::new();
+            OptVM::new(crate::optimized::compile_ast(code))
+                .run(&mut SliceInput::new(input), &mut output)
+                .unwrap();
+            output.into_bytes()
+        };
+
+        // Whether t is cleared at the end depends on whether anything sees it
+        for (after, clears_t) in [(read(2), false), (write(2), true)] {
+            let unfolded = swap(after);
+            let mut folded = unfolded.clone();
+            assert_eq!(fold_swaps(&mut folded), 1);
+            assert!(!folded.iter().any(|cmd| matches!(cmd, AST::IfNonZero { .. })));
+            assert_eq!(folded.contains(&set(0, 2)), clears_t);
+
+            for input in [
+                &[3, 4, 5, 6][..],
+                &[0, 4, 5, 6],
+                &[3, 0, 5, 6],
+                &[0, 0, 5, 6],
+                &[3, 4, 0, 6],
+                &[255, 1, 1, 6],
+            ] {
+                assert_eq!(run(&folded, input), run(&unfolded, input));
+            }
+        }
+
+        // Not a swap, since the last move doesn't go back into b
+        let mut not_swap = vec![moved(0, 2), moved(1, 0), moved(2, 3)];
+        assert_eq!(fold_swaps(&mut not_swap), 0);
+
+        // And the source, once the loops are folded
+        let code = crate::optimized_parse(",>,>,<<>>[-]<<[>>+<<-]>[<+>-]>[<+>-]<<.>.").unwrap();
+        assert!(!code.iter().any(|instr| matches!(instr, crate::CompiledInstr::JumpIfZero { .. })));
+    }
+
+    #[test]
+    fn adds_are_fused_into_combines() {
+        use crate::io::{SliceInput, VecOutput};
+        use crate::vm::OptVM;
+
+        // data[1] = data[1] + data[0] * 3, with 200 added to data[0] first, then data[0] cleared
+        let unfused = vec![
+            read(0),
+            read(1),
+            AST::ModData {
+                kind: DatamodKind::AddData { amount: 200 },
+                dp_offset: 0,
+                code_p: SourcePos(None),
+            },
+            AST::CombineData {
+                source_dp_offset: 0,
+                target_dp_offset: 1,
+                source_amt_mult: 3,
+                code_p: SourcePos(None),
+            },
+            set(0, 0),
+            write(1),
+        ];
+
+        let mut fused = unfused.clone();
+        assert_eq!(fuse_adds_into_combines(&mut fused, OverflowPolicy::Saturate), 0);
+        assert_eq!(fuse_adds_into_combines(&mut fused, OverflowPolicy::Wrap), 1);
+        assert_eq!(
+            fused[3],
+            AST::ModData {
+                kind: DatamodKind::AddData { amount: 88 },
+                dp_offset: 1,
+                code_p: SourcePos(None),
+            }
+        );
+
+        // 200 * 3 wraps, and so does adding it, for most of these
+        let run = |code: &[AST], input: &[u8]| {
+            let mut output = VecOutput::new();
+            OptVM::new(crate::optimized::compile_ast(code))
+                .run(&mut SliceInput::new(input), &mut output)
+                .unwrap();
+            output.into_bytes()
+        };
+        for s in (0..=255).step_by(5) {
+            for t in &[0, 1, 100, 255] {
+                assert_eq!(run(&unfused, &[s, *t]), run(&fused, &[s, *t]), "{} {}", s, t);
+            }
+        }
+    }
+
+    #[test]
+    fn dead_stores_are_removed() {
+        let add = |amount, dp_offset| AST::ModData {
+            kind: DatamodKind::AddData { amount },
+            dp_offset,
+            code_p: SourcePos(None),
+        };
+
+        // data[1] is overwritten twice before it's written out; data[0] is read in between
+        let mut cmds = vec![
+            add(3, 0),
+            set(4, 1),
+            AST::CombineData {
+                source_dp_offset: 0,
+                target_dp_offset: 1,
+                source_amt_mult: 2,
+                code_p: SourcePos(None),
+            },
+            write(0),
+            shift(1),
+            read(0),
+            write(0),
+        ];
+
+        assert_eq!(dead_store_elim(&mut cmds, OverflowPolicy::Wrap), 2);
+        assert_eq!(cmds, vec![add(3, 0), write(0), shift(1), read(0), write(0),]);
+    }
+
+    #[test]
+    fn loop_conditions_keep_stores() {
+        let mut cmds = vec![
+            set(1, 0),
+            AST::Loop {
+                known_to_be_nontrivial: false,
+                cond_dp_offset: 0,
+                elements: vec![read(0)],
+                code_p: SourcePos(None),
+            },
+            set(2, 0),
+        ];
+
+        assert_eq!(dead_store_elim(&mut cmds, OverflowPolicy::Wrap), 0);
+        assert_eq!(cmds.len(), 3);
+    }
+
+    #[test]
+    fn static_bounds_of_blocks() {
+        assert_eq!(static_bounds(&[]), Some((0, 0)));
+
+        let block = vec![
+            set(1, -2),
+            shift(3),
+            write(1),
+            AST::CombineData {
+                source_dp_offset: -6,
+                target_dp_offset: 0,
+                source_amt_mult: 2,
+                code_p: SourcePos(None),
+            },
+        ];
+        assert_eq!(static_bounds(&block), Some((-3, 4)));
+
+        // loops which end where they started are fine, wherever they look inside
+        let balanced_loop = vec![AST::Loop {
+            known_to_be_nontrivial: false,
+            cond_dp_offset: 1,
+            elements: vec![shift(5), read(2), shift(-5)],
+            code_p: SourcePos(None),
+        }];
+        assert_eq!(static_bounds(&balanced_loop), Some((1, 7)));
+
+        let drifting_loop = vec![AST::Loop {
+            known_to_be_nontrivial: false,
+            cond_dp_offset: 0,
+            elements: vec![shift(1)],
+            code_p: SourcePos(None),
+        }];
+        assert_eq!(static_bounds(&drifting_loop), None);
+
+        let shift_loop = vec![AST::ShiftLoop {
+            known_to_be_nontrivial: false,
+            cond_dp_offset: 0,
+            dp_shift: -1,
+            code_p: SourcePos(None),
+        }];
+        assert_eq!(static_bounds(&shift_loop), None);
+    }
+
+    #[test]
+    fn branches_on_the_same_cell_are_merged() {
+        let branch = |elements| AST::IfNonZero {
+            cond_dp_offset: 0,
+            elements,
+            code_p: SourcePos(None),
+        };
+
+        let mut cmds = vec![branch(vec![write(0), set(1, 1)]), branch(vec![read(2)])];
+        assert_eq!(collapse_consecutive(&mut cmds, OverflowPolicy::Wrap), 1);
+        assert_eq!(cmds, vec![branch(vec![write(0), set(1, 1), read(2)])]);
+
+        // The first branch changes the condition, so the second might not run after it
+        let mut cmds = vec![branch(vec![set(0, 0)]), branch(vec![set(1, 1)])];
+        assert_eq!(collapse_consecutive(&mut cmds, OverflowPolicy::Wrap), 0);
+        assert_eq!(cmds.len(), 2);
+
+        // Or it moves the pointer, so the second is looking at a different cell
+        let mut cmds = vec![branch(vec![shift(1)]), branch(vec![set(1, 1)])];
+        assert_eq!(collapse_consecutive(&mut cmds, OverflowPolicy::Wrap), 0);
+        assert_eq!(cmds.len(), 2);
+    }
+
+    #[test]
+    fn known_scans_become_shifts() {
+        let scan = AST::ShiftLoop {
+            known_to_be_nontrivial: false,
+            cond_dp_offset: 0,
+            dp_shift: 1,
+            code_p: SourcePos(None),
+        };
+
+        // Three nonzero cells, then a scan from the first one, which has to stop on the fourth
+        let mut cmds = vec![set(1, 0), set(2, 1), set(3, 2), scan.clone(), write(-1)];
+        run_simulation(&mut cmds, DataState::Known(0), OverflowPolicy::Wrap);
+        assert_eq!(cmds, vec![set(1, 0), set(2, 1), set(3, 2), shift(3), AST::WriteConst { out: 3 },]);
+
+        // One of them might be zero, so the scan has to stay
+        let mut cmds = vec![set(1, 0), read(1), set(3, 2), scan];
+        run_simulation(&mut cmds, DataState::Known(0), OverflowPolicy::Wrap);
+        assert_eq!(cmds.len(), 4);
+    }
+
+    #[test]
+    fn running_ahead_needs_a_blank_tape() {
+        let run = |passes| {
+            let mut cmds = crate::optimized::parse("+.,.").unwrap();
+            let used = optimize_with_input(&mut cmds, OverflowPolicy::Wrap, b"a", None, passes);
+            (cmds, used)
+        };
+
+        let (cmds, used) = run(OptPasses::default());
+        assert_eq!((cmds, used), (vec![AST::WriteConstStr { bytes: vec![1, b'a'] }], 1));
+
+        // Cell 0 could start out as anything, so it can't be run, nor can the + be folded away
+        let (cmds, used) = run(OptPasses {
+            blank_tape: false,
+            ..OptPasses::default()
+        });
+        assert_eq!(used, 0);
+        assert!(cmds.contains(&write(0)), "{:?}", cmds);
+        assert!(
+            !cmds
+                .iter()
+                .any(|cmd| matches!(cmd, AST::WriteConst { .. } | AST::WriteConstStr { .. })),
+            "{:?}",
+            cmds
+        );
+    }
+
+    #[test]
+    fn loops_on_zeroed_cells_are_dropped() {
+        let mut cmds = vec![
+            set(0, 1),
+            AST::Loop {
+                known_to_be_nontrivial: false,
+                cond_dp_offset: 1,
+                elements: vec![write(1)],
+                code_p: SourcePos(None),
+            },
+            AST::ShiftLoop {
+                known_to_be_nontrivial: false,
+                cond_dp_offset: 1,
+                dp_shift: 1,
+                code_p: SourcePos(None),
+            },
+            AST::ShiftLoop {
+                known_to_be_nontrivial: false,
+                cond_dp_offset: 0,
+                dp_shift: 1,
+                code_p: SourcePos(None),
+            },
+        ];
+
+        assert_eq!(collapse_consecutive(&mut cmds, OverflowPolicy::Wrap), 2);
+        assert_eq!(cmds.len(), 2);
+        assert_eq!(cmds[0], set(0, 1));
+    }
+}
@@ -0,0 +1,92 @@
+use alloc::vec::Vec;
+
+use super::CompiledInstr;
+
+/// CompiledInstr, but with each jump's target given relative to the jump itself (so a delta of
+/// 1 is the next instruction), rather than as an ip. The deltas are small, which is good for
+/// storing code compactly, and a stretch of code means the same wherever it's put.
+#[derive(Clone, Eq, PartialEq, Hash, Debug)]
+pub enum RelInstr {
+    JumpIfZero { cond_dp_offset: isize, delta: isize },
+    JumpIfNonzero { cond_dp_offset: isize, delta: isize },
+    // Anything which isn't a jump, as it is
+    Other(CompiledInstr),
+}
+
+/// Turns the absolute jump targets into relative ones; see RelInstr
+pub fn to_relative(code: &[CompiledInstr]) -> Vec<RelInstr> {
+    let delta = |ip: usize, target_ip: usize| target_ip as isize - ip as isize;
+
+    code.iter()
+        .enumerate()
+        .map(|(ip, instr)| match *instr {
+            CompiledInstr::JumpIfZero { cond_dp_offset, target_ip } => RelInstr::JumpIfZero {
+                cond_dp_offset,
+                delta: delta(ip, target_ip),
+            },
+            CompiledInstr::JumpIfNonzero { cond_dp_offset, target_ip } => RelInstr::JumpIfNonzero {
+                cond_dp_offset,
+                delta: delta(ip, target_ip),
+            },
+            ref other => RelInstr::Other(other.clone()),
+        })
+        .collect()
+}
+
+/// Undoes to_relative, putting the absolute targets back. Nothing is checked; a jump off either
+/// end of the code ends up with a target past the end of it, so code from anywhere untrusted
+/// should go through OptVM::try_new after this.
+///
+/// Panics if one of the Other instructions is a jump.
+pub fn from_relative(code: &[RelInstr]) -> Vec<CompiledInstr> {
+    code.iter()
+        .enumerate()
+        .map(|(ip, instr)| match *instr {
+            RelInstr::JumpIfZero { cond_dp_offset, delta } => CompiledInstr::JumpIfZero {
+                cond_dp_offset,
+                target_ip: ip.wrapping_add_signed(delta),
+            },
+            RelInstr::JumpIfNonzero { cond_dp_offset, delta } => CompiledInstr::JumpIfNonzero {
+                cond_dp_offset,
+                target_ip: ip.wrapping_add_signed(delta),
+            },
+            RelInstr::Other(CompiledInstr::JumpIfZero { .. }) | RelInstr::Other(CompiledInstr::JumpIfNonzero { .. }) => {
+                panic!("The jump at ip {} should have been a relative one", ip)
+            }
+            RelInstr::Other(ref other) => other.clone(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{from_relative, to_relative, RelInstr};
+
+    #[test]
+    fn relative_code_round_trips() {
+        for source in &[
+            include_str!("../../../input/hello_world.b"),
+            include_str!("../../../input/rot13.b"),
+            include_str!("../../../input/hanoi.b"),
+        ] {
+            let code = crate::optimized_parse(source).unwrap();
+            assert_eq!(from_relative(&to_relative(&code)), code);
+        }
+    }
+
+    #[test]
+    fn relative_code_can_be_moved() {
+        // The same loop, after one read or after three instructions; only the absolute targets differ
+        let short = to_relative(&crate::optimized_parse(",[.>]").unwrap());
+        let long = to_relative(&crate::optimized_parse(",.,[.>]").unwrap());
+        assert_eq!(short[1..], long[3..]);
+        assert!(matches!(short[1], RelInstr::JumpIfZero { delta: 4, .. }));
+
+        // Off the front of the code, which try_new turns away
+        let bad = vec![RelInstr::JumpIfNonzero {
+            cond_dp_offset: 0,
+            delta: -1,
+        }];
+        assert!(crate::OptVM::try_new(from_relative(&bad)).is_err());
+    }
+}
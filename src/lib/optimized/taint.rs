@@ -0,0 +1,209 @@
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+use super::{DatamodKind, AST};
+
+/// Whether anything the program writes could depend on what it reads: this follows which cells
+/// hold something derived from a ReadByte, through arithmetic, CombineData and control flow,
+/// and reports whether a write could see one. Anything written inside a loop or branch whose
+/// condition depends on the input counts as depending on it too, constants included.
+///
+/// This is conservative, so a true only means the analysis couldn't rule it out; ",[-]+." counts,
+/// although it always prints 1. Once the data pointer depends on the data (after [>] and the
+/// like), every cell counts as tainted if any cell is. Whether the program stops, or stops with
+/// an error, isn't counted as output.
+pub fn output_depends_on_input(cmds: &[AST]) -> bool {
+    let mut state = TaintState::new();
+    state.run_block(cmds, false);
+    state.output_tainted
+}
+
+// What's known about which cells are tainted, in the same shape as the simulation's SimState:
+// cells are keyed by their offset from where dp was when it was last lost track of, and
+// losses counts how many times that's happened, so branches can tell if they still agree on it
+#[derive(Clone)]
+struct TaintState {
+    cells: BTreeMap<isize, bool>,
+    def_taint: bool,
+    dp: isize,
+    losses: usize,
+    output_tainted: bool,
+}
+
+impl TaintState {
+    fn new() -> Self {
+        TaintState {
+            cells: BTreeMap::new(),
+            def_taint: false,
+            dp: 0,
+            losses: 0,
+            output_tainted: false,
+        }
+    }
+
+    fn get(&self, dp_offset: isize) -> bool {
+        self.cells.get(&(self.dp + dp_offset)).copied().unwrap_or(self.def_taint)
+    }
+
+    fn set(&mut self, dp_offset: isize, taint: bool) {
+        self.cells.insert(self.dp + dp_offset, taint);
+    }
+
+    fn any_tainted(&self) -> bool {
+        self.def_taint || self.cells.values().any(|&t| t)
+    }
+
+    // Nothing is known about where anything is, so every cell might be any tainted one
+    fn lose_dp(&mut self, extra_taint: bool) {
+        self.def_taint = self.any_tainted() || extra_taint;
+        self.cells.clear();
+        self.dp = 0;
+        self.losses += 1;
+    }
+
+    // Everything which is tainted in either; losses_at_split is where they both started. If the
+    // branch was taken because of the input (pc), and they disagree on dp, then so does where
+    // the data pointer ends up
+    fn merge(&mut self, other: TaintState, losses_at_split: usize, pc: bool) {
+        self.output_tainted |= other.output_tainted;
+
+        if self.losses != losses_at_split || other.losses != losses_at_split || self.dp != other.dp {
+            let extra_taint = other.any_tainted() || pc;
+            self.lose_dp(extra_taint);
+            return;
+        }
+
+        for (&cell, &taint) in &other.cells {
+            let mine = self.cells.get(&cell).copied().unwrap_or(self.def_taint);
+            self.cells.insert(cell, mine || taint);
+        }
+        let untouched_by_other: Vec<isize> = self.cells.keys().filter(|c| !other.cells.contains_key(c)).copied().collect();
+        for cell in untouched_by_other {
+            let mine = self.cells[&cell];
+            self.cells.insert(cell, mine || other.def_taint);
+        }
+        self.def_taint |= other.def_taint;
+    }
+
+    fn same_as(&self, other: &TaintState) -> bool {
+        self.cells == other.cells && self.def_taint == other.def_taint && self.dp == other.dp && self.output_tainted == other.output_tainted
+    }
+
+    // pc is whether we only got here because of something derived from the input
+    fn run_block(&mut self, cmds: &[AST], pc: bool) {
+        for cmd in cmds {
+            self.run(cmd, pc);
+        }
+    }
+
+    fn run(&mut self, cmd: &AST, pc: bool) {
+        match cmd {
+            AST::Loop {
+                cond_dp_offset, elements, ..
+            } => {
+                // Go around until nothing new is tainted; each time can only taint more, so
+                // this stops
+                loop {
+                    let inner_pc = pc || self.get(*cond_dp_offset);
+                    let mut body = self.clone();
+                    body.run_block(elements, inner_pc);
+
+                    let mut merged = self.clone();
+                    merged.merge(body, self.losses, inner_pc);
+                    if merged.same_as(self) {
+                        break;
+                    }
+                    *self = merged;
+                }
+            }
+            AST::IfNonZero {
+                cond_dp_offset, elements, ..
+            } => {
+                let inner_pc = pc || self.get(*cond_dp_offset);
+                let mut body = self.clone();
+                body.run_block(elements, inner_pc);
+                let losses = self.losses;
+                self.merge(body, losses, inner_pc);
+            }
+            AST::ShiftLoop { cond_dp_offset, .. } => {
+                let extra_taint = pc || self.get(*cond_dp_offset);
+                self.lose_dp(extra_taint);
+            }
+            AST::ShiftDataPtr { amount, .. } => {
+                self.dp += amount;
+            }
+            AST::ModData { kind, dp_offset, .. } => {
+                let taint = match kind {
+                    DatamodKind::SetData { .. } => pc,
+                    DatamodKind::AddData { .. } => pc || self.get(*dp_offset),
+                };
+                self.set(*dp_offset, taint);
+            }
+            AST::CombineData {
+                source_dp_offset,
+                target_dp_offset,
+                source_amt_mult,
+                ..
+            } => {
+                let from_source = *source_amt_mult != 0 && self.get(*source_dp_offset);
+                let taint = pc || from_source || self.get(*target_dp_offset);
+                self.set(*target_dp_offset, taint);
+            }
+            AST::ReadByte { dp_offset, .. } => {
+                self.set(*dp_offset, true);
+            }
+            AST::WriteByte { dp_offset, .. } | AST::WriteTransformed { dp_offset, .. } => {
+                self.output_tainted |= pc || self.get(*dp_offset);
+            }
+            AST::WriteConst { .. } | AST::WriteConstStr { .. } => {
+                self.output_tainted |= pc;
+            }
+            // The tape is blank afterward, and dp is back at the start
+            AST::ResetTape => {
+                self.lose_dp(false);
+                self.def_taint = pc;
+            }
+            AST::InfiniteLoop | AST::AssertEquals { .. } => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::output_depends_on_input;
+    use crate::optimized::{parse, SourcePos, AST};
+
+    fn depends(source: &str) -> bool {
+        output_depends_on_input(&parse(source).unwrap())
+    }
+
+    #[test]
+    fn output_from_input_is_found() {
+        assert!(depends(include_str!("../../../input/rot13.b")));
+        assert!(!depends(include_str!("../../../input/hello_world.b")));
+
+        // Reading without writing what was read
+        assert!(!depends(",>++."));
+        assert!(depends(",+."));
+        // Through a multiplication loop, or just through which way a branch went
+        assert!(depends(",[->++<]>."));
+        assert!(depends(",[>+++.<[-]]"));
+        // Once dp depends on the input, so does everything
+        assert!(depends(",[>,]<[<]>."));
+        assert!(!depends("+[>+]+."));
+
+        // A blank tape doesn't depend on anything
+        let reset = [
+            AST::ReadByte {
+                dp_offset: 0,
+                code_p: SourcePos(None),
+            },
+            AST::ResetTape,
+            AST::WriteByte {
+                dp_offset: 0,
+                code_p: SourcePos(None),
+            },
+        ];
+        assert!(!output_depends_on_input(&reset));
+    }
+}
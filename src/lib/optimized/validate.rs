@@ -0,0 +1,113 @@
+use alloc::collections::BTreeSet;
+use alloc::vec::Vec;
+
+use super::{lex, parse, BfCmd, CommandMap, ParseError};
+
+#[derive(Debug)]
+pub enum ValidationError {
+    // The brackets don't match up
+    Parse(ParseError),
+    // The command at code point code_p might touch this cell, which isn't on a tape this size
+    OutOfRange { code_p: usize, cell: isize, tape_size: usize },
+}
+
+/// Checks a program is safe to run on a tape of the given size, before running it: the brackets
+/// have to match, and every cell it could touch has to be on the tape. That second part is
+/// conservative, since it counts cells touched in loops and branches which might never run.
+///
+/// Once the data pointer depends on the data (after something like [>], which goes however
+/// far it goes), there's no telling which cells come next, so the rest of the program is let
+/// through; such a program can still walk off the tape at runtime, where the VM will stop it.
+pub fn validate(src: &str, tape_size: usize) -> Result<(), ValidationError> {
+    parse(src).map_err(ValidationError::Parse)?;
+
+    let tokens: Vec<(usize, BfCmd)> = lex(&mut src.chars(), &CommandMap::default()).collect();
+    let unbalanced = unbalanced_loops(&tokens);
+
+    let mut dp: isize = 0;
+    for &(code_p, token) in &tokens {
+        match token {
+            BfCmd::IncPtr => dp += 1,
+            BfCmd::DecPtr => dp -= 1,
+            // Everything else looks at the current cell, including both ends of a loop
+            _ => {
+                if dp < 0 || dp as usize >= tape_size {
+                    return Err(ValidationError::OutOfRange {
+                        code_p,
+                        cell: dp,
+                        tape_size,
+                    });
+                }
+
+                if unbalanced.contains(&code_p) {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// The code points of the [ of every loop which doesn't end where it started, or contains one
+// that doesn't. A balanced loop leaves dp where it found it every time round, so walking through
+// its body once sees every cell it can touch. The brackets have to match.
+fn unbalanced_loops(tokens: &[(usize, BfCmd)]) -> BTreeSet<usize> {
+    let mut unbalanced = BTreeSet::new();
+    // The start of each loop we're in, the net shift of its body so far, and whether it's balanced so far
+    let mut running: Vec<(usize, isize, bool)> = Vec::new();
+
+    for &(code_p, token) in tokens {
+        match token {
+            BfCmd::LoopStart => running.push((code_p, 0, true)),
+            BfCmd::LoopEnd => {
+                let (start, shift, balanced) = running.pop().expect("The brackets were checked by parsing");
+                if shift != 0 || !balanced {
+                    unbalanced.insert(start);
+                    if let Some(outer) = running.last_mut() {
+                        outer.2 = false;
+                    }
+                }
+            }
+            BfCmd::IncPtr | BfCmd::DecPtr => {
+                if let Some(inner) = running.last_mut() {
+                    inner.1 += if token == BfCmd::IncPtr { 1 } else { -1 };
+                }
+            }
+            _ => {}
+        }
+    }
+
+    unbalanced
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn out_of_range(src: &str, tape_size: usize) -> Option<(usize, isize)> {
+        match validate(src, tape_size) {
+            Ok(()) => None,
+            Err(ValidationError::OutOfRange { code_p, cell, .. }) => Some((code_p, cell)),
+            Err(e) => panic!("Expected the brackets to match, got {:?}", e),
+        }
+    }
+
+    #[test]
+    fn programs_are_validated() {
+        assert!(matches!(validate("+[>]]", 10), Err(ValidationError::Parse(_))));
+
+        assert_eq!(out_of_range("+[->+<]>.", 2), None);
+        // The loop might not run, but if it does it looks at cell 2
+        assert_eq!(out_of_range(",[->>+<<]", 2), Some((5, 2)));
+        assert_eq!(out_of_range("  <+", 10), Some((3, -1)));
+        // Moving off the tape and back is fine
+        assert_eq!(out_of_range("<<>>+", 1), None);
+
+        // Nobody knows where [>] ends up, so anything after it goes
+        assert_eq!(out_of_range("+[>]<<<<+", 3), None);
+        assert_eq!(out_of_range("+[[>]<+]>>>>+", 3), None);
+        // but not before it
+        assert_eq!(out_of_range(">>>+[>]", 3), Some((3, 3)));
+    }
+}
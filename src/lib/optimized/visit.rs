@@ -0,0 +1,128 @@
+use super::CompiledInstr;
+
+/// Something which looks at compiled code an instruction at a time, like a linter or a profiler;
+/// see walk. Every method does nothing unless it's overridden, so a visitor only has to write
+/// the ones for the instructions it cares about. Each gets the ip of the instruction.
+///
+/// visit_instr sees every instruction, and by default hands it to the method for its variant;
+/// a visitor which overrides it (to see everything first, say) can call visit_instr_variant to
+/// keep that going.
+#[allow(unused_variables)]
+pub trait InstrVisitor {
+    fn visit_instr(&mut self, ip: usize, instr: &CompiledInstr) {
+        visit_instr_variant(self, ip, instr);
+    }
+
+    fn visit_jump_if_zero(&mut self, ip: usize, cond_dp_offset: isize, target_ip: usize) {}
+
+    fn visit_jump_if_nonzero(&mut self, ip: usize, cond_dp_offset: isize, target_ip: usize) {}
+
+    fn visit_infinite_loop(&mut self, ip: usize) {}
+
+    fn visit_halt_if_nonzero(&mut self, ip: usize, cond_dp_offset: isize) {}
+
+    fn visit_reset_tape(&mut self, ip: usize) {}
+
+    fn visit_add_ptr(&mut self, ip: usize, amount: usize) {}
+
+    fn visit_sub_ptr(&mut self, ip: usize, amount: usize) {}
+
+    fn visit_add_data(&mut self, ip: usize, amount: u8, dp_offset: isize) {}
+
+    fn visit_set_data(&mut self, ip: usize, amount: u8, dp_offset: isize) {}
+
+    fn visit_assert_equals(&mut self, ip: usize, dp_offset: isize, val: u8) {}
+
+    fn visit_add_two_data(&mut self, ip: usize, source_dp_offset: isize, target_dp_offset: isize, source_amt_mult: u8) {}
+
+    fn visit_read_byte(&mut self, ip: usize, dp_offset: isize) {}
+
+    fn visit_write_byte(&mut self, ip: usize, dp_offset: isize) {}
+
+    fn visit_write_transformed(&mut self, ip: usize, dp_offset: isize, add: u8) {}
+
+    fn visit_write_const(&mut self, ip: usize, out: u8) {}
+
+    fn visit_write_const_str(&mut self, ip: usize, bytes: &[u8]) {}
+}
+
+/// Hands every instruction of the code to the visitor, in order (not in the order they'd run).
+pub fn walk<V: InstrVisitor + ?Sized>(code: &[CompiledInstr], visitor: &mut V) {
+    for (ip, instr) in code.iter().enumerate() {
+        visitor.visit_instr(ip, instr);
+    }
+}
+
+/// Calls the visitor's method for this instruction's variant; what visit_instr does by default.
+pub fn visit_instr_variant<V: InstrVisitor + ?Sized>(visitor: &mut V, ip: usize, instr: &CompiledInstr) {
+    match *instr {
+        CompiledInstr::JumpIfZero { cond_dp_offset, target_ip } => visitor.visit_jump_if_zero(ip, cond_dp_offset, target_ip),
+        CompiledInstr::JumpIfNonzero { cond_dp_offset, target_ip } => visitor.visit_jump_if_nonzero(ip, cond_dp_offset, target_ip),
+        CompiledInstr::InfiniteLoop => visitor.visit_infinite_loop(ip),
+        CompiledInstr::HaltIfNonzero { cond_dp_offset } => visitor.visit_halt_if_nonzero(ip, cond_dp_offset),
+        CompiledInstr::ResetTape => visitor.visit_reset_tape(ip),
+        CompiledInstr::AddPtr { amount } => visitor.visit_add_ptr(ip, amount),
+        CompiledInstr::SubPtr { amount } => visitor.visit_sub_ptr(ip, amount),
+        CompiledInstr::AddData { amount, dp_offset } => visitor.visit_add_data(ip, amount, dp_offset),
+        CompiledInstr::SetData { amount, dp_offset } => visitor.visit_set_data(ip, amount, dp_offset),
+        CompiledInstr::AssertEquals { dp_offset, val } => visitor.visit_assert_equals(ip, dp_offset, val),
+        CompiledInstr::AddTwoData {
+            source_dp_offset,
+            target_dp_offset,
+            source_amt_mult,
+        } => visitor.visit_add_two_data(ip, source_dp_offset, target_dp_offset, source_amt_mult),
+        CompiledInstr::ReadByte { dp_offset } => visitor.visit_read_byte(ip, dp_offset),
+        CompiledInstr::WriteByte { dp_offset } => visitor.visit_write_byte(ip, dp_offset),
+        CompiledInstr::WriteTransformed { dp_offset, add } => visitor.visit_write_transformed(ip, dp_offset, add),
+        CompiledInstr::WriteConst { out } => visitor.visit_write_const(ip, out),
+        CompiledInstr::WriteConstStr { ref bytes } => visitor.visit_write_const_str(ip, bytes),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{walk, InstrVisitor};
+    use crate::CompiledInstr;
+
+    // How many bytes the write instructions write, if each runs once; and how many instructions
+    // there are in all
+    #[derive(Default)]
+    struct WriteCounter {
+        writes: usize,
+        instrs: usize,
+    }
+
+    impl InstrVisitor for WriteCounter {
+        fn visit_instr(&mut self, ip: usize, instr: &CompiledInstr) {
+            self.instrs += 1;
+            super::visit_instr_variant(self, ip, instr);
+        }
+
+        fn visit_write_byte(&mut self, _ip: usize, _dp_offset: isize) {
+            self.writes += 1;
+        }
+
+        fn visit_write_const(&mut self, _ip: usize, _out: u8) {
+            self.writes += 1;
+        }
+
+        fn visit_write_const_str(&mut self, _ip: usize, bytes: &[u8]) {
+            self.writes += bytes.len();
+        }
+    }
+
+    #[test]
+    fn visitors_see_every_instruction() {
+        let code = crate::optimized_parse(",[.,]+++.").unwrap();
+        let mut counter = WriteCounter::default();
+        walk(&code, &mut counter);
+        assert_eq!((counter.writes, counter.instrs), (2, code.len()));
+
+        let mut counter = WriteCounter::default();
+        walk(
+            &crate::optimized_parse(include_str!("../../../input/hello_world.b")).unwrap(),
+            &mut counter,
+        );
+        assert_eq!(counter.writes, "Hello World!\n".len());
+    }
+}
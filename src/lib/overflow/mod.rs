@@ -0,0 +1,158 @@
+/// What happens when a cell is pushed past 0 or 255.
+///
+/// With Wrap, an amount like 255 is the same as -1, so it doesn't matter how amounts are read.
+/// With the others it does, so the amounts in AddData and AddTwoData are read as signed bytes:
+/// adding 255 means subtracting one. The optimizer only merges arithmetic when that reading
+/// stays correct (see OverflowPolicy::merge_amounts).
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub enum OverflowPolicy {
+    /// The usual 8-bit cell; 255 + 1 is 0
+    #[default]
+    Wrap,
+    /// Stop at the ends; 255 + 1 is 255 and 0 - 1 is 0
+    Saturate,
+    /// Going past either end is a RuntimeError::Overflow
+    Error,
+}
+
+impl OverflowPolicy {
+    /// cell + amount, where amount is a signed byte (unless wrapping, where it makes no
+    /// difference). None if that overflows and the policy says that's an error.
+    pub fn add(self, cell: u8, amount: u8) -> Option<u8> {
+        match self {
+            OverflowPolicy::Wrap => Some(u8::wrapping_add(cell, amount)),
+            _ => self.add_wide(cell, signed(amount)),
+        }
+    }
+
+    /// cell + source * mult, where mult is a signed byte (as for add).
+    pub fn add_product(self, cell: u8, source: u8, mult: u8) -> Option<u8> {
+        match self {
+            OverflowPolicy::Wrap => Some(u8::wrapping_add(cell, u8::wrapping_mul(source, mult))),
+            _ => self.add_wide(cell, i32::from(source) * signed(mult)),
+        }
+    }
+
+    fn add_wide(self, cell: u8, delta: i32) -> Option<u8> {
+        let sum = i32::from(cell) + delta;
+        match self {
+            OverflowPolicy::Wrap => Some(sum.rem_euclid(256) as u8),
+            OverflowPolicy::Saturate => Some(sum.clamp(0, 255) as u8),
+            OverflowPolicy::Error => {
+                if (0..=255).contains(&sum) {
+                    Some(sum as u8)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    /// The single amount which does the same as adding a and then b, if there is one. When
+    /// wrapping there always is; otherwise both have to go the same way (since e.g. +1 then -1
+    /// isn't a no-op at 255) and the total has to fit in a signed byte.
+    pub(crate) fn merge_amounts(self, a: u8, b: u8) -> Option<u8> {
+        match self {
+            OverflowPolicy::Wrap => Some(u8::wrapping_add(a, b)),
+            _ => {
+                let (a, b) = (signed(a), signed(b));
+                if a < 0 && b > 0 || a > 0 && b < 0 {
+                    None
+                } else {
+                    to_amount(a + b)
+                }
+            }
+        }
+    }
+
+    /// The amount to add in place of adding source * mult (where source is a known cell value,
+    /// and mult is a signed byte), if it can be written as one (see merge_amounts).
+    pub(crate) fn product_amount(self, source: u8, mult: u8) -> Option<u8> {
+        match self {
+            OverflowPolicy::Wrap => Some(u8::wrapping_mul(source, mult)),
+            _ => to_amount(i32::from(source) * signed(mult)),
+        }
+    }
+}
+
+fn signed(amount: u8) -> i32 {
+    i32::from(amount as i8)
+}
+
+fn to_amount(total: i32) -> Option<u8> {
+    if (i32::from(i8::MIN)..=i32::from(i8::MAX)).contains(&total) {
+        Some(total as i8 as u8)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::OverflowPolicy::*;
+
+    #[test]
+    fn adding_at_the_ends() {
+        assert_eq!(Wrap.add(255, 1), Some(0));
+        assert_eq!(Wrap.add(0, 255), Some(255));
+        assert_eq!(Saturate.add(255, 1), Some(255));
+        assert_eq!(Saturate.add(0, 255), Some(0));
+        assert_eq!(Saturate.add(3, 254), Some(1));
+        assert_eq!(Error.add(255, 1), None);
+        assert_eq!(Error.add(0, 255), None);
+        assert_eq!(Error.add(3, 254), Some(1));
+
+        assert_eq!(Wrap.add_product(1, 10, 255), Some(247));
+        assert_eq!(Saturate.add_product(1, 10, 255), Some(0));
+        assert_eq!(Error.add_product(200, 50, 2), None);
+        assert_eq!(Error.add_product(100, 50, 2), Some(200));
+    }
+
+    #[test]
+    fn merging_amounts() {
+        assert_eq!(Wrap.merge_amounts(1, 255), Some(0));
+        assert_eq!(Saturate.merge_amounts(1, 255), None);
+        assert_eq!(Error.merge_amounts(255, 255), Some(254));
+        assert_eq!(Error.merge_amounts(100, 100), None);
+
+        assert_eq!(Wrap.product_amount(200, 2), Some(144));
+        assert_eq!(Saturate.product_amount(200, 2), None);
+        assert_eq!(Saturate.product_amount(20, 255), Some(236));
+    }
+
+    // Both VMs have to agree, or comparing them (as the fuzzing does) means nothing
+    #[cfg(feature = "simple")]
+    #[test]
+    fn both_vms_decrement_zero_the_same() {
+        use crate::io::{SliceInput, VecOutput};
+        use crate::vm::{RuntimeError, VmError};
+        use crate::{OptVM, SimpleVM};
+
+        for (policy, expected) in [
+            (Wrap, Ok(vec![255])),
+            (Saturate, Ok(vec![0])),
+            (Error, Err(RuntimeError::Overflow { dp: 0 })),
+        ] {
+            let mut output = VecOutput::new();
+            let simple = SimpleVM::new(crate::simple_parse("-.").unwrap())
+                .with_overflow_policy(policy)
+                .run(&mut SliceInput::new(b""), &mut output)
+                .map(|()| output.into_bytes());
+
+            let options = crate::ParseOptions {
+                policy,
+                ..crate::ParseOptions::default()
+            };
+            let parsed = crate::optimized_parse_with_options("-.", &options).unwrap();
+            let mut output = VecOutput::new();
+            let opt = OptVM::new(parsed.code)
+                .with_overflow_policy(policy)
+                .run(&mut SliceInput::new(b""), &mut output)
+                .map(|()| output.into_bytes());
+
+            let expected = expected.map_err(VmError::Runtime);
+            assert_eq!(simple, expected, "{:?}", policy);
+            assert_eq!(opt, expected, "{:?}", policy);
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::io::{SliceInput, VecOutput};
+use crate::vm::{OptVM, RuntimeError, VmError};
+use crate::{ParseError, ParseOptions};
+
+/// Why run_program failed: either the source didn't parse, or the program went wrong running.
+/// Nothing can go wrong with the input and output, which are just in memory.
+#[derive(Debug)]
+pub enum RunError {
+    Parse(ParseError),
+    /// The program went wrong; near is where in the source (counting characters), if that's
+    /// known (see run_program)
+    Runtime {
+        error: RuntimeError,
+        near: Option<usize>,
+    },
+}
+
+/// Parse, optimize and run the program on the given input, all in one go, and give back what it
+/// wrote. It runs on a fresh OptVM with the defaults, so there's no step limit; a program which
+/// never finishes never returns.
+///
+/// When the program goes wrong, near is where in the source the instruction that failed came
+/// from, from the source map (see OptVM::with_source_map). The optimizer merges and moves
+/// commands around, so that's roughly where it went wrong rather than exactly: for an add to a
+/// cell off the tape, say, it may be the first of several adds which were merged into one, or
+/// the [ of a loop which was folded into a few instructions. It's None for a program which
+/// would never have finished, which the optimizer caught.
+pub fn run_program(src: &str, input: &[u8]) -> Result<Vec<u8>, RunError> {
+    let options = ParseOptions {
+        source_map: true,
+        ..ParseOptions::default()
+    };
+    let parsed = crate::optimized_parse_with_options(src, &options).map_err(RunError::Parse)?;
+    let mut vm = OptVM::new(parsed.code).with_source_map(parsed.source_map.expect("Asked for a source map"));
+    let mut input = SliceInput::new(input);
+    let mut output = VecOutput::new();
+    loop {
+        match vm.step(&mut input, &mut output) {
+            Ok(true) => {}
+            Ok(false) => return Ok(output.into_bytes()),
+            Err(VmError::Runtime(error)) => return Err(RunError::Runtime { error, near: vm.code_p() }),
+            Err(VmError::Input(e)) | Err(VmError::Output(e)) => match e {},
+        }
+    }
+}
+
+/// The same as run_program, but gives back the output as text; anything which isn't valid UTF-8
+/// comes out as replacement characters.
+pub fn run_program_str(src: &str, input: &[u8]) -> Result<String, RunError> {
+    run_program(src, input).map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{run_program, run_program_str, RunError};
+    use crate::{ParseError, RuntimeError};
+
+    #[test]
+    fn programs_run_in_one_call() {
+        assert_eq!(
+            run_program_str(include_str!("../../../input/hello_world.b"), b"").unwrap(),
+            "Hello World!\n"
+        );
+        assert_eq!(run_program(",[.,]", b"echo").unwrap(), b"echo");
+        assert_eq!(
+            run_program_str(include_str!("../../../input/rot13.b"), b"Hello\n").unwrap(),
+            "Uryyb\n"
+        );
+
+        // Half of a two-byte character, then a whole one
+        assert_eq!(run_program_str(",.,.,.", &[0xC3, 0xC3, 0xA9]).unwrap(), "\u{FFFD}é");
+
+        assert!(matches!(
+            run_program("+[", b""),
+            Err(RunError::Parse(ParseError::UnterminatedLoop { code_p: 1 }))
+        ));
+        assert!(matches!(
+            run_program("+[]", b""),
+            Err(RunError::Runtime {
+                error: RuntimeError::InfiniteLoopDetected,
+                near: None
+            })
+        ));
+    }
+
+    #[test]
+    fn runtime_errors_say_where_they_were() {
+        // The + at 7 is the first thing to touch a cell left of the tape
+        assert!(matches!(
+            run_program("++>+<<<+.", b""),
+            Err(RunError::Runtime {
+                error: RuntimeError::OutOfBounds { .. },
+                near: Some(7)
+            })
+        ));
+
+        // The same in a loop the optimizer folds away, which is put down to the loop's [ at 25
+        assert!(matches!(
+            run_program("++++[>+++<-]> move left: [<<+>>-]<<.", b""),
+            Err(RunError::Runtime {
+                error: RuntimeError::OutOfBounds { .. },
+                near: Some(25)
+            })
+        ));
+    }
+}
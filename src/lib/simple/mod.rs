@@ -1,3 +1,9 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::ParseError;
+
 #[derive(Copy, Clone, Eq, PartialEq, Debug)]
 pub enum BfInstr {
     IncPtr { code_p: usize },
@@ -10,17 +16,132 @@ pub enum BfInstr {
     LoopEnd { code_p: usize, start_ip: usize },
 }
 
+impl BfInstr {
+    /// Where the instruction was in the source, counting characters
+    pub fn code_p(&self) -> usize {
+        match *self {
+            BfInstr::IncPtr { code_p }
+            | BfInstr::DecPtr { code_p }
+            | BfInstr::IncByte { code_p }
+            | BfInstr::DecByte { code_p }
+            | BfInstr::ReadByte { code_p }
+            | BfInstr::WriteByte { code_p }
+            | BfInstr::LoopStart { code_p, .. }
+            | BfInstr::LoopEnd { code_p, .. } => code_p,
+        }
+    }
+}
+
+/// The source character the instruction was parsed from
+impl fmt::Display for BfInstr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let c = match self {
+            BfInstr::IncPtr { .. } => '>',
+            BfInstr::DecPtr { .. } => '<',
+            BfInstr::IncByte { .. } => '+',
+            BfInstr::DecByte { .. } => '-',
+            BfInstr::ReadByte { .. } => ',',
+            BfInstr::WriteByte { .. } => '.',
+            BfInstr::LoopStart { .. } => '[',
+            BfInstr::LoopEnd { .. } => ']',
+        };
+        write!(f, "{}", c)
+    }
+}
+
+/// Turns a parsed program back into BF source. Since parsing throws away everything that isn't
+/// a command, parsing and then doing this strips the comments out of a program.
+pub fn to_source(code: &[BfInstr]) -> String {
+    use core::fmt::Write;
+
+    let mut out = String::with_capacity(code.len());
+    for instr in code {
+        write!(out, "{}", instr).unwrap();
+    }
+    out
+}
+
+/// Turns a parsed program back into BF source laid out to be read: each bracket on a line of its
+/// own, with the body of each loop indented (four spaces a level) on the lines between them, and
+/// the commands between brackets together on one line. Like to_source, this drops the comments.
+pub fn format_source(code: &[BfInstr]) -> String {
+    // Write out the commands since the last bracket, if there are any
+    fn flush(out: &mut String, line: &mut String, depth: usize) {
+        if !line.is_empty() {
+            push_line(out, line, depth);
+            line.clear();
+        }
+    }
+
+    fn push_line(out: &mut String, line: &str, depth: usize) {
+        for _ in 0..depth {
+            out.push_str("    ");
+        }
+        out.push_str(line);
+        out.push('\n');
+    }
+
+    let mut out = String::with_capacity(code.len() * 2);
+    let mut line = String::new();
+    let mut depth = 0;
+
+    for instr in code {
+        match instr {
+            BfInstr::LoopStart { .. } => {
+                flush(&mut out, &mut line, depth);
+                push_line(&mut out, "[", depth);
+                depth += 1;
+            }
+            BfInstr::LoopEnd { .. } => {
+                flush(&mut out, &mut line, depth);
+                depth -= 1;
+                push_line(&mut out, "]", depth);
+            }
+            _ => {
+                use core::fmt::Write;
+                write!(line, "{}", instr).unwrap();
+            }
+        }
+    }
+    flush(&mut out, &mut line, depth);
+
+    out
+}
+
+/// The ips of each loop's [ and its matching ], in order of the [; e.g. for editors which
+/// highlight matching brackets. The code_p of each end is in the instructions at those ips.
+pub fn loop_pairs(code: &[BfInstr]) -> Vec<(usize, usize)> {
+    code.iter()
+        .enumerate()
+        .filter_map(|(ip, instr)| match *instr {
+            BfInstr::LoopStart { end_ip, .. } => Some((ip, end_ip)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Splits a file at its first !, into the program and the input to feed it, as many BF
+/// distributions do. Without a !, it's all program and there's no input. This is opt-in, since
+/// to the parser ! is just another comment character.
+pub fn split_source_and_input(src: &str) -> (&str, &str) {
+    match src.find('!') {
+        Some(bang) => (&src[..bang], &src[bang + 1..]),
+        None => (src, ""),
+    }
+}
+
 /// This produces a parsed list of instructions in a "one pass" manner.
 /// No optimizations are applied, but (e.g.) loops are linked up correctly, and the instructions
-/// can be executed immediately and directly.
-pub fn parse(input_str: &str) -> Result<Vec<BfInstr>, ()> {
+/// can be executed immediately and directly. Mismatched brackets are a ParseError, the same as
+/// for the optimized parser.
+pub fn parse(input_str: &str) -> Result<Vec<BfInstr>, ParseError> {
     use crate::BfInstr::*;
 
     let mut code = Vec::new();
 
-    // ip of start counter; used to go back and modify the loop start variable to point to the
-    // right place
-    let mut loop_stack: Vec<usize> = Vec::new();
+    // ip (and code point) of start counter; used to go back and modify the loop start variable
+    // to point to the right place
+    let mut loop_stack: Vec<(usize, usize)> = Vec::new();
     let mut ip = 0;
 
     for (code_p, code_char) in input_str.chars().enumerate() {
@@ -32,36 +153,15 @@ pub fn parse(input_str: &str) -> Result<Vec<BfInstr>, ()> {
             '.' => Some(WriteByte { code_p }),
             ',' => Some(ReadByte { code_p }),
             '[' => {
-                loop_stack.push(ip);
+                loop_stack.push((ip, code_p));
                 // end_ip will be modified when we find the end
                 Some(LoopStart { code_p, end_ip: 0 })
             }
             ']' => {
-                if loop_stack.is_empty() {
-                    println!("No loop start for the loop end at codepoint {}", code_p);
-                    return Err(());
-                }
-                let start_ip = loop_stack.pop().unwrap();
+                let (start_ip, _) = loop_stack.pop().ok_or(ParseError::EndLoopWithoutStart { code_p })?;
                 match code.get_mut(start_ip) {
-                    None => {
-                        println!(
-                            "At codepoint {}, loop start pointer {} is invalid; only {} ips so far",
-                            code_p,
-                            start_ip,
-                            code.len()
-                        );
-                        return Err(());
-                    }
-                    Some(LoopStart { ref mut end_ip, .. }) => {
-                        *end_ip = ip;
-                    }
-                    Some(other) => {
-                        println!(
-                            "At codepoint {}, loop start pointer {} is pointing to {:?} which is not a loop start",
-                            code_p, start_ip, other
-                        );
-                        return Err(());
-                    }
+                    Some(LoopStart { ref mut end_ip, .. }) => *end_ip = ip,
+                    other => unreachable!("Loop start pointer {} is pointing to {:?}, not a loop start", start_ip, other),
                 }
                 Some(LoopEnd { code_p, start_ip })
             }
@@ -75,10 +175,66 @@ pub fn parse(input_str: &str) -> Result<Vec<BfInstr>, ()> {
         }
     }
 
-    if !loop_stack.is_empty() {
-        println!("At end of parsing, {} loops remain unclosed, which is an error.", loop_stack.len());
-        return Err(());
+    if let Some((_, code_p)) = loop_stack.pop() {
+        return Err(ParseError::UnterminatedLoop { code_p });
     }
 
     Ok(code)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{format_source, loop_pairs, parse, split_source_and_input, to_source};
+    use crate::ParseError;
+
+    #[test]
+    fn source_round_trips() {
+        let stripped = "+[->,.<]>>[-]<<[[>+<-]>.]";
+        assert_eq!(to_source(&parse(stripped).unwrap()), stripped);
+
+        let commented = "add two: +[->+<] and print it >.";
+        assert_eq!(to_source(&parse(commented).unwrap()), "+[->+<]>.");
+    }
+
+    #[test]
+    fn source_is_formatted() {
+        let minified = "++[>+++[>++<-]<-]>>.[]";
+        let expected = "\
+++
+[
+    >+++
+    [
+        >++<-
+    ]
+    <-
+]
+>>.
+[
+]
+";
+        assert_eq!(format_source(&parse(minified).unwrap()), expected);
+
+        // Formatting only adds whitespace
+        assert_eq!(to_source(&parse(&format_source(&parse(minified).unwrap())).unwrap()), minified);
+        assert_eq!(format_source(&[]), "");
+    }
+
+    #[test]
+    fn input_follows_the_bang() {
+        assert_eq!(split_source_and_input(",[.,]!hello! world"), (",[.,]", "hello! world"));
+        assert_eq!(split_source_and_input(",[.,]"), (",[.,]", ""));
+    }
+
+    #[test]
+    fn loops_are_paired() {
+        let code = parse("+[>[-]<[.]]").unwrap();
+        assert_eq!(loop_pairs(&code), vec![(1, 10), (3, 5), (7, 9)]);
+        assert_eq!(loop_pairs(&parse("+-.").unwrap()), vec![]);
+    }
+
+    #[test]
+    fn mismatched_loops_are_errors() {
+        assert!(matches!(parse("+[-]]"), Err(ParseError::EndLoopWithoutStart { code_p: 4 })));
+        assert!(matches!(parse("[+[-] [.["), Err(ParseError::UnterminatedLoop { code_p: 8 })));
+    }
+}
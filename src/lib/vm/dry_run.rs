@@ -0,0 +1,89 @@
+use alloc::vec::Vec;
+use core::convert::Infallible;
+
+use super::{RuntimeError, VmError};
+use crate::io::{Input, VecOutput};
+
+/// What a program did when run with no input, as from SimpleVM::dry_run or OptVM::dry_run.
+#[derive(Clone, Eq, PartialEq, Debug)]
+pub struct DryRunReport {
+    /// How many bytes the program asked for; every one of them was EOF
+    pub reads: u64,
+    /// How many bytes it wrote, which are all in output
+    pub writes: u64,
+    pub output: Vec<u8>,
+    /// Instructions executed, which for OptVM is usually far fewer than in the source
+    pub steps: u64,
+    /// Whether the program ran to the end; if not, error says why
+    pub terminated: bool,
+    pub error: Option<RuntimeError>,
+}
+
+impl DryRunReport {
+    pub(crate) fn new(result: Result<(), VmError<Infallible, Infallible>>, steps: u64, input: ZeroInput, output: VecOutput) -> Self {
+        let error = match result {
+            Ok(()) => None,
+            Err(VmError::Runtime(e)) => Some(e),
+            Err(VmError::Input(e)) | Err(VmError::Output(e)) => match e {},
+        };
+        let output = output.into_bytes();
+
+        DryRunReport {
+            reads: input.reads,
+            writes: output.len() as u64,
+            output,
+            steps,
+            terminated: error.is_none(),
+            error,
+        }
+    }
+}
+
+// Input which is always at EOF, and counts how often it's asked
+#[derive(Default)]
+pub(crate) struct ZeroInput {
+    reads: u64,
+}
+
+impl Input for ZeroInput {
+    type InputError = Infallible;
+
+    fn read_byte(&mut self) -> Result<u8, Self::InputError> {
+        self.reads += 1;
+        Ok(0)
+    }
+
+    fn read_byte_or_eof(&mut self) -> Result<Option<u8>, Self::InputError> {
+        self.reads += 1;
+        Ok(None)
+    }
+}
+
+#[cfg(all(test, feature = "simple"))]
+mod tests {
+    use super::*;
+    use crate::{OptVM, SimpleVM};
+
+    #[test]
+    fn dry_runs_are_reported() {
+        let source = ",[.,]+.,.";
+
+        let report = SimpleVM::new(crate::simple_parse(source).unwrap()).dry_run(100);
+        assert_eq!((report.reads, report.writes, report.steps), (2, 2, 6));
+        assert_eq!(report.output, vec![1, 0]);
+        assert!(report.terminated);
+
+        let report = OptVM::new(crate::optimized_parse(source).unwrap()).dry_run(100);
+        assert_eq!((report.reads, report.writes), (2, 2));
+        assert_eq!(report.output, vec![1, 0]);
+        assert!(report.terminated);
+
+        let report = SimpleVM::new(crate::simple_parse("+.[]").unwrap()).dry_run(100);
+        assert_eq!(report.output, vec![1]);
+        assert!(!report.terminated);
+        assert_eq!(report.error, Some(RuntimeError::StepLimitExceeded { max_steps: 100 }));
+
+        let report = OptVM::new(crate::optimized_parse("+.[]").unwrap()).dry_run(100);
+        assert_eq!(report.error, Some(RuntimeError::InfiniteLoopDetected));
+    }
+}
@@ -0,0 +1,112 @@
+use core::fmt;
+use core::time::Duration;
+
+/// Errors caused by the program itself, as opposed to the IO it's hooked up to.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum RuntimeError {
+    // Tried to read or write the cell at dp, which is not on the tape; for an instruction which
+    // touches two cells, role says which of them it was
+    OutOfBounds { dp: isize, role: Option<Operand> },
+    // The cell at dp went past 0 or 255, under OverflowPolicy::Error
+    Overflow { dp: isize },
+    // Ran for max_steps instructions without finishing
+    StepLimitExceeded { max_steps: u64 },
+    // Ran for longer than the timeout given to run_with_timeout without finishing
+    Timeout { timeout: Duration },
+    // The optimizer proved the program would spin forever without doing any IO
+    InfiniteLoopDetected,
+    // The same, but the optimizer could say why: it would spin forever because cell dp was nonzero
+    WouldNotTerminate { dp: isize },
+    // Read into cell dp after the input ran out, under EofPolicy::Error
+    UnexpectedEof { dp: isize },
+}
+
+/// Which of the two cells of an OptVM AddTwoData (target += source * mult) an error is about
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum Operand {
+    Source,
+    Target,
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RuntimeError::OutOfBounds { dp, role: None } => write!(f, "Tried to access cell {}, which is off the tape", dp),
+            RuntimeError::OutOfBounds {
+                dp,
+                role: Some(Operand::Source),
+            } => write!(f, "Tried to read cell {} (the source of an add), which is off the tape", dp),
+            RuntimeError::OutOfBounds {
+                dp,
+                role: Some(Operand::Target),
+            } => write!(f, "Tried to add to cell {}, which is off the tape", dp),
+            RuntimeError::Overflow { dp } => write!(f, "Cell {} went out of the range 0-255", dp),
+            RuntimeError::StepLimitExceeded { max_steps } => write!(f, "Still running after {} instructions", max_steps),
+            RuntimeError::Timeout { timeout } => write!(f, "Still running after {:?}", timeout),
+            RuntimeError::InfiniteLoopDetected => write!(f, "Infinite non-IO loop detected (spin-loop)"),
+            RuntimeError::WouldNotTerminate { dp } => {
+                write!(f, "Infinite non-IO loop detected (spin-loop), because cell {} was nonzero", dp)
+            }
+            RuntimeError::UnexpectedEof { dp } => write!(f, "Tried to read into cell {} after the input ran out", dp),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for RuntimeError {}
+
+/// A jump in code given to OptVM::try_new which goes past the end of the code; see there.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct BadJumpTarget {
+    pub ip: usize,
+    pub target_ip: usize,
+}
+
+impl fmt::Display for BadJumpTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "The jump at ip {} goes to {}, which is past the end of the code",
+            self.ip, self.target_ip
+        )
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for BadJumpTarget {}
+
+/// Anything which can stop a VM: either the program went wrong, or the input / output it was
+/// given did. IE and OE are the error types of that Input and Output.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum VmError<IE, OE> {
+    Runtime(RuntimeError),
+    Input(IE),
+    Output(OE),
+}
+
+impl<IE, OE> From<RuntimeError> for VmError<IE, OE> {
+    fn from(e: RuntimeError) -> Self {
+        VmError::Runtime(e)
+    }
+}
+
+impl<IE: fmt::Display, OE: fmt::Display> fmt::Display for VmError<IE, OE> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmError::Runtime(e) => write!(f, "{}", e),
+            VmError::Input(e) => write!(f, "Error reading input: {}", e),
+            VmError::Output(e) => write!(f, "Error writing output: {}", e),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<IE: std::error::Error + 'static, OE: std::error::Error + 'static> std::error::Error for VmError<IE, OE> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            VmError::Runtime(e) => Some(e),
+            VmError::Input(e) => Some(e),
+            VmError::Output(e) => Some(e),
+        }
+    }
+}
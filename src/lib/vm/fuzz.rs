@@ -0,0 +1,232 @@
+//! Runs random programs through both VMs and checks the optimizer didn't change what they do.
+
+use proptest::prelude::*;
+
+use super::{OptVM, RuntimeError, SimpleVM, Tape, VmError};
+use crate::io::{Input, SliceInput, VecOutput};
+use crate::OverflowPolicy;
+
+// Enough for most random programs to finish; anything which doesn't is skipped
+const MAX_STEPS: u64 = 20_000;
+
+fn command() -> impl Strategy<Value = String> {
+    prop::sample::select(vec!["+", "-", ">", "<", ".", ","]).prop_map(String::from)
+}
+
+/// Random BF source with balanced brackets
+fn program() -> impl Strategy<Value = String> {
+    let block = command().prop_recursive(4, 64, 8, |inner| {
+        prop_oneof![
+            prop::collection::vec(inner.clone(), 0..8).prop_map(|cmds| cmds.concat()),
+            prop::collection::vec(inner, 0..8).prop_map(|cmds| format!("[{}]", cmds.concat())),
+        ]
+    });
+
+    prop::collection::vec(block, 0..16).prop_map(|blocks| blocks.concat())
+}
+
+fn policy() -> impl Strategy<Value = OverflowPolicy> {
+    prop::sample::select(vec![OverflowPolicy::Wrap, OverflowPolicy::Saturate, OverflowPolicy::Error])
+}
+
+fn check_same_behavior<I>(source: &str, input: I, policy: OverflowPolicy) -> Result<(), TestCaseError>
+where
+    I: Input<InputError = core::convert::Infallible> + Clone + PartialEq + core::fmt::Debug,
+{
+    let mut simple_input = input.clone();
+    let mut simple_output = VecOutput::new();
+    let simple_result = SimpleVM::new(crate::simple_parse(source).unwrap())
+        .with_max_steps(MAX_STEPS)
+        .with_overflow_policy(policy)
+        .run(&mut simple_input, &mut simple_output);
+
+    // The optimizer assumes an infinite tape, so walking off it isn't something it has to
+    // preserve; and a program which doesn't finish has nothing to compare. An overflow has to
+    // happen in both, but if two cells would overflow, it doesn't matter which one goes first
+    // (and for the same reason, the optimized code may walk off the tape first instead).
+    let overflowed = match simple_result {
+        Err(VmError::Runtime(RuntimeError::OutOfBounds { .. })) | Err(VmError::Runtime(RuntimeError::StepLimitExceeded { .. })) => {
+            return Ok(())
+        }
+        Err(VmError::Runtime(RuntimeError::Overflow { .. })) => true,
+        other => {
+            prop_assert_eq!(other, Ok(()));
+            false
+        }
+    };
+
+    let mut opt_input = input;
+    let mut opt_output = VecOutput::new();
+    let options = crate::ParseOptions {
+        policy,
+        ..crate::ParseOptions::default()
+    };
+    let parsed = crate::optimized_parse_with_options(source, &options).unwrap();
+    // Every optimized instruction stands in for at least one simple one, so this is plenty
+    let opt_result = OptVM::new(parsed.code)
+        .with_max_steps(MAX_STEPS)
+        .with_overflow_policy(policy)
+        .run(&mut opt_input, &mut opt_output);
+
+    if overflowed {
+        prop_assert!(
+            matches!(
+                opt_result,
+                Err(VmError::Runtime(RuntimeError::Overflow { .. })) | Err(VmError::Runtime(RuntimeError::OutOfBounds { .. }))
+            ),
+            "Expected an overflow, got {:?}",
+            opt_result
+        );
+    } else {
+        prop_assert_eq!(opt_result, Ok(()));
+    }
+    prop_assert_eq!(simple_output, opt_output);
+    prop_assert_eq!(simple_input, opt_input);
+
+    Ok(())
+}
+
+// Running ahead on the first known bytes of the input, then running what's left on the rest of
+// it, has to do the same as running the whole program on all of it
+fn check_run_ahead_same_behavior(source: &str, input: &[u8], known: usize) -> Result<(), TestCaseError> {
+    let mut simple_output = VecOutput::new();
+    let simple_result = SimpleVM::new(crate::simple_parse(source).unwrap())
+        .with_max_steps(MAX_STEPS)
+        .run(&mut SliceInput::new(input), &mut simple_output);
+    if simple_result.is_err() {
+        return Ok(());
+    }
+
+    let options = crate::ParseOptions {
+        input: Some(&input[..known]),
+        ..crate::ParseOptions::default()
+    };
+    let parsed = crate::optimized_parse_with_options(source, &options).unwrap();
+    let mut opt_output = VecOutput::new();
+    let opt_result = OptVM::new(parsed.code)
+        .with_max_steps(MAX_STEPS)
+        .run(&mut SliceInput::new(&input[parsed.input_used..]), &mut opt_output);
+
+    prop_assert_eq!(opt_result, Ok(()));
+    prop_assert_eq!(simple_output, opt_output);
+
+    Ok(())
+}
+
+// A loop whose condition starts at zero doesn't run, so however the optimizer folds it, it can't
+// touch the tape. blank_tape is off, so the optimizer can't see that and drop the loop outright.
+fn check_skipped_loop_changes_nothing(body: &str, mut cells: Vec<u8>, policy: OverflowPolicy) -> Result<(), TestCaseError> {
+    let source = format!(">>>>[{}]", body);
+    cells[4] = 0;
+
+    let passes = crate::OptPasses {
+        blank_tape: false,
+        dead_tail: false,
+        ..crate::OptPasses::default()
+    };
+    let options = crate::ParseOptions {
+        policy,
+        passes,
+        ..crate::ParseOptions::default()
+    };
+    let parsed = crate::optimized_parse_with_options(&source, &options).unwrap();
+    let mut vm = OptVM::with_initial_tape(parsed.code, &cells)
+        .with_max_steps(MAX_STEPS)
+        .with_overflow_policy(policy);
+
+    let mut output = VecOutput::new();
+    loop {
+        match vm.step(&mut SliceInput::new(b""), &mut output) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(e) => return Err(TestCaseError::fail(format!("{} failed with {:?}", source, e))),
+        }
+    }
+
+    let tape: Vec<u8> = (0..cells.len() + 64).map(|i| vm.tape().get(i)).collect();
+    cells.resize(tape.len(), 0);
+    prop_assert_eq!(tape, cells, "{}", source);
+    prop_assert_eq!(output.into_bytes(), Vec::<u8>::new());
+
+    Ok(())
+}
+
+// The JIT has no step limit, so this only runs programs OptVM finishes (one way or another)
+#[cfg(feature = "jit")]
+fn check_jit_same_behavior(source: &str, input: &[u8]) -> Result<(), TestCaseError> {
+    let code = crate::optimized_parse(source).unwrap();
+
+    let mut opt_input = SliceInput::new(input);
+    let mut opt_output = VecOutput::new();
+    let opt_result = OptVM::new(code.clone())
+        .with_max_steps(MAX_STEPS)
+        .run(&mut opt_input, &mut opt_output);
+    if let Err(VmError::Runtime(RuntimeError::StepLimitExceeded { .. })) = opt_result {
+        return Ok(());
+    }
+
+    let mut jit_input = SliceInput::new(input);
+    let mut jit_output = VecOutput::new();
+    let jit_result = super::JitVM::new(code).run(&mut jit_input, &mut jit_output);
+
+    prop_assert_eq!(opt_result, jit_result);
+    prop_assert_eq!(opt_output, jit_output);
+    prop_assert_eq!(opt_input, jit_input);
+
+    Ok(())
+}
+
+#[cfg(feature = "jit")]
+proptest! {
+    #[test]
+    fn jit_matches_opt(source in program(), input in prop::collection::vec(any::<u8>(), 0..16)) {
+        check_jit_same_behavior(&format!(">>>>{}", source), &input)?;
+    }
+}
+
+proptest! {
+    #[test]
+    fn opt_matches_simple(source in program(), input in prop::collection::vec(any::<u8>(), 0..16), policy in policy()) {
+        // Start a little way in, so fewer programs fall off the left end straight away
+        check_same_behavior(&format!(">>>>{}", source), SliceInput::new(&input), policy)?;
+    }
+}
+
+// Input which never runs out, so programs which read until they get a zero get further
+#[cfg(feature = "rand")]
+proptest! {
+    #[test]
+    fn opt_matches_simple_on_random_input(source in program(), seed in any::<u64>(), policy in policy()) {
+        check_same_behavior(&format!(">>>>{}", source), crate::io::RandomInput::new(seed), policy)?;
+    }
+}
+
+proptest! {
+    #[test]
+    fn run_ahead_matches_simple(source in program(), input in prop::collection::vec(any::<u8>(), 0..16), known in 0..16usize) {
+        check_run_ahead_same_behavior(&format!(">>>>{}", source), &input, known.min(input.len()))?;
+    }
+}
+
+proptest! {
+    #[test]
+    fn skipped_loops_change_nothing(body in program(), cells in prop::collection::vec(any::<u8>(), 16), policy in policy()) {
+        check_skipped_loop_changes_nothing(&body, cells, policy)?;
+    }
+}
+
+#[test]
+fn found_by_fuzzing() {
+    let cases: &[(&str, &[u8])] = &[
+        // const_loop_remove panicked on a loop which never touches its condition
+        ("[<>]", b""),
+        // a singleton loop it couldn't simplify was dropped entirely
+        ("+[><,]", &[0]),
+        // run_simulation thought a shift loop stopped on data[dp], not data[dp + cond_dp_offset]
+        ("<><+[>>]<><<><[++>>-+]+[>]<.", b""),
+    ];
+
+    for (source, input) in cases {
+        check_same_behavior(&format!(">>>>{}", source), SliceInput::new(input), OverflowPolicy::Wrap).unwrap();
+    }
+}
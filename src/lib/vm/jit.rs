@@ -0,0 +1,592 @@
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ffi::c_void;
+use core::mem::ManuallyDrop;
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+
+use cranelift_codegen::ir::condcodes::IntCC;
+use cranelift_codegen::ir::{types, AbiParam, Block, InstBuilder, MemFlags, Type, Value};
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, DataDescription, FuncId, Linkage, Module};
+
+use super::{Operand, RuntimeError, VmError, DEFAULT_TAPE_SIZE};
+use crate::io::{Input, Output};
+use crate::CompiledInstr;
+
+/// Runs optimized code by compiling it to machine code with cranelift first, which is a lot
+/// quicker than OptVM for anything that runs for a while. This is only there with the `jit`
+/// feature, and only runs on the machines cranelift can compile for (x86-64, aarch64, riscv64 and
+/// s390x); anywhere else, run panics.
+///
+/// Cells always wrap, so the code should have been optimized for `OverflowPolicy::Wrap`, and the
+/// tape is a plain buffer of DEFAULT_TAPE_SIZE cells unless it's changed with with_tape_size. As
+/// with OptVM, dp may wander off the tape, but touching a cell that isn't there is an error; every
+/// access is checked, so a bad program can't touch anything but its tape. There's no step limit,
+/// since counting steps is most of the cost of interpreting, and reads past the end of the input
+/// are always 0, as under `EofPolicy::Zero`.
+pub struct JitVM {
+    code: Vec<CompiledInstr>,
+    tape: Vec<u8>,
+}
+
+impl JitVM {
+    pub fn new(code: Vec<CompiledInstr>) -> Self {
+        JitVM {
+            code,
+            tape: vec![0; DEFAULT_TAPE_SIZE],
+        }
+    }
+
+    /// Replace the tape with a blank one of the given length
+    pub fn with_tape_size(mut self, tape_size: usize) -> Self {
+        self.tape = vec![0; tape_size];
+        self
+    }
+
+    pub fn run<I: Input, O: Output>(mut self, input: &mut I, output: &mut O) -> Result<(), VmError<I::InputError, O::OutputError>> {
+        let compiled = Compiled::new::<I, O>(&self.code);
+
+        let mut context = Context {
+            input,
+            output,
+            error: None,
+            panic: None,
+        };
+        let mut fault: isize = 0;
+
+        // Safety: the compiled code only touches cells it has checked are on the tape, and only
+        // calls the callbacks for this I and O, which get the context they expect
+        let status = unsafe {
+            (compiled.entry)(
+                self.tape.as_mut_ptr(),
+                self.tape.len(),
+                &mut context as *mut Context<I, O> as *mut c_void,
+                &mut fault,
+            )
+        };
+
+        if let Some(payload) = context.panic {
+            panic::resume_unwind(payload);
+        }
+
+        match status {
+            DONE => Ok(()),
+            OUT_OF_BOUNDS => Err(RuntimeError::OutOfBounds { dp: fault, role: None }.into()),
+            SOURCE_OUT_OF_BOUNDS => Err(RuntimeError::OutOfBounds {
+                dp: fault,
+                role: Some(Operand::Source),
+            }
+            .into()),
+            TARGET_OUT_OF_BOUNDS => Err(RuntimeError::OutOfBounds {
+                dp: fault,
+                role: Some(Operand::Target),
+            }
+            .into()),
+            INFINITE_LOOP => Err(RuntimeError::InfiniteLoopDetected.into()),
+            WOULD_NOT_TERMINATE => Err(RuntimeError::WouldNotTerminate { dp: fault }.into()),
+            IO_FAILED => Err(context.error.expect("A failed callback leaves its error behind")),
+            other => unreachable!("The compiled code returned the unknown status {}", other),
+        }
+    }
+}
+
+// What the compiled code returns
+const DONE: u32 = 0;
+const OUT_OF_BOUNDS: u32 = 1;
+const SOURCE_OUT_OF_BOUNDS: u32 = 2;
+const TARGET_OUT_OF_BOUNDS: u32 = 3;
+const INFINITE_LOOP: u32 = 4;
+const IO_FAILED: u32 = 5;
+const WOULD_NOT_TERMINATE: u32 = 6;
+
+// The compiled code is called as this; the cell which was off the tape goes in the last argument
+type Entry = unsafe extern "C" fn(tape: *mut u8, tape_len: usize, context: *mut c_void, fault: *mut isize) -> u32;
+
+// The input and output, for the callbacks. The compiled code can't be unwound through, so a panic
+// in the IO is caught, and picked up again once the compiled code has returned.
+struct Context<'a, I: Input, O: Output> {
+    input: &'a mut I,
+    output: &'a mut O,
+    error: Option<VmError<I::InputError, O::OutputError>>,
+    panic: Option<Box<dyn Any + Send>>,
+}
+
+impl<I: Input, O: Output> Context<'_, I, O> {
+    fn call<T, F>(&mut self, f: F) -> Option<T>
+    where
+        F: FnOnce(&mut Self) -> Result<T, VmError<I::InputError, O::OutputError>>,
+    {
+        match panic::catch_unwind(AssertUnwindSafe(|| f(self))) {
+            Ok(Ok(t)) => Some(t),
+            Ok(Err(e)) => {
+                self.error = Some(e);
+                None
+            }
+            Err(payload) => {
+                self.panic = Some(payload);
+                None
+            }
+        }
+    }
+}
+
+// The byte read, or -1 if it failed
+extern "C" fn read_byte<I: Input, O: Output>(context: *mut c_void) -> i32 {
+    let context = unsafe { &mut *(context as *mut Context<I, O>) };
+    match context.call(|c| c.input.read_byte().map_err(VmError::Input)) {
+        Some(byte) => byte as i32,
+        None => -1,
+    }
+}
+
+// 0, or -1 if it failed
+extern "C" fn write_byte<I: Input, O: Output>(context: *mut c_void, byte: u32) -> i32 {
+    let context = unsafe { &mut *(context as *mut Context<I, O>) };
+    match context.call(|c| c.output.write_byte(byte as u8).map_err(VmError::Output)) {
+        Some(()) => 0,
+        None => -1,
+    }
+}
+
+extern "C" fn write_bytes<I: Input, O: Output>(context: *mut c_void, bytes: *const u8, len: usize) -> i32 {
+    let context = unsafe { &mut *(context as *mut Context<I, O>) };
+    let bytes = unsafe { core::slice::from_raw_parts(bytes, len) };
+    match context.call(|c| c.output.write_bytes(bytes).map_err(VmError::Output)) {
+        Some(()) => 0,
+        None => -1,
+    }
+}
+
+// The compiled code for one run, with the callbacks for its input and output types built in;
+// the memory it's in goes when this does
+struct Compiled {
+    module: ManuallyDrop<JITModule>,
+    entry: Entry,
+}
+
+impl Compiled {
+    fn new<I: Input, O: Output>(code: &[CompiledInstr]) -> Self {
+        let mut builder =
+            JITBuilder::with_flags(&[("opt_level", "speed")], default_libcall_names()).expect("cranelift can compile for this machine");
+        builder.symbol("read_byte", read_byte::<I, O> as *const u8);
+        builder.symbol("write_byte", write_byte::<I, O> as *const u8);
+        builder.symbol("write_bytes", write_bytes::<I, O> as *const u8);
+        let mut module = JITModule::new(builder);
+
+        let ptr = module.target_config().pointer_type();
+        let callbacks = Callbacks {
+            read_byte: import(&mut module, "read_byte", &[ptr]),
+            write_byte: import(&mut module, "write_byte", &[ptr, types::I32]),
+            write_bytes: import(&mut module, "write_bytes", &[ptr, ptr, ptr]),
+        };
+
+        let mut ctx = module.make_context();
+        let sig = &mut ctx.func.signature;
+        sig.params.extend(vec![AbiParam::new(ptr); 4]);
+        sig.returns.push(AbiParam::new(types::I32));
+        let id = module
+            .declare_anonymous_function(&ctx.func.signature)
+            .expect("The function is declared once");
+
+        let mut fn_ctx = FunctionBuilderContext::new();
+        let builder = FunctionBuilder::new(&mut ctx.func, &mut fn_ctx);
+        Translator::new(builder, &mut module, &callbacks, ptr).translate(code);
+
+        module.define_function(id, &mut ctx).expect("cranelift compiles what it's given");
+        module.clear_context(&mut ctx);
+        module.finalize_definitions().expect("The callbacks are all there");
+
+        let entry = unsafe { core::mem::transmute::<*const u8, Entry>(module.get_finalized_function(id)) };
+        Compiled {
+            module: ManuallyDrop::new(module),
+            entry,
+        }
+    }
+}
+
+impl Drop for Compiled {
+    fn drop(&mut self) {
+        // Safety: entry is never called again, and isn't running, since run has returned
+        unsafe {
+            ManuallyDrop::take(&mut self.module).free_memory();
+        }
+    }
+}
+
+// A callback which returns an i32, defined in the JITBuilder's symbols
+fn import(module: &mut JITModule, name: &str, params: &[Type]) -> FuncId {
+    let mut sig = module.make_signature();
+    sig.params.extend(params.iter().map(|&ty| AbiParam::new(ty)));
+    sig.returns.push(AbiParam::new(types::I32));
+    module
+        .declare_function(name, Linkage::Import, &sig)
+        .expect("Each callback is declared once")
+}
+
+struct Callbacks {
+    read_byte: FuncId,
+    write_byte: FuncId,
+    write_bytes: FuncId,
+}
+
+// Turns the code into cranelift IR, one block for each ip. dp is a variable, and cranelift works
+// out where it has to live; the arguments are only ever read, so they're used as they come.
+struct Translator<'a> {
+    b: FunctionBuilder<'a>,
+    module: &'a mut JITModule,
+    callbacks: &'a Callbacks,
+    ptr: Type,
+    dp: Variable,
+    tape: Value,
+    tape_len: Value,
+    context: Value,
+    fault: Value,
+}
+
+impl<'a> Translator<'a> {
+    fn new(mut b: FunctionBuilder<'a>, module: &'a mut JITModule, callbacks: &'a Callbacks, ptr: Type) -> Self {
+        let entry = b.create_block();
+        b.append_block_params_for_function_params(entry);
+        b.switch_to_block(entry);
+        let params = b.block_params(entry).to_vec();
+
+        let dp = Variable::from_u32(0);
+        b.declare_var(dp, ptr);
+        let zero = b.ins().iconst(ptr, 0);
+        b.def_var(dp, zero);
+
+        Translator {
+            b,
+            module,
+            callbacks,
+            ptr,
+            dp,
+            tape: params[0],
+            tape_len: params[1],
+            context: params[2],
+            fault: params[3],
+        }
+    }
+
+    fn translate(mut self, code: &[CompiledInstr]) {
+        // One block for each ip, and one for the end
+        let ips: Vec<Block> = (0..=code.len()).map(|_| self.b.create_block()).collect();
+        // These take the cell which was off the tape
+        let out_of_bounds = self.fault_block();
+        let source_out_of_bounds = self.fault_block();
+        let target_out_of_bounds = self.fault_block();
+        let would_not_terminate = self.fault_block();
+        let io_failed = self.b.create_block();
+
+        self.b.ins().jump(ips[0], &[]);
+
+        for (ip, instr) in code.iter().enumerate() {
+            self.b.switch_to_block(ips[ip]);
+            let next = ips[ip + 1];
+
+            match *instr {
+                CompiledInstr::JumpIfZero { cond_dp_offset, target_ip } => {
+                    let cell = self.load(cond_dp_offset, out_of_bounds);
+                    self.b.ins().brif(cell, next, &[], ips[target_ip], &[]);
+                    continue;
+                }
+                CompiledInstr::JumpIfNonzero { cond_dp_offset, target_ip } => {
+                    let cell = self.load(cond_dp_offset, out_of_bounds);
+                    self.b.ins().brif(cell, ips[target_ip], &[], next, &[]);
+                    continue;
+                }
+                CompiledInstr::AddPtr { amount } => self.move_dp(amount, false, out_of_bounds),
+                CompiledInstr::SubPtr { amount } => self.move_dp(amount, true, out_of_bounds),
+                CompiledInstr::AddData { amount, dp_offset } => {
+                    let addr = self.cell_addr(dp_offset, out_of_bounds);
+                    let cell = self.b.ins().load(types::I8, MemFlags::trusted(), addr, 0);
+                    let sum = self.b.ins().iadd_imm(cell, i64::from(amount));
+                    self.b.ins().store(MemFlags::trusted(), sum, addr, 0);
+                }
+                CompiledInstr::SetData { amount, dp_offset } => {
+                    let addr = self.cell_addr(dp_offset, out_of_bounds);
+                    let value = self.b.ins().iconst(types::I8, i64::from(amount));
+                    self.b.ins().store(MemFlags::trusted(), value, addr, 0);
+                }
+                CompiledInstr::AddTwoData {
+                    source_dp_offset,
+                    target_dp_offset,
+                    source_amt_mult,
+                } => {
+                    let source = self.load(source_dp_offset, source_out_of_bounds);
+                    let product = self.b.ins().imul_imm(source, i64::from(source_amt_mult));
+                    let addr = self.cell_addr(target_dp_offset, target_out_of_bounds);
+                    let target = self.b.ins().load(types::I8, MemFlags::trusted(), addr, 0);
+                    let sum = self.b.ins().iadd(target, product);
+                    self.b.ins().store(MemFlags::trusted(), sum, addr, 0);
+                }
+                CompiledInstr::ReadByte { dp_offset } => {
+                    let addr = self.cell_addr(dp_offset, out_of_bounds);
+                    let read = self.call(self.callbacks.read_byte, &[]);
+                    let failed = self.b.ins().icmp_imm(IntCC::SignedLessThan, read, 0);
+                    self.branch_to(failed, io_failed);
+                    let byte = self.b.ins().ireduce(types::I8, read);
+                    self.b.ins().store(MemFlags::trusted(), byte, addr, 0);
+                }
+                CompiledInstr::WriteByte { dp_offset } => {
+                    let addr = self.cell_addr(dp_offset, out_of_bounds);
+                    let byte = self.b.ins().uload8(types::I32, MemFlags::trusted(), addr, 0);
+                    let result = self.call(self.callbacks.write_byte, &[byte]);
+                    self.branch_to(result, io_failed);
+                }
+                CompiledInstr::WriteTransformed { dp_offset, add } => {
+                    let addr = self.cell_addr(dp_offset, out_of_bounds);
+                    let byte = self.b.ins().uload8(types::I32, MemFlags::trusted(), addr, 0);
+                    let sum = self.b.ins().iadd_imm(byte, i64::from(add));
+                    let byte = self.b.ins().band_imm(sum, 0xFF);
+                    let result = self.call(self.callbacks.write_byte, &[byte]);
+                    self.branch_to(result, io_failed);
+                }
+                CompiledInstr::WriteConst { out } => {
+                    let byte = self.b.ins().iconst(types::I32, i64::from(out));
+                    let result = self.call(self.callbacks.write_byte, &[byte]);
+                    self.branch_to(result, io_failed);
+                }
+                CompiledInstr::WriteConstStr { ref bytes } => {
+                    let data = self
+                        .module
+                        .declare_anonymous_data(false, false)
+                        .expect("Anonymous data can always be declared");
+                    let mut description = DataDescription::new();
+                    description.define(bytes.clone().into_boxed_slice());
+                    self.module.define_data(data, &description).expect("The data is defined once");
+                    let global = self.module.declare_data_in_func(data, self.b.func);
+
+                    let bytes_ptr = self.b.ins().global_value(self.ptr, global);
+                    let len = self.b.ins().iconst(self.ptr, bytes.len() as i64);
+                    let result = self.call(self.callbacks.write_bytes, &[bytes_ptr, len]);
+                    self.branch_to(result, io_failed);
+                }
+                CompiledInstr::InfiniteLoop => {
+                    self.exit(INFINITE_LOOP);
+                    continue;
+                }
+                CompiledInstr::HaltIfNonzero { cond_dp_offset } => {
+                    let index = self.cell_index(cond_dp_offset, out_of_bounds);
+                    let addr = self.b.ins().iadd(self.tape, index);
+                    let cell = self.b.ins().load(types::I8, MemFlags::trusted(), addr, 0);
+                    self.b.ins().brif(cell, would_not_terminate, &[index], next, &[]);
+                    continue;
+                }
+                CompiledInstr::ResetTape => {
+                    let zero = self.b.ins().iconst(types::I8, 0);
+                    let config = self.module.target_config();
+                    self.b.call_memset(config, self.tape, zero, self.tape_len);
+                    let start = self.b.ins().iconst(self.ptr, 0);
+                    self.b.def_var(self.dp, start);
+                }
+                // OptVM only logs these, but the cell still has to be on the tape
+                CompiledInstr::AssertEquals { dp_offset, .. } => {
+                    self.cell_index(dp_offset, out_of_bounds);
+                }
+            }
+
+            self.b.ins().jump(next, &[]);
+        }
+
+        self.b.switch_to_block(ips[code.len()]);
+        self.exit(DONE);
+
+        for &(block, status) in &[
+            (out_of_bounds, OUT_OF_BOUNDS),
+            (source_out_of_bounds, SOURCE_OUT_OF_BOUNDS),
+            (target_out_of_bounds, TARGET_OUT_OF_BOUNDS),
+            (would_not_terminate, WOULD_NOT_TERMINATE),
+        ] {
+            self.b.switch_to_block(block);
+            let cell = self.b.block_params(block)[0];
+            self.b.ins().store(MemFlags::trusted(), cell, self.fault, 0);
+            self.exit(status);
+        }
+
+        self.b.switch_to_block(io_failed);
+        self.exit(IO_FAILED);
+
+        self.b.seal_all_blocks();
+        self.b.finalize();
+    }
+
+    // dp + dp_offset, saturating like OptVM does; then off to the block (with that) if it's not
+    // on the tape. As unsigned numbers, negative cells are past the end.
+    fn cell_index(&mut self, dp_offset: isize, off_tape: Block) -> Value {
+        let dp = self.b.use_var(self.dp);
+        let index = if dp_offset == 0 {
+            dp
+        } else {
+            let offset = self.b.ins().iconst(self.ptr, dp_offset as i64);
+            let (sum, overflowed) = self.b.ins().sadd_overflow(dp, offset);
+            let saturated = self.b.ins().iconst(self.ptr, if dp_offset > 0 { i64::MAX } else { i64::MIN });
+            self.b.ins().select(overflowed, saturated, sum)
+        };
+
+        let on_tape = self.b.ins().icmp(IntCC::UnsignedLessThan, index, self.tape_len);
+        let next = self.b.create_block();
+        self.b.ins().brif(on_tape, next, &[], off_tape, &[index]);
+        self.b.switch_to_block(next);
+
+        index
+    }
+
+    fn cell_addr(&mut self, dp_offset: isize, off_tape: Block) -> Value {
+        let index = self.cell_index(dp_offset, off_tape);
+        self.b.ins().iadd(self.tape, index)
+    }
+
+    fn load(&mut self, dp_offset: isize, off_tape: Block) -> Value {
+        let addr = self.cell_addr(dp_offset, off_tape);
+        self.b.ins().load(types::I8, MemFlags::trusted(), addr, 0)
+    }
+
+    // Moves dp, or goes off to the block with the end of isize it went past, if it can't
+    fn move_dp(&mut self, amount: usize, left: bool, off_tape: Block) {
+        let saturated = self.b.ins().iconst(self.ptr, if left { i64::MIN } else { i64::MAX });
+
+        if amount > isize::MAX as usize {
+            let next = self.b.create_block();
+            self.b.ins().jump(off_tape, &[saturated]);
+            self.b.switch_to_block(next);
+            return;
+        }
+
+        let dp = self.b.use_var(self.dp);
+        let amount = self.b.ins().iconst(self.ptr, if left { -(amount as i64) } else { amount as i64 });
+        let (moved, overflowed) = self.b.ins().sadd_overflow(dp, amount);
+        let next = self.b.create_block();
+        self.b.ins().brif(overflowed, off_tape, &[saturated], next, &[]);
+        self.b.switch_to_block(next);
+        self.b.def_var(self.dp, moved);
+    }
+
+    // Calls the callback with the context and then these arguments, and gives back what it returned
+    fn call(&mut self, callback: FuncId, args: &[Value]) -> Value {
+        let callee = self.module.declare_func_in_func(callback, self.b.func);
+        let mut all_args = vec![self.context];
+        all_args.extend_from_slice(args);
+        let call = self.b.ins().call(callee, &all_args);
+        self.b.inst_results(call)[0]
+    }
+
+    // Off to the block if the value is nonzero, otherwise on to the next instruction
+    fn branch_to(&mut self, cond: Value, block: Block) {
+        let next = self.b.create_block();
+        self.b.ins().brif(cond, block, &[], next, &[]);
+        self.b.switch_to_block(next);
+    }
+
+    fn exit(&mut self, status: u32) {
+        let status = self.b.ins().iconst(types::I32, i64::from(status));
+        self.b.ins().return_(&[status]);
+    }
+
+    // A block for failing on a cell, which it takes; filled in at the end
+    fn fault_block(&mut self) -> Block {
+        let block = self.b.create_block();
+        self.b.append_block_param(block, self.ptr);
+        block
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::{SliceInput, VecOutput};
+    use crate::OptVM;
+
+    // With $ for ResetTape, which none of the programs use otherwise
+    fn run_both(source: &str, input: &[u8], tape_size: usize) {
+        let options = crate::ParseOptions {
+            commands: crate::CommandMap {
+                reset_tape: Some('$'),
+                ..crate::CommandMap::default()
+            },
+            ..crate::ParseOptions::default()
+        };
+        let code = crate::optimized_parse_with_options(source, &options).unwrap().code;
+
+        let mut opt_input = SliceInput::new(input);
+        let mut opt_output = VecOutput::new();
+        let opt_result = OptVM::new(code.clone())
+            .with_tape_size(tape_size)
+            .run(&mut opt_input, &mut opt_output);
+
+        let mut jit_input = SliceInput::new(input);
+        let mut jit_output = VecOutput::new();
+        let jit_result = JitVM::new(code).with_tape_size(tape_size).run(&mut jit_input, &mut jit_output);
+
+        assert_eq!(jit_result, opt_result, "{}", source);
+        assert_eq!(jit_output, opt_output, "{}", source);
+        assert_eq!(jit_input, opt_input, "{}", source);
+    }
+
+    #[test]
+    fn jit_runs_like_opt_vm() {
+        run_both(include_str!("../../../input/hello_world.b"), b"", DEFAULT_TAPE_SIZE);
+        run_both(include_str!("../../../input/rot13.b"), b"Hello, World!\n", DEFAULT_TAPE_SIZE);
+        run_both(include_str!("../../../input/squares.b"), b"", DEFAULT_TAPE_SIZE);
+        run_both(",>,<[->[->+>+<<]>>[-<<+>>]<<<]>>.", &[7, 9], DEFAULT_TAPE_SIZE);
+        // Each byte written with 3 taken off, wrapping
+        run_both(",[---.,]", &[1, 2, 3, 255], DEFAULT_TAPE_SIZE);
+        // A reset puts dp back at the start, so the << after it walks off the tape
+        run_both(",>,>,$.<<,.>>>,.", b"abcdef", DEFAULT_TAPE_SIZE);
+    }
+
+    #[test]
+    fn jit_errors_like_opt_vm() {
+        // Off either end of the tape
+        run_both("<+", b"", 10);
+        run_both(">>>>>>>>>>.", b"", 10);
+        run_both("+[>+]", b"", 10);
+        // An AddTwoData with a target off the tape
+        run_both("+[->>>>>>>>>>+<<<<<<<<<<]", b"", 10);
+        // The optimizer knows this one never ends
+        run_both("+[]", b"", 10);
+        run_both(">,[]", b"a", 10);
+        run_both(">,[]", b"\0", 10);
+        // Reading past the end of the input
+        run_both(",,,.", b"ab", 10);
+    }
+
+    struct Broken;
+
+    impl Output for Broken {
+        type OutputError = std::io::Error;
+
+        fn write_byte(&mut self, _byte: u8) -> Result<(), Self::OutputError> {
+            Err(std::io::Error::other("broken"))
+        }
+    }
+
+    #[test]
+    fn io_errors_come_back_out() {
+        let code = crate::optimized_parse("+.").unwrap();
+        match JitVM::new(code).run(&mut SliceInput::new(b""), &mut Broken) {
+            Err(VmError::Output(e)) => assert_eq!(e.to_string(), "broken"),
+            other => panic!("Expected an output error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "boom")]
+    fn panics_come_back_out() {
+        struct Boom;
+
+        impl Output for Boom {
+            type OutputError = core::convert::Infallible;
+
+            fn write_byte(&mut self, _byte: u8) -> Result<(), Self::OutputError> {
+                panic!("boom")
+            }
+        }
+
+        let code = crate::optimized_parse(",.").unwrap();
+        let _ = JitVM::new(code).run(&mut SliceInput::new(b"a"), &mut Boom);
+    }
+}
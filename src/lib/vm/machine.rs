@@ -0,0 +1,120 @@
+use super::{PointerMode, RuntimeError, Tape, VecTape, DEFAULT_TAPE_SIZE};
+use crate::io::EofPolicy;
+use crate::OverflowPolicy;
+
+/// Everything a VM has besides its code: the tape, the data pointer, and the limits and policies
+/// it runs under. SimpleVM and OptVM both keep one of these, so they can't disagree about which
+/// cells are on the tape, what overflows, or when the step limit is hit.
+pub(crate) struct Machine<T: Tape> {
+    pub(crate) tape: T,
+    pub(crate) dp: isize,
+
+    pub(crate) steps: u64,
+    pub(crate) max_steps: Option<u64>,
+    pub(crate) overflow: OverflowPolicy,
+    pub(crate) pointer: PointerMode,
+    pub(crate) eof: EofPolicy,
+}
+
+impl Machine<VecTape> {
+    pub(crate) fn new() -> Self {
+        Machine {
+            tape: VecTape::new(DEFAULT_TAPE_SIZE),
+            dp: 0,
+            steps: 0,
+            max_steps: None,
+            overflow: OverflowPolicy::Wrap,
+            pointer: PointerMode::Bounded,
+            eof: EofPolicy::Zero,
+        }
+    }
+}
+
+impl<T: Tape> Machine<T> {
+    pub(crate) fn with_tape<U: Tape>(self, tape: U) -> Machine<U> {
+        Machine {
+            tape,
+            dp: self.dp,
+            steps: self.steps,
+            max_steps: self.max_steps,
+            overflow: self.overflow,
+            pointer: self.pointer,
+            eof: self.eof,
+        }
+    }
+
+    // Counts an instruction about to be executed, if there's budget left for it
+    pub(crate) fn take_step(&mut self) -> Result<(), RuntimeError> {
+        if let Some(max_steps) = self.max_steps {
+            if self.steps >= max_steps {
+                return Err(RuntimeError::StepLimitExceeded { max_steps });
+            }
+        }
+        self.steps += 1;
+        Ok(())
+    }
+
+    // Moves dp according to the pointer mode; a move so far that dp can't even represent it is
+    // certainly off the tape
+    pub(crate) fn move_ptr(&mut self, amount: usize, left: bool) -> Result<(), RuntimeError> {
+        match self.pointer.move_dp(self.dp, amount, left, self.tape.len()) {
+            Some(dp) => {
+                self.dp = dp;
+                Ok(())
+            }
+            None => Err(RuntimeError::OutOfBounds {
+                dp: if left { isize::MIN } else { isize::MAX },
+                role: None,
+            }),
+        }
+    }
+
+    // The index of data[dp + dp_offset], if that's on the tape
+    pub(crate) fn cell_index(&self, dp_offset: isize) -> Result<usize, RuntimeError> {
+        self.pointer.cell_index(self.dp, dp_offset, self.tape.len())
+    }
+
+    // cell_index(0), skipping the offset arithmetic when dp is on the tape, which it almost
+    // always is; most adds and sets are to dp itself, so this is worth having in the hot loop
+    #[inline]
+    pub(crate) fn cell_index_at_dp(&self) -> Result<usize, RuntimeError> {
+        if self.dp >= 0 && (self.dp as usize) < self.tape.len() {
+            Ok(self.dp as usize)
+        } else {
+            self.cell_index(0)
+        }
+    }
+
+    // What the cell at this index would be with a signed amount added, according to the overflow
+    // policy; the VM does the writing, since OptVM has watches to tell
+    pub(crate) fn added(&self, cell: usize, amount: u8) -> Result<u8, RuntimeError> {
+        self.added_to(cell, self.tape.get(cell), amount)
+    }
+
+    // The same as added, for source * mult rather than a fixed amount
+    pub(crate) fn added_product(&self, cell: usize, source: usize, mult: u8) -> Result<u8, RuntimeError> {
+        self.added_product_to(cell, self.tape.get(cell), self.tape.get(source), mult)
+    }
+
+    // The same as added, where the cell's value isn't on the tape, as for an OptVM IO port
+    pub(crate) fn added_to(&self, cell: usize, value: u8, amount: u8) -> Result<u8, RuntimeError> {
+        self.overflow.add(value, amount).ok_or(RuntimeError::Overflow { dp: cell as isize })
+    }
+
+    // The same as added_product, where neither value need be on the tape
+    pub(crate) fn added_product_to(&self, cell: usize, value: u8, source_value: u8, mult: u8) -> Result<u8, RuntimeError> {
+        self.overflow
+            .add_product(value, source_value, mult)
+            .ok_or(RuntimeError::Overflow { dp: cell as isize })
+    }
+
+    // What a read into the cell at this index gives when the input has run out, according to the
+    // EOF policy; None means the program should stop there
+    pub(crate) fn eof_read(&self, cell: usize) -> Result<Option<u8>, RuntimeError> {
+        match self.eof {
+            EofPolicy::Zero => Ok(Some(0)),
+            EofPolicy::Error => Err(RuntimeError::UnexpectedEof { dp: cell as isize }),
+            EofPolicy::Halt => Ok(None),
+        }
+    }
+}
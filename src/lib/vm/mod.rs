@@ -0,0 +1,31 @@
+mod dry_run;
+mod error;
+#[cfg(feature = "jit")]
+mod jit;
+mod machine;
+mod opt_vm;
+#[cfg(feature = "simple")]
+mod simple_vm;
+mod tape;
+mod threaded;
+
+pub use self::dry_run::DryRunReport;
+pub use self::error::{BadJumpTarget, Operand, RuntimeError, VmError};
+#[cfg(feature = "jit")]
+pub use self::jit::JitVM;
+pub use self::opt_vm::{OptVM, VmState, WatchEvent};
+#[cfg(feature = "simple")]
+pub use self::simple_vm::SimpleVM;
+pub use self::tape::{FixedTape, GrowableTape, PointerMode, Tape, UsageTape, VecTape};
+pub use self::threaded::ThreadedVM;
+
+/// How many cells the VMs get, unless they're told otherwise
+pub const DEFAULT_TAPE_SIZE: usize = 30_000;
+
+// How many instructions run_with_timeout executes between looks at the clock; reading it is
+// much slower than an instruction, so it can't be every time
+#[cfg(feature = "std")]
+const CLOCK_CHECK_INTERVAL: u64 = 65_536;
+
+#[cfg(all(test, feature = "simple"))]
+mod fuzz;
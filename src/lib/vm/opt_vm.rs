@@ -0,0 +1,723 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use core::time::Duration;
+
+use super::dry_run::ZeroInput;
+use super::machine::Machine;
+use super::{BadJumpTarget, DryRunReport, Operand, PointerMode, RuntimeError, Tape, VecTape, VmError, DEFAULT_TAPE_SIZE};
+use crate::io::{EofPolicy, Input, Output, VecOutput};
+use crate::OverflowPolicy;
+use crate::{CompiledInstr, OffsetBounds, SourceMap};
+
+/// As with SimpleVM, dp may wander off the tape, but touching a cell that isn't there is an error.
+/// This can't be checked when the pointer moves, because the optimizer folds pointer moves into
+/// the offsets of the commands around them, so dp here isn't the same as dp in the source.
+/// With PointerMode::Wrapping, the tape is a circle instead.
+///
+/// The tape is a VecTape unless it's swapped out with with_tape.
+pub struct OptVM<T: Tape = VecTape> {
+    instr: Vec<CompiledInstr>,
+    ip: usize,
+    machine: Machine<T>,
+    watches: Vec<(usize, WatchCallback)>,
+    ports: Vec<(usize, PortRead, PortWrite)>,
+    source_map: Option<SourceMap>,
+}
+
+type WatchCallback = Box<dyn FnMut(WatchEvent)>;
+type PortRead = Box<dyn FnMut() -> u8>;
+type PortWrite = Box<dyn FnMut(u8)>;
+
+/// A write to a watched cell; see OptVM::watch
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub struct WatchEvent {
+    pub cell: usize,
+    pub old: u8,
+    pub new: u8,
+    // The ip of the (optimized) instruction doing the write
+    pub ip: usize,
+}
+
+/// Where an OptVM is up to, from OptVM::checkpoint: the instruction it's on, the data pointer,
+/// the tape, and how many steps it's taken, but not its code or settings.
+#[derive(Clone)]
+pub struct VmState<T: Tape = VecTape> {
+    ip: usize,
+    dp: isize,
+    steps: u64,
+    tape: T,
+}
+
+impl OptVM {
+    /// The code is trusted to be well formed, as it is coming out of the optimizer; code from
+    /// anywhere else should go through try_new.
+    pub fn new(code: Vec<CompiledInstr>) -> Self {
+        Self {
+            instr: code,
+            ip: 0,
+            machine: Machine::new(),
+            watches: Vec::new(),
+            ports: Vec::new(),
+            source_map: None,
+        }
+    }
+
+    /// The same as new, but first checks that every jump lands inside the code (or just past the
+    /// end of it, which ends the program), for code which didn't come straight from the
+    /// optimizer and might be corrupt. A jump anywhere else would just stop the program, as if
+    /// it had finished.
+    pub fn try_new(code: Vec<CompiledInstr>) -> Result<Self, BadJumpTarget> {
+        for (ip, instr) in code.iter().enumerate() {
+            if let CompiledInstr::JumpIfZero { target_ip, .. } | CompiledInstr::JumpIfNonzero { target_ip, .. } = *instr {
+                if target_ip > code.len() {
+                    return Err(BadJumpTarget { ip, target_ip });
+                }
+            }
+        }
+        Ok(OptVM::new(code))
+    }
+
+    /// A VM whose tape starts out with these cells (then zeros, out to DEFAULT_TAPE_SIZE if
+    /// there are fewer), for running a routine on data that's already there. The code can't have
+    /// been optimized for a blank tape (see OptPasses::blank_tape), nor, if the tape is looked at
+    /// afterwards, with OptPasses::dead_tail.
+    pub fn with_initial_tape(code: Vec<CompiledInstr>, cells: &[u8]) -> Self {
+        let mut vm = OptVM::new(code).with_tape_size(cells.len().max(DEFAULT_TAPE_SIZE));
+        vm.set_tape(cells, 0).expect("The tape was made big enough");
+        vm
+    }
+
+    /// A VM whose cells all start at value, for dialects which don't start from zero. The
+    /// optimizer assumes a zeroed tape unless it's told otherwise, so the code has to have been
+    /// optimized with OptPasses::blank_tape off. ResetTape still zeroes the tape, and
+    /// with_tape_size afterwards gives back a zeroed one.
+    pub fn with_fill(code: Vec<CompiledInstr>, value: u8) -> Self {
+        OptVM::new(code).with_tape(VecTape::filled(DEFAULT_TAPE_SIZE, value))
+    }
+}
+
+impl<T: Tape> OptVM<T> {
+    /// Replace the tape with a blank one of the given length
+    pub fn with_tape_size(self, tape_size: usize) -> OptVM<VecTape> {
+        self.with_tape(VecTape::new(tape_size))
+    }
+
+    /// Replace the tape with the given one
+    pub fn with_tape<U: Tape>(self, tape: U) -> OptVM<U> {
+        OptVM {
+            instr: self.instr,
+            ip: self.ip,
+            machine: self.machine.with_tape(tape),
+            watches: self.watches,
+            ports: self.ports,
+            source_map: self.source_map,
+        }
+    }
+
+    pub fn tape(&self) -> &T {
+        &self.machine.tape
+    }
+
+    /// The lowest and highest cells the program has read or written so far, if the tape keeps
+    /// track (see UsageTape); for working out how big a tape it needs.
+    pub fn tape_usage(&self) -> Option<(usize, usize)> {
+        self.machine.tape.usage()
+    }
+
+    /// Writes these cells to the start of the tape (leaving the rest as it is) and puts dp at
+    /// start_dp, before running. As with with_initial_tape, the code can't have been optimized
+    /// for a blank tape. Fails with OutOfBounds if the cells don't fit on the tape.
+    pub fn set_tape(&mut self, cells: &[u8], start_dp: isize) -> Result<(), RuntimeError> {
+        if cells.len() > self.machine.tape.len() {
+            return Err(RuntimeError::OutOfBounds {
+                dp: cells.len() as isize - 1,
+                role: None,
+            });
+        }
+
+        for (i, &cell) in cells.iter().enumerate() {
+            self.machine.tape.set(i, cell);
+        }
+        self.machine.dp = start_dp;
+        Ok(())
+    }
+
+    /// Where in the source each instruction came from, from Parsed::source_map, so that
+    /// code_p can say where the program went wrong
+    pub fn with_source_map(mut self, source_map: SourceMap) -> Self {
+        self.source_map = Some(source_map);
+        self
+    }
+
+    /// Where in the source the next instruction to run came from, which after step fails is the
+    /// one that failed; None without a source map, once the program has finished, or if the
+    /// instruction came from nowhere in particular
+    pub fn code_p(&self) -> Option<usize> {
+        self.source_map.as_ref()?.get(self.ip).copied().flatten()
+    }
+
+    /// Give up with StepLimitExceeded once this many instructions have been executed
+    pub fn with_max_steps(mut self, max_steps: u64) -> Self {
+        self.machine.max_steps = Some(max_steps);
+        self
+    }
+
+    /// Check up front that every cell in the given bounds (relative to the current dp, as from
+    /// Parsed::bounds) is on the tape. This is conservative: the bounds include
+    /// cells which are only touched on paths that might never run, so a program can fail this
+    /// and still run fine.
+    pub fn check_bounds(&self, (min_offset, max_offset): OffsetBounds) -> Result<(), RuntimeError> {
+        self.machine.cell_index(min_offset)?;
+        self.machine.cell_index(max_offset)?;
+        Ok(())
+    }
+
+    /// What to do when a cell goes past 0 or 255. This has to match what the code was
+    /// optimized for, if it was.
+    pub fn with_overflow_policy(mut self, overflow: OverflowPolicy) -> Self {
+        self.machine.overflow = overflow;
+        self
+    }
+
+    /// What to do when the data pointer goes past either end of the tape (see PointerMode)
+    pub fn with_pointer_mode(mut self, pointer: PointerMode) -> Self {
+        self.machine.pointer = pointer;
+        self
+    }
+
+    /// What to do when the program reads past the end of its input (see EofPolicy)
+    pub fn with_eof_policy(mut self, eof: EofPolicy) -> Self {
+        self.machine.eof = eof;
+        self
+    }
+
+    /// Call the callback whenever something writes to the cell with this index on the tape,
+    /// whether or not that changes it. Since the optimizer merges and removes writes, this sees
+    /// the writes the optimized code does, which needn't be the ones in the source.
+    pub fn watch<F: FnMut(WatchEvent) + 'static>(&mut self, cell: usize, callback: F) {
+        self.watches.push((cell, Box::new(callback)));
+    }
+
+    /// Make the cell with this index on the tape an IO port: whenever an instruction reads it,
+    /// read is called for its value, and whenever one writes it, write is called with the new
+    /// value, and the tape itself is left alone; an add does both. Mapping a cell again replaces
+    /// its callbacks. The optimizer assumes a cell holds what was last written to it, and merges,
+    /// drops and constant-folds reads and writes on that basis, so code for a device should be
+    /// optimized with OptPasses::none(), or the device sees something quite unlike the source.
+    /// Watches don't see writes to ports, nor do AssertEquals read them.
+    pub fn map_io_port<R: FnMut() -> u8 + 'static, W: FnMut(u8) + 'static>(&mut self, cell: usize, read: R, write: W) {
+        self.ports.retain(|(mapped, _, _)| *mapped != cell);
+        self.ports.push((cell, Box::new(read), Box::new(write)));
+    }
+
+    // The value of the cell, which is on the tape unless it's a port; without any ports, this
+    // only costs checking an empty Vec
+    fn get_cell(&mut self, cell: usize) -> u8 {
+        if !self.ports.is_empty() {
+            if let Some((_, read, _)) = self.ports.iter_mut().find(|(mapped, _, _)| *mapped == cell) {
+                return read();
+            }
+        }
+        self.machine.tape.get(cell)
+    }
+
+    fn set_cell(&mut self, cell: usize, new: u8) {
+        if !self.ports.is_empty() {
+            if let Some((_, _, write)) = self.ports.iter_mut().find(|(mapped, _, _)| *mapped == cell) {
+                write(new);
+                return;
+            }
+        }
+        if !self.watches.is_empty() {
+            let old = self.machine.tape.get(cell);
+            let ip = self.ip;
+            for (_, callback) in self.watches.iter_mut().filter(|(watched, _)| *watched == cell) {
+                callback(WatchEvent { cell, old, new, ip });
+            }
+        }
+        self.machine.tape.set(cell, new);
+    }
+
+    // cell_index, for one of the two cells of an instruction which touches two
+    fn operand_index(&self, dp_offset: isize, operand: Operand) -> Result<usize, RuntimeError> {
+        self.machine.cell_index(dp_offset).map_err(|e| match e {
+            RuntimeError::OutOfBounds { dp, .. } => RuntimeError::OutOfBounds { dp, role: Some(operand) },
+            e => e,
+        })
+    }
+
+    pub fn run<I: Input, O: Output>(mut self, input: &mut I, output: &mut O) -> Result<(), VmError<I::InputError, O::OutputError>> {
+        self.execute(input, output)
+    }
+
+    /// Like run, but gives up with RuntimeError::Timeout once the program has been running for
+    /// longer than the timeout. The clock is only checked every so often, so this can overshoot
+    /// by a little.
+    #[cfg(feature = "std")]
+    pub fn run_with_timeout<I: Input, O: Output>(
+        mut self,
+        input: &mut I,
+        output: &mut O,
+        timeout: Duration,
+    ) -> Result<(), VmError<I::InputError, O::OutputError>> {
+        let start = std::time::Instant::now();
+
+        while self.ip < self.instr.len() {
+            self.execute_instr(input, output)?;
+            if self.machine.steps.is_multiple_of(super::CLOCK_CHECK_INTERVAL) && start.elapsed() > timeout {
+                return Err(RuntimeError::Timeout { timeout }.into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Run the program with no input (every read is EOF) for at most max_steps instructions,
+    /// collecting what it writes rather than sending it anywhere. Errors go in the report too.
+    pub fn dry_run(mut self, max_steps: u64) -> DryRunReport {
+        self.machine.max_steps = Some(max_steps);
+        let mut input = ZeroInput::default();
+        let mut output = VecOutput::new();
+        let result = self.execute(&mut input, &mut output);
+        DryRunReport::new(result, self.machine.steps, input, output)
+    }
+
+    fn execute<I: Input, O: Output>(&mut self, input: &mut I, output: &mut O) -> Result<(), VmError<I::InputError, O::OutputError>> {
+        while self.ip < self.instr.len() {
+            self.execute_instr(input, output)?;
+        }
+
+        log!("Process took {} instructions", self.machine.steps);
+        if let Some((lowest, highest)) = self.machine.tape.usage() {
+            log!("Used cells {} to {}", lowest, highest);
+        }
+
+        Ok(())
+    }
+
+    /// Execute a single instruction. Returns false (without doing anything) if the program
+    /// has already finished.
+    pub fn step<I: Input, O: Output>(&mut self, input: &mut I, output: &mut O) -> Result<bool, VmError<I::InputError, O::OutputError>> {
+        if self.ip >= self.instr.len() {
+            return Ok(false);
+        }
+        self.execute_instr(input, output)?;
+        Ok(true)
+    }
+
+    /// Go back to a state saved by checkpoint, which has to have come from this program. The
+    /// watches aren't told about the cells this changes.
+    pub fn restore(&mut self, state: VmState<T>) {
+        self.ip = state.ip;
+        self.machine.dp = state.dp;
+        self.machine.steps = state.steps;
+        self.machine.tape = state.tape;
+    }
+
+    // Execute the instruction at ip, which has to exist
+    fn execute_instr<I: Input, O: Output>(&mut self, input: &mut I, output: &mut O) -> Result<(), VmError<I::InputError, O::OutputError>> {
+        self.machine.take_step()?;
+        match self.instr[self.ip] {
+            CompiledInstr::JumpIfNonzero { target_ip, cond_dp_offset } => {
+                let actual_dp = self.machine.cell_index(cond_dp_offset)?;
+                if self.get_cell(actual_dp) != 0 {
+                    self.ip = target_ip;
+                } else {
+                    self.ip += 1;
+                }
+            }
+            CompiledInstr::JumpIfZero { target_ip, cond_dp_offset } => {
+                let actual_dp = self.machine.cell_index(cond_dp_offset)?;
+                if self.get_cell(actual_dp) == 0 {
+                    self.ip = target_ip;
+                } else {
+                    self.ip += 1;
+                }
+            }
+            CompiledInstr::AddData { amount, dp_offset } => {
+                let local_dp = if dp_offset == 0 {
+                    self.machine.cell_index_at_dp()?
+                } else {
+                    self.machine.cell_index(dp_offset)?
+                };
+                let old_val = self.get_cell(local_dp);
+                let new_val = self.machine.added_to(local_dp, old_val, amount)?;
+                self.set_cell(local_dp, new_val);
+                self.ip += 1;
+            }
+            CompiledInstr::SetData { amount, dp_offset } => {
+                let local_dp = if dp_offset == 0 {
+                    self.machine.cell_index_at_dp()?
+                } else {
+                    self.machine.cell_index(dp_offset)?
+                };
+                self.set_cell(local_dp, amount);
+                self.ip += 1;
+            }
+            CompiledInstr::AddTwoData {
+                source_dp_offset,
+                target_dp_offset,
+                source_amt_mult,
+            } => {
+                let source_dp = self.operand_index(source_dp_offset, Operand::Source)?;
+                let target_dp = self.operand_index(target_dp_offset, Operand::Target)?;
+
+                let source_val = self.get_cell(source_dp);
+                let old_val = self.get_cell(target_dp);
+                let new_val = self.machine.added_product_to(target_dp, old_val, source_val, source_amt_mult)?;
+                self.set_cell(target_dp, new_val);
+                self.ip += 1;
+            }
+            CompiledInstr::AddPtr { amount } => {
+                self.machine.move_ptr(amount, false)?;
+                self.ip += 1;
+            }
+            CompiledInstr::SubPtr { amount } => {
+                self.machine.move_ptr(amount, true)?;
+                self.ip += 1;
+            }
+            CompiledInstr::ReadByte { dp_offset } => {
+                let actual_dp = self.machine.cell_index(dp_offset)?;
+                let read = match input.read_byte_or_eof().map_err(VmError::Input)? {
+                    Some(byte) => byte,
+                    None => match self.machine.eof_read(actual_dp)? {
+                        Some(byte) => byte,
+                        None => {
+                            self.ip = self.instr.len();
+                            return Ok(());
+                        }
+                    },
+                };
+                self.set_cell(actual_dp, read);
+                self.ip += 1;
+            }
+            CompiledInstr::WriteByte { dp_offset } => {
+                let actual_dp = self.machine.cell_index(dp_offset)?;
+                let write = self.get_cell(actual_dp);
+                output.write_byte(write).map_err(VmError::Output)?;
+                self.ip += 1;
+            }
+            CompiledInstr::WriteTransformed { dp_offset, add } => {
+                let actual_dp = self.machine.cell_index(dp_offset)?;
+                let val = self.get_cell(actual_dp);
+                let write = self.machine.added_to(actual_dp, val, add)?;
+                output.write_byte(write).map_err(VmError::Output)?;
+                self.ip += 1;
+            }
+            CompiledInstr::WriteConst { out } => {
+                output.write_byte(out).map_err(VmError::Output)?;
+                self.ip += 1;
+            }
+            CompiledInstr::WriteConstStr { ref bytes } => {
+                output.write_bytes(bytes).map_err(VmError::Output)?;
+                self.ip += 1;
+            }
+            CompiledInstr::InfiniteLoop => {
+                return Err(RuntimeError::InfiniteLoopDetected.into());
+            }
+            CompiledInstr::HaltIfNonzero { cond_dp_offset } => {
+                let actual_dp = self.machine.cell_index(cond_dp_offset)?;
+                if self.get_cell(actual_dp) != 0 {
+                    return Err(RuntimeError::WouldNotTerminate { dp: actual_dp as isize }.into());
+                }
+                self.ip += 1;
+            }
+            // Watched cells and ports see this as a write of zero, like any other
+            CompiledInstr::ResetTape => {
+                let mut written: Vec<usize> = self
+                    .watches
+                    .iter()
+                    .map(|(cell, _)| *cell)
+                    .chain(self.ports.iter().map(|(cell, _, _)| *cell))
+                    .filter(|&cell| cell < self.machine.tape.len())
+                    .collect();
+                written.sort_unstable();
+                written.dedup();
+                for cell in written {
+                    self.set_cell(cell, 0);
+                }
+                self.machine.tape.clear();
+                self.machine.dp = 0;
+                self.ip += 1;
+            }
+            CompiledInstr::AssertEquals { dp_offset, val } => {
+                let actual_dp = self.machine.cell_index(dp_offset)?;
+                if val != self.machine.tape.get(actual_dp) {
+                    log!(
+                        "AssertErr: data[{}] is {}; expected {}:",
+                        actual_dp,
+                        self.machine.tape.get(actual_dp),
+                        val
+                    );
+                }
+                self.ip += 1;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<T: Tape + Clone> OptVM<T> {
+    /// Save where the VM is up to, to go back to with restore, e.g. to step backward in a
+    /// debugger. This copies the whole tape, so it isn't cheap on a big one.
+    pub fn checkpoint(&self) -> VmState<T> {
+        VmState {
+            ip: self.ip,
+            dp: self.machine.dp,
+            steps: self.machine.steps,
+            tape: self.machine.tape.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::SliceInput;
+    use alloc::rc::Rc;
+    use core::cell::RefCell;
+
+    #[test]
+    fn watched_cells_report_writes() {
+        let events = Rc::new(RefCell::new(Vec::new()));
+
+        // Reads into cell 1, then adds it into cell 2 twice over, and writes that
+        let mut vm = OptVM::new(crate::optimized_parse(">,[->++<]>.").unwrap());
+        let seen = Rc::clone(&events);
+        vm.watch(2, move |event| seen.borrow_mut().push(event));
+        vm.run(&mut SliceInput::new(&[3]), &mut VecOutput::new()).unwrap();
+
+        let events = events.borrow();
+        assert_eq!(events.len(), 1);
+        assert_eq!((events[0].cell, events[0].old, events[0].new), (2, 0, 6));
+    }
+
+    #[test]
+    fn restoring_goes_back_to_the_checkpoint() {
+        let mut vm = OptVM::new(crate::optimized_parse(",[.-]+++.").unwrap());
+        let mut input = SliceInput::new(&[3]);
+
+        let mut output = VecOutput::new();
+        while vm.step(&mut input, &mut output).unwrap() && output.as_bytes().is_empty() {}
+        let checkpoint = vm.checkpoint();
+
+        let rest = |vm: &mut OptVM, input: &mut SliceInput| {
+            let mut output = VecOutput::new();
+            while vm.step(input, &mut output).unwrap() {}
+            output.into_bytes()
+        };
+
+        let first = rest(&mut vm, &mut input);
+        vm.restore(checkpoint);
+        let second = rest(&mut vm, &mut input);
+
+        assert_eq!(first, vec![2, 1, 3]);
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn routines_run_on_a_seeded_tape() {
+        let parse = |source, blank_tape| {
+            let passes = crate::OptPasses {
+                blank_tape,
+                dead_tail: false,
+                ..crate::OptPasses::default()
+            };
+            let options = crate::ParseOptions {
+                passes,
+                ..crate::ParseOptions::default()
+            };
+            crate::optimized_parse_with_options(source, &options).unwrap().code
+        };
+        let finish = |mut vm: OptVM| {
+            while vm.step(&mut SliceInput::new(b""), &mut VecOutput::new()).unwrap() {}
+            (vm.tape().get(0), vm.tape().get(1))
+        };
+
+        // Adds cell 0 into cell 1; on a blank tape, that's nothing at all
+        assert!(parse("[->+<]", true).is_empty());
+        let code = parse("[->+<]", false);
+        assert_eq!(finish(OptVM::with_initial_tape(code, &[5, 2])), (0, 7));
+
+        // Adds the cell at dp into the one on its left
+        let mut vm = OptVM::new(parse("[-<+>]", false)).with_tape_size(2);
+        vm.set_tape(&[3, 4], 1).unwrap();
+        assert_eq!(finish(vm), (7, 0));
+        assert!(OptVM::new(Vec::new()).with_tape_size(2).set_tape(&[1, 2, 3], 0).is_err());
+    }
+
+    #[test]
+    fn tapes_can_start_filled() {
+        let source = ">+.<[.-]";
+        let run = |code| {
+            let mut output = VecOutput::new();
+            OptVM::with_fill(code, 3).run(&mut SliceInput::new(b""), &mut output).unwrap();
+            output.into_bytes()
+        };
+
+        let passes = crate::OptPasses {
+            blank_tape: false,
+            ..crate::OptPasses::default()
+        };
+        let options = crate::ParseOptions {
+            passes,
+            ..crate::ParseOptions::default()
+        };
+        let code = crate::optimized_parse_with_options(source, &options).unwrap().code;
+        assert_eq!(run(code), vec![4, 3, 2, 1]);
+
+        // Optimized for a zeroed tape, the loop is gone and the add is folded into a constant
+        assert_eq!(run(crate::optimized_parse(source).unwrap()), vec![1]);
+
+        #[cfg(feature = "simple")]
+        {
+            let mut output = VecOutput::new();
+            crate::SimpleVM::with_fill(crate::simple_parse(source).unwrap(), 3)
+                .run(&mut SliceInput::new(b""), &mut output)
+                .unwrap();
+            assert_eq!(output.into_bytes(), vec![4, 3, 2, 1]);
+        }
+    }
+
+    #[test]
+    fn jumps_past_the_end_are_rejected() {
+        let code = crate::optimized_parse(",[.,]").unwrap();
+        assert!(OptVM::try_new(code.clone()).is_ok());
+
+        let mut bad = code;
+        let last = bad.len() - 1;
+        bad[last] = CompiledInstr::JumpIfNonzero {
+            cond_dp_offset: 0,
+            target_ip: 40,
+        };
+        assert_eq!(OptVM::try_new(bad).err(), Some(BadJumpTarget { ip: last, target_ip: 40 }));
+
+        // Jumping to just past the end is how a program finishes
+        let end = vec![CompiledInstr::JumpIfZero {
+            cond_dp_offset: 0,
+            target_ip: 1,
+        }];
+        assert!(OptVM::try_new(end).is_ok());
+    }
+
+    #[test]
+    fn spinning_names_the_cell() {
+        // If the byte read in is nonzero, this loop never ends
+        let code = crate::optimized_parse(">>,[]").unwrap();
+        assert_eq!(code[1], CompiledInstr::HaltIfNonzero { cond_dp_offset: 2 });
+
+        let run = |input: &[u8]| OptVM::new(code.clone()).run(&mut SliceInput::new(input), &mut VecOutput::new());
+        assert_eq!(run(b"\0"), Ok(()));
+        assert_eq!(run(b"a"), Err(VmError::Runtime(RuntimeError::WouldNotTerminate { dp: 2 })));
+    }
+
+    #[test]
+    fn out_of_range_operands_are_named() {
+        let run = |source_dp_offset, target_dp_offset| {
+            let code = vec![CompiledInstr::AddTwoData {
+                source_dp_offset,
+                target_dp_offset,
+                source_amt_mult: 1,
+            }];
+            OptVM::new(code)
+                .with_tape_size(10)
+                .run(&mut SliceInput::new(b""), &mut VecOutput::new())
+        };
+
+        assert_eq!(run(3, 4), Ok(()));
+        assert_eq!(
+            run(-1, 4),
+            Err(VmError::Runtime(RuntimeError::OutOfBounds {
+                dp: -1,
+                role: Some(Operand::Source)
+            }))
+        );
+        assert_eq!(
+            run(3, 10),
+            Err(VmError::Runtime(RuntimeError::OutOfBounds {
+                dp: 10,
+                role: Some(Operand::Target)
+            }))
+        );
+    }
+
+    #[test]
+    fn eof_is_handled_by_policy() {
+        let run = |eof, input: &[u8]| {
+            let mut output = VecOutput::new();
+            let result = OptVM::new(crate::optimized_parse(">+.,.+.").unwrap())
+                .with_eof_policy(eof)
+                .run(&mut SliceInput::new(input), &mut output);
+            (result, output.into_bytes())
+        };
+
+        // With input left, the policy makes no difference
+        for eof in [EofPolicy::Zero, EofPolicy::Error, EofPolicy::Halt] {
+            assert_eq!(run(eof, b"A"), (Ok(()), vec![1, 65, 66]));
+        }
+
+        assert_eq!(run(EofPolicy::Zero, b""), (Ok(()), vec![1, 0, 1]));
+        assert_eq!(
+            run(EofPolicy::Error, b""),
+            (Err(VmError::Runtime(RuntimeError::UnexpectedEof { dp: 1 })), vec![1])
+        );
+        assert_eq!(run(EofPolicy::Halt, b""), (Ok(()), vec![1]));
+
+        #[cfg(feature = "simple")]
+        {
+            let run_simple = |eof| {
+                let mut output = VecOutput::new();
+                let result = crate::SimpleVM::new(crate::simple_parse(">+.,.+.").unwrap())
+                    .with_eof_policy(eof)
+                    .run(&mut SliceInput::new(b""), &mut output);
+                (result, output.into_bytes())
+            };
+            for eof in [EofPolicy::Zero, EofPolicy::Error, EofPolicy::Halt] {
+                assert_eq!(run_simple(eof), run(eof, b""));
+            }
+        }
+    }
+
+    #[test]
+    fn io_ports_go_to_their_callbacks() {
+        use crate::OptPasses;
+
+        // Cell 1 is a device which always reads as 7, and records what's written to it
+        let written = Rc::new(RefCell::new(Vec::new()));
+        let options = crate::ParseOptions {
+            passes: OptPasses::none(),
+            ..crate::ParseOptions::default()
+        };
+        let code = crate::optimized_parse_with_options("+>.,+<.", &options).unwrap().code;
+        let mut vm = OptVM::new(code);
+        let seen = Rc::clone(&written);
+        vm.map_io_port(1, || 7, move |byte| seen.borrow_mut().push(byte));
+
+        let mut output = VecOutput::new();
+        vm.run(&mut SliceInput::new(b"A"), &mut output).unwrap();
+
+        // The read goes to the device, and so does the add, which is to the 7 it reads
+        assert_eq!(output.into_bytes(), vec![7, 1]);
+        assert_eq!(*written.borrow(), vec![b'A', 8]);
+    }
+
+    #[test]
+    fn resets_clear_io_ports() {
+        let written = Rc::new(RefCell::new(Vec::new()));
+        let code = vec![
+            CompiledInstr::SetData { amount: 5, dp_offset: 1 },
+            CompiledInstr::ResetTape,
+            CompiledInstr::AddData { amount: 2, dp_offset: 1 },
+        ];
+        let mut vm = OptVM::new(code);
+        let seen = Rc::clone(&written);
+        vm.map_io_port(1, || 0, move |byte| seen.borrow_mut().push(byte));
+        // Watching the port as well mustn't write it twice
+        vm.watch(1, |_| {});
+        vm.run(&mut SliceInput::new(b""), &mut VecOutput::new()).unwrap();
+
+        assert_eq!(*written.borrow(), vec![5, 0, 2]);
+    }
+}
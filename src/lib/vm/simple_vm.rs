@@ -0,0 +1,225 @@
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use core::time::Duration;
+
+use super::dry_run::ZeroInput;
+use super::machine::Machine;
+use super::{DryRunReport, PointerMode, RuntimeError, Tape, VecTape, VmError, DEFAULT_TAPE_SIZE};
+use crate::io::{EofPolicy, Input, Output, VecOutput};
+use crate::BfInstr;
+use crate::OverflowPolicy;
+
+/// The data pointer is allowed to wander off either end of the tape; it's only an error to
+/// actually read or write a cell that isn't there. This is the same policy as OptVM, where
+/// consecutive pointer moves get folded together, so a brief trip off the tape may not happen
+/// at all in the optimized code. With PointerMode::Wrapping, the tape is a circle instead.
+///
+/// The tape is a VecTape unless it's swapped out with with_tape.
+pub struct SimpleVM<T: Tape = VecTape> {
+    instr: Vec<BfInstr>,
+    ip: usize,
+    machine: Machine<T>,
+}
+
+impl SimpleVM {
+    pub fn new(code: Vec<BfInstr>) -> Self {
+        Self {
+            instr: code,
+            ip: 0,
+            machine: Machine::new(),
+        }
+    }
+
+    /// A VM whose cells all start at value, rather than zero; as with OptVM::with_fill
+    pub fn with_fill(code: Vec<BfInstr>, value: u8) -> Self {
+        SimpleVM::new(code).with_tape(VecTape::filled(DEFAULT_TAPE_SIZE, value))
+    }
+}
+
+impl<T: Tape> SimpleVM<T> {
+    /// Replace the tape with a blank one of the given length
+    pub fn with_tape_size(self, tape_size: usize) -> SimpleVM<VecTape> {
+        self.with_tape(VecTape::new(tape_size))
+    }
+
+    /// Replace the tape with the given one
+    pub fn with_tape<U: Tape>(self, tape: U) -> SimpleVM<U> {
+        SimpleVM {
+            instr: self.instr,
+            ip: self.ip,
+            machine: self.machine.with_tape(tape),
+        }
+    }
+
+    pub fn tape(&self) -> &T {
+        &self.machine.tape
+    }
+
+    /// The lowest and highest cells the program has read or written so far, as with OptVM::tape_usage
+    pub fn tape_usage(&self) -> Option<(usize, usize)> {
+        self.machine.tape.usage()
+    }
+
+    /// Where in the source the next instruction to run is, which after step fails is the one
+    /// that failed; None once the program has finished
+    pub fn code_p(&self) -> Option<usize> {
+        self.instr.get(self.ip).map(BfInstr::code_p)
+    }
+
+    /// Give up with StepLimitExceeded once this many instructions have been executed
+    pub fn with_max_steps(mut self, max_steps: u64) -> Self {
+        self.machine.max_steps = Some(max_steps);
+        self
+    }
+
+    /// What to do when a cell goes past 0 or 255. This has to match what the code was
+    /// optimized for, if it was.
+    pub fn with_overflow_policy(mut self, overflow: OverflowPolicy) -> Self {
+        self.machine.overflow = overflow;
+        self
+    }
+
+    /// What to do when the data pointer goes past either end of the tape (see PointerMode)
+    pub fn with_pointer_mode(mut self, pointer: PointerMode) -> Self {
+        self.machine.pointer = pointer;
+        self
+    }
+
+    /// What to do when the program reads past the end of its input (see EofPolicy)
+    pub fn with_eof_policy(mut self, eof: EofPolicy) -> Self {
+        self.machine.eof = eof;
+        self
+    }
+
+    fn cell(&self) -> Result<u8, RuntimeError> {
+        self.machine.cell_index(0).map(|i| self.machine.tape.get(i))
+    }
+
+    // Add a signed amount to the current cell, according to the overflow policy
+    fn add_to_cell(&mut self, amount: u8) -> Result<(), RuntimeError> {
+        let i = self.machine.cell_index(0)?;
+        let new_val = self.machine.added(i, amount)?;
+        self.machine.tape.set(i, new_val);
+        Ok(())
+    }
+
+    /// Replace the code being run; the tape and data pointer are left as they are, so this can
+    /// be used to run several programs one after another on the same machine.
+    pub fn load(&mut self, code: Vec<BfInstr>) {
+        self.instr = code;
+        self.ip = 0;
+    }
+
+    pub fn run<I: Input, O: Output>(mut self, input: &mut I, output: &mut O) -> Result<(), VmError<I::InputError, O::OutputError>> {
+        while self.step(input, output)? {}
+
+        log!("Executing took {} instructions", self.machine.steps);
+        if let Some((lowest, highest)) = self.machine.tape.usage() {
+            log!("Used cells {} to {}", lowest, highest);
+        }
+
+        Ok(())
+    }
+
+    /// Like run, but gives up with RuntimeError::Timeout once the program has been running for
+    /// longer than the timeout. The clock is only checked every so often, so this can overshoot
+    /// by a little.
+    #[cfg(feature = "std")]
+    pub fn run_with_timeout<I: Input, O: Output>(
+        mut self,
+        input: &mut I,
+        output: &mut O,
+        timeout: Duration,
+    ) -> Result<(), VmError<I::InputError, O::OutputError>> {
+        let start = std::time::Instant::now();
+
+        while self.step(input, output)? {
+            if self.machine.steps.is_multiple_of(super::CLOCK_CHECK_INTERVAL) && start.elapsed() > timeout {
+                return Err(RuntimeError::Timeout { timeout }.into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Run the program with no input (every read is EOF) for at most max_steps instructions,
+    /// collecting what it writes rather than sending it anywhere. Errors go in the report too.
+    pub fn dry_run(mut self, max_steps: u64) -> DryRunReport {
+        self.machine.max_steps = Some(max_steps);
+        let mut input = ZeroInput::default();
+        let mut output = VecOutput::new();
+        let result = loop {
+            match self.step(&mut input, &mut output) {
+                Ok(true) => {}
+                Ok(false) => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        };
+        DryRunReport::new(result, self.machine.steps, input, output)
+    }
+
+    /// Execute a single instruction. Returns false (without doing anything) if the program
+    /// has already finished.
+    pub fn step<I: Input, O: Output>(&mut self, input: &mut I, output: &mut O) -> Result<bool, VmError<I::InputError, O::OutputError>> {
+        if self.ip >= self.instr.len() {
+            return Ok(false);
+        }
+
+        self.machine.take_step()?;
+
+        match *self.instr.get(self.ip).unwrap() {
+            BfInstr::LoopEnd { start_ip, .. } => {
+                if self.cell()? != 0 {
+                    self.ip = start_ip;
+                } else {
+                    self.ip += 1;
+                }
+            }
+            BfInstr::LoopStart { end_ip, .. } => {
+                if self.cell()? == 0 {
+                    self.ip = end_ip + 1;
+                } else {
+                    self.ip += 1;
+                }
+            }
+            BfInstr::IncByte { .. } => {
+                self.add_to_cell(1)?;
+                self.ip += 1;
+            }
+            BfInstr::DecByte { .. } => {
+                self.add_to_cell(u8::MAX)?;
+                self.ip += 1;
+            }
+            BfInstr::IncPtr { .. } => {
+                self.machine.move_ptr(1, false)?;
+                self.ip += 1;
+            }
+            BfInstr::DecPtr { .. } => {
+                self.machine.move_ptr(1, true)?;
+                self.ip += 1;
+            }
+            BfInstr::ReadByte { .. } => {
+                let i = self.machine.cell_index(0)?;
+                let read = match input.read_byte_or_eof().map_err(VmError::Input)? {
+                    Some(byte) => byte,
+                    None => match self.machine.eof_read(i)? {
+                        Some(byte) => byte,
+                        None => {
+                            self.ip = self.instr.len();
+                            return Ok(true);
+                        }
+                    },
+                };
+                self.machine.tape.set(i, read);
+                self.ip += 1;
+            }
+            BfInstr::WriteByte { .. } => {
+                let write = self.cell()?;
+                output.write_byte(write).map_err(VmError::Output)?;
+                self.ip += 1;
+            }
+        }
+
+        Ok(true)
+    }
+}
@@ -0,0 +1,377 @@
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::Cell;
+use core::convert::{TryFrom, TryInto};
+
+use super::RuntimeError;
+
+/// The memory a VM runs on. Cells are indexed from 0 up to (but not including) len; the VMs
+/// check the data pointer against len before every access, so get and set are only ever called
+/// with an index that's in range, and can panic otherwise.
+pub trait Tape {
+    fn get(&self, i: usize) -> u8;
+
+    fn set(&mut self, i: usize, v: u8);
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Set every cell back to zero
+    fn clear(&mut self) {
+        for i in 0..self.len() {
+            self.set(i, 0);
+        }
+    }
+
+    /// The lowest and highest cells read or written so far, for a tape which keeps track of
+    /// that (see UsageTape); None if it doesn't, or nothing has been.
+    fn usage(&self) -> Option<(usize, usize)> {
+        None
+    }
+}
+
+/// A tape whose length is part of its type, e.g. the classic FixedTape<30_000>. The cells live on
+/// the heap anyway, since a big array on the stack is asking for trouble.
+#[derive(Clone)]
+pub struct FixedTape<const N: usize> {
+    cells: Box<[u8; N]>,
+}
+
+impl<const N: usize> FixedTape<N> {
+    pub fn new() -> Self {
+        let cells: Box<[u8]> = vec![0; N].into_boxed_slice();
+        FixedTape {
+            cells: cells.try_into().expect("The slice has length N"),
+        }
+    }
+}
+
+impl<const N: usize> Default for FixedTape<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Tape for FixedTape<N> {
+    fn get(&self, i: usize) -> u8 {
+        self.cells[i]
+    }
+
+    fn set(&mut self, i: usize, v: u8) {
+        self.cells[i] = v;
+    }
+
+    fn len(&self) -> usize {
+        N
+    }
+
+    fn clear(&mut self) {
+        self.cells.fill(0);
+    }
+}
+
+/// A tape whose length is picked at runtime; this is what the VMs use unless they're told otherwise.
+#[derive(Clone)]
+pub struct VecTape {
+    cells: Vec<u8>,
+}
+
+impl VecTape {
+    pub fn new(len: usize) -> Self {
+        VecTape { cells: vec![0; len] }
+    }
+
+    /// A tape whose cells all start at value, rather than zero
+    pub fn filled(len: usize, value: u8) -> Self {
+        VecTape { cells: vec![value; len] }
+    }
+}
+
+impl Tape for VecTape {
+    fn get(&self, i: usize) -> u8 {
+        self.cells[i]
+    }
+
+    fn set(&mut self, i: usize, v: u8) {
+        self.cells[i] = v;
+    }
+
+    fn len(&self) -> usize {
+        self.cells.len()
+    }
+
+    fn clear(&mut self) {
+        self.cells.fill(0);
+    }
+}
+
+/// A tape with no right end, which only takes up as much memory as the furthest cell written so
+/// far. There's still a left end, since the data pointer starts at zero. Something like +[>+]
+/// will happily eat all the memory there is, so this goes well with a step limit.
+#[derive(Clone, Default)]
+pub struct GrowableTape {
+    cells: Vec<u8>,
+}
+
+impl GrowableTape {
+    pub fn new() -> Self {
+        GrowableTape { cells: Vec::new() }
+    }
+}
+
+impl Tape for GrowableTape {
+    fn get(&self, i: usize) -> u8 {
+        self.cells.get(i).copied().unwrap_or(0)
+    }
+
+    fn set(&mut self, i: usize, v: u8) {
+        if i >= self.cells.len() {
+            if v == 0 {
+                return;
+            }
+            self.cells.resize(i + 1, 0);
+        }
+        self.cells[i] = v;
+    }
+
+    // No Vec can be longer than this anyway
+    fn len(&self) -> usize {
+        isize::MAX as usize
+    }
+
+    // Every cell past the end of the Vec is already zero
+    fn clear(&mut self) {
+        self.cells.clear();
+    }
+}
+
+/// Another tape, which also keeps track of the lowest and highest cells read or written, for
+/// working out how big a tape a program really needs; see Tape::usage. Only the cells the VM
+/// actually looks at count, not those dp just passes over, and clearing doesn't count. That's
+/// a little extra work on every access, so the VMs don't do it unless they're given one of these.
+#[derive(Clone)]
+pub struct UsageTape<T: Tape> {
+    tape: T,
+    // lowest > highest until a cell has been used
+    lowest: Cell<usize>,
+    highest: Cell<usize>,
+}
+
+impl<T: Tape> UsageTape<T> {
+    pub fn new(tape: T) -> Self {
+        UsageTape {
+            tape,
+            lowest: Cell::new(usize::MAX),
+            highest: Cell::new(0),
+        }
+    }
+
+    /// The tape underneath
+    pub fn inner(&self) -> &T {
+        &self.tape
+    }
+
+    fn used(&self, i: usize) {
+        self.lowest.set(self.lowest.get().min(i));
+        self.highest.set(self.highest.get().max(i));
+    }
+}
+
+impl<T: Tape> Tape for UsageTape<T> {
+    fn get(&self, i: usize) -> u8 {
+        self.used(i);
+        self.tape.get(i)
+    }
+
+    fn set(&mut self, i: usize, v: u8) {
+        self.used(i);
+        self.tape.set(i, v);
+    }
+
+    fn len(&self) -> usize {
+        self.tape.len()
+    }
+
+    fn clear(&mut self) {
+        self.tape.clear();
+    }
+
+    fn usage(&self) -> Option<(usize, usize)> {
+        let (lowest, highest) = (self.lowest.get(), self.highest.get());
+        if lowest <= highest {
+            Some((lowest, highest))
+        } else {
+            None
+        }
+    }
+}
+
+/// What happens when the data pointer goes past either end of the tape.
+///
+/// The optimizer assumes different offsets are different cells, which isn't true on a circular
+/// tape shorter than the stretch of cells a piece of code touches; so for OptVM, a Wrapping tape
+/// should be comfortably longer than that.
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub enum PointerMode {
+    /// The pointer can wander off the tape, but touching a cell that isn't there is a RuntimeError::OutOfBounds
+    #[default]
+    Bounded,
+    /// The tape is a circle, so moving left from the first cell lands on the last one, and the
+    /// pointer is always on the tape (unless the tape is empty)
+    Wrapping,
+}
+
+impl PointerMode {
+    // dp moved by amount, to the left or the right; None if that's so far off the tape that dp
+    // can't even represent it
+    pub(crate) fn move_dp(self, dp: isize, amount: usize, left: bool, len: usize) -> Option<isize> {
+        match self {
+            PointerMode::Bounded => {
+                isize::try_from(amount)
+                    .ok()
+                    .and_then(|amount| if left { dp.checked_sub(amount) } else { dp.checked_add(amount) })
+            }
+            PointerMode::Wrapping if len == 0 => Some(dp),
+            PointerMode::Wrapping => {
+                let amount = (amount % len) as i128;
+                let moved = if left { dp as i128 - amount } else { dp as i128 + amount };
+                Some(moved.rem_euclid(len as i128) as isize)
+            }
+        }
+    }
+
+    // The index of data[dp + dp_offset] on a tape of length len, if that's on the tape
+    pub(crate) fn cell_index(self, dp: isize, dp_offset: isize, len: usize) -> Result<usize, RuntimeError> {
+        match self {
+            PointerMode::Wrapping if len > 0 => Ok((dp as i128 + dp_offset as i128).rem_euclid(len as i128) as usize),
+            _ => {
+                let dp = dp.saturating_add(dp_offset);
+                if dp >= 0 && (dp as usize) < len {
+                    Ok(dp as usize)
+                } else {
+                    Err(RuntimeError::OutOfBounds { dp, role: None })
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn growable_tape_grows_when_written() {
+        let mut tape = GrowableTape::new();
+        assert_eq!(tape.get(1_000_000), 0);
+        tape.set(1_000_000, 0);
+        assert!(tape.cells.is_empty());
+
+        tape.set(10, 5);
+        assert_eq!(tape.cells.len(), 11);
+        assert_eq!(tape.get(10), 5);
+        assert_eq!(tape.get(9), 0);
+    }
+
+    #[test]
+    fn fixed_tape_has_its_length() {
+        let mut tape = FixedTape::<3>::new();
+        assert_eq!(tape.len(), 3);
+        tape.set(2, 7);
+        assert_eq!(tape.get(2), 7);
+    }
+
+    #[test]
+    #[cfg(feature = "simple")]
+    fn vms_run_on_any_tape() {
+        use crate::io::{SliceInput, VecOutput};
+        use crate::{OptVM, RuntimeError, SimpleVM, VmError};
+
+        // Carries a counter right 200 cells at a time, so it ends up well past the end of the default tape
+        let hop = ">".repeat(200);
+        let back = "<".repeat(200);
+        let source = format!("-[[-{}+{}]{}-]+.", hop, back, hop);
+
+        let mut output = VecOutput::new();
+        let vm = OptVM::new(crate::optimized_parse(&source).unwrap()).with_tape(GrowableTape::new());
+        vm.run(&mut SliceInput::new(b""), &mut output).unwrap();
+        assert_eq!(output.as_bytes(), &[1]);
+
+        let err = SimpleVM::new(crate::simple_parse(">>>+").unwrap())
+            .with_tape(FixedTape::<3>::new())
+            .run(&mut SliceInput::new(b""), &mut VecOutput::new())
+            .unwrap_err();
+        assert_eq!(err, VmError::Runtime(RuntimeError::OutOfBounds { dp: 3, role: None }));
+    }
+
+    #[test]
+    fn usage_tapes_track_the_cells_used() {
+        use crate::io::{SliceInput, VecOutput};
+        use crate::OptVM;
+
+        let usage = |source: &str, input: &[u8]| {
+            let mut vm = OptVM::new(crate::optimized_parse(source).unwrap()).with_tape(UsageTape::new(VecTape::new(1000)));
+            assert_eq!(vm.tape_usage(), None);
+            let mut input = SliceInput::new(input);
+            while vm.step(&mut input, &mut VecOutput::new()).unwrap() {}
+            vm.tape_usage()
+        };
+
+        // Reads into cells 0 to 100
+        let source = format!(",{}.", ">,".repeat(100));
+        assert_eq!(usage(&source, &[1; 101]), Some((0, 100)));
+
+        // It's the cells which count, not where dp goes (the optimizer doesn't move it here at all)
+        assert_eq!(usage(">>,>>,<<<<<<<<>>>>>>.", b"ab"), Some((2, 4)));
+        assert_eq!(usage("<<<>>>", b""), None);
+
+        // Other tapes don't keep track
+        let mut vm = OptVM::new(crate::optimized_parse(",.").unwrap());
+        while vm.step(&mut SliceInput::new(b"a"), &mut VecOutput::new()).unwrap() {}
+        assert_eq!(vm.tape_usage(), None);
+
+        #[cfg(feature = "simple")]
+        {
+            let mut vm =
+                crate::SimpleVM::new(crate::simple_parse(">>,>>,<<<<<<<<>>>>>>.").unwrap()).with_tape(UsageTape::new(VecTape::new(10)));
+            let mut input = SliceInput::new(b"ab");
+            while vm.step(&mut input, &mut VecOutput::new()).unwrap() {}
+            assert_eq!(vm.tape_usage(), Some((2, 4)));
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "simple")]
+    fn pointers_can_wrap() {
+        use crate::io::{SliceInput, VecOutput};
+        use crate::{OptVM, SimpleVM, VmError};
+
+        // Going left from cell 0 lands on the last cell
+        let mut vm = SimpleVM::new(crate::simple_parse("<+++").unwrap())
+            .with_tape_size(5)
+            .with_pointer_mode(PointerMode::Wrapping);
+        while vm.step(&mut SliceInput::new(b""), &mut VecOutput::new()).unwrap() {}
+        assert_eq!(vm.tape().get(4), 3);
+        assert_eq!(vm.tape().get(0), 0);
+
+        // Reads into cells 0, 1, 0, 1, ... on a tape of two cells, until it reads a zero
+        let source = ",[.<,]";
+        let mut output = VecOutput::new();
+        OptVM::new(crate::optimized_parse(source).unwrap())
+            .with_tape_size(2)
+            .with_pointer_mode(PointerMode::Wrapping)
+            .run(&mut SliceInput::new(&[3, 2, 1, 0]), &mut output)
+            .unwrap();
+        assert_eq!(output.as_bytes(), &[3, 2, 1]);
+
+        let err = OptVM::new(crate::optimized_parse(source).unwrap())
+            .with_tape_size(2)
+            .run(&mut SliceInput::new(&[3, 2, 1, 0]), &mut VecOutput::new())
+            .unwrap_err();
+        assert_eq!(err, VmError::Runtime(RuntimeError::OutOfBounds { dp: -1, role: None }));
+    }
+}